@@ -0,0 +1,748 @@
+use crate::itemize::Change;
+use crate::options::SyncOptions;
+use crate::store::{self, Tree, TreeEntry};
+use crate::{control, hash, state, timestamp, SyncError};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+// How a `--chmod` item changes the permission bits of a mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ModeChange {
+    Set(u32),
+    // `who` masks the bits `bits` may touch; `x_if_any` is `X`, execute for
+    // directories and for files someone may already execute.
+    Symbolic { who: u32, op: char, bits: u32, x_if_any: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ChmodRule {
+    dirs: bool,
+    files: bool,
+    change: ModeChange,
+}
+
+// Permission rules in rsync's `--chmod` syntax: comma-separated items, each
+// an octal mode or a chmod(1)-style `[ugoa]*[-+=][rwxXst]*` change,
+// optionally prefixed with `D` or `F` to apply to directories or files
+// only, e.g. `D755,F644` or `Fgo-w`. Items apply in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chmod {
+    rules: Vec<ChmodRule>,
+}
+
+impl std::str::FromStr for Chmod {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = |item: &str| format!("Invalid chmod rule: {} (expected e.g. D755, F644 or go-w)", item);
+        let mut rules = Vec::new();
+        for item in value.split(',').filter(|item| !item.is_empty()) {
+            let (dirs, files, spec) = match item.as_bytes()[0] {
+                b'D' => (true, false, &item[1..]),
+                b'F' => (false, true, &item[1..]),
+                _ => (true, true, item),
+            };
+            let change = if !spec.is_empty() && spec.len() <= 4 && spec.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+                ModeChange::Set(u32::from_str_radix(spec, 8).unwrap())
+            } else {
+                let at = spec.find(['+', '-', '=']).ok_or_else(|| invalid(item))?;
+                let mut who = 0;
+                for c in spec[..at].chars() {
+                    who |= match c {
+                        'u' => 0o4700,
+                        'g' => 0o2070,
+                        'o' => 0o1007,
+                        'a' => 0o7777,
+                        _ => return Err(invalid(item)),
+                    };
+                }
+                let (mut bits, mut x_if_any) = (0, false);
+                for c in spec[at + 1..].chars() {
+                    match c {
+                        'r' => bits |= 0o444,
+                        'w' => bits |= 0o222,
+                        'x' => bits |= 0o111,
+                        'X' => x_if_any = true,
+                        's' => bits |= 0o6000,
+                        't' => bits |= 0o1000,
+                        _ => return Err(invalid(item)),
+                    }
+                }
+                let who = if who == 0 { 0o7777 } else { who };
+                ModeChange::Symbolic { who, op: spec.as_bytes()[at] as char, bits, x_if_any }
+            };
+            rules.push(ChmodRule { dirs, files, change });
+        }
+        Ok(Chmod { rules })
+    }
+}
+
+impl FromIterator<Chmod> for Chmod {
+    fn from_iter<I: IntoIterator<Item = Chmod>>(iter: I) -> Self {
+        Chmod { rules: iter.into_iter().flat_map(|chmod| chmod.rules).collect() }
+    }
+}
+
+impl Chmod {
+    fn applies(&self, is_dir: bool) -> bool {
+        self.rules.iter().any(|rule| if is_dir { rule.dirs } else { rule.files })
+    }
+
+    // The permission bits of `mode` once the rules are applied.
+    fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let mut mode = mode & 0o7777;
+        for rule in self.rules.iter().filter(|rule| if is_dir { rule.dirs } else { rule.files }) {
+            mode = match rule.change {
+                ModeChange::Set(set) => set,
+                ModeChange::Symbolic { who, op, bits, x_if_any } => {
+                    let bits = if x_if_any && (is_dir || mode & 0o111 != 0) { bits | 0o111 } else { bits };
+                    match op {
+                        '+' => mode | (bits & who),
+                        '-' => mode & !(bits & who),
+                        _ => (mode & !who) | (bits & who),
+                    }
+                }
+            };
+        }
+        mode
+    }
+}
+
+// Owner and group given to everything a job writes, whatever owns the
+// source: `USER:GROUP`, `USER` or `:GROUP`, by name or numeric id.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Chown {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl std::str::FromStr for Chown {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (user, group) = value.split_once(':').unwrap_or((value, ""));
+        let uid = match user {
+            "" => None,
+            _ => Some(user.parse().or_else(|_| lookup_user(user)).map_err(|e| format!("Invalid owner {}: {}", user, e))?),
+        };
+        let gid = match group {
+            "" => None,
+            _ => Some(group.parse().or_else(|_| lookup_group(group)).map_err(|e| format!("Invalid group {}: {}", group, e))?),
+        };
+        if uid.is_none() && gid.is_none() {
+            return Err(format!("Invalid ownership: {} (expected USER:GROUP, USER or :GROUP)", value));
+        }
+        Ok(Chown { uid, gid })
+    }
+}
+
+impl Chown {
+    fn is_set(&self) -> bool {
+        self.uid.is_some() || self.gid.is_some()
+    }
+}
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<u32, String> {
+    let c_name = std::ffi::CString::new(name).map_err(|e| e.to_string())?;
+    let entry = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    match entry.is_null() {
+        true => Err("no such user".to_string()),
+        false => Ok(unsafe { (*entry).pw_uid }),
+    }
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<u32, String> {
+    let c_name = std::ffi::CString::new(name).map_err(|e| e.to_string())?;
+    let entry = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    match entry.is_null() {
+        true => Err("no such group".to_string()),
+        false => Ok(unsafe { (*entry).gr_gid }),
+    }
+}
+
+#[cfg(not(unix))]
+fn lookup_user(_name: &str) -> Result<u32, String> {
+    Err("ownership is not available on this platform".to_string())
+}
+
+#[cfg(not(unix))]
+fn lookup_group(_name: &str) -> Result<u32, String> {
+    Err("ownership is not available on this platform".to_string())
+}
+
+// Owner and group ids to give destination entries in place of their
+// source's, for files coming from a machine with a different user
+// database. Ids not listed carry over unchanged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IdMap {
+    users: HashMap<u32, u32>,
+    groups: HashMap<u32, u32>,
+}
+
+impl IdMap {
+    fn owners(&self, (uid, gid): (u32, u32)) -> (u32, u32) {
+        (self.users.get(&uid).copied().unwrap_or(uid), self.groups.get(&gid).copied().unwrap_or(gid))
+    }
+}
+
+// Parses an id map, one mapping per line:
+//
+//   alice:1000 -> 1500
+//   user 1001 -> bob
+//   group staff:20 -> 100
+//
+// Lines map users unless they start with `group`. The source side is an
+// id, optionally labelled with the name it has on the other machine, or a
+// name as known here; the destination side an id or a local name. With
+// `numeric`, names are never looked up and only ids are accepted.
+pub fn parse_id_map(text: &str, numeric: bool) -> Result<IdMap, SyncError> {
+    let mut map = IdMap::default();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |message: String| SyncError::ConfigError(format!("ID map line {}: {}", index + 1, message));
+        let (group, line) = match line.split_once(char::is_whitespace) {
+            Some(("group", rest)) => (true, rest.trim()),
+            Some(("user", rest)) => (false, rest.trim()),
+            _ => (false, line),
+        };
+        let (from, to) = line.split_once("->").ok_or_else(|| invalid("expected `source -> destination`".to_string()))?;
+        let resolve = |value: &str| -> Result<u32, SyncError> {
+            match value.parse() {
+                Ok(id) => Ok(id),
+                Err(_) if numeric => Err(invalid(format!("{} is not a numeric id", value))),
+                Err(_) if group => lookup_group(value).map_err(|e| invalid(format!("{}: {}", value, e))),
+                Err(_) => lookup_user(value).map_err(|e| invalid(format!("{}: {}", value, e))),
+            }
+        };
+        let from = from.trim();
+        let from = resolve(from.rsplit_once(':').map_or(from, |(_, id)| id))?;
+        let to = resolve(to.trim())?;
+        if group { map.groups.insert(from, to) } else { map.users.insert(from, to) };
+    }
+    Ok(map)
+}
+
+pub async fn load_id_map(path: &Path, numeric: bool) -> Result<IdMap, SyncError> {
+    parse_id_map(&tokio::fs::read_to_string(path).await?, numeric)
+}
+
+#[cfg(unix)]
+fn mode_of(permissions: &fs::Permissions) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    permissions.mode() & 0o7777
+}
+
+#[cfg(unix)]
+fn with_mode(_permissions: fs::Permissions, mode: u32) -> fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+    fs::Permissions::from_mode(mode)
+}
+
+// Elsewhere only the read-only flag is kept, taken from the write bits.
+#[cfg(not(unix))]
+fn mode_of(permissions: &fs::Permissions) -> u32 {
+    if permissions.readonly() {
+        0o555
+    } else {
+        0o777
+    }
+}
+
+#[cfg(not(unix))]
+fn with_mode(mut permissions: fs::Permissions, mode: u32) -> fs::Permissions {
+    permissions.set_readonly(mode & 0o222 == 0);
+    permissions
+}
+
+fn chmodded(permissions: fs::Permissions, is_dir: bool, options: &SyncOptions) -> fs::Permissions {
+    match options.chmod.applies(is_dir) {
+        true => {
+            let mode = options.chmod.apply(mode_of(&permissions), is_dir);
+            with_mode(permissions, mode)
+        }
+        false => permissions,
+    }
+}
+
+// Permissions and ownership an entry is meant to have.
+struct Access {
+    permissions: fs::Permissions,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+// What `dest` should have: the permissions of `source`, or its own without
+// one, with the `--chmod` rules applied, and the `--chown` owner or else
+// the source's, mapped through the id map.
+fn expected(source: Option<&fs::Metadata>, dest: &fs::Metadata, options: &SyncOptions) -> Access {
+    let permissions = chmodded(source.unwrap_or(dest).permissions(), dest.is_dir(), options);
+    let owners = source.and_then(owners).map(|owners| options.id_map.owners(owners));
+    Access {
+        permissions,
+        uid: options.chown.uid.or(owners.map(|(uid, _)| uid)),
+        gid: options.chown.gid.or(owners.map(|(_, gid)| gid)),
+    }
+}
+
+// Failing to apply `--chown` is only reported once per run.
+static UNOWNED_WARNED: AtomicBool = AtomicBool::new(false);
+
+// Gives a file or directory just written to `dest` the permissions of
+// `source` under the `--chmod` rules, and the `--chown` owner. Directories
+// are only touched when a rule or owner applies to them.
+pub fn settle(source: &fs::Metadata, dest: &Path, options: &SyncOptions) -> Result<(), SyncError> {
+    if source.is_dir() && !options.chmod.applies(true) && !options.chown.is_set() {
+        return Ok(());
+    }
+    let permissions = chmodded(source.permissions(), source.is_dir(), options);
+    if options.fake_super && options.chown.is_set() {
+        let (uid, gid) = owners(&fs::metadata(dest)?).unwrap_or_default();
+        record(dest, mode_of(&permissions), options.chown.uid.unwrap_or(uid), options.chown.gid.unwrap_or(gid));
+    }
+    fs::set_permissions(dest, permissions)?;
+    if options.chown.is_set() && !options.fake_super {
+        match chown(dest, options.chown.uid, options.chown.gid) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                if !UNOWNED_WARNED.swap(true, Ordering::Relaxed) {
+                    warn!("Couldn't give {:?} the --chown owner: {}; only root can give files away", dest, e);
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+// Which of a destination entry's attributes were brought in line.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Applied {
+    time: bool,
+    perms: bool,
+    owner: bool,
+    group: bool,
+}
+
+impl Applied {
+    fn any(&self) -> bool {
+        self.time || self.perms || self.owner || self.group
+    }
+}
+
+#[cfg(unix)]
+fn owners(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owners(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(unix)]
+fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> std::io::Result<()> {
+    std::os::unix::fs::chown(path, uid, gid)
+}
+
+#[cfg(not(unix))]
+fn chown(_path: &Path, _uid: Option<u32>, _gid: Option<u32>) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "ownership is not available on this platform"))
+}
+
+// Owners may set times through a read-only handle on Unix, which is all a
+// directory or a file `--chmod` made read-only can be opened with.
+pub fn set_modified(path: &Path, modified: std::time::SystemTime) -> std::io::Result<()> {
+    let file = File::options().write(true).open(path).or_else(|_| File::open(path))?;
+    file.set_modified(modified)
+}
+
+// Gives `dest` the permissions and, where allowed, owner and group in
+// `access`, or only works out which differ when `dry_run` is set. Only
+// root may give files away, so failing to change the owner is counted in
+// `unowned` rather than being an error. With `fake_super`, differing
+// owners are recorded instead of being changed.
+fn set_access(access: Access, dest_path: &Path, dest: &fs::Metadata, dry_run: bool, fake_super: bool, unowned: &mut usize) -> Result<Applied, SyncError> {
+    let mut applied = Applied::default();
+    if fake_super && !dry_run {
+        if let Some((dest_uid, dest_gid)) = owners(dest) {
+            let (uid, gid) = (access.uid.unwrap_or(dest_uid), access.gid.unwrap_or(dest_gid));
+            if (uid, gid) != (dest_uid, dest_gid) {
+                record(dest_path, mode_of(&access.permissions), uid, gid);
+            }
+        }
+    }
+    if mode_of(&access.permissions) != mode_of(&dest.permissions()) {
+        if !dry_run {
+            fs::set_permissions(dest_path, access.permissions)?;
+        }
+        applied.perms = true;
+    }
+    if let Some((dest_uid, dest_gid)) = owners(dest).filter(|_| !fake_super) {
+        let (uid, gid) = (access.uid.filter(|uid| *uid != dest_uid), access.gid.filter(|gid| *gid != dest_gid));
+        if uid.is_some() || gid.is_some() {
+            match if dry_run { Ok(()) } else { chown(dest_path, uid, gid) } {
+                Ok(()) => (applied.owner, applied.group) = (uid.is_some(), gid.is_some()),
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    debug!("Couldn't change the owner of {:?}: {}", dest_path, e);
+                    *unowned += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(applied)
+}
+
+// Gives `dest` the modification time and access of `source`. Times go
+// first, since opening a file to set them may need the write permission
+// the new mode takes away.
+fn apply(source: &fs::Metadata, dest_path: &Path, dest: &fs::Metadata, options: &SyncOptions, unowned: &mut usize) -> Result<Applied, SyncError> {
+    let mut time = false;
+    if let (Ok(src_modified), Ok(dest_modified)) = (source.modified(), dest.modified()) {
+        if !options.same_time(src_modified, dest_modified) {
+            set_modified(dest_path, src_modified)?;
+            time = true;
+        }
+    }
+    Ok(Applied { time, ..set_access(expected(Some(source), dest, options), dest_path, dest, false, options.fake_super, unowned)? })
+}
+
+// The hash `dest` shares with `source`, or None when their contents differ.
+// The destination's is reused from `cached`, its manifest entry, when the
+// file hasn't changed since it was recorded.
+async fn matching_hash(source: &Path, dest: &Path, dest_metadata: &fs::Metadata, cached: Option<&TreeEntry>, options: &SyncOptions) -> Result<Option<String>, SyncError> {
+    let TreeEntry::File { hash: dest_hash, .. } = state::manifest_entry(dest, dest_metadata, cached, options.hash, options.buffer_size).await? else {
+        return Ok(None);
+    };
+    let src_hash = hash::file(source, options.hash, options.buffer_size).await?;
+    Ok((src_hash == dest_hash).then_some(dest_hash))
+}
+
+// Brings the attributes of every destination entry whose contents already
+// match the source in line with it, without copying any data, as after an
+// initial copy made by another tool. Files that differ, or are missing
+// from the destination, are reported and left alone.
+pub async fn sync_metadata(source: &str, destination: &str, options: &SyncOptions) -> Result<(), SyncError> {
+    let dest_root = Path::new(destination);
+    let cached = state::load_manifest(&state::manifest_path(dest_root)).await?;
+    let mut hashed = Tree::new();
+    let (mut differing, mut unowned) = (0, 0);
+
+    for entry in WalkDir::new(source).min_depth(1).into_iter().filter_entry(|e| !state::is_state_dir(e)) {
+        control::checkpoint(options).await;
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(source)?;
+        let key = store::relative_key(relative);
+        let dest_path = dest_root.join(relative);
+        let src_metadata = entry.metadata()?;
+        let Ok(dest_metadata) = fs::metadata(&dest_path) else {
+            debug!("Skipping {:?}: not in the destination", dest_path);
+            continue;
+        };
+        if src_metadata.is_dir() != dest_metadata.is_dir() {
+            warn!("Skipping {:?}: a {} in the source", dest_path, if src_metadata.is_dir() { "directory" } else { "file" });
+            differing += 1;
+            continue;
+        }
+        let mut dest_hash = None;
+        if src_metadata.is_file() {
+            let hash = match src_metadata.len() == dest_metadata.len() {
+                true => matching_hash(entry.path(), &dest_path, &dest_metadata, cached.get(&key), options).await?,
+                false => None,
+            };
+            let Some(hash) = hash else {
+                warn!("Skipping {:?}: its contents differ from the source", dest_path);
+                differing += 1;
+                continue;
+            };
+            dest_hash = Some(hash);
+        }
+
+        let applied = apply(&src_metadata, &dest_path, &dest_metadata, options, &mut unowned)?;
+        if applied.any() {
+            info!("Updating attributes of {:?}", dest_path);
+            let change = Change::Attributes { dir: src_metadata.is_dir(), time: applied.time, perms: applied.perms, owner: applied.owner, group: applied.group };
+            options.observer.on_change(relative, &change);
+        } else {
+            debug!("Skipping unchanged attributes: {:?}", dest_path);
+        }
+        if let Some(hash) = dest_hash {
+            let metadata = fs::metadata(&dest_path)?;
+            hashed.insert(key, TreeEntry::File { hash, size: metadata.len(), modified: timestamp::to_secs(metadata.modified()?) });
+        }
+    }
+
+    if differing > 0 {
+        warn!("{} entries differ from the source and were left alone; a mirror mode copies them", differing);
+    }
+    if unowned > 0 {
+        warn!("Couldn't change the owner of {} entries; only root can give files away", unowned);
+    }
+    state::update_manifest(dest_root, &hashed, options).await
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FixReport {
+    pub checked: usize,
+    // Entries whose permissions or ownership had drifted, relative to the
+    // destination, with what differed.
+    pub drifted: Vec<(PathBuf, Change)>,
+    // Destination entries with nothing to compare against in the source.
+    pub unmatched: usize,
+    pub unowned: usize,
+}
+
+// Walks `destination` and puts back the permissions and ownership expected
+// from the matching entry in `source` and the `--chmod` and `--chown` rules
+// in `options` wherever they have drifted, or only reports the drift when
+// `dry_run` is set. Without a source, only the rules are enforced.
+// Contents and times are left alone.
+pub async fn fix_perms(source: Option<&Path>, destination: &Path, dry_run: bool, options: &SyncOptions) -> Result<FixReport, SyncError> {
+    let mut report = FixReport::default();
+    for entry in WalkDir::new(destination).min_depth(1).into_iter().filter_entry(|e| !state::is_state_dir(e)) {
+        let entry = entry?;
+        if entry.path_is_symlink() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(destination)?;
+        let src_metadata = match source {
+            Some(source) => match fs::metadata(source.join(relative)) {
+                Ok(metadata) => Some(metadata),
+                Err(_) => {
+                    report.unmatched += 1;
+                    continue;
+                }
+            },
+            None => None,
+        };
+        report.checked += 1;
+        let metadata = entry.metadata()?;
+        let access = expected(src_metadata.as_ref(), &metadata, options);
+        let applied = set_access(access, entry.path(), &metadata, dry_run, options.fake_super, &mut report.unowned)?;
+        if applied.any() {
+            if !dry_run {
+                info!("Fixed the permissions of {:?}", entry.path());
+            }
+            let change = Change::Attributes { dir: entry.file_type().is_dir(), time: false, perms: applied.perms, owner: applied.owner, group: applied.group };
+            report.drifted.push((relative.to_path_buf(), change));
+        }
+    }
+    if options.fake_super && !dry_run {
+        save_recorded(destination).await?;
+    }
+    Ok(report)
+}
+
+// Ownership that couldn't be given, kept for a privileged run to apply.
+// Unprivileged users can't give files away, so with `--fake-super` the
+// intended owner and mode of each entry go into the destination's state
+// directory instead, like rsync's `--fake-super` keeps them in xattrs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Recorded {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+// Recorded during a pass, deep in the copy code, and saved at its end.
+static PENDING: Mutex<BTreeMap<PathBuf, Recorded>> = Mutex::new(BTreeMap::new());
+
+fn record(path: &Path, mode: u32, uid: u32, gid: u32) {
+    PENDING.lock().unwrap().insert(path.to_path_buf(), Recorded { mode, uid, gid });
+}
+
+pub fn recorded_path(destination: &Path) -> PathBuf {
+    state::state_dir(destination).join("fake-super")
+}
+
+// One `<mode> <uid>:<gid> <path>` line per entry, mode in octal.
+async fn load_recorded(destination: &Path) -> Result<BTreeMap<String, Recorded>, SyncError> {
+    let path = recorded_path(destination);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let mut recorded = BTreeMap::new();
+    for (index, line) in tokio::fs::read_to_string(&path).await?.lines().enumerate() {
+        let invalid = || SyncError::ConfigError(format!("{:?} line {}: expected `<mode> <uid>:<gid> <path>`", path, index + 1));
+        let mut parts = line.splitn(3, ' ');
+        let (Some(mode), Some(owner), Some(key)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let (uid, gid) = owner.split_once(':').ok_or_else(invalid)?;
+        let entry = Recorded {
+            mode: u32::from_str_radix(mode, 8).map_err(|_| invalid())?,
+            uid: uid.parse().map_err(|_| invalid())?,
+            gid: gid.parse().map_err(|_| invalid())?,
+        };
+        recorded.insert(key.to_string(), entry);
+    }
+    Ok(recorded)
+}
+
+async fn write_recorded(destination: &Path, recorded: &BTreeMap<String, Recorded>) -> Result<(), SyncError> {
+    let path = recorded_path(destination);
+    if recorded.is_empty() {
+        if path.exists() {
+            tokio::fs::remove_file(&path).await?;
+        }
+        return Ok(());
+    }
+    let text: String = recorded.iter().map(|(key, entry)| format!("{:o} {}:{} {}\n", entry.mode, entry.uid, entry.gid, key)).collect();
+    tokio::fs::create_dir_all(state::state_dir(destination)).await?;
+    tokio::fs::write(&path, text).await?;
+    Ok(())
+}
+
+// Adds what was recorded below `destination` to its saved records, and
+// forgets records of entries that are gone.
+pub async fn save_recorded(destination: &Path) -> Result<(), SyncError> {
+    let pending: BTreeMap<PathBuf, Recorded> = {
+        let mut pending = PENDING.lock().unwrap();
+        let (below, rest) = std::mem::take(&mut *pending).into_iter().partition(|(path, _)| path.starts_with(destination));
+        *pending = rest;
+        below
+    };
+    let saved = load_recorded(destination).await?;
+    let mut recorded = saved.clone();
+    for (path, entry) in pending {
+        recorded.insert(store::relative_key(path.strip_prefix(destination)?), entry);
+    }
+    recorded.retain(|key, _| fs::symlink_metadata(store::key_path(destination, key)).is_ok());
+    if recorded != saved {
+        debug!("Recording intended ownership of {} entries", recorded.len());
+        write_recorded(destination, &recorded).await?;
+    }
+    Ok(())
+}
+
+// Gives every entry recorded under `destination` by `--fake-super` its
+// intended owner and mode, as root restoring a backup made without root
+// would, or only reports which differ when `dry_run` is set. Applied
+// records are dropped.
+pub async fn apply_recorded(destination: &Path, dry_run: bool) -> Result<FixReport, SyncError> {
+    let mut report = FixReport::default();
+    let mut recorded = load_recorded(destination).await?;
+    let mut applied_keys = Vec::new();
+    for (key, entry) in &recorded {
+        let path = store::key_path(destination, key);
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            report.unmatched += 1;
+            continue;
+        };
+        report.checked += 1;
+        let access = Access { permissions: with_mode(metadata.permissions(), entry.mode), uid: Some(entry.uid), gid: Some(entry.gid) };
+        let before = report.unowned;
+        let applied = set_access(access, &path, &metadata, dry_run, false, &mut report.unowned)?;
+        if report.unowned == before {
+            applied_keys.push(key.clone());
+        }
+        if applied.any() {
+            let change = Change::Attributes { dir: metadata.is_dir(), time: false, perms: applied.perms, owner: applied.owner, group: applied.group };
+            report.drifted.push((PathBuf::from(key), change));
+        }
+    }
+    if !dry_run {
+        for key in applied_keys {
+            recorded.remove(&key);
+        }
+        write_recorded(destination, &recorded).await?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chmod(rules: &str) -> Chmod {
+        rules.parse().unwrap()
+    }
+
+    #[test]
+    fn sets_octal_modes_for_dirs_and_files_apart() {
+        let rules = chmod("D755,F644");
+        assert_eq!(rules.apply(0o700, true), 0o755);
+        assert_eq!(rules.apply(0o777, false), 0o644);
+        assert!(chmod("D755").applies(true));
+        assert!(!chmod("D755").applies(false));
+        assert!(!Chmod::default().applies(true));
+    }
+
+    #[test]
+    fn applies_symbolic_changes() {
+        assert_eq!(chmod("go-w").apply(0o777, false), 0o755);
+        assert_eq!(chmod("u+x").apply(0o644, false), 0o744);
+        assert_eq!(chmod("u=rw").apply(0o777, false), 0o677);
+        assert_eq!(chmod("o=").apply(0o777, true), 0o770);
+        // Without `ugoa` a change applies to everyone.
+        assert_eq!(chmod("+x").apply(0o644, false), 0o755);
+        assert_eq!(chmod("a+t").apply(0o777, true), 0o1777);
+        assert_eq!(chmod("g+s").apply(0o755, true), 0o2755);
+        // Only the permission bits of a full mode are kept.
+        assert_eq!(chmod("go-w").apply(0o100666, false), 0o644);
+    }
+
+    #[test]
+    fn gives_capital_x_to_directories_and_executables() {
+        let rules = chmod("a+X");
+        assert_eq!(rules.apply(0o644, true), 0o755);
+        assert_eq!(rules.apply(0o644, false), 0o644);
+        assert_eq!(rules.apply(0o744, false), 0o755);
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        assert_eq!(chmod("F644,Fu+x").apply(0o600, false), 0o744);
+        assert_eq!(chmod("Fu+x,F644").apply(0o600, false), 0o644);
+        let joined: Chmod = [chmod("F644"), chmod("F600")].into_iter().collect();
+        assert_eq!(joined.apply(0o777, false), 0o600);
+        assert_eq!(chmod(""), Chmod::default());
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for rules in ["D", "F", "755x", "77777", "8755", "q+w", "u+z", "go", "D755,Fqq"] {
+            assert!(rules.parse::<Chmod>().is_err(), "{}", rules);
+        }
+    }
+
+    #[test]
+    fn maps_users_and_groups_by_id() {
+        let text = "# from the old server\nalice:1000 -> 1500\nuser 1001 -> 1501  # bob\n\ngroup staff:20 -> 100\n";
+        let map = parse_id_map(text, true).unwrap();
+        assert_eq!(map.owners((1000, 20)), (1500, 100));
+        assert_eq!(map.owners((1001, 1000)), (1501, 1000));
+        // Ids the map doesn't list carry over.
+        assert_eq!(map.owners((7, 8)), (7, 8));
+        assert_eq!(parse_id_map("", true).unwrap(), IdMap::default());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn looks_up_local_names_unless_numeric() {
+        let map = parse_id_map("5000 -> root\ngroup 5000 -> root", false).unwrap();
+        assert_eq!(map.owners((5000, 5000)), (0, 0));
+        let err = parse_id_map("5000 -> root", true).unwrap_err().to_string();
+        assert!(err.contains("line 1: root is not a numeric id"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_malformed_id_maps() {
+        for text in ["1000 1500", "1000 -> ", "1000 -> -1", "\nuser 1000 => 1500"] {
+            assert!(parse_id_map(text, true).is_err(), "{}", text);
+        }
+        let err = parse_id_map("1 -> 2\n\n3 4", true).unwrap_err().to_string();
+        assert!(err.contains("line 3"), "{}", err);
+    }
+}
@@ -0,0 +1,594 @@
+use crate::itemize::Change;
+use crate::options::{DeleteTiming, SyncOptions};
+use crate::memory::MemoryBackend;
+use crate::{check_deletions, control, faults, locked, metered, naming, paths, store, SyncError};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// An entry of a backend, addressed by its `/`-separated path relative to the
+// backend root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStat {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+// Where a backend pass (`sync_between`) can read from and write to. The
+// local filesystem and memory implement it, and other crates can add remote
+// ones through `register`. The local mirror, store and snapshot engines work
+// on the filesystem directly and don't go through it.
+pub trait StorageBackend: Send + Sync {
+    // Direct children of the directory `path` ("" is the root).
+    fn list(&self, path: &str) -> io::Result<Vec<FileStat>>;
+
+    fn stat(&self, path: &str) -> io::Result<Option<FileStat>>;
+
+    fn read(&self, path: &str) -> io::Result<Box<dyn Read + Send>>;
+
+    // Creates or truncates `path`, creating missing parent directories.
+    fn write(&self, path: &str) -> io::Result<Box<dyn Write + Send>>;
+
+    fn create_dir(&self, path: &str) -> io::Result<()>;
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    // Removes a file, or a directory with everything below it.
+    fn delete(&self, path: &str) -> io::Result<()>;
+
+    // Where `path` is on the local filesystem, for backends keeping it there.
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+
+    // Gives the file `path` the source's modification time once uploaded,
+    // for `stat` and `list` to report from then on, e.g. from object
+    // metadata. Backends that can't keep times leave their own.
+    fn set_modified(&self, _path: &str, _modified: SystemTime) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Whether writes are stamped by a clock other than this machine's, which
+    // `sync_between` then measures before comparing times. Backends keeping
+    // times through `set_modified` report the source's, so they return false.
+    fn remote_clock(&self) -> bool {
+        self.local_path("").is_none()
+    }
+}
+
+pub type BackendFactory = fn(&str) -> io::Result<Arc<dyn StorageBackend>>;
+
+// What a backend returns, wrapped in an `io::Error` by `throttled`, when
+// the service asks it to slow down, e.g. HTTP 429 or a provider's
+// throttling error code, so the request is made again later rather than
+// failing the pass.
+#[derive(Debug)]
+pub struct Throttled {
+    // How long the service asked to wait (`Retry-After`), when it said.
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "requests are being throttled")
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+pub fn throttled(retry_after: Option<Duration>) -> io::Error {
+    io::Error::other(Throttled { retry_after })
+}
+
+// Attempts made at a request the service keeps throttling, or that keeps
+// losing its connection.
+const THROTTLE_RETRIES: u32 = 8;
+
+// Errors of a connection that dropped or stopped answering, which a
+// backend reconnects from on its next request.
+fn disconnected(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::NotConnected | io::ErrorKind::BrokenPipe | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof
+    )
+}
+
+// Runs `request`, retrying while the backend reports being throttled or
+// its connection drops: after as long as the service asked, or else
+// backing off exponentially from a second to a minute. Passes make one
+// request at a time, so waiting is all there is to slow down, and a failed
+// upload starts over while the files before it stay done. It sleeps, so
+// callers run it on a blocking thread, never on the runtime's.
+fn patiently<T>(what: &str, mut request: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        let e = match request() {
+            Err(e) if attempt < THROTTLE_RETRIES => e,
+            result => return result,
+        };
+        let backoff = Duration::from_secs(1 << attempt.min(6));
+        let delay = match e.get_ref().and_then(|inner| inner.downcast_ref::<Throttled>()) {
+            Some(throttled) => {
+                let delay = throttled.retry_after.unwrap_or(backoff);
+                warn!("Throttled while {}, retrying in {}", what, humantime::format_duration(delay));
+                delay
+            }
+            None if disconnected(&e) => {
+                warn!("Connection lost while {} ({}), reconnecting in {}", what, e, humantime::format_duration(backoff));
+                backoff
+            }
+            None => return Err(e),
+        };
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+static BACKENDS: Mutex<BTreeMap<String, BackendFactory>> = Mutex::new(BTreeMap::new());
+
+// Makes destinations of the form `<scheme>://<location>` resolve to the
+// backend built by `factory` from `<location>`.
+pub fn register(scheme: &str, factory: BackendFactory) {
+    BACKENDS.lock().unwrap().insert(scheme.to_string(), factory);
+}
+
+fn local_factory(location: &str) -> io::Result<Arc<dyn StorageBackend>> {
+    Ok(Arc::new(LocalBackend::new(paths::file_url_path(location))))
+}
+
+fn memory_factory(location: &str) -> io::Result<Arc<dyn StorageBackend>> {
+    Ok(MemoryBackend::named(location))
+}
+
+// Returns None for plain paths, which the local engine handles directly.
+pub fn open(destination: &str) -> Option<Result<Arc<dyn StorageBackend>, SyncError>> {
+    let (scheme, location) = destination.split_once("://")?;
+    let factory = match scheme {
+        "file" => local_factory,
+        "memory" => memory_factory,
+        _ => match BACKENDS.lock().unwrap().get(scheme) {
+            Some(factory) => *factory,
+            None => return Some(Err(SyncError::ConfigError(format!("No storage backend registered for {}://", scheme)))),
+        },
+    };
+    Some(factory(location).map_err(SyncError::from))
+}
+
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalBackend { root: root.into() }
+    }
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        store::key_path(&self.root, path)
+    }
+}
+
+fn join_key(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+fn file_stat(path: String, metadata: &fs::Metadata) -> io::Result<FileStat> {
+    Ok(FileStat { path, is_dir: metadata.is_dir(), size: metadata.len(), modified: metadata.modified()? })
+}
+
+impl StorageBackend for LocalBackend {
+    fn list(&self, path: &str) -> io::Result<Vec<FileStat>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.resolve(path))? {
+            let entry = entry?;
+            let key = join_key(path, &entry.file_name().to_string_lossy());
+            entries.push(file_stat(key, &entry.metadata()?)?);
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Option<FileStat>> {
+        match fs::metadata(self.resolve(path)) {
+            Ok(metadata) => Ok(Some(file_stat(path.to_string(), &metadata)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read(&self, path: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(fs::File::open(self.resolve(path))?))
+    }
+
+    fn write(&self, path: &str) -> io::Result<Box<dyn Write + Send>> {
+        let full = self.resolve(path);
+        if let Some(parent) = full.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Box::new(fs::File::create(full)?))
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        fs::create_dir_all(self.resolve(path))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.resolve(from), self.resolve(to))
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        let full = self.resolve(path);
+        if full.is_dir() {
+            fs::remove_dir_all(full)
+        } else {
+            fs::remove_file(full)
+        }
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        Some(self.resolve(path))
+    }
+
+    fn set_modified(&self, path: &str, modified: SystemTime) -> io::Result<()> {
+        fs::File::options().write(true).open(self.resolve(path))?.set_modified(modified)
+    }
+}
+
+fn list_all(backend: &dyn StorageBackend, dir: &str, entries: &mut Vec<FileStat>) -> io::Result<()> {
+    for entry in patiently(&format!("listing {:?}", dir), || backend.list(dir))? {
+        let is_dir = entry.is_dir;
+        let path = entry.path.clone();
+        entries.push(entry);
+        if is_dir {
+            list_all(backend, &path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+// Why the stored copy `dest` is out of date, or None when it is current.
+fn change(source: &FileStat, dest: Option<&FileStat>, options: &SyncOptions) -> Option<Change> {
+    match dest {
+        Some(dest) if dest.is_dir => Some(Change::Created { dir: false }),
+        Some(dest) => {
+            let (size, time) = (dest.size != source.size, options.is_newer(source.modified, dest.modified, dest.size == source.size));
+            (size || time).then_some(Change::Updated { checksum: false, size, time })
+        }
+        None => Some(Change::Created { dir: false }),
+    }
+}
+
+// Files are written under a temporary name and renamed into place, so an
+// interrupted transfer never leaves a truncated file behind.
+fn upload(source: &dyn StorageBackend, from: &FileStat, backend: &dyn StorageBackend, key: &str, buffer_size: usize) -> io::Result<u64> {
+    let (parent, name) = key.rsplit_once('/').unwrap_or(("", key));
+    let tmp = join_key(parent, &format!(".{}.tmp", name));
+    let mut reader = source.read(&from.path)?;
+    let mut writer = backend.write(&tmp)?;
+    let mut buffer = vec![0; buffer_size];
+    let mut copied = 0;
+    loop {
+        faults::before_read();
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        faults::before_write()?;
+        writer.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+    if backend.stat(key)?.is_some_and(|stat| stat.is_dir) {
+        backend.delete(key)?;
+    }
+    faults::before_rename()?;
+    backend.rename(&tmp, key)?;
+    backend.set_modified(key, from.modified)?;
+    Ok(copied)
+}
+
+// Clock differences below this are put down to timestamp rounding and
+// the time a write takes.
+const SKEW_TOLERANCE: Duration = Duration::from_secs(2);
+
+// How far a backend's clock is from this machine's.
+#[derive(Debug, Clone, Copy)]
+struct ClockSkew {
+    ahead: bool,
+    by: Duration,
+}
+
+impl ClockSkew {
+    // `remote`, a time stamped by the backend, on this machine's clock.
+    fn to_local(self, remote: SystemTime) -> SystemTime {
+        if self.ahead {
+            remote.checked_sub(self.by).unwrap_or(remote)
+        } else {
+            remote.checked_add(self.by).unwrap_or(remote)
+        }
+    }
+}
+
+// Writes a probe file and compares the time the backend stamps it with
+// against this machine's clock, or None when they agree. Stored copies are
+// stamped with the backend's time of upload, so a backend clock running
+// behind makes recently changed sources look newer on every pass.
+fn measure_skew(backend: &dyn StorageBackend) -> io::Result<Option<ClockSkew>> {
+    let key = format!(".rusty_file_sync-clock-{}", std::process::id());
+    let before = SystemTime::now();
+    let mut writer = backend.write(&key)?;
+    writer.flush()?;
+    drop(writer);
+    let after = SystemTime::now();
+    let stamped = backend.stat(&key)?.map(|stat| stat.modified);
+    backend.delete(&key)?;
+    let Some(stamped) = stamped else {
+        return Ok(None);
+    };
+    let local = before + after.duration_since(before).unwrap_or_default() / 2;
+    let skew = match stamped.duration_since(local) {
+        Ok(by) => ClockSkew { ahead: true, by },
+        Err(e) => ClockSkew { ahead: false, by: e.duration() },
+    };
+    Ok((skew.by > SKEW_TOLERANCE).then_some(skew))
+}
+
+fn join_error(e: tokio::task::JoinError) -> SyncError {
+    SyncError::FileSystemError(io::Error::other(e))
+}
+
+// Everything in `backend`, parents before their children. A missing root
+// lists as empty when `missing_ok`, as a destination not created yet does.
+async fn list_tree(backend: Arc<dyn StorageBackend>, missing_ok: bool) -> Result<Vec<FileStat>, SyncError> {
+    tokio::task::spawn_blocking(move || {
+        let mut entries = Vec::new();
+        match list_all(backend.as_ref(), "", &mut entries) {
+            Err(e) if !(missing_ok && e.kind() == io::ErrorKind::NotFound) => Err(e),
+            _ => Ok(entries),
+        }
+    })
+    .await
+    .map_err(join_error)?
+    .map_err(SyncError::from)
+}
+
+// One-way mirror from a local directory to a backend.
+pub async fn sync_to_backend(source: &str, backend: Arc<dyn StorageBackend>, delete: bool, options: &SyncOptions) -> Result<(), SyncError> {
+    sync_between(Arc::new(LocalBackend::new(source)), backend, delete, options).await
+}
+
+// One-way mirror between backends, which only need the generic operations
+// above, so none of the local engine's I/O options apply. Locked and
+// metered-network checks only apply to sources on the local filesystem.
+pub async fn sync_between(source: Arc<dyn StorageBackend>, backend: Arc<dyn StorageBackend>, delete: bool, options: &SyncOptions) -> Result<(), SyncError> {
+    // The probe goes in before listing so it is never taken for a stray file.
+    let skew = match backend.remote_clock() {
+        false => None,
+        true => {
+            let probing = backend.clone();
+            match tokio::task::spawn_blocking(move || measure_skew(probing.as_ref())).await.map_err(join_error)? {
+                Ok(skew) => skew,
+                Err(e) => {
+                    warn!("Couldn't compare the destination's clock with this machine's: {}", e);
+                    None
+                }
+            }
+        }
+    };
+    if let Some(skew) = skew {
+        let by = humantime::format_duration(Duration::from_secs(skew.by.as_secs()));
+        warn!("The destination's clock is {} {} this machine's; allowing for it", by, if skew.ahead { "ahead of" } else { "behind" });
+    }
+    let existing = list_tree(backend.clone(), true).await?;
+    let existing: BTreeMap<String, FileStat> = existing
+        .into_iter()
+        .map(|entry| match skew {
+            Some(skew) => FileStat { modified: skew.to_local(entry.modified), ..entry },
+            None => entry,
+        })
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+    let mut seen = HashSet::new();
+    let mut mapper = naming::PathMapper::new(options);
+    let listing = list_tree(source.clone(), false).await?;
+    if delete && options.delete_timing == DeleteTiming::Before {
+        let mut mapper = naming::PathMapper::new(options);
+        let seen = listing.iter().filter_map(|entry| mapper.map(Path::new(&entry.path), entry.is_dir)).map(|mapped| store::relative_key(&mapped)).collect();
+        remove_missing(backend.clone(), existing.clone(), seen, listing.is_empty(), options.clone()).await?;
+    }
+    let mut source_empty = true;
+
+    for entry in listing {
+        control::checkpoint(options).await;
+        source_empty = false;
+        let Some(mapped) = mapper.map(Path::new(&entry.path), entry.is_dir) else {
+            continue;
+        };
+        let key = store::relative_key(&mapped);
+        seen.insert(key.clone());
+
+        if entry.is_dir {
+            if !existing.get(&key).is_some_and(|stat| stat.is_dir) {
+                info!("Creating directory: {}", key);
+                options.observer.on_change(&mapped, &Change::Created { dir: true });
+                let (creating, dir) = (backend.clone(), key.clone());
+                tokio::task::spawn_blocking(move || patiently(&format!("creating {}", dir), || creating.create_dir(&dir))).await.map_err(join_error)??;
+            }
+            continue;
+        }
+
+        // Observers see local paths where there are any, like the local engine.
+        let local = source.local_path(&entry.path);
+        let path = local.clone().unwrap_or_else(|| PathBuf::from(&entry.path));
+        let Some(change) = change(&entry, existing.get(&key), options) else {
+            debug!("Skipping unchanged file: {:?}", path);
+            continue;
+        };
+        if let Some(local) = &local {
+            if metered::defers(local, entry.size, options).await || !locked::readable(local, options).await? {
+                continue;
+            }
+        }
+        options.observer.on_change(&mapped, &change);
+
+        info!("Uploading {:?} to {}", path, key);
+        options.observer.on_file_start(&path);
+        let (reading, uploading, from, buffer_size) = (source.clone(), backend.clone(), entry.clone(), options.buffer_size);
+        let upload_key = key.clone();
+        let bytes = tokio::task::spawn_blocking(move || patiently(&format!("uploading {}", upload_key), || upload(reading.as_ref(), &from, uploading.as_ref(), &upload_key, buffer_size)))
+            .await
+            .map_err(join_error)??;
+        options.observer.on_file_done(&path, bytes);
+    }
+    if delete && options.delete_timing == DeleteTiming::After {
+        remove_missing(backend.clone(), existing, seen, source_empty, options.clone()).await?;
+    }
+    Ok(())
+}
+
+// Deletes what `backend` holds of `existing` but the source, whose mapped
+// keys are `seen`, doesn't. Removals run on a blocking thread like uploads,
+// since a throttled one can wait for minutes.
+async fn remove_missing(backend: Arc<dyn StorageBackend>, existing: BTreeMap<String, FileStat>, seen: HashSet<String>, source_empty: bool, options: SyncOptions) -> Result<(), SyncError> {
+    tokio::task::spawn_blocking(move || {
+        // Parents sort before their children, so skip anything already
+        // removed together with its directory.
+        let mut removed: Vec<&str> = Vec::new();
+        let deleting: Vec<&String> = existing.keys().filter(|key| !seen.contains(*key) && !options.is_marker(Path::new(key))).collect();
+        check_deletions(deleting.len(), existing.len(), source_empty, &options)?;
+        for key in deleting {
+            if removed.iter().any(|dir| key.starts_with(&format!("{}/", dir))) {
+                continue;
+            }
+            info!("Removing: {}", key);
+            options.observer.on_change(Path::new(key), &Change::Deleted);
+            patiently(&format!("removing {}", key), || backend.delete(key))?;
+            options.observer.on_remove(Path::new(key));
+            removed.push(key);
+        }
+        Ok(())
+    })
+    .await
+    .map_err(join_error)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn source() -> Arc<MemoryBackend> {
+        let source = MemoryBackend::new();
+        source.insert("a.txt", b"alpha").unwrap();
+        source.insert("dir/b.txt", b"beta").unwrap();
+        Arc::new(source)
+    }
+
+    fn modified(backend: &MemoryBackend, path: &str) -> SystemTime {
+        backend.stat(path).unwrap().unwrap().modified
+    }
+
+    #[tokio::test]
+    async fn mirrors_one_memory_backend_to_another() {
+        let (source, dest) = (source(), Arc::new(MemoryBackend::new()));
+        dest.insert("a.txt", b"old").unwrap();
+        dest.insert("old/c.txt", b"gone").unwrap();
+        sync_between(source.clone(), dest.clone(), true, &SyncOptions::default()).await.unwrap();
+        assert_eq!(dest.paths(), ["a.txt", "dir", "dir/b.txt"]);
+        assert_eq!(dest.contents("a.txt").as_deref(), Some(&b"alpha"[..]));
+        assert_eq!(dest.contents("dir/b.txt").as_deref(), Some(&b"beta"[..]));
+        // Copies take their source's times, which the source's clock made
+        // the same on every run.
+        assert_eq!(modified(&dest, "a.txt"), SystemTime::UNIX_EPOCH + Duration::from_secs(946_684_801));
+        assert_eq!(modified(&dest, "dir/b.txt"), modified(&source, "dir/b.txt"));
+    }
+
+    #[tokio::test]
+    async fn leaves_unchanged_copies_alone() {
+        let (source, dest) = (source(), Arc::new(MemoryBackend::new()));
+        sync_between(source.clone(), dest.clone(), true, &SyncOptions::default()).await.unwrap();
+        // Any upload would go through a write, which this makes fail.
+        dest.fail("write", usize::MAX, || io::Error::from(io::ErrorKind::PermissionDenied));
+        sync_between(source.clone(), dest.clone(), true, &SyncOptions::default()).await.unwrap();
+
+        source.insert("a.txt", b"changed").unwrap();
+        assert!(sync_between(source, dest, true, &SyncOptions::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_files_missing_from_the_source_without_delete() {
+        let dest = Arc::new(MemoryBackend::new());
+        dest.insert("extra.txt", b"kept").unwrap();
+        sync_between(source(), dest.clone(), false, &SyncOptions::default()).await.unwrap();
+        assert_eq!(dest.paths(), ["a.txt", "dir", "dir/b.txt", "extra.txt"]);
+    }
+
+    #[test]
+    fn opens_backends_by_scheme() {
+        assert!(open("/tmp/plain").is_none());
+        let local = open("file:///tmp/backend-tests").unwrap().unwrap();
+        assert_eq!(local.local_path("a.txt"), Some(PathBuf::from("/tmp/backend-tests/a.txt")));
+
+        MemoryBackend::named("backend-tests").insert("a.txt", b"alpha").unwrap();
+        let memory = open("memory://backend-tests").unwrap().unwrap();
+        assert_eq!(memory.local_path("a.txt"), None);
+        assert_eq!(memory.stat("a.txt").unwrap().map(|stat| stat.size), Some(5));
+
+        let Some(Err(SyncError::ConfigError(message))) = open("nowhere://bucket") else { panic!("unknown scheme opened") };
+        assert_eq!(message, "No storage backend registered for nowhere://");
+        register("nowhere", |location| Ok(MemoryBackend::named(location)));
+        assert!(open("nowhere://bucket").unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn waits_as_long_as_a_throttled_service_asks() {
+        let dest = Arc::new(MemoryBackend::new());
+        dest.fail("rename", 1, || throttled(Some(Duration::from_millis(200))));
+        let started = Instant::now();
+        sync_between(source(), dest.clone(), false, &SyncOptions::default()).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(dest.contents("a.txt").as_deref(), Some(&b"alpha"[..]));
+        assert_eq!(dest.contents("dir/b.txt").as_deref(), Some(&b"beta"[..]));
+    }
+
+    #[tokio::test]
+    async fn retries_a_throttled_request_up_to_the_limit() {
+        let dest = Arc::new(MemoryBackend::new());
+        dest.fail("write", THROTTLE_RETRIES as usize, || throttled(Some(Duration::from_millis(1))));
+        sync_between(source(), dest.clone(), false, &SyncOptions::default()).await.unwrap();
+        assert_eq!(dest.contents("a.txt").as_deref(), Some(&b"alpha"[..]));
+
+        let dest = Arc::new(MemoryBackend::new());
+        dest.fail("write", THROTTLE_RETRIES as usize + 1, || throttled(Some(Duration::from_millis(1))));
+        assert!(sync_between(source(), dest.clone(), false, &SyncOptions::default()).await.is_err());
+        assert_eq!(dest.contents("a.txt"), None);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_a_dropped_connection() {
+        let dest = Arc::new(MemoryBackend::new());
+        dest.insert("stale.txt", b"old").unwrap();
+        dest.fail("delete", 1, || io::Error::from(io::ErrorKind::ConnectionReset));
+        sync_between(source(), dest.clone(), true, &SyncOptions::default()).await.unwrap();
+        assert_eq!(dest.contents("stale.txt"), None);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_other_errors() {
+        let dest = Arc::new(MemoryBackend::new());
+        dest.fail("create_dir", usize::MAX, || io::Error::from(io::ErrorKind::PermissionDenied));
+        let started = Instant::now();
+        assert!(sync_between(source(), dest, false, &SyncOptions::default()).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::options::SyncOptions;
+use log::info;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+// Checked between every file, so the readings are reused for a little while.
+const CACHE_FOR: Duration = Duration::from_secs(30);
+// How often passes start under `--battery throttle` while the battery is low.
+const THROTTLED_INTERVAL: Duration = Duration::from_secs(600);
+
+static CACHE: Mutex<Option<(Instant, Option<Power>)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BatteryPolicy {
+    #[default]
+    Ignore,
+    // No copying while the battery is low.
+    Pause,
+    // Passes start less often while the battery is low.
+    Throttle,
+}
+
+impl std::str::FromStr for BatteryPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ignore" => Ok(BatteryPolicy::Ignore),
+            "pause" => Ok(BatteryPolicy::Pause),
+            "throttle" => Ok(BatteryPolicy::Throttle),
+            _ => Err(format!("Invalid battery policy: {} (expected ignore, pause or throttle)", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Power {
+    on_battery: bool,
+    percent: u8,
+}
+
+#[cfg(target_os = "linux")]
+fn query() -> Option<Power> {
+    let read = |path: std::path::PathBuf| std::fs::read_to_string(path).map(|value| value.trim().to_string());
+    let mut on_ac = false;
+    let mut levels = Vec::new();
+    for entry in std::fs::read_dir("/sys/class/power_supply").ok()?.flatten() {
+        let path = entry.path();
+        match read(path.join("type")).unwrap_or_default().as_str() {
+            "Mains" | "USB" => on_ac |= read(path.join("online")).is_ok_and(|online| online == "1"),
+            "Battery" => levels.extend(read(path.join("capacity")).ok().and_then(|capacity| capacity.parse::<u32>().ok())),
+            _ => {}
+        }
+    }
+    if levels.is_empty() {
+        return None;
+    }
+    let percent = levels.iter().sum::<u32>() / levels.len() as u32;
+    Some(Power { on_battery: !on_ac, percent: percent.min(100) as u8 })
+}
+
+#[cfg(windows)]
+fn query() -> Option<Power> {
+    #[repr(C)]
+    struct SystemPowerStatus {
+        ac_line_status: u8,
+        battery_flag: u8,
+        battery_life_percent: u8,
+        system_status_flag: u8,
+        battery_life_time: u32,
+        battery_full_life_time: u32,
+    }
+    extern "system" {
+        fn GetSystemPowerStatus(status: *mut SystemPowerStatus) -> i32;
+    }
+
+    let mut status: SystemPowerStatus = unsafe { std::mem::zeroed() };
+    // A battery flag of 128 means there is no battery; 255 and a percentage
+    // of 255 mean the state is unknown.
+    if unsafe { GetSystemPowerStatus(&mut status) } == 0 || status.battery_flag >= 128 || status.battery_life_percent > 100 {
+        return None;
+    }
+    Some(Power { on_battery: status.ac_line_status == 0, percent: status.battery_life_percent })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn query() -> Option<Power> {
+    None
+}
+
+fn power() -> Option<Power> {
+    if let Some((checked, power)) = *CACHE.lock().unwrap() {
+        if checked.elapsed() < CACHE_FOR {
+            return power;
+        }
+    }
+    let power = query();
+    *CACHE.lock().unwrap() = Some((Instant::now(), power));
+    power
+}
+
+// Machines without a battery, or where it can't be read, are never low.
+fn is_low(options: &SyncOptions) -> bool {
+    options.battery != BatteryPolicy::Ignore
+        && power().is_some_and(|power| power.on_battery && power.percent < options.battery_min)
+}
+
+// Holds a pass under `--battery pause` until the machine is back on AC power
+// or charged above the limit.
+pub async fn wait_for_power(options: &SyncOptions) {
+    if options.battery != BatteryPolicy::Pause || !is_low(options) {
+        return;
+    }
+    info!("Battery below {}%, pausing until on AC power", options.battery_min);
+    while is_low(options) {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    info!("Power restored, resuming");
+}
+
+// The time to wait before the next pass.
+pub fn interval(options: &SyncOptions, base: Duration) -> Duration {
+    if options.battery == BatteryPolicy::Throttle && is_low(options) {
+        return base.max(THROTTLED_INTERVAL);
+    }
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("ignore".parse(), Ok(BatteryPolicy::Ignore));
+        assert_eq!("pause".parse(), Ok(BatteryPolicy::Pause));
+        assert_eq!("throttle".parse(), Ok(BatteryPolicy::Throttle));
+        assert!("slow".parse::<BatteryPolicy>().is_err());
+    }
+
+    #[test]
+    fn ignore_keeps_the_interval() {
+        let options = SyncOptions::default();
+        assert!(!is_low(&options));
+        assert_eq!(interval(&options, Duration::from_secs(5)), Duration::from_secs(5));
+    }
+}
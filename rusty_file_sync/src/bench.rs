@@ -0,0 +1,98 @@
+use crate::options::SyncOptions;
+use crate::{hash, sync_oneway, SyncError};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+// Generated files are spread over directories of this many files.
+const FILES_PER_DIR: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub files: usize,
+    pub bytes: u64,
+    pub scan: Duration,
+    pub hash: Duration,
+    pub copy: Duration,
+}
+
+// Per-second rate of `amount` over `elapsed`.
+pub fn rate(amount: u64, elapsed: Duration) -> u64 {
+    (amount as f64 / elapsed.as_secs_f64().max(1e-9)) as u64
+}
+
+// Fills `path` with `size` bytes from a generator seeded by `seed`, so files
+// differ from each other and don't compress or deduplicate away.
+fn write_file(path: &Path, size: u64, seed: u64, buffer_size: usize) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let mut buffer = vec![0; buffer_size.max(8)];
+    let mut left = size;
+    while left > 0 {
+        for word in buffer.chunks_mut(8) {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            word.copy_from_slice(&state.to_le_bytes()[..word.len()]);
+        }
+        let n = left.min(buffer.len() as u64) as usize;
+        file.write_all(&buffer[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+// Creates `files` files of `size` bytes under `source`.
+async fn generate(source: PathBuf, files: usize, size: u64, buffer_size: usize) -> Result<(), SyncError> {
+    tokio::task::spawn_blocking(move || {
+        for index in 0..files {
+            let dir = source.join(format!("d{:04}", index / FILES_PER_DIR));
+            if index % FILES_PER_DIR == 0 {
+                std::fs::create_dir_all(&dir)?;
+            }
+            write_file(&dir.join(format!("f{:06}", index)), size, index as u64, buffer_size)?;
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await
+    .map_err(|e| SyncError::FileSystemError(std::io::Error::other(e)))??;
+    Ok(())
+}
+
+// Generates a tree in `dir`, then times walking it, hashing every file and
+// mirroring it into a fresh destination next to it with `options`. Files
+// just written are likely still cached, so reads are as fast as they get.
+pub async fn run(dir: &Path, files: usize, size: u64, options: &SyncOptions) -> Result<BenchReport, SyncError> {
+    let (source, destination) = (dir.join("source"), dir.join("destination"));
+    for path in [&source, &destination] {
+        if path.exists() {
+            return Err(SyncError::ConfigError(format!("{} already exists; bench needs an empty directory", path.display())));
+        }
+    }
+    generate(source.clone(), files, size, options.buffer_size).await?;
+
+    let started = Instant::now();
+    let mut paths = Vec::new();
+    for entry in WalkDir::new(&source) {
+        let entry = entry?;
+        if entry.metadata()?.is_file() {
+            paths.push(entry.into_path());
+        }
+    }
+    let scan = started.elapsed();
+
+    let started = Instant::now();
+    for path in &paths {
+        hash::file(path, options.hash, options.buffer_size).await?;
+    }
+    let hash = started.elapsed();
+
+    let started = Instant::now();
+    std::fs::create_dir_all(&destination)?;
+    sync_oneway(&source.to_string_lossy(), &destination.to_string_lossy(), true, options).await?;
+    let copy = started.elapsed();
+
+    Ok(BenchReport { files, bytes: files as u64 * size, scan, hash, copy })
+}
@@ -0,0 +1,166 @@
+use crate::faults;
+use std::io::Read;
+
+// 256 fixed pseudo-random values from SplitMix64. Chunk boundaries depend
+// on them, so changing them would stop new chunks matching stored ones.
+const fn gear() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x5275_7374_7946_696c;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+static GEAR: [u64; 256] = gear();
+
+// Splits data into content-defined chunks the FastCDC way: a rolling gear
+// hash over the last 64 bytes cuts wherever its top bits are all zero, so
+// inserting or removing bytes only moves the boundaries next to the edit
+// and the rest of the file still splits into the chunks stored before.
+// Before the average size a stricter mask is used and past it a looser
+// one, which keeps most chunks close to the average; none is smaller than
+// a quarter of it or larger than eight times it.
+pub struct Chunker {
+    min: usize,
+    average: usize,
+    pub max: usize,
+    strict: u64,
+    loose: u64,
+}
+
+impl Chunker {
+    pub fn new(average: usize) -> Self {
+        let average = average.max(256);
+        let bits = average.next_power_of_two().trailing_zeros();
+        Chunker { min: average / 4, average, max: average * 8, strict: !0 << (64 - (bits + 2)), loose: !0 << (64 - (bits - 2)) }
+    }
+
+    // Length of the chunk `data` starts with. `data` holds at least `max`
+    // bytes unless it runs to the end of the input.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+        let end = data.len().min(self.max);
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(end).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if i < self.average { self.strict } else { self.loose };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+// Reads `reader` to the end, handing `each` one chunk at a time.
+pub fn split(reader: &mut impl Read, chunker: &Chunker, mut each: impl FnMut(&[u8]) -> std::io::Result<()>) -> std::io::Result<()> {
+    let mut buffer = vec![0; chunker.max * 2];
+    let (mut start, mut end, mut eof) = (0, 0, false);
+    loop {
+        if !eof && end - start < chunker.max {
+            buffer.copy_within(start..end, 0);
+            (start, end) = (0, end - start);
+            while !eof && end < buffer.len() {
+                faults::before_read();
+                match reader.read(&mut buffer[end..])? {
+                    0 => eof = true,
+                    n => end += n,
+                }
+            }
+        }
+        if start == end {
+            return Ok(());
+        }
+        let n = chunker.cut(&buffer[start..end]);
+        each(&buffer[start..start + n])?;
+        start += n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn read_chunks(reader: &mut impl Read, chunker: &Chunker) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        split(reader, chunker, |chunk| {
+            chunks.push(chunk.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        chunks
+    }
+
+    fn chunks(data: &[u8], chunker: &Chunker) -> Vec<Vec<u8>> {
+        read_chunks(&mut &data[..], chunker)
+    }
+
+    // Hands out at most a few bytes per read, as pipes and sockets may.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let n = buffer.len().min(self.0.len()).min(7);
+            buffer[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn keeps_chunks_between_the_bounds() {
+        let chunker = Chunker::new(1024);
+        let data = noise(200_000, 1);
+        let chunks = chunks(&data, &chunker);
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!((256..=8192).contains(&chunk.len()), "{}", chunk.len());
+        }
+        let average = data.len() / chunks.len();
+        assert!((512..=2048).contains(&average), "{}", average);
+        // Tiny averages are raised to a floor.
+        assert_eq!(Chunker::new(1).max, 2048);
+    }
+
+    #[test]
+    fn splits_the_same_however_the_input_arrives() {
+        let chunker = Chunker::new(512);
+        let data = noise(50_000, 2);
+        assert_eq!(read_chunks(&mut Trickle(&data), &chunker), chunks(&data, &chunker));
+        assert!(chunks(&[], &chunker).is_empty());
+        assert_eq!(chunks(b"short", &chunker), [b"short"]);
+    }
+
+    #[test]
+    fn only_moves_boundaries_next_to_an_edit() {
+        let chunker = Chunker::new(1024);
+        let data = noise(100_000, 3);
+        let mut edited = data[..50_000].to_vec();
+        edited.extend_from_slice(b"inserted in the middle");
+        edited.extend_from_slice(&data[50_000..]);
+        let (before, after) = (chunks(&data, &chunker), chunks(&edited, &chunker));
+        let shared = after.iter().filter(|chunk| before.contains(chunk)).count();
+        assert!(shared + 3 >= before.len(), "{} of {} chunks kept", shared, before.len());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{keyring, SyncError};
+
+// A daemon configuration is a list of jobs, each written as the arguments
+// of a `sync` invocation:
+//
+//     max_concurrent_jobs = 2
+//
+//     [documents]
+//     source = /home/me/Documents
+//     destination = /mnt/backup/documents
+//     mode = snapshot
+//     keep-daily = 7
+//
+// Keys other than source, destination and mode become `--key value`
+// options; `true` turns a key into a bare flag and `false` drops it. Any
+// key can take its value from elsewhere instead, so secrets such as
+// credentials in a backend URL needn't be written into the file:
+//
+//     destination.env = BACKUP_URL
+//     destination.command = pass show backup/s3-url
+//     destination.keyring = backup-url
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub max_concurrent_jobs: usize,
+    pub max_concurrent_transfers: usize,
+    pub jobs: Vec<JobConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+struct JobBuilder {
+    name: String,
+    source: Option<String>,
+    destination: Option<String>,
+    mode: Option<String>,
+    options: Vec<String>,
+}
+
+impl JobBuilder {
+    fn build(self) -> Result<JobConfig, SyncError> {
+        let missing = |key: &str| SyncError::ConfigError(format!("Job {} is missing `{}`", self.name, key));
+        let source = self.source.clone().ok_or_else(|| missing("source"))?;
+        let destination = self.destination.clone().ok_or_else(|| missing("destination"))?;
+        let mode = self.mode.clone().ok_or_else(|| missing("mode"))?;
+        let mut args = vec!["sync".to_string(), source, destination, mode];
+        args.extend(self.options);
+        Ok(JobConfig { name: self.name, args })
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> std::process::Command {
+    let mut shell = std::process::Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> std::process::Command {
+    let mut shell = std::process::Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+// The key a `key.env`, `key.command` or `key.keyring` line sets and its
+// value, read from the environment variable, the first line the command
+// prints or the secret stored under that name by `credentials set`.
+fn resolve<'a>(key: &'a str, value: &str) -> Result<(&'a str, String), SyncError> {
+    if let Some(key) = key.strip_suffix(".keyring") {
+        return Ok((key, keyring::get(value)?));
+    }
+    if let Some(key) = key.strip_suffix(".env") {
+        let value = std::env::var(value).map_err(|_| SyncError::ConfigError(format!("`{}.env`: environment variable {} is not set", key, value)))?;
+        return Ok((key, value));
+    }
+    let Some(key) = key.strip_suffix(".command") else {
+        return Ok((key, value.to_string()));
+    };
+    let output = shell(value).stderr(std::process::Stdio::inherit()).output().map_err(|e| SyncError::ConfigError(format!("`{}.command`: can't run {:?}: {}", key, value, e)))?;
+    if !output.status.success() {
+        return Err(SyncError::ConfigError(format!("`{}.command`: {:?} failed with {}", key, value, output.status)));
+    }
+    let printed = String::from_utf8(output.stdout).map_err(|_| SyncError::ConfigError(format!("`{}.command`: {:?} printed something other than UTF-8", key, value)))?;
+    Ok((key, printed.lines().next().unwrap_or_default().to_string()))
+}
+
+fn parse_count(key: &str, value: &str) -> Result<usize, SyncError> {
+    value
+        .parse()
+        .map_err(|_| SyncError::ConfigError(format!("`{}` must be a number, got {:?}", key, value)))
+}
+
+pub fn parse(contents: &str) -> Result<Config, SyncError> {
+    let mut config = Config::default();
+    let mut job: Option<JobBuilder> = None;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            if let Some(finished) = job.take() {
+                config.jobs.push(finished.build()?);
+            }
+            job = Some(JobBuilder {
+                name: name.trim().to_string(),
+                source: None,
+                destination: None,
+                mode: None,
+                options: Vec::new(),
+            });
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+            .ok_or_else(|| SyncError::ConfigError(format!("Line {}: expected `key = value`", index + 1)))?;
+        let (key, value) = resolve(key, value)?;
+        let value = value.as_str();
+
+        match (&mut job, key) {
+            (None, "max_concurrent_jobs") => config.max_concurrent_jobs = parse_count(key, value)?,
+            (None, "max_concurrent_transfers") => config.max_concurrent_transfers = parse_count(key, value)?,
+            (None, _) => return Err(SyncError::ConfigError(format!("Line {}: unknown setting `{}`", index + 1, key))),
+            (Some(job), "source") => job.source = Some(value.to_string()),
+            (Some(job), "destination") => job.destination = Some(value.to_string()),
+            (Some(job), "mode") => job.mode = Some(value.to_string()),
+            (Some(_), _) if value == "false" => {}
+            (Some(job), _) if value == "true" => job.options.push(format!("--{}", key)),
+            (Some(job), _) => {
+                job.options.push(format!("--{}", key));
+                job.options.push(value.to_string());
+            }
+        }
+    }
+
+    if let Some(finished) = job.take() {
+        config.jobs.push(finished.build()?);
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turns_jobs_into_sync_arguments() {
+        let config = parse(
+            "# limits\nmax_concurrent_jobs = 2\nmax_concurrent_transfers = 8\n\n\
+             [documents]\nsource = /home/me/Documents\ndestination = \"/mnt/backup/documents\"\nmode = snapshot\n\
+             keep-daily = 7\nchecksum = true\ndelete = false\n\n\
+             [ photos ]\nmode = mirror\nsource = /photos\ndestination = /mnt/photos\n",
+        )
+        .unwrap();
+        assert_eq!((config.max_concurrent_jobs, config.max_concurrent_transfers), (2, 8));
+        assert_eq!(config.jobs.len(), 2);
+        assert_eq!(config.jobs[0].name, "documents");
+        assert_eq!(
+            config.jobs[0].args,
+            ["sync", "/home/me/Documents", "/mnt/backup/documents", "snapshot", "--keep-daily", "7", "--checksum"]
+        );
+        assert_eq!(config.jobs[1].name, "photos");
+        assert_eq!(config.jobs[1].args, ["sync", "/photos", "/mnt/photos", "mirror"]);
+        assert_eq!(parse("").unwrap().jobs.len(), 0);
+    }
+
+    #[test]
+    fn rejects_malformed_configs() {
+        let error = |contents: &str| parse(contents).unwrap_err().to_string();
+        assert!(error("[a]\nsource = /a\nmode = mirror").contains("Job a is missing `destination`"));
+        assert!(error("\n\nnonsense").contains("Line 3: expected `key = value`"));
+        assert!(error("delete = true").contains("Line 1: unknown setting `delete`"));
+        assert!(error("max_concurrent_jobs = many").contains("`max_concurrent_jobs` must be a number"));
+    }
+
+    #[test]
+    fn reads_values_from_the_environment() {
+        std::env::set_var("RUSTY_FILE_SYNC_TEST_URL", "s3://key:secret@bucket/backup");
+        let config = parse("[a]\nsource = /a\ndestination.env = RUSTY_FILE_SYNC_TEST_URL\nmode = mirror").unwrap();
+        assert_eq!(config.jobs[0].args[2], "s3://key:secret@bucket/backup");
+        let error = parse("[a]\nsource.env = RUSTY_FILE_SYNC_TEST_UNSET").unwrap_err().to_string();
+        assert!(error.contains("`source.env`: environment variable RUSTY_FILE_SYNC_TEST_UNSET is not set"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reads_values_from_commands() {
+        let config = parse("[a]\nsource.command = printf '/from/cmd\\nignored'\ndestination = /b\nmode = mirror\nbwlimit.command = echo 1M").unwrap();
+        assert_eq!(config.jobs[0].args, ["sync", "/from/cmd", "/b", "mirror", "--bwlimit", "1M"]);
+        let error = parse("[a]\nsource.command = exit 4").unwrap_err().to_string();
+        assert!(error.contains("`source.command`: \"exit 4\" failed"), "{}", error);
+    }
+}
@@ -0,0 +1,616 @@
+use crate::hash::{self, HashAlgorithm};
+use crate::options::SyncOptions;
+use crate::store::{self, TreeEntry};
+use crate::{merge, state, timestamp, SyncError};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+use walkdir::WalkDir;
+
+// Text files up to this size keep their last-synced version as the base of
+// later merges.
+const MAX_MERGE_SIZE: u64 = 1 << 20;
+
+// How a conflict was settled by the pass that found it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Merged,
+    // The destination's version was kept as a conflict copy.
+    ConflictCopy,
+    // The newer side overwrote the other.
+    KeptSource,
+    KeptDestination,
+    // Neither side is newer, so both were left as they are.
+    Unresolved,
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Merged => "merged",
+            Resolution::ConflictCopy => "conflict-copy",
+            Resolution::KeptSource => "kept-source",
+            Resolution::KeptDestination => "kept-destination",
+            Resolution::Unresolved => "unresolved",
+        }
+    }
+}
+
+impl std::str::FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "merged" => Ok(Resolution::Merged),
+            "conflict-copy" => Ok(Resolution::ConflictCopy),
+            "kept-source" => Ok(Resolution::KeptSource),
+            "kept-destination" => Ok(Resolution::KeptDestination),
+            "unresolved" => Ok(Resolution::Unresolved),
+            _ => Err(format!("Invalid resolution: {}", value)),
+        }
+    }
+}
+
+// The version a user settles a conflict copy on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Keep {
+    Source,
+    Destination,
+    Both,
+}
+
+impl std::str::FromStr for Keep {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "source" => Ok(Keep::Source),
+            "destination" => Ok(Keep::Destination),
+            "both" => Ok(Keep::Both),
+            _ => Err(format!("Invalid version: {} (expected source, destination or both)", value)),
+        }
+    }
+}
+
+// Whether a user has looked at a conflict yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Open,
+    Acknowledged,
+    Kept(Keep),
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Open => "open",
+            Status::Acknowledged => "acknowledged",
+            Status::Kept(Keep::Source) => "kept-source",
+            Status::Kept(Keep::Destination) => "kept-destination",
+            Status::Kept(Keep::Both) => "kept-both",
+        }
+    }
+}
+
+impl std::str::FromStr for Status {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "open" => Ok(Status::Open),
+            "acknowledged" => Ok(Status::Acknowledged),
+            _ => match value.strip_prefix("kept-") {
+                Some(keep) => keep.parse().map(Status::Kept),
+                None => Err(format!("Invalid status: {}", value)),
+            },
+        }
+    }
+}
+
+// The content hash and modification time (in seconds) of one side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version {
+    pub hash: String,
+    pub modified: u64,
+}
+
+// One line of the conflict journal, tab-separated with the path last:
+//
+//     3    2026-10-14T10:00:00    open    conflict-copy    <hash>    <mtime>    <hash>    <mtime>    notes/todo.txt
+//
+// i.e. id, time found, status, resolution, then the source's and the
+// destination's version and the path relative to both sides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub id: u64,
+    pub time: String,
+    pub status: Status,
+    pub resolution: Resolution,
+    pub source: Version,
+    pub dest: Version,
+    pub path: String,
+}
+
+pub fn journal_path(destination: &Path) -> PathBuf {
+    state::state_dir(destination).join("conflicts")
+}
+
+fn conflict_line(conflict: &Conflict) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+        conflict.id,
+        conflict.time,
+        conflict.status.as_str(),
+        conflict.resolution.as_str(),
+        conflict.source.hash,
+        conflict.source.modified,
+        conflict.dest.hash,
+        conflict.dest.modified,
+        conflict.path
+    )
+}
+
+fn parse_line(line: &str) -> Option<Conflict> {
+    let fields: Vec<&str> = line.splitn(9, '\t').collect();
+    let [id, time, status, resolution, src_hash, src_modified, dest_hash, dest_modified, path] = fields.as_slice() else {
+        return None;
+    };
+    Some(Conflict {
+        id: id.parse().ok()?,
+        time: time.to_string(),
+        status: status.parse().ok()?,
+        resolution: resolution.parse().ok()?,
+        source: Version { hash: src_hash.to_string(), modified: src_modified.parse().ok()? },
+        dest: Version { hash: dest_hash.to_string(), modified: dest_modified.parse().ok()? },
+        path: path.to_string(),
+    })
+}
+
+// Every conflict recorded for `destination`, oldest first.
+pub async fn load(destination: &Path) -> Result<Vec<Conflict>, SyncError> {
+    let path = journal_path(destination);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(tokio::fs::read_to_string(&path).await?.lines().filter_map(parse_line).collect())
+}
+
+async fn record(destination: &Path, mut conflict: Conflict) -> Result<(), SyncError> {
+    conflict.id = load(destination).await?.last().map_or(1, |last| last.id + 1);
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(journal_path(destination)).await?;
+    file.write_all(conflict_line(&conflict).as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+// Marks conflict `id` of `destination` as looked at. With `keep`, a
+// conflict copy is settled for good on both sides: `Source` drops the
+// copies, `Destination` puts the copy back in place of the file, and
+// `Both` leaves both as they are.
+pub async fn review(destination: &Path, source: Option<&Path>, id: u64, keep: Option<Keep>) -> Result<Conflict, SyncError> {
+    let mut conflicts = load(destination).await?;
+    let conflict = conflicts
+        .iter_mut()
+        .find(|conflict| conflict.id == id)
+        .ok_or_else(|| SyncError::ConflictError(format!("No conflict {} in {:?}", id, destination)))?;
+    if let Some(keep) = keep {
+        if conflict.resolution != Resolution::ConflictCopy {
+            return Err(SyncError::ConflictError(format!("Conflict {} was {}, there is no conflict copy to keep", id, conflict.resolution.as_str())));
+        }
+        let copy = conflict_copy_path(Path::new(&conflict.path), &conflict.time);
+        if keep != Keep::Both {
+            let source = source.ok_or_else(|| SyncError::ConflictError("Keeping one version needs --source".to_string()))?;
+            for root in [destination, source] {
+                match keep {
+                    Keep::Source => tokio::fs::remove_file(root.join(&copy)).await?,
+                    _ => tokio::fs::rename(root.join(&copy), root.join(&conflict.path)).await?,
+                }
+            }
+        }
+        conflict.status = Status::Kept(keep);
+    } else {
+        conflict.status = Status::Acknowledged;
+    }
+    let reviewed = conflict.clone();
+
+    let path = journal_path(destination);
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, conflicts.iter().map(conflict_line).collect::<String>()).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+    Ok(reviewed)
+}
+
+// What bidirectional modes do with a file changed on both sides since the
+// last pass.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ConflictPolicy {
+    // The newer side overwrites the other.
+    #[default]
+    Newer,
+    // Text files are merged against their last-synced version, anything
+    // that can't be merged is kept as a conflict copy.
+    Merge,
+    // The user picks a version at the terminal.
+    Ask,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "newer" => Ok(ConflictPolicy::Newer),
+            "merge" => Ok(ConflictPolicy::Merge),
+            "ask" => Ok(ConflictPolicy::Ask),
+            _ => Err(format!("Invalid conflict policy: {} (expected newer, merge or ask)", value)),
+        }
+    }
+}
+
+// Last-synced versions, stored by hash in the destination's state directory.
+fn bases_dir(destination: &Path) -> PathBuf {
+    state::state_dir(destination).join("bases")
+}
+
+fn is_text(data: &[u8]) -> bool {
+    !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+// `report.txt` becomes `report.conflict-2026-10-14T100000.txt`, next to it,
+// for a conflict found at `time`.
+pub fn conflict_copy_path(path: &Path, time: &str) -> PathBuf {
+    let stamp = time.replace(':', "");
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.conflict-{}.{}", stem, stamp, ext.to_string_lossy()),
+        None => format!("{}.conflict-{}", stem, stamp),
+    };
+    path.with_file_name(name)
+}
+
+fn differs(metadata: &std::fs::Metadata, entry: &TreeEntry) -> bool {
+    match entry {
+        TreeEntry::File { size, modified, .. } => {
+            *size != metadata.len() || metadata.modified().map(timestamp::to_secs).ok() != Some(*modified)
+        }
+        _ => true,
+    }
+}
+
+async fn try_merge(base: &Path, source: &Path, dest: &Path) -> Result<Option<String>, SyncError> {
+    if !base.exists() {
+        return Ok(None);
+    }
+    let (base, ours, theirs) = (tokio::fs::read(base).await?, tokio::fs::read(source).await?, tokio::fs::read(dest).await?);
+    if !is_text(&ours) || !is_text(&theirs) {
+        return Ok(None);
+    }
+    let text = |data: Vec<u8>| String::from_utf8(data).unwrap_or_default();
+    Ok(merge::merge3(&text(base), &text(ours), &text(theirs)))
+}
+
+// Moves the destination's version of `relative` aside as a conflict copy on
+// both sides.
+async fn keep_both(source: &Path, destination: &Path, relative: &Path, time: &str, options: &SyncOptions) -> Result<Resolution, SyncError> {
+    let copy = conflict_copy_path(relative, time);
+    warn!("Keeping the destination's version of {:?} as {:?}", relative, copy);
+    options.guard_write(&source.join(&copy))?;
+    tokio::fs::copy(destination.join(relative), source.join(&copy)).await?;
+    tokio::fs::rename(destination.join(relative), destination.join(&copy)).await?;
+    Ok(Resolution::ConflictCopy)
+}
+
+// The application owns the terminal, so lines typed at it are handed over
+// with `answer` while a prompt waits, once `accept_answers` says they will be.
+static ANSWERING: AtomicBool = AtomicBool::new(false);
+static PROMPT: Mutex<Option<oneshot::Sender<String>>> = Mutex::new(None);
+
+pub fn accept_answers() {
+    ANSWERING.store(true, Ordering::SeqCst);
+}
+
+// Passes `line` to a waiting prompt, returning false when there is none.
+pub fn answer(line: &str) -> bool {
+    match PROMPT.lock().unwrap().take() {
+        Some(prompt) => prompt.send(line.to_string()).is_ok(),
+        None => false,
+    }
+}
+
+async fn diff(source: &Path, dest: &Path) -> Result<String, SyncError> {
+    let (ours, theirs) = (tokio::fs::read(source).await?, tokio::fs::read(dest).await?);
+    if !is_text(&ours) || !is_text(&theirs) {
+        return Ok(format!("Binary files differ: {} bytes in the source, {} in the destination\n", ours.len(), theirs.len()));
+    }
+    let text = |data: Vec<u8>| String::from_utf8(data).unwrap_or_default();
+    Ok(merge::diff(&text(ours), &text(theirs)).unwrap_or_else(|| "Too many changes to show\n".to_string()))
+}
+
+async fn ask(relative: &Path, source: &Path, dest: &Path) -> Result<Keep, SyncError> {
+    loop {
+        eprint!("{:?} changed on both sides. Keep the [s]ource's version, the [d]estination's, [b]oth, or [v]iew the differences? ", relative);
+        let (prompt, answered) = oneshot::channel();
+        *PROMPT.lock().unwrap() = Some(prompt);
+        let Ok(answer) = answered.await else {
+            return Ok(Keep::Both);
+        };
+        match answer.trim() {
+            "s" => return Ok(Keep::Source),
+            "d" => return Ok(Keep::Destination),
+            "b" => return Ok(Keep::Both),
+            "v" => eprint!("{}", diff(source, dest).await?),
+            _ => {}
+        }
+    }
+}
+
+// Finds the files changed on both sides since the last pass before the
+// mirror passes run and records them in the conflict journal. Under
+// `ConflictPolicy::Merge` they are settled so neither version is lost:
+// merged text is written to both sides, and otherwise the destination's
+// version is moved aside as a conflict copy on both sides, where the mirror
+// passes leave it alone. `ConflictPolicy::Ask` leaves the choice to the
+// user, keeping both versions when nobody can be asked.
+pub async fn resolve(source: &str, destination: &str, options: &SyncOptions) -> Result<(), SyncError> {
+    let dest_root = Path::new(destination);
+    let manifest = state::load_manifest(&state::manifest_path(dest_root)).await?;
+    for entry in WalkDir::new(source).min_depth(1).into_iter().filter_entry(|e| !state::is_state_dir(e)) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(source)?;
+        let dest_path = dest_root.join(relative);
+        // Without a record of the last pass there's nothing to tell a
+        // conflict from a one-sided change.
+        let Some(last_synced @ TreeEntry::File { hash: base_hash, .. }) = manifest.get(&store::relative_key(relative)) else {
+            continue;
+        };
+        let (Ok(src_metadata), Ok(dest_metadata)) = (entry.metadata(), std::fs::metadata(&dest_path)) else {
+            continue;
+        };
+        if !differs(&src_metadata, last_synced) || !differs(&dest_metadata, last_synced) {
+            continue;
+        }
+        // Both sides are hashed the way the manifest's entry was, whatever
+        // `--hash` says now.
+        let algorithm = HashAlgorithm::of(base_hash);
+        let src_hash = hash::file(entry.path(), algorithm, options.buffer_size).await?;
+        let dest_hash = hash::file(&dest_path, algorithm, options.buffer_size).await?;
+        if src_hash == *base_hash || dest_hash == *base_hash || src_hash == dest_hash {
+            continue;
+        }
+
+        let time = timestamp::now();
+        let (src_modified, dest_modified) = (timestamp::to_secs(src_metadata.modified()?), timestamp::to_secs(dest_metadata.modified()?));
+        let (resolution, status) = match options.conflict {
+            ConflictPolicy::Newer => {
+                let resolution = match src_modified.cmp(&dest_modified) {
+                    std::cmp::Ordering::Greater => Resolution::KeptSource,
+                    std::cmp::Ordering::Less => Resolution::KeptDestination,
+                    std::cmp::Ordering::Equal => Resolution::Unresolved,
+                };
+                warn!("{:?} changed on both sides, {}", relative, resolution.as_str());
+                (resolution, Status::Open)
+            }
+            ConflictPolicy::Merge => match try_merge(&bases_dir(dest_root).join(base_hash), entry.path(), &dest_path).await? {
+                Some(merged) => {
+                    info!("Merged changes made on both sides to {:?}", relative);
+                    options.guard_write(entry.path())?;
+                    tokio::fs::write(&dest_path, &merged).await?;
+                    tokio::fs::write(entry.path(), &merged).await?;
+                    (Resolution::Merged, Status::Open)
+                }
+                None => {
+                    warn!("{:?} changed on both sides and can't be merged", relative);
+                    (keep_both(Path::new(source), dest_root, relative, &time, options).await?, Status::Open)
+                }
+            },
+            ConflictPolicy::Ask if ANSWERING.load(Ordering::SeqCst) => {
+                let keep = ask(relative, entry.path(), &dest_path).await?;
+                let resolution = match keep {
+                    Keep::Source => {
+                        tokio::fs::copy(entry.path(), &dest_path).await?;
+                        Resolution::KeptSource
+                    }
+                    Keep::Destination => {
+                        options.guard_write(entry.path())?;
+                        tokio::fs::copy(&dest_path, entry.path()).await?;
+                        Resolution::KeptDestination
+                    }
+                    Keep::Both => keep_both(Path::new(source), dest_root, relative, &time, options).await?,
+                };
+                (resolution, Status::Kept(keep))
+            }
+            ConflictPolicy::Ask => {
+                warn!("{:?} changed on both sides and there is no terminal to ask", relative);
+                (keep_both(Path::new(source), dest_root, relative, &time, options).await?, Status::Open)
+            }
+        };
+        let conflict = Conflict {
+            id: 0,
+            time,
+            status,
+            resolution,
+            source: Version { hash: src_hash, modified: src_modified },
+            dest: Version { hash: dest_hash, modified: dest_modified },
+            path: store::relative_key(relative),
+        };
+        record(dest_root, conflict).await?;
+    }
+    Ok(())
+}
+
+// Keeps the last-synced version of every small text file in the manifest
+// of `destination` as the base of later merges, dropping the rest.
+pub async fn save_bases(destination: &Path) -> Result<(), SyncError> {
+    let manifest = state::load_manifest(&state::manifest_path(destination)).await?;
+    let dir = bases_dir(destination);
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut kept = HashSet::new();
+    for (key, entry) in &manifest {
+        let TreeEntry::File { hash, size, .. } = entry else {
+            continue;
+        };
+        if *size > MAX_MERGE_SIZE {
+            continue;
+        }
+        let base = dir.join(hash);
+        if !base.exists() {
+            let data = tokio::fs::read(store::key_path(destination, key)).await?;
+            if !is_text(&data) {
+                continue;
+            }
+            tokio::fs::write(&base, &data).await?;
+        }
+        kept.insert(hash.clone());
+    }
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !kept.contains(entry.file_name().to_string_lossy().as_ref()) {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, touch, write};
+
+    #[tokio::test]
+    async fn one_sided_edits_are_no_conflict_under_other_hashes() {
+        for algorithm in [HashAlgorithm::Md5, HashAlgorithm::Sha1] {
+            let root = scratch(&format!("conflict-{:?}", algorithm));
+            let (source, dest) = (root.join("source"), root.join("dest"));
+            std::fs::create_dir_all(&source).unwrap();
+            std::fs::create_dir_all(&dest).unwrap();
+            std::fs::write(source.join("a.txt"), "first").unwrap();
+            touch(&source.join("a.txt"), 7200);
+            let options = SyncOptions { hash: algorithm, ..SyncOptions::default() };
+            let (source_str, dest_str) = (source.to_str().unwrap(), dest.to_str().unwrap());
+            crate::sync(source_str, dest_str, "bi", &options).await.unwrap();
+
+            // The source is edited; the destination only has its time changed.
+            std::fs::write(source.join("a.txt"), "second edit").unwrap();
+            touch(&dest.join("a.txt"), 3600);
+            crate::sync(source_str, dest_str, "bi", &options).await.unwrap();
+            assert!(load(&dest).await.unwrap().is_empty());
+            assert_eq!(std::fs::read_to_string(dest.join("a.txt")).unwrap(), "second edit");
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn settling_conflicts_never_writes_a_read_only_source() {
+        let root = scratch("conflict-read-only");
+        let (source, dest) = (root.join("source"), root.join("dest"));
+        write(&source.join("a.txt"), "base");
+        std::fs::create_dir_all(&dest).unwrap();
+        touch(&source.join("a.txt"), 7200);
+        let (source_str, dest_str) = (source.to_str().unwrap(), dest.to_str().unwrap());
+        crate::sync(source_str, dest_str, "bi", &SyncOptions::default()).await.unwrap();
+        write(&source.join("a.txt"), "source edit");
+        write(&dest.join("a.txt"), "destination edit");
+        touch(&dest.join("a.txt"), 3600);
+
+        let options = SyncOptions { conflict: ConflictPolicy::Ask, read_only_source: Some(source.clone()), ..SyncOptions::default() };
+        // Nobody to ask: both versions would be kept, with a copy in the source.
+        assert!(matches!(resolve(source_str, dest_str, &options).await, Err(SyncError::FileSystemError(_))));
+        // Keeping the destination's version would overwrite the source's.
+        accept_answers();
+        let resolving = tokio::spawn(async move { resolve(source.to_str().unwrap(), dest.to_str().unwrap(), &options).await });
+        while !answer("d") {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(matches!(resolving.await.unwrap(), Err(SyncError::FileSystemError(_))));
+        assert_eq!(std::fs::read_to_string(root.join("source/a.txt")).unwrap(), "source edit");
+        assert_eq!(std::fs::read_dir(root.join("source")).unwrap().count(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn conflict(path: &str, resolution: Resolution) -> Conflict {
+        Conflict {
+            id: 0,
+            time: "2026-10-14T10:00:00".to_string(),
+            status: Status::Open,
+            resolution,
+            source: Version { hash: "aa".to_string(), modified: 1 },
+            dest: Version { hash: "bb".to_string(), modified: 2 },
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn names_conflict_copies_after_the_time() {
+        let time = "2026-10-14T10:00:00";
+        assert_eq!(conflict_copy_path(Path::new("docs/report.txt"), time), Path::new("docs/report.conflict-2026-10-14T100000.txt"));
+        assert_eq!(conflict_copy_path(Path::new("Makefile"), time), Path::new("Makefile.conflict-2026-10-14T100000"));
+        assert_eq!(conflict_copy_path(Path::new("a.tar.gz"), time), Path::new("a.tar.conflict-2026-10-14T100000.gz"));
+    }
+
+    #[test]
+    fn reads_back_journal_lines() {
+        for status in [Status::Open, Status::Acknowledged, Status::Kept(Keep::Source), Status::Kept(Keep::Destination), Status::Kept(Keep::Both)] {
+            let conflict = Conflict { id: 3, status, ..conflict("notes/a\tb.txt", Resolution::ConflictCopy) };
+            let line = conflict_line(&conflict);
+            assert_eq!(parse_line(line.trim_end_matches('\n')), Some(conflict));
+        }
+        for resolution in [Resolution::Merged, Resolution::KeptSource, Resolution::KeptDestination, Resolution::Unresolved] {
+            assert_eq!(resolution.as_str().parse(), Ok(resolution));
+        }
+        assert_eq!(parse_line("3\t2026-10-14T10:00:00\topen\tmerged\taa\t1\tbb\t2"), None);
+        assert_eq!(parse_line("x\t2026-10-14T10:00:00\topen\tmerged\taa\t1\tbb\t2\ta.txt"), None);
+        assert_eq!(parse_line("3\t2026-10-14T10:00:00\tkept-nothing\tmerged\taa\t1\tbb\t2\ta.txt"), None);
+    }
+
+    #[tokio::test]
+    async fn numbers_and_reviews_recorded_conflicts() {
+        let root = scratch("conflict-journal");
+        std::fs::create_dir_all(state::state_dir(&root)).unwrap();
+        assert!(load(&root).await.unwrap().is_empty());
+        record(&root, conflict("a.txt", Resolution::Merged)).await.unwrap();
+        record(&root, conflict("b.txt", Resolution::KeptSource)).await.unwrap();
+        let conflicts = load(&root).await.unwrap();
+        assert_eq!(conflicts.iter().map(|conflict| (conflict.id, conflict.path.as_str())).collect::<Vec<_>>(), [(1, "a.txt"), (2, "b.txt")]);
+
+        assert_eq!(review(&root, None, 2, None).await.unwrap().status, Status::Acknowledged);
+        assert_eq!(load(&root).await.unwrap()[1].status, Status::Acknowledged);
+        assert_eq!(load(&root).await.unwrap()[0].status, Status::Open);
+        // Only conflict copies have a version to keep.
+        assert!(matches!(review(&root, None, 1, Some(Keep::Both)).await, Err(SyncError::ConflictError(_))));
+        assert!(matches!(review(&root, None, 9, None).await, Err(SyncError::ConflictError(_))));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_one_version_of_a_conflict_copy_on_both_sides() {
+        let root = scratch("conflict-keep");
+        let (source, dest) = (root.join("source"), root.join("dest"));
+        std::fs::create_dir_all(state::state_dir(&dest)).unwrap();
+        let copy = conflict_copy_path(Path::new("a.txt"), "2026-10-14T10:00:00");
+        for (path, keep) in [("a.txt", Keep::Source), ("b.txt", Keep::Destination)] {
+            let copy = conflict_copy_path(Path::new(path), "2026-10-14T10:00:00");
+            for side in [&source, &dest] {
+                write(&side.join(path), "source version");
+                write(&side.join(&copy), "destination version");
+            }
+            record(&dest, conflict(path, Resolution::ConflictCopy)).await.unwrap();
+            let id = load(&dest).await.unwrap().last().unwrap().id;
+            assert!(matches!(review(&dest, None, id, Some(keep)).await, Err(SyncError::ConflictError(_))));
+            assert_eq!(review(&dest, Some(&source), id, Some(keep)).await.unwrap().status, Status::Kept(keep));
+        }
+        for side in [&source, &dest] {
+            assert_eq!(std::fs::read_to_string(side.join("a.txt")).unwrap(), "source version");
+            assert!(!side.join(&copy).exists());
+            assert_eq!(std::fs::read_to_string(side.join("b.txt")).unwrap(), "destination version");
+            assert!(!side.join(conflict_copy_path(Path::new("b.txt"), "2026-10-14T10:00:00")).exists());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+use crate::itemize::Change;
+use crate::observer::Observer;
+use crate::options::format_size;
+use crate::SyncError;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// Prints what a pass does for someone watching it in a terminal, one line
+// per action with the verb in its own column, and a summary at the end,
+// before passing the action on to the observer it wraps. Quiet consoles
+// only print the summary, errors being left to the log.
+pub struct Console {
+    inner: Arc<dyn Observer>,
+    color: bool,
+    quiet: bool,
+    copied: AtomicU64,
+    bytes: AtomicU64,
+    removed: AtomicU64,
+}
+
+impl Console {
+    pub fn new(color: bool, quiet: bool, inner: Arc<dyn Observer>) -> Self {
+        Console { inner, color, quiet, copied: AtomicU64::new(0), bytes: AtomicU64::new(0), removed: AtomicU64::new(0) }
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    // Counts of the pass so far, reset for the next one.
+    fn take(&self) -> (u64, u64, u64) {
+        (self.copied.swap(0, Ordering::SeqCst), self.bytes.swap(0, Ordering::SeqCst), self.removed.swap(0, Ordering::SeqCst))
+    }
+
+    fn print(&self, color: &str, verb: &str, detail: &str) {
+        // Padded before painting so escape codes don't upset the alignment.
+        let line = format!("{} {}\n", self.paint(color, &format!("{:<7}", verb)), detail);
+        let _ = std::io::stdout().lock().write_all(line.as_bytes());
+    }
+}
+
+impl Observer for Console {
+    fn on_change(&self, path: &Path, change: &Change) {
+        self.inner.on_change(path, change);
+    }
+
+    fn on_file_start(&self, path: &Path) {
+        self.inner.on_file_start(path);
+    }
+
+    fn on_file_progress(&self, path: &Path, copied: u64, total: u64) {
+        self.inner.on_file_progress(path, copied, total);
+    }
+
+    fn on_file_done(&self, path: &Path, bytes: u64) {
+        self.copied.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+        if !self.quiet {
+            self.print(GREEN, "copy", &format!("{}  {}", path.display(), format_size(bytes)));
+        }
+        self.inner.on_file_done(path, bytes);
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.removed.fetch_add(1, Ordering::SeqCst);
+        if !self.quiet {
+            self.print(RED, "remove", &path.display().to_string());
+        }
+        self.inner.on_remove(path);
+    }
+
+    fn on_error(&self, error: &SyncError) {
+        self.take();
+        if !self.quiet {
+            self.print(BOLD_RED, "error", &error.to_string());
+        }
+        self.inner.on_error(error);
+    }
+
+    fn on_pass_complete(&self) {
+        let (copied, bytes, removed) = self.take();
+        // Quiet runs pass over repeat passes that find nothing to do.
+        if !self.quiet || copied > 0 || removed > 0 {
+            self.print(BOLD, "done", &format!("{} copied ({}), {} removed", copied, format_size(bytes), removed));
+        }
+        self.inner.on_pass_complete();
+    }
+}
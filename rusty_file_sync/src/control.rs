@@ -0,0 +1,119 @@
+use crate::battery;
+use crate::options::SyncOptions;
+use crate::window::{self, WindowPolicy};
+use crate::SyncError;
+use log::info;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static RESUMED: Notify = Notify::const_new();
+// Bumped by every trigger, so one arriving mid-pass is not lost.
+static TRIGGERS: AtomicU64 = AtomicU64::new(0);
+static TRIGGERED: Notify = Notify::const_new();
+
+fn pause() {
+    if !PAUSED.swap(true, Ordering::SeqCst) {
+        info!("Pausing after the current file");
+    }
+}
+
+fn resume() {
+    if PAUSED.swap(false, Ordering::SeqCst) {
+        info!("Resuming");
+        RESUMED.notify_waiters();
+    }
+}
+
+fn trigger() {
+    info!("Sync triggered");
+    TRIGGERS.fetch_add(1, Ordering::SeqCst);
+    TRIGGERED.notify_waiters();
+}
+
+pub fn triggers() -> u64 {
+    TRIGGERS.load(Ordering::SeqCst)
+}
+
+// Sleeps out the sync interval, returning early if a trigger has arrived
+// since `seen` was read.
+pub async fn wait_for_next_pass(interval: Duration, seen: u64) {
+    let triggered = TRIGGERED.notified();
+    if triggers() != seen {
+        return;
+    }
+    tokio::select! {
+        _ = tokio::time::sleep(interval) => {}
+        _ = triggered => {}
+    }
+}
+
+// Called between files by every pass, so a pause, leaving the job's time
+// window under `--window-policy pause` or running low on battery under
+// `--battery pause` takes effect once the file in progress is done.
+pub async fn checkpoint(options: &SyncOptions) {
+    wait_while_paused().await;
+    battery::wait_for_power(options).await;
+    if options.window_policy == WindowPolicy::Pause {
+        window::wait_for_window(&options.windows).await;
+    }
+}
+
+async fn wait_while_paused() {
+    loop {
+        // Registered before checking the flag so a resume in between is not missed.
+        let resumed = RESUMED.notified();
+        if !PAUSED.load(Ordering::SeqCst) {
+            return;
+        }
+        resumed.await;
+    }
+}
+
+// SIGUSR1 pauses every job, SIGUSR2 resumes them and SIGHUP starts a pass
+// right away.
+#[cfg(unix)]
+pub fn listen() {
+    use log::warn;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut usr1), Ok(mut usr2), Ok(mut hup)) =
+        (signal(SignalKind::user_defined1()), signal(SignalKind::user_defined2()), signal(SignalKind::hangup()))
+    else {
+        warn!("Could not install the control signal handlers");
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = usr1.recv() => pause(),
+                _ = usr2.recv() => resume(),
+                _ = hup.recv() => trigger(),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn listen() {}
+
+// Sends `command` to a running `sync` or `daemon` process as a signal.
+#[cfg(unix)]
+pub fn send(command: &str, pid: i32) -> Result<(), SyncError> {
+    let signal = match command {
+        "trigger" => libc::SIGHUP,
+        "pause" => libc::SIGUSR1,
+        "resume" => libc::SIGUSR2,
+        _ => return Err(SyncError::ControlError(format!("Unknown command: {}", command))),
+    };
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        return Err(SyncError::ControlError(format!("Could not signal process {}: {}", pid, std::io::Error::last_os_error())));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn send(_command: &str, _pid: i32) -> Result<(), SyncError> {
+    Err(SyncError::ControlError("Control commands are only supported on Unix".to_string()))
+}
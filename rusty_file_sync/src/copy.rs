@@ -0,0 +1,399 @@
+use crate::observer::Observer;
+use crate::options::{format_size, SyncOptions};
+use crate::hash::Hasher;
+use crate::{attrs, faults, scheduler, space, SyncError};
+use log::{debug, info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Chunks in flight between two stages; bounds memory use per copy.
+const CHANNEL_DEPTH: usize = 4;
+// How often the progress of a large file is reported.
+const FILE_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+// O_DIRECT transfers must start at, and be sized in multiples of, the
+// device block size; 4 KiB covers every common device.
+const DIRECT_ALIGN: usize = 4096;
+
+fn join_error(e: tokio::task::JoinError) -> SyncError {
+    SyncError::FileSystemError(std::io::Error::other(e))
+}
+
+// A heap buffer whose usable region starts on a DIRECT_ALIGN boundary.
+struct AlignedBuffer {
+    storage: Vec<u8>,
+    start: usize,
+    capacity: usize,
+}
+
+impl AlignedBuffer {
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.div_ceil(DIRECT_ALIGN) * DIRECT_ALIGN;
+        let storage = vec![0u8; capacity + DIRECT_ALIGN];
+        let start = storage.as_ptr().align_offset(DIRECT_ALIGN);
+        AlignedBuffer { storage, start, capacity }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.storage[self.start..self.start + self.capacity]
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(options: &OpenOptions, path: &Path) -> std::io::Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+        Ok(file) => Ok(Some(file)),
+        // Filesystems such as tmpfs reject O_DIRECT; fall back to buffered I/O.
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            debug!("O_DIRECT not supported for {:?}, using buffered I/O", path);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_options: &OpenOptions, path: &Path) -> std::io::Result<Option<File>> {
+    debug!("Direct I/O is only supported on Linux, using buffered I/O for {:?}", path);
+    Ok(None)
+}
+
+// Reserves `size` bytes for `file` so fragmentation stays low and a full
+// destination fails before any data is written.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+    use nix::errno::Errno;
+    use nix::fcntl::{fallocate, FallocateFlags};
+    match fallocate(file, FallocateFlags::empty(), 0, size as libc::off_t) {
+        Ok(()) => Ok(()),
+        Err(Errno::EOPNOTSUPP) => {
+            debug!("Preallocation not supported by the destination filesystem");
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// On Windows setting the length reserves the space (SetEndOfFile).
+#[cfg(windows)]
+fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+    file.set_len(size)
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn preallocate(_file: &File, _size: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn read_stage(source: PathBuf, buffer_size: usize, direct: bool, tx: mpsc::Sender<Vec<u8>>) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    let direct_file = if direct { open_direct(&options, &source)? } else { None };
+    let send = |chunk: &[u8]| tx.blocking_send(chunk.to_vec()).is_ok();
+
+    if let Some(mut file) = direct_file {
+        let mut buffer = AlignedBuffer::new(buffer_size);
+        loop {
+            faults::before_read();
+            let n = file.read(buffer.as_mut_slice())?;
+            if n == 0 || !send(&buffer.as_mut_slice()[..n]) {
+                return Ok(());
+            }
+        }
+    }
+
+    let mut file = options.open(&source)?;
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        faults::before_read();
+        let n = file.read(&mut buffer)?;
+        if n == 0 || !send(&buffer[..n]) {
+            return Ok(());
+        }
+    }
+}
+
+fn write_stage(dest: PathBuf, buffer_size: usize, direct: bool, preallocated: Option<u64>, mut rx: mpsc::Receiver<Vec<u8>>) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    let direct_file = if direct { open_direct(&options, &dest)? } else { None };
+
+    let Some(mut file) = direct_file else {
+        let mut file = options.open(&dest)?;
+        if let Some(size) = preallocated {
+            preallocate(&file, size)?;
+        }
+        let mut written = 0;
+        while let Some(chunk) = rx.blocking_recv() {
+            faults::before_write()?;
+            file.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+        file.flush()?;
+        // The source may have shrunk since it was measured.
+        if preallocated.is_some() {
+            file.set_len(written)?;
+        }
+        return Ok(());
+    };
+    if let Some(size) = preallocated {
+        preallocate(&file, size)?;
+    }
+
+    // Only whole aligned blocks go through O_DIRECT; the unaligned tail is
+    // written through a regular handle once the data runs out.
+    let mut buffer = AlignedBuffer::new(buffer_size);
+    let mut filled = 0;
+    let mut written = 0;
+    while let Some(chunk) = rx.blocking_recv() {
+        let mut chunk = &chunk[..];
+        while !chunk.is_empty() {
+            let n = chunk.len().min(buffer.capacity - filled);
+            buffer.as_mut_slice()[filled..filled + n].copy_from_slice(&chunk[..n]);
+            filled += n;
+            chunk = &chunk[n..];
+            if filled == buffer.capacity {
+                faults::before_write()?;
+                file.write_all(buffer.as_mut_slice())?;
+                written += filled as u64;
+                filled = 0;
+            }
+        }
+    }
+    drop(file);
+    let mut tail = OpenOptions::new().write(true).open(&dest)?;
+    tail.seek(SeekFrom::Start(written))?;
+    tail.write_all(&buffer.as_mut_slice()[..filled])?;
+    tail.set_len(written + filled as u64)?;
+    Ok(())
+}
+
+// Reports how far the copy of `source` has got every FILE_PROGRESS_INTERVAL
+// until aborted. Runs apart from the copy so a copy stuck on a hung read or
+// write still shows up, as stalled.
+async fn report_progress(source: PathBuf, total: u64, copied: Arc<AtomicU64>, observer: Arc<dyn Observer>) {
+    let mut last = 0;
+    loop {
+        tokio::time::sleep(FILE_PROGRESS_INTERVAL).await;
+        let now = copied.load(Ordering::SeqCst);
+        let rate = (now - last) as f64 / FILE_PROGRESS_INTERVAL.as_secs_f64();
+        last = now;
+        let percent = now as f64 / total.max(1) as f64 * 100.0;
+        if rate == 0.0 {
+            warn!("Copying {:?}: {:.0}% ({} of {}), stalled", source, percent, format_size(now), format_size(total));
+        } else {
+            info!("Copying {:?}: {:.0}% ({} of {}), {}/s", source, percent, format_size(now), format_size(total), format_size(rate as u64));
+        }
+        observer.on_file_progress(&source, now, total);
+    }
+}
+
+fn start_reporter(source: &Path, metadata: &std::fs::Metadata, copied: &Arc<AtomicU64>, options: &SyncOptions) -> Option<tokio::task::JoinHandle<()>> {
+    options.file_progress.filter(|min_size| metadata.len() >= *min_size).map(|_| {
+        tokio::spawn(report_progress(source.to_path_buf(), metadata.len(), copied.clone(), options.observer.clone()))
+    })
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buffer, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buffer: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buffer: &[u8], mut offset: u64) -> std::io::Result<()> {
+    while !buffer.is_empty() {
+        let n = std::os::windows::fs::FileExt::seek_write(file, buffer, offset)?;
+        buffer = &buffer[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at(_file: &File, _buffer: &mut [u8], _offset: u64) -> std::io::Result<usize> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "positional reads are not available on this platform"))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_at(_file: &File, _buffer: &[u8], _offset: u64) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "positional writes are not available on this platform"))
+}
+
+// One of `streams` workers copying every `streams`th chunk of `size` bytes,
+// starting with chunk `index`, and handing each on to be hashed in turn.
+fn stream_stage(source: Arc<File>, dest: Arc<File>, index: usize, streams: usize, chunk_size: usize, size: u64, tx: mpsc::Sender<Vec<u8>>) -> std::io::Result<()> {
+    let mut offset = (index * chunk_size) as u64;
+    while offset < size {
+        let wanted = chunk_size.min((size - offset) as usize);
+        let mut chunk = vec![0; wanted];
+        let mut filled = 0;
+        while filled < chunk.len() {
+            faults::before_read();
+            match read_at(&source, &mut chunk[filled..], offset + filled as u64)? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        chunk.truncate(filled);
+        faults::before_write()?;
+        write_at(&dest, &chunk, offset)?;
+        // A short chunk means the source shrank, so nothing follows it.
+        if tx.blocking_send(chunk).is_err() || filled < wanted {
+            return Ok(());
+        }
+        offset += (streams * chunk_size) as u64;
+    }
+    Ok(())
+}
+
+// Copies `source` with `options.streams` workers reading and writing
+// interleaved chunks at once into a destination sized up front, which a
+// single stream on fast disks or links can't keep busy. Chunk `i` goes to
+// worker `i % streams`, so taking one chunk from each worker in turn hashes
+// the file in order, and no worker runs more than CHANNEL_DEPTH chunks
+// ahead of the hash.
+async fn copy_streams(source: &Path, dest: &Path, metadata: &std::fs::Metadata, options: &SyncOptions) -> Result<String, SyncError> {
+    let (size, streams, chunk_size) = (metadata.len(), options.streams, options.buffer_size);
+    let source_file = Arc::new(File::open(source)?);
+    let dest_file = OpenOptions::new().write(true).create(true).truncate(true).open(dest)?;
+    if size > 0 {
+        preallocate(&dest_file, size)?;
+    }
+    dest_file.set_len(size)?;
+    let dest_file = Arc::new(dest_file);
+
+    let mut receivers = Vec::with_capacity(streams);
+    let mut workers = Vec::with_capacity(streams);
+    for index in 0..streams {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>(CHANNEL_DEPTH);
+        let (source_file, dest_file) = (source_file.clone(), dest_file.clone());
+        workers.push(tokio::task::spawn_blocking(move || stream_stage(source_file, dest_file, index, streams, chunk_size, size, tx)));
+        receivers.push(rx);
+    }
+
+    let copied = Arc::new(AtomicU64::new(0));
+    let reporter = start_reporter(source, metadata, &copied, options);
+    let mut hasher = Hasher::new(options.hash);
+    for index in 0.. {
+        let Some(chunk) = receivers[index % streams].recv().await else {
+            break;
+        };
+        hasher.update(&chunk);
+        copied.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+        if chunk.len() < chunk_size {
+            break;
+        }
+    }
+    // Workers still waiting to hand on a chunk give up once nobody listens.
+    drop(receivers);
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+
+    for worker in workers {
+        worker.await.map_err(join_error)??;
+    }
+    // The source may have shrunk since it was measured.
+    let written = copied.load(Ordering::SeqCst);
+    if written < size {
+        dest_file.set_len(written)?;
+    }
+    Ok(hasher.finish())
+}
+
+// Attempts made at copying a file that keeps changing underneath us.
+const CHANGE_RETRIES: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Copied {
+    pub hash: String,
+    // Source metadata taken before the copy that produced `hash`.
+    pub source: std::fs::Metadata,
+}
+
+fn same_version(before: &std::fs::Metadata, after: &std::fs::Metadata) -> bool {
+    before.len() == after.len() && before.modified().ok() == after.modified().ok()
+}
+
+// Copies `source` to `dest` in three overlapping stages (read, hash, write)
+// connected by bounded channels, so the source is read only once and its
+// hash comes for free with the copy.
+async fn copy_once(source: &Path, dest: &Path, metadata: &std::fs::Metadata, options: &SyncOptions) -> Result<String, SyncError> {
+    let direct = options.direct_io.is_some_and(|min_size| metadata.len() >= min_size);
+    let preallocated = options.preallocate.then_some(metadata.len()).filter(|size| *size > 0);
+    let buffer_size = options.buffer_size;
+
+    let (read_tx, mut read_rx) = mpsc::channel::<Vec<u8>>(CHANNEL_DEPTH);
+    let (write_tx, write_rx) = mpsc::channel::<Vec<u8>>(CHANNEL_DEPTH);
+
+    let source_path = source.to_path_buf();
+    let reader = tokio::task::spawn_blocking(move || read_stage(source_path, buffer_size, direct, read_tx));
+    let dest_path = dest.to_path_buf();
+    let writer = tokio::task::spawn_blocking(move || write_stage(dest_path, buffer_size, direct, preallocated, write_rx));
+
+    let copied = Arc::new(AtomicU64::new(0));
+    let reporter = start_reporter(source, metadata, &copied, options);
+
+    let mut hasher = Hasher::new(options.hash);
+    while let Some(chunk) = read_rx.recv().await {
+        hasher.update(&chunk);
+        let len = chunk.len() as u64;
+        if write_tx.send(chunk).await.is_err() {
+            break;
+        }
+        copied.fetch_add(len, Ordering::SeqCst);
+    }
+    drop(write_tx);
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+
+    writer.await.map_err(join_error)??;
+    reader.await.map_err(join_error)??;
+    Ok(hasher.finish())
+}
+
+// A source whose size or mtime moved during the copy was being written to,
+// so the copy may be torn; it is re-copied a few times before giving up
+// with a warning.
+pub async fn copy_file(source: &Path, dest: &Path, options: &SyncOptions) -> Result<Copied, SyncError> {
+    options.guard_write(dest)?;
+    let _permit = scheduler::transfer_permit().await;
+    options.observer.on_file_start(source);
+    let mut attempt = 1;
+    loop {
+        let metadata = std::fs::metadata(source)?;
+        space::ensure_headroom(dest, metadata.len(), options)?;
+        let hash = match options.multi_stream.is_some_and(|min_size| metadata.len() >= min_size) && options.streams > 1 {
+            true => copy_streams(source, dest, &metadata, options).await?,
+            false => copy_once(source, dest, &metadata, options).await?,
+        };
+        let after = std::fs::metadata(source)?;
+        let stable = same_version(&metadata, &after);
+        if stable || attempt == CHANGE_RETRIES {
+            if !stable {
+                warn!("{:?} kept changing while being copied, the copy may be inconsistent", source);
+            }
+            attrs::settle(&metadata, dest, options)?;
+            options.observer.on_file_done(source, metadata.len());
+            return Ok(Copied { hash, source: metadata });
+        }
+        warn!("{:?} changed while being copied, copying it again", source);
+        attempt += 1;
+    }
+}
@@ -0,0 +1,199 @@
+use crate::options::format_size;
+use crate::space;
+use crate::SyncError;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Clock differences below this are put down to timestamp rounding.
+const SKEW_TOLERANCE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Ok => "ok",
+            Level::Warn => "warn",
+            Level::Fail => "FAIL",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub level: Level,
+    pub check: &'static str,
+    pub detail: String,
+}
+
+fn finding(level: Level, check: &'static str, detail: impl Into<String>) -> Finding {
+    Finding { level, check, detail: detail.into() }
+}
+
+// What probing a directory found, settled by the checks that can only be
+// judged with both sides in view.
+#[derive(Debug, Clone, Default)]
+pub struct Probe {
+    pub findings: Vec<Finding>,
+    pub case_sensitive: Option<bool>,
+}
+
+// Smallest step the file system keeps modification times to, found by
+// setting one with every digit filled in and seeing what is left of it.
+fn mtime_resolution(file: &File, path: &Path) -> std::io::Result<Duration> {
+    let probe = UNIX_EPOCH + Duration::new(1_600_000_001, 123_456_789);
+    file.set_modified(probe)?;
+    let kept = fs::metadata(path)?.modified()?;
+    let lost = probe.duration_since(kept).unwrap_or_else(|e| e.duration());
+    Ok([1, 100, 1_000, 1_000_000, 1_000_000_000, 2_000_000_000]
+        .into_iter()
+        .map(Duration::from_nanos)
+        .find(|step| lost < *step)
+        .unwrap_or(Duration::from_secs(2)))
+}
+
+fn describe(resolution: Duration) -> String {
+    match resolution.as_nanos() {
+        n if n < 1_000 => format!("{}ns", n),
+        n if n < 1_000_000 => format!("{}µs", n / 1_000),
+        n if n < 1_000_000_000 => format!("{}ms", n / 1_000_000),
+        n => format!("{}s", n / 1_000_000_000),
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "symlinks are not available on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_xattr(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let (name, value) = (c"user.rusty_file_sync.doctor", b"1");
+    if unsafe { libc::setxattr(c_path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_xattr(_path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "not checked on this platform"))
+}
+
+// Runs every check that needs a file of our own in `scratch`.
+fn probe_writes(scratch: &Path, probe: &mut Probe) -> std::io::Result<()> {
+    let before = SystemTime::now();
+    let path = scratch.join("probe");
+    let file = File::create(&path)?;
+    let written = fs::metadata(&path)?.modified()?;
+    probe.findings.push(finding(Level::Ok, "write", "files can be created"));
+
+    // Network file systems stamp files with the server's clock.
+    let skew = written.duration_since(before).or_else(|e| before.duration_since(written).map_err(|_| e));
+    match skew {
+        Ok(skew) if skew > SKEW_TOLERANCE => probe.findings.push(finding(
+            Level::Warn,
+            "clock",
+            format!("file times are {} off this machine's clock; newer-wins comparisons may pick the wrong side", humantime::format_duration(Duration::from_secs(skew.as_secs()))),
+        )),
+        _ => probe.findings.push(finding(Level::Ok, "clock", "file times agree with this machine's clock")),
+    }
+
+    match mtime_resolution(&file, &path) {
+        Ok(resolution) if resolution >= Duration::from_secs(1) => probe.findings.push(finding(
+            Level::Warn,
+            "mtime",
+            format!("modification times are kept to {}; files changed twice within that look unchanged", describe(resolution)),
+        )),
+        Ok(resolution) => probe.findings.push(finding(Level::Ok, "mtime", format!("modification times are kept to {}", describe(resolution)))),
+        Err(e) => probe.findings.push(finding(Level::Warn, "mtime", format!("modification times can't be set: {}", e))),
+    }
+    drop(file);
+
+    let upper = scratch.join("Case");
+    File::create(&upper)?;
+    let case_sensitive = !scratch.join("case").exists();
+    probe.case_sensitive = Some(case_sensitive);
+    probe.findings.push(finding(Level::Ok, "case", if case_sensitive { "names are case-sensitive" } else { "names are case-insensitive" }));
+
+    match symlink(Path::new("probe"), &scratch.join("link")) {
+        Ok(()) => probe.findings.push(finding(Level::Ok, "symlink", "symbolic links can be created")),
+        Err(e) => probe.findings.push(finding(Level::Warn, "symlink", format!("symbolic links can't be created: {}", e))),
+    }
+    match set_xattr(&path) {
+        Ok(()) => probe.findings.push(finding(Level::Ok, "xattr", "extended attributes can be set")),
+        Err(e) => probe.findings.push(finding(Level::Warn, "xattr", format!("extended attributes can't be set: {}", e))),
+    }
+    Ok(())
+}
+
+fn probe_dir(dir: PathBuf, writes: bool) -> Probe {
+    let mut probe = Probe::default();
+    match fs::metadata(&dir) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            probe.findings.push(finding(Level::Fail, "exists", "not a directory"));
+            return probe;
+        }
+        Err(e) => {
+            probe.findings.push(finding(Level::Fail, "exists", e.to_string()));
+            return probe;
+        }
+    }
+    match fs::read_dir(&dir) {
+        Ok(_) => probe.findings.push(finding(Level::Ok, "read", "the directory can be listed")),
+        Err(e) => probe.findings.push(finding(Level::Fail, "read", e.to_string())),
+    }
+    match space::disk_space(&dir) {
+        Ok(space) => probe.findings.push(finding(Level::Ok, "space", format!("{} free of {}", format_size(space.available), format_size(space.total)))),
+        Err(e) => probe.findings.push(finding(Level::Warn, "space", format!("free space unknown: {}", e))),
+    }
+
+    let scratch = dir.join(format!(".rusty_file_sync-doctor-{}", std::process::id()));
+    let result = fs::create_dir(&scratch).and_then(|_| probe_writes(&scratch, &mut probe));
+    let _ = fs::remove_dir_all(&scratch);
+    if let Err(e) = result {
+        let level = if writes { Level::Fail } else { Level::Warn };
+        probe.findings.push(finding(level, "write", format!("can't write here ({}); only usable as a one-way source", e)));
+    }
+    probe
+}
+
+// Checks what the file system at `dir` supports, without touching anything
+// but a scratch directory it removes again. Not being able to write only
+// fails the check when `writes` is set, as it is for destinations.
+pub async fn probe(dir: &Path, writes: bool) -> Result<Probe, SyncError> {
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || probe_dir(dir, writes)).await.map_err(|e| SyncError::FileSystemError(std::io::Error::other(e)))
+}
+
+// Checks that depend on both sides together.
+pub fn compare(source: &Probe, destination: &Probe) -> Vec<Finding> {
+    match (source.case_sensitive, destination.case_sensitive) {
+        (Some(true), Some(false)) => vec![finding(
+            Level::Warn,
+            "case",
+            "the source tells names apart by case but the destination doesn't; use --name-case or --rename to avoid collisions",
+        )],
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,145 @@
+use crate::SyncError;
+use log::debug;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+// Files written before every batch is flushed under `per-batch`.
+const BATCH_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FsyncPolicy {
+    #[default]
+    Never,
+    PerFile,
+    PerBatch,
+    End,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "never" => Ok(FsyncPolicy::Never),
+            "per-file" => Ok(FsyncPolicy::PerFile),
+            "per-batch" => Ok(FsyncPolicy::PerBatch),
+            "end" => Ok(FsyncPolicy::End),
+            _ => Err(format!("Invalid fsync policy: {} (expected never, per-file, per-batch or end)", value)),
+        }
+    }
+}
+
+fn sync_path(path: &Path) -> Result<(), SyncError> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+// Directories can't be opened for syncing on every platform; a failure
+// there is not worth failing the pass for.
+fn sync_dir(path: &Path) {
+    if let Err(e) = File::open(path).and_then(|dir| dir.sync_all()) {
+        debug!("Could not fsync directory {:?}: {}", path, e);
+    }
+}
+
+// Tracks files written during a pass and fsyncs them, together with the
+// directories holding them, when the policy asks for it.
+pub struct Durability {
+    policy: FsyncPolicy,
+    files: Vec<PathBuf>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl Durability {
+    pub fn new(policy: FsyncPolicy) -> Self {
+        Durability { policy, files: Vec::new(), dirs: BTreeSet::new() }
+    }
+
+    pub fn written(&mut self, path: &Path) -> Result<(), SyncError> {
+        if self.policy == FsyncPolicy::Never {
+            return Ok(());
+        }
+        self.files.push(path.to_path_buf());
+        if let Some(parent) = path.parent() {
+            self.dirs.insert(parent.to_path_buf());
+        }
+        match self.policy {
+            FsyncPolicy::PerFile => self.flush(),
+            FsyncPolicy::PerBatch if self.files.len() >= BATCH_SIZE => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn created(&mut self, dir: &Path) {
+        if self.policy == FsyncPolicy::Never {
+            return;
+        }
+        if let Some(parent) = dir.parent() {
+            self.dirs.insert(parent.to_path_buf());
+        }
+        if self.policy == FsyncPolicy::PerFile {
+            for dir in std::mem::take(&mut self.dirs) {
+                sync_dir(&dir);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), SyncError> {
+        for file in self.files.drain(..) {
+            sync_path(&file)?;
+        }
+        for dir in std::mem::take(&mut self.dirs) {
+            sync_dir(&dir);
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), SyncError> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+
+    #[test]
+    fn syncs_when_the_policy_says_so() {
+        let dir = scratch("durability");
+        let file = dir.join("sub/file");
+        write(&file, "data");
+
+        let mut never = Durability::new(FsyncPolicy::Never);
+        never.written(&dir.join("missing")).unwrap();
+        never.created(&dir.join("sub"));
+        assert!(never.files.is_empty() && never.dirs.is_empty());
+
+        let mut per_file = Durability::new(FsyncPolicy::PerFile);
+        per_file.written(&file).unwrap();
+        assert!(per_file.files.is_empty() && per_file.dirs.is_empty());
+        assert!(per_file.written(&dir.join("missing")).is_err());
+
+        let mut per_batch = Durability::new(FsyncPolicy::PerBatch);
+        for _ in 1..BATCH_SIZE {
+            per_batch.written(&file).unwrap();
+        }
+        assert_eq!(per_batch.files.len(), BATCH_SIZE - 1);
+        per_batch.written(&file).unwrap();
+        assert!(per_batch.files.is_empty());
+
+        // Files written under `end` wait for the end of the pass.
+        let mut end = Durability::new(FsyncPolicy::End);
+        end.written(&file).unwrap();
+        end.created(&dir.join("sub"));
+        assert_eq!(end.dirs.iter().collect::<Vec<_>>(), [&dir, &dir.join("sub")]);
+        end.finish().unwrap();
+        let mut end = Durability::new(FsyncPolicy::End);
+        end.written(&dir.join("missing")).unwrap();
+        assert!(end.finish().is_err());
+        assert_eq!("per-batch".parse(), Ok(FsyncPolicy::PerBatch));
+        assert!("always".parse::<FsyncPolicy>().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use crate::json::escape;
+use crate::observer::Observer;
+use crate::{timestamp, SyncError};
+use log::warn;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Writes one JSON object per line for every observed action, to stdout (`-`)
+// or to a file or FIFO, so other tools can follow a sync as it happens.
+pub struct EventStream {
+    job: String,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl EventStream {
+    pub fn open(target: &str, job: &str) -> Result<Self, SyncError> {
+        let out: Box<dyn Write + Send> = if target == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(target)?)
+        };
+        Ok(EventStream { job: job.to_string(), out: Mutex::new(out) })
+    }
+
+    // `fields` are already rendered `"key":value` pairs.
+    fn emit(&self, event: &str, fields: &[String]) {
+        let mut line = format!(
+            "{{\"time\":{},\"job\":{},\"event\":{}",
+            escape(&timestamp::now()),
+            escape(&self.job),
+            escape(event)
+        );
+        for field in fields {
+            line.push(',');
+            line.push_str(field);
+        }
+        line.push_str("}\n");
+
+        let mut out = self.out.lock().unwrap();
+        // A consumer going away must not stop the sync.
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            warn!("Could not write event: {}", e);
+        }
+    }
+}
+
+fn path_field(path: &Path) -> String {
+    format!("\"path\":{}", escape(&path.to_string_lossy()))
+}
+
+impl Observer for EventStream {
+    fn on_file_start(&self, path: &Path) {
+        self.emit("file_start", &[path_field(path)]);
+    }
+
+    fn on_file_progress(&self, path: &Path, copied: u64, total: u64) {
+        self.emit("file_progress", &[path_field(path), format!("\"bytes\":{}", copied), format!("\"total\":{}", total)]);
+    }
+
+    fn on_file_done(&self, path: &Path, bytes: u64) {
+        self.emit("file_done", &[path_field(path), format!("\"bytes\":{}", bytes)]);
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.emit("remove", &[path_field(path)]);
+    }
+
+    fn on_error(&self, error: &SyncError) {
+        self.emit("error", &[format!("\"message\":{}", escape(&error.to_string()))]);
+    }
+
+    fn on_pass_complete(&self) {
+        self.emit("pass_complete", &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn writes_one_object_per_event() {
+        let dir = crate::testing::scratch("events");
+        let target = dir.join("events.ndjson");
+        let events = EventStream::open(&target.to_string_lossy(), "a \"job\"").unwrap();
+        events.on_file_start(Path::new("dir/a\tb.txt"));
+        events.on_file_progress(Path::new("dir/a\tb.txt"), 512, 1024);
+        events.on_file_done(Path::new("dir/a\tb.txt"), 1024);
+        events.on_remove(Path::new("old.txt"));
+        events.on_error(&SyncError::ConfigError("bad\nline".to_string()));
+        events.on_pass_complete();
+
+        let contents = std::fs::read_to_string(&target).unwrap();
+        let lines: Vec<json::Value> = contents.lines().map(|line| json::parse(line).unwrap()).collect();
+        let field = |index: usize, key: &str| lines[index].get(key).and_then(json::Value::as_str).map(str::to_string);
+        assert_eq!(lines.len(), 6);
+        assert!(lines.iter().all(|line| line.get("job").and_then(json::Value::as_str) == Some("a \"job\"")));
+        assert!(timestamp::parse(&field(0, "time").unwrap()).is_some());
+        assert_eq!(field(0, "event").as_deref(), Some("file_start"));
+        assert_eq!(field(1, "path").as_deref(), Some("dir/a\tb.txt"));
+        assert_eq!(lines[1].get("bytes").and_then(json::Value::as_u64), Some(512));
+        assert_eq!(lines[1].get("total").and_then(json::Value::as_u64), Some(1024));
+        assert_eq!(lines[2].get("bytes").and_then(json::Value::as_u64), Some(1024));
+        assert_eq!(field(3, "event").as_deref(), Some("remove"));
+        assert_eq!(field(4, "message").as_deref(), Some("Config error: bad\nline"));
+        assert_eq!(field(5, "event").as_deref(), Some("pass_complete"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
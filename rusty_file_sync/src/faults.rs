@@ -0,0 +1,103 @@
+use log::warn;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// Failures injected on purpose, to exercise retries, resumption and the
+// temporary-file-and-rename dance without waiting for a disk to fail.
+// Meant for tests and for checking a setup, never for real syncs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    // Whole-process ordinal of the write that fails, counting from 1.
+    pub write_error: Option<u64>,
+    // Pause before every read of a source chunk.
+    pub slow_read: Option<Duration>,
+    // Ordinal of the rename into place that fails, leaving the temporary
+    // file behind as an interrupted transfer would.
+    pub rename_error: Option<u64>,
+}
+
+impl std::str::FromStr for Faults {
+    type Err = String;
+
+    // e.g. `write-error=3,slow-read=200ms,rename-error=1`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut faults = Faults::default();
+        for part in value.split(',').filter(|part| !part.is_empty()) {
+            let (name, setting) = part.split_once('=').ok_or_else(|| format!("Invalid fault: {} (expected name=value)", part))?;
+            let count = || setting.parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(|| format!("Invalid fault count: {}", setting));
+            match name {
+                "write-error" => faults.write_error = Some(count()?),
+                "rename-error" => faults.rename_error = Some(count()?),
+                "slow-read" => faults.slow_read = Some(humantime::parse_duration(setting).map_err(|e| format!("Invalid fault delay {}: {}", setting, e))?),
+                _ => return Err(format!("Invalid fault: {} (expected write-error, slow-read or rename-error)", name)),
+            }
+        }
+        Ok(faults)
+    }
+}
+
+static FAULTS: OnceLock<Faults> = OnceLock::new();
+static WRITES: AtomicU64 = AtomicU64::new(0);
+static RENAMES: AtomicU64 = AtomicU64::new(0);
+
+// Turns on `faults` for the rest of the process; only the first call counts.
+pub fn inject(faults: Faults) {
+    warn!("Injecting faults: {:?}", faults);
+    let _ = FAULTS.set(faults);
+}
+
+fn injected(what: &str, ordinal: u64) -> io::Error {
+    warn!("Injected fault: failing {} {}", what, ordinal);
+    io::Error::other(format!("injected fault: {} {}", what, ordinal))
+}
+
+// Called before writing a chunk of file data.
+pub fn before_write() -> io::Result<()> {
+    let Some(nth) = FAULTS.get().and_then(|faults| faults.write_error) else {
+        return Ok(());
+    };
+    match WRITES.fetch_add(1, Ordering::SeqCst) + 1 {
+        n if n == nth => Err(injected("write", n)),
+        _ => Ok(()),
+    }
+}
+
+// Called before reading a chunk of file data, from blocking code.
+pub fn before_read() {
+    if let Some(delay) = FAULTS.get().and_then(|faults| faults.slow_read) {
+        std::thread::sleep(delay);
+    }
+}
+
+// Called before renaming a finished temporary file into place.
+pub fn before_rename() -> io::Result<()> {
+    let Some(nth) = FAULTS.get().and_then(|faults| faults.rename_error) else {
+        return Ok(());
+    };
+    match RENAMES.fetch_add(1, Ordering::SeqCst) + 1 {
+        n if n == nth => Err(injected("rename", n)),
+        _ => Ok(()),
+    }
+}
+
+// Only parsing is tested here: injected faults are process-wide, and would
+// fail the writes of every other test running alongside.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fault_lists() {
+        assert_eq!(
+            "write-error=3,slow-read=200ms,rename-error=1".parse(),
+            Ok(Faults { write_error: Some(3), slow_read: Some(Duration::from_millis(200)), rename_error: Some(1) })
+        );
+        assert_eq!("".parse(), Ok(Faults::default()));
+        assert_eq!("rename-error=2,".parse::<Faults>().unwrap().rename_error, Some(2));
+        for value in ["write-error", "write-error=0", "write-error=x", "slow-read=fast", "disk-full=1"] {
+            assert!(value.parse::<Faults>().is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::backend::StorageBackend;
+use crate::SyncError;
+use std::collections::HashSet;
+use std::io::Write;
+
+// Fixtures describe a tree in a subset of YAML: a mapping is a directory,
+// `{}` an empty one, and anything else a file holding the value, written
+// plain, quoted or as a `|` block.
+//
+//   docs:
+//     readme.txt: |
+//       first line
+//       second line
+//     empty: {}
+//   notes.txt: "no trailing newline"
+
+// A path and the file's contents, or None for a directory.
+pub type Entry = (String, Option<Vec<u8>>);
+
+// Entries come out parents first, in the order they are written.
+pub fn parse(text: &str) -> Result<Vec<Entry>, SyncError> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut entries = Vec::new();
+    let mut index = 0;
+    parse_map(&lines, &mut index, 0, "", &mut entries)?;
+    Ok(entries)
+}
+
+fn invalid(index: usize, message: &str) -> SyncError {
+    SyncError::ConfigError(format!("Fixture line {}: {}", index + 1, message))
+}
+
+fn skipped(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || line.starts_with('#')
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn parse_map(lines: &[&str], index: &mut usize, level: usize, prefix: &str, entries: &mut Vec<Entry>) -> Result<(), SyncError> {
+    let mut names = HashSet::new();
+    while *index < lines.len() {
+        let line = lines[*index];
+        if skipped(line) {
+            *index += 1;
+            continue;
+        }
+        if line.trim_start_matches(' ').starts_with('\t') {
+            return Err(invalid(*index, "tabs can't be used for indentation"));
+        }
+        match indent(line) {
+            n if n < level => return Ok(()),
+            n if n > level => return Err(invalid(*index, "unexpected indentation")),
+            _ => {}
+        }
+        let (name, value) = split_entry(line.trim(), *index)?;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(invalid(*index, &format!("invalid name {:?}", name)));
+        }
+        if !names.insert(name.clone()) {
+            return Err(invalid(*index, &format!("{} is listed twice", name)));
+        }
+        let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        let start = *index;
+        *index += 1;
+
+        let next = lines[*index..].iter().position(|line| !skipped(line)).map(|offset| *index + offset);
+        let nested = next.filter(|&next| indent(lines[next]) > level);
+        match value {
+            "" => match nested {
+                Some(next) => {
+                    entries.push((path.clone(), None));
+                    parse_map(lines, index, indent(lines[next]), &path, entries)?;
+                }
+                None => entries.push((path, Some(Vec::new()))),
+            },
+            "{}" => entries.push((path, None)),
+            "|" | "|-" => entries.push((path, Some(block(lines, index, level, value == "|").into_bytes()))),
+            _ if value.starts_with('"') => entries.push((path, Some(double_quoted(value, start)?.into_bytes()))),
+            _ if value.starts_with('\'') => entries.push((path, Some(single_quoted(value, start)?.into_bytes()))),
+            _ => {
+                let plain = value.split(" #").next().unwrap().trim_end();
+                entries.push((path, Some(plain.as_bytes().to_vec())));
+            }
+        }
+        if let Some(next) = nested.filter(|_| !value.is_empty() && !value.starts_with('|')) {
+            return Err(invalid(next, "unexpected indentation"));
+        }
+    }
+    Ok(())
+}
+
+// A line's name, unquoted, and the value after its colon.
+fn split_entry(line: &str, index: usize) -> Result<(String, &str), SyncError> {
+    let (name, rest) = if line.starts_with('"') || line.starts_with('\'') {
+        let end = quote_end(line).ok_or_else(|| invalid(index, "unterminated name"))?;
+        let name = if line.starts_with('"') { double_quoted(&line[..=end], index)? } else { single_quoted(&line[..=end], index)? };
+        (name, &line[end + 1..])
+    } else {
+        let colon = line.find(':').ok_or_else(|| invalid(index, "expected `name: value`"))?;
+        (line[..colon].trim_end().to_string(), &line[colon..])
+    };
+    match rest.strip_prefix(':') {
+        Some(value) if value.is_empty() || value.starts_with(' ') => Ok((name, value.trim())),
+        _ => Err(invalid(index, "expected `name: value`")),
+    }
+}
+
+// Where the quoted string `line` starts with ends, skipping escaped quotes.
+fn quote_end(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let quote = bytes[0];
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if quote == b'"' => i += 1,
+            b'\'' if quote == b'\'' && bytes.get(i + 1) == Some(&b'\'') => i += 1,
+            c if c == quote => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// The lines indented past `level` after a `|`, with the indentation of the
+// first one removed. `keep` leaves a single newline at the end, as `|` does.
+fn block(lines: &[&str], index: &mut usize, level: usize, keep: bool) -> String {
+    let mut taken = Vec::new();
+    while *index < lines.len() && (lines[*index].trim().is_empty() || indent(lines[*index]) > level) {
+        taken.push(lines[*index]);
+        *index += 1;
+    }
+    // Trailing blank lines belong to whatever follows.
+    while taken.last().is_some_and(|line| line.trim().is_empty()) {
+        taken.pop();
+        *index -= 1;
+    }
+    let width = taken.first().map_or(0, |line| indent(line));
+    let mut text = taken.iter().map(|line| line.get(width..).unwrap_or("")).collect::<Vec<_>>().join("\n");
+    if keep && !text.is_empty() {
+        text.push('\n');
+    }
+    text
+}
+
+fn double_quoted(value: &str, index: usize) -> Result<String, SyncError> {
+    let mut text = String::new();
+    let mut chars = value[1..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                return match chars.as_str().trim() {
+                    rest if rest.is_empty() || rest.starts_with('#') => Ok(text),
+                    _ => Err(invalid(index, "unexpected text after the closing quote")),
+                }
+            }
+            '\\' => text.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('"') => '"',
+                Some('\\') => '\\',
+                other => return Err(invalid(index, &format!("unknown escape \\{}", other.map(String::from).unwrap_or_default()))),
+            }),
+            c => text.push(c),
+        }
+    }
+    Err(invalid(index, "unterminated string"))
+}
+
+fn single_quoted(value: &str, index: usize) -> Result<String, SyncError> {
+    let end = quote_end(value).ok_or_else(|| invalid(index, "unterminated string"))?;
+    match value[end + 1..].trim() {
+        rest if rest.is_empty() || rest.starts_with('#') => Ok(value[1..end].replace("''", "'")),
+        _ => Err(invalid(index, "unexpected text after the closing quote")),
+    }
+}
+
+// Creates the tree described by `text` in `backend`, next to whatever is
+// there already. Returns how many entries were written.
+pub fn load(text: &str, backend: &dyn StorageBackend) -> Result<usize, SyncError> {
+    let entries = parse(text)?;
+    for (path, contents) in &entries {
+        match contents {
+            None => backend.create_dir(path)?,
+            Some(contents) => {
+                let mut writer = backend.write(path)?;
+                writer.write_all(contents)?;
+                writer.flush()?;
+            }
+        }
+    }
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::sync_between;
+    use crate::memory::MemoryBackend;
+    use crate::options::SyncOptions;
+    use std::sync::Arc;
+
+    fn file(path: &str, contents: &str) -> Entry {
+        (path.to_string(), Some(contents.as_bytes().to_vec()))
+    }
+
+    fn dir(path: &str) -> Entry {
+        (path.to_string(), None)
+    }
+
+    fn error(text: &str) -> String {
+        match parse(text) {
+            Err(SyncError::ConfigError(message)) => message,
+            other => panic!("{:?} parsed as {:?}", text, other.map(|entries| entries.len())),
+        }
+    }
+
+    #[test]
+    fn parses_nested_directories() {
+        let text = "docs:\n  guide:\n    intro.txt: hello\n  empty: {}\n\n# comment\ntop.txt: value # trailing\nblank:\n";
+        assert_eq!(parse(text).unwrap(), [dir("docs"), dir("docs/guide"), file("docs/guide/intro.txt", "hello"), dir("docs/empty"), file("top.txt", "value"), file("blank", "")]);
+    }
+
+    #[test]
+    fn parses_quoted_values_and_names() {
+        let text = "a: \"tab\\there\\n\"\nb: 'it''s # not a comment'\n\"c: d\": '' # empty\n'e f': x\n";
+        assert_eq!(parse(text).unwrap(), [file("a", "tab\there\n"), file("b", "it's # not a comment"), file("c: d", ""), file("e f", "x")]);
+    }
+
+    #[test]
+    fn parses_blocks() {
+        let text = "dir:\n  kept: |\n    one\n      two\n\n  stripped: |-\n    three\n\nafter: x\n";
+        assert_eq!(parse(text).unwrap(), [dir("dir"), file("dir/kept", "one\n  two\n"), file("dir/stripped", "three"), file("after", "x")]);
+    }
+
+    #[test]
+    fn rejects_bad_indentation() {
+        assert_eq!(error("a: x\n  b: y\n"), "Fixture line 2: unexpected indentation");
+        assert_eq!(error("  a: x\n"), "Fixture line 1: unexpected indentation");
+        assert_eq!(error("dir:\n\tb: y\n"), "Fixture line 2: tabs can't be used for indentation");
+        // A sibling must line up with the entries before it.
+        assert_eq!(error("dir:\n    a: x\n  b: y\n"), "Fixture line 3: unexpected indentation");
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        assert_eq!(error("a x\n"), "Fixture line 1: expected `name: value`");
+        assert_eq!(error("a: x\na: y\n"), "Fixture line 2: a is listed twice");
+        assert_eq!(error("../a: x\n"), "Fixture line 1: invalid name \"../a\"");
+        assert_eq!(error("a: \"open\n"), "Fixture line 1: unterminated string");
+        assert_eq!(error("a: \"\\q\"\n"), "Fixture line 1: unknown escape \\q");
+        assert_eq!(error("a: 'x' y\n"), "Fixture line 1: unexpected text after the closing quote");
+    }
+
+    // Everything `backend` holds, as `parse` would list it.
+    fn tree(backend: &MemoryBackend) -> Vec<Entry> {
+        backend.paths().into_iter().map(|path| (path.clone(), backend.contents(&path))).collect()
+    }
+
+    fn sorted(mut entries: Vec<Entry>) -> Vec<Entry> {
+        entries.sort();
+        entries
+    }
+
+    #[tokio::test]
+    async fn mirrors_a_fixture_onto_another() {
+        let (source, dest) = (Arc::new(MemoryBackend::new()), Arc::new(MemoryBackend::new()));
+        let wanted = "docs:\n  readme.txt: |\n    first\n    second\n  empty: {}\nnotes.txt: \"changed\"\n";
+        assert_eq!(load(wanted, source.as_ref()).unwrap(), 4);
+        load("docs:\n  old.txt: gone\nnotes.txt: before\nstale: {}\n", dest.as_ref()).unwrap();
+
+        sync_between(source, dest.clone(), true, &SyncOptions::default()).await.unwrap();
+        assert_eq!(tree(&dest), sorted(parse(wanted).unwrap()));
+    }
+}
@@ -0,0 +1,359 @@
+use crate::SyncError;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+// Digest used for a job's manifests and comparisons. SHA-1 and MD5 are only
+// there to match manifests and remote listings that publish nothing else;
+// their digests carry a `sha1-` or `md5-` prefix so every recorded hash
+// says how it was made, while SHA-256 digests stay bare as they always were.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha1,
+    Md5,
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "md5" => Ok(HashAlgorithm::Md5),
+            _ => Err(format!("Invalid hash algorithm: {} (expected sha256, sha1 or md5)", value)),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    fn prefix(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "",
+            HashAlgorithm::Sha1 => "sha1-",
+            HashAlgorithm::Md5 => "md5-",
+        }
+    }
+
+    // The algorithm a recorded digest was made with.
+    pub fn of(hash: &str) -> HashAlgorithm {
+        if hash.starts_with("sha1-") {
+            HashAlgorithm::Sha1
+        } else if hash.starts_with("md5-") {
+            HashAlgorithm::Md5
+        } else {
+            HashAlgorithm::Sha256
+        }
+    }
+}
+
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+        }
+    }
+
+    // The digest in hex, prefixed as `HashAlgorithm::of` expects.
+    pub fn finish(self) -> String {
+        let (algorithm, digest) = match self {
+            Hasher::Sha256(hasher) => (HashAlgorithm::Sha256, hasher.finalize().to_vec()),
+            Hasher::Sha1(hasher) => (HashAlgorithm::Sha1, hasher.finish().to_vec()),
+            Hasher::Md5(hasher) => (HashAlgorithm::Md5, hasher.finish().to_vec()),
+        };
+        let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}{}", algorithm.prefix(), hex)
+    }
+}
+
+pub async fn file<P: AsRef<Path>>(path: P, algorithm: HashAlgorithm, buffer_size: usize) -> Result<String, SyncError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+// Whether `path` still has the digest `expected`, hashed the way it was.
+pub async fn matches<P: AsRef<Path>>(path: P, expected: &str, buffer_size: usize) -> Result<bool, SyncError> {
+    Ok(file(path, HashAlgorithm::of(expected), buffer_size).await? == expected)
+}
+
+// SHA-1 and MD5 share the Merkle–Damgård layout: 64-byte blocks, and a
+// final block padded with 0x80, zeros and the message length in bits.
+struct Blocks {
+    pending: Vec<u8>,
+    length: u64,
+}
+
+impl Blocks {
+    fn new() -> Self {
+        Blocks { pending: Vec::with_capacity(64), length: 0 }
+    }
+
+    fn update(&mut self, mut data: &[u8], mut compress: impl FnMut(&[u8; 64])) {
+        self.length += data.len() as u64;
+        if !self.pending.is_empty() {
+            let take = data.len().min(64 - self.pending.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            compress(self.pending.as_slice().try_into().unwrap());
+            self.pending.clear();
+        }
+        let mut chunks = data.chunks_exact(64);
+        for block in &mut chunks {
+            compress(block.try_into().unwrap());
+        }
+        self.pending.extend_from_slice(chunks.remainder());
+    }
+
+    fn finish(mut self, length: [u8; 8], mut compress: impl FnMut(&[u8; 64])) {
+        let mut tail = std::mem::take(&mut self.pending);
+        tail.push(0x80);
+        while tail.len() % 64 != 56 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&length);
+        for block in tail.chunks_exact(64) {
+            compress(block.try_into().unwrap());
+        }
+    }
+}
+
+pub struct Sha1 {
+    state: [u32; 5],
+    blocks: Blocks,
+}
+
+impl Sha1 {
+    fn new() -> Self {
+        Sha1 { state: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0], blocks: Blocks::new() }
+    }
+
+    fn compress(state: &mut [u32; 5], block: &[u8; 64]) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = *state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d, e]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(data, |block| Self::compress(state, block));
+    }
+
+    fn finish(mut self) -> [u8; 20] {
+        let length = (self.blocks.length * 8).to_be_bytes();
+        let state = &mut self.state;
+        self.blocks.finish(length, |block| Self::compress(state, block));
+        let mut digest = [0; 20];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+// The integer parts of 2^32 * |sin(i + 1)|.
+const MD5_CONSTANTS: [u32; 64] = [
+    0xd76a_a478, 0xe8c7_b756, 0x2420_70db, 0xc1bd_ceee,
+    0xf57c_0faf, 0x4787_c62a, 0xa830_4613, 0xfd46_9501,
+    0x6980_98d8, 0x8b44_f7af, 0xffff_5bb1, 0x895c_d7be,
+    0x6b90_1122, 0xfd98_7193, 0xa679_438e, 0x49b4_0821,
+    0xf61e_2562, 0xc040_b340, 0x265e_5a51, 0xe9b6_c7aa,
+    0xd62f_105d, 0x0244_1453, 0xd8a1_e681, 0xe7d3_fbc8,
+    0x21e1_cde6, 0xc337_07d6, 0xf4d5_0d87, 0x455a_14ed,
+    0xa9e3_e905, 0xfcef_a3f8, 0x676f_02d9, 0x8d2a_4c8a,
+    0xfffa_3942, 0x8771_f681, 0x6d9d_6122, 0xfde5_380c,
+    0xa4be_ea44, 0x4bde_cfa9, 0xf6bb_4b60, 0xbebf_bc70,
+    0x289b_7ec6, 0xeaa1_27fa, 0xd4ef_3085, 0x0488_1d05,
+    0xd9d4_d039, 0xe6db_99e5, 0x1fa2_7cf8, 0xc4ac_5665,
+    0xf429_2244, 0x432a_ff97, 0xab94_23a7, 0xfc93_a039,
+    0x655b_59c3, 0x8f0c_cc92, 0xffef_f47d, 0x8584_5dd1,
+    0x6fa8_7e4f, 0xfe2c_e6e0, 0xa301_4314, 0x4e08_11a1,
+    0xf753_7e82, 0xbd3a_f235, 0x2ad7_d2bb, 0xeb86_d391,
+];
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+pub struct Md5 {
+    state: [u32; 4],
+    blocks: Blocks,
+}
+
+impl Md5 {
+    fn new() -> Self {
+        Md5 { state: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476], blocks: Blocks::new() }
+    }
+
+    fn compress(state: &mut [u32; 4], block: &[u8; 64]) {
+        let mut m = [0u32; 16];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            m[i] = u32::from_le_bytes(word.try_into().unwrap());
+        }
+        let [mut a, mut b, mut c, mut d] = *state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let shift = MD5_SHIFTS[(i / 16) * 4 + i % 4];
+            let rotated = a.wrapping_add(f).wrapping_add(MD5_CONSTANTS[i]).wrapping_add(m[g]).rotate_left(shift);
+            (a, d, c, b) = (d, c, b, b.wrapping_add(rotated));
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(data, |block| Self::compress(state, block));
+    }
+
+    fn finish(mut self) -> [u8; 16] {
+        let length = (self.blocks.length * 8).to_le_bytes();
+        let state = &mut self.state;
+        self.blocks.finish(length, |block| Self::compress(state, block));
+        let mut digest = [0; 16];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algorithm: HashAlgorithm, data: &[u8]) -> String {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    const RFC_MESSAGE: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+
+    // Messages around the 56 bytes after which padding spills into a block
+    // of its own, and around a whole block.
+    fn messages() -> Vec<Vec<u8>> {
+        let mut messages = vec![b"".to_vec(), b"abc".to_vec(), RFC_MESSAGE.to_vec()];
+        messages.extend([55, 56, 63, 64, 65].map(|n| vec![b'a'; n]));
+        messages
+    }
+
+    #[test]
+    fn sha1_known_answers() {
+        let expected = [
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709",
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            "c1c8bbdc22796e28c0e15163d20899b65621d65a",
+            "c2db330f6083854c99d4b5bfb6e8f29f201be699",
+            "03f09f5b158a7a8cdad920bddc29b81c18a551f5",
+            "0098ba824b5c16427bd7a1122a5a442a25ec644d",
+            "11655326c708d70319be2610e8a57d9a5b959d3b",
+        ];
+        for (message, expected) in messages().iter().zip(expected) {
+            assert_eq!(digest(HashAlgorithm::Sha1, message), format!("sha1-{}", expected), "{} bytes", message.len());
+        }
+    }
+
+    #[test]
+    fn md5_known_answers() {
+        let expected = [
+            "d41d8cd98f00b204e9800998ecf8427e",
+            "900150983cd24fb0d6963f7d28e17f72",
+            "8215ef0796a20bcaaae116d3876c664a",
+            "ef1772b6dff9a122358552954ad0df65",
+            "3b0c8ac703f828b04c6c197006d17218",
+            "b06521f39153d618550606be297466d5",
+            "014842d480b571495a4a0363793f7367",
+            "c743a45e0d2e6a95cb859adae0248435",
+        ];
+        for (message, expected) in messages().iter().zip(expected) {
+            assert_eq!(digest(HashAlgorithm::Md5, message), format!("md5-{}", expected), "{} bytes", message.len());
+        }
+    }
+
+    #[test]
+    fn sha256_digests_stay_bare() {
+        assert_eq!(digest(HashAlgorithm::Sha256, b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn streaming_matches_a_single_update() {
+        let data: Vec<u8> = (0..1280).map(|i| i as u8).collect();
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Sha1, HashAlgorithm::Md5] {
+            // Pieces that straddle block boundaries, and empty ones.
+            let mut hasher = Hasher::new(algorithm);
+            let mut rest = &data[..];
+            for size in [0, 1, 62, 3, 64, 0, 127, 200].into_iter().cycle() {
+                let (piece, after) = rest.split_at(size.min(rest.len()));
+                hasher.update(piece);
+                rest = after;
+                if rest.is_empty() {
+                    break;
+                }
+            }
+            assert_eq!(hasher.finish(), digest(algorithm, &data), "{:?}", algorithm);
+        }
+        assert_eq!(digest(HashAlgorithm::Sha1, &data), "sha1-e37a04cb2353309f5cff4ee036cfb91a5e31cefd");
+        assert_eq!(digest(HashAlgorithm::Md5, &data), "md5-82829f1f3f2bb0f18b25f278e5bba8bd");
+    }
+
+    #[test]
+    fn recorded_digests_name_their_algorithm() {
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Sha1, HashAlgorithm::Md5] {
+            assert_eq!(HashAlgorithm::of(&digest(algorithm, b"abc")), algorithm);
+        }
+        assert_eq!("md5".parse(), Ok(HashAlgorithm::Md5));
+        assert!("crc32".parse::<HashAlgorithm>().is_err());
+    }
+}
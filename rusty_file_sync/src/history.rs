@@ -0,0 +1,247 @@
+use crate::itemize::Change;
+use crate::observer::Observer;
+use crate::options::format_size;
+use crate::{paths, timestamp, SyncError};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+
+// One line per pass, tab-separated, with the job name last since it is the
+// only field that may contain anything:
+//
+//     2026-10-14T10:00:00    2026-10-14T10:00:12    5    1258291    0    documents
+//
+// i.e. start, end, files copied, bytes copied, errors and job.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pass {
+    pub job: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub files: u64,
+    pub bytes: u64,
+    pub errors: u64,
+}
+
+impl Pass {
+    pub fn duration(&self) -> Duration {
+        self.end.duration_since(self.start).unwrap_or_default()
+    }
+}
+
+// `$XDG_STATE_HOME/rusty_file_sync/history`, falling back to
+// `~/.local/state`, or `%LOCALAPPDATA%\rusty_file_sync\history` on Windows.
+pub fn default_path() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")))
+    };
+    base.map(|base| base.join("rusty_file_sync").join("history"))
+}
+
+pub async fn record(path: &Path, pass: &Pass) -> Result<(), SyncError> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let line = format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\n",
+        timestamp::format(pass.start),
+        timestamp::format(pass.end),
+        pass.files,
+        pass.bytes,
+        pass.errors,
+        pass.job.replace(['\t', '\n'], " ")
+    );
+    // Appended in a single write so jobs sharing the file don't interleave.
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(line.as_bytes()).await?;
+    // Without it the write may still be in flight when the file is dropped.
+    file.flush().await?;
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Option<Pass> {
+    let fields: Vec<&str> = line.splitn(6, '\t').collect();
+    let [start, end, files, bytes, errors, job] = fields.as_slice() else {
+        return None;
+    };
+    Some(Pass {
+        job: job.to_string(),
+        start: timestamp::parse(start)?,
+        end: timestamp::parse(end)?,
+        files: files.parse().ok()?,
+        bytes: bytes.parse().ok()?,
+        errors: errors.parse().ok()?,
+    })
+}
+
+// The passes recorded for `job`, oldest first.
+pub async fn load(path: &Path, job: &str) -> Result<Vec<Pass>, SyncError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = tokio::fs::read_to_string(path).await?;
+    // Destinations are recorded as normalized, however they are typed here.
+    let destination = paths::normalize(job);
+    // A line cut short by a crash is skipped rather than spoiling the rest.
+    Ok(contents.lines().filter_map(parse_line).filter(|pass| pass.job == job || pass.job == destination).collect())
+}
+
+fn average_duration(passes: &[Pass]) -> Duration {
+    let total: Duration = passes.iter().map(Pass::duration).sum();
+    total / passes.len().max(1) as u32
+}
+
+// Human-readable history of `passes`: totals, averages, how the latest
+// `recent` passes compare with the ones before, and a list of them.
+pub fn report(job: &str, passes: &[Pass], recent: usize) -> String {
+    let Some(first) = passes.first() else {
+        return format!("No passes recorded for {}", job);
+    };
+    let count = passes.len() as u64;
+    let failed = passes.iter().filter(|pass| pass.errors > 0).count();
+    let files: u64 = passes.iter().map(|pass| pass.files).sum();
+    let bytes: u64 = passes.iter().map(|pass| pass.bytes).sum();
+    let mut lines = vec![
+        format!("{}: {} passes since {}, {} failed", job, count, timestamp::format(first.start), failed),
+        format!(
+            "Average pass: {}, {} files, {} (total {} in {} files)",
+            humantime::format_duration(average_duration(passes)),
+            files / count,
+            format_size(bytes / count),
+            format_size(bytes),
+            files
+        ),
+    ];
+
+    let split = passes.len().saturating_sub(recent);
+    let (older, latest) = passes.split_at(split);
+    if !older.is_empty() {
+        let (before, now) = (average_duration(older).as_secs_f64(), average_duration(latest).as_secs_f64());
+        let trend = if before == 0.0 {
+            "no comparable passes before".to_string()
+        } else {
+            let change = (now - before) / before * 100.0;
+            format!("{:.0}% {} than the {} before", change.abs(), if change >= 0.0 { "longer" } else { "shorter" }, older.len())
+        };
+        lines.push(format!("Last {} passes took {}, {}", latest.len(), humantime::format_duration(Duration::from_secs(now as u64)), trend));
+    }
+
+    lines.push("Recent passes:".to_string());
+    for pass in latest.iter().rev() {
+        lines.push(format!(
+            "  {}  {:>10}  {:>6} files  {:>8}{}",
+            timestamp::format(pass.start),
+            humantime::format_duration(pass.duration()).to_string(),
+            pass.files,
+            format_size(pass.bytes),
+            if pass.errors > 0 { "  failed" } else { "" }
+        ));
+    }
+    lines.join("\n")
+}
+
+// Counts what a pass copied on its way to the observer it wraps.
+pub struct Recorder {
+    inner: Arc<dyn Observer>,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Recorder {
+    pub fn new(inner: Arc<dyn Observer>) -> Self {
+        Recorder { inner, files: AtomicU64::new(0), bytes: AtomicU64::new(0) }
+    }
+
+    // Files and bytes copied since the last call.
+    pub fn take(&self) -> (u64, u64) {
+        (self.files.swap(0, Ordering::SeqCst), self.bytes.swap(0, Ordering::SeqCst))
+    }
+}
+
+impl Observer for Recorder {
+    fn on_change(&self, path: &Path, change: &Change) {
+        self.inner.on_change(path, change);
+    }
+
+    fn on_file_start(&self, path: &Path) {
+        self.inner.on_file_start(path);
+    }
+
+    fn on_file_progress(&self, path: &Path, copied: u64, total: u64) {
+        self.inner.on_file_progress(path, copied, total);
+    }
+
+    fn on_file_done(&self, path: &Path, bytes: u64) {
+        self.files.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+        self.inner.on_file_done(path, bytes);
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.inner.on_remove(path);
+    }
+
+    fn on_error(&self, error: &SyncError) {
+        self.inner.on_error(error);
+    }
+
+    fn on_pass_complete(&self) {
+        self.inner.on_pass_complete();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    fn pass(job: &str, start: u64, secs: u64, errors: u64) -> Pass {
+        let start = timestamp::from_secs(start);
+        Pass { job: job.to_string(), start, end: start + Duration::from_secs(secs), files: 5, bytes: 2048, errors }
+    }
+
+    #[tokio::test]
+    async fn loads_the_passes_of_one_job() {
+        let dir = scratch("history-load");
+        let path = dir.join("state/history");
+        assert!(load(&path, "docs").await.unwrap().is_empty());
+        record(&path, &pass("docs", 1_000, 10, 0)).await.unwrap();
+        record(&path, &pass("photos", 2_000, 10, 0)).await.unwrap();
+        record(&path, &pass("a\tjob", 3_000, 10, 0)).await.unwrap();
+        record(&path, &pass("docs", 4_000, 20, 1)).await.unwrap();
+        // A line cut short by a crash.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, b"1970-01-01T01:00:00\t1970").unwrap();
+
+        assert_eq!(load(&path, "docs").await.unwrap(), [pass("docs", 1_000, 10, 0), pass("docs", 4_000, 20, 1)]);
+        assert_eq!(load(&path, "a job").await.unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_destinations_however_they_are_typed() {
+        let passes = [pass("/mnt/backup", 1_000, 10, 0)];
+        let line = format!("{}\t{}\t5\t2048\t0\t/mnt/backup", timestamp::format(passes[0].start), timestamp::format(passes[0].end));
+        assert_eq!(parse_line(&line).as_slice(), passes);
+        assert_eq!(paths::normalize("/mnt/backup/"), passes[0].job);
+    }
+
+    #[test]
+    fn reports_totals_and_trends() {
+        assert_eq!(report("docs", &[], 5), "No passes recorded for docs");
+        let passes = [pass("docs", 1_000, 10, 0), pass("docs", 2_000, 10, 0), pass("docs", 3_000, 30, 1)];
+        let report = report("docs", &passes, 1);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "docs: 3 passes since 1970-01-01T00:16:40, 1 failed");
+        assert_eq!(lines[1], "Average pass: 16s 666ms 666us 666ns, 5 files, 2.0K (total 6.0K in 15 files)");
+        assert_eq!(lines[2], "Last 1 passes took 30s, 200% longer than the 2 before");
+        assert_eq!(lines[3], "Recent passes:");
+        assert!(lines[4].ends_with("  failed"), "{}", lines[4]);
+        assert_eq!(lines.len(), 5);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::observer::Observer;
+use crate::SyncError;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+// Why a path in the destination was written or removed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Created { dir: bool },
+    // Which of the contents, size and modification time differed.
+    Updated { checksum: bool, size: bool, time: bool },
+    // Attributes brought in line without copying any data.
+    Attributes { dir: bool, time: bool, perms: bool, owner: bool, group: bool },
+    // A symbolic link made or pointed somewhere else.
+    Linked,
+    // A device, FIFO or socket node made.
+    Special { device: bool },
+    Deleted,
+}
+
+impl Change {
+    // The rsync `--itemize-changes` code for the change, e.g. `>f.st......`
+    // for a file copied because its size and modification time differ.
+    pub fn code(&self) -> String {
+        let flag = |set: bool, c: char| if set { c } else { '.' };
+        match *self {
+            Change::Created { dir: false } => ">f+++++++++".to_string(),
+            Change::Created { dir: true } => "cd+++++++++".to_string(),
+            Change::Updated { checksum, size, time } => {
+                format!(">f{}{}{}......", flag(checksum, 'c'), flag(size, 's'), flag(time, 't'))
+            }
+            Change::Attributes { dir, time, perms, owner, group } => {
+                format!(".{}..{}{}{}{}...", if dir { 'd' } else { 'f' }, flag(time, 't'), flag(perms, 'p'), flag(owner, 'o'), flag(group, 'g'))
+            }
+            Change::Linked => "cL+++++++++".to_string(),
+            Change::Special { device: true } => "cD+++++++++".to_string(),
+            Change::Special { device: false } => "cS+++++++++".to_string(),
+            Change::Deleted => "*deleting  ".to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for Change {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let chars: Vec<char> = value.chars().collect();
+        match value {
+            ">f+++++++++" => Ok(Change::Created { dir: false }),
+            "cd+++++++++" => Ok(Change::Created { dir: true }),
+            "cL+++++++++" => Ok(Change::Linked),
+            "cD+++++++++" => Ok(Change::Special { device: true }),
+            "cS+++++++++" => Ok(Change::Special { device: false }),
+            _ if value.trim_end() == "*deleting" => Ok(Change::Deleted),
+            _ if value.starts_with(">f") && chars.len() == 11 => Ok(Change::Updated {
+                checksum: chars[2] == 'c',
+                size: chars[3] == 's',
+                time: chars[4] == 't',
+            }),
+            _ if value.starts_with('.') && matches!(chars.get(1), Some('f' | 'd')) && chars.len() == 11 => Ok(Change::Attributes {
+                dir: chars[1] == 'd',
+                time: chars[4] == 't',
+                perms: chars[5] == 'p',
+                owner: chars[6] == 'o',
+                group: chars[7] == 'g',
+            }),
+            _ => Err(format!("Invalid change code: {}", value)),
+        }
+    }
+}
+
+// Prints one line per change to stdout, with its code and the path relative
+// to the destination, before passing it on to the observer it wraps.
+pub struct Itemizer {
+    inner: Arc<dyn Observer>,
+}
+
+impl Itemizer {
+    pub fn new(inner: Arc<dyn Observer>) -> Self {
+        Itemizer { inner }
+    }
+}
+
+impl Observer for Itemizer {
+    fn on_change(&self, path: &Path, change: &Change) {
+        let line = format!("{} {}\n", change.code(), path.display());
+        let _ = std::io::stdout().lock().write_all(line.as_bytes());
+        self.inner.on_change(path, change);
+    }
+
+    fn on_file_start(&self, path: &Path) {
+        self.inner.on_file_start(path);
+    }
+
+    fn on_file_progress(&self, path: &Path, copied: u64, total: u64) {
+        self.inner.on_file_progress(path, copied, total);
+    }
+
+    fn on_file_done(&self, path: &Path, bytes: u64) {
+        self.inner.on_file_done(path, bytes);
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.inner.on_remove(path);
+    }
+
+    fn on_error(&self, error: &SyncError) {
+        self.inner.on_error(error);
+    }
+
+    fn on_pass_complete(&self) {
+        self.inner.on_pass_complete();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_changes_like_rsync() {
+        assert_eq!(Change::Created { dir: false }.code(), ">f+++++++++");
+        assert_eq!(Change::Created { dir: true }.code(), "cd+++++++++");
+        assert_eq!(Change::Updated { checksum: false, size: true, time: true }.code(), ">f.st......");
+        assert_eq!(Change::Updated { checksum: true, size: false, time: false }.code(), ">fc........");
+        assert_eq!(Change::Attributes { dir: true, time: true, perms: false, owner: true, group: false }.code(), ".d..t.o....");
+        assert_eq!(Change::Deleted.code(), "*deleting  ");
+        // Every code is as wide as the others, keeping paths aligned.
+        for change in [Change::Linked, Change::Special { device: true }, Change::Deleted, Change::Created { dir: false }] {
+            assert_eq!(change.code().len(), 11);
+        }
+    }
+
+    #[test]
+    fn reads_back_every_code() {
+        let mut changes = vec![Change::Created { dir: false }, Change::Created { dir: true }, Change::Linked, Change::Deleted];
+        changes.extend([true, false].map(|device| Change::Special { device }));
+        for bits in 0..8 {
+            let set = |bit: u32| bits & (1 << bit) != 0;
+            changes.push(Change::Updated { checksum: set(0), size: set(1), time: set(2) });
+        }
+        for bits in 0..32 {
+            let set = |bit: u32| bits & (1 << bit) != 0;
+            changes.push(Change::Attributes { dir: set(0), time: set(1), perms: set(2), owner: set(3), group: set(4) });
+        }
+        for change in changes {
+            assert_eq!(change.code().parse(), Ok(change), "{}", change.code());
+        }
+        // Saved plans may have lost the padding after `*deleting`.
+        assert_eq!("*deleting".parse(), Ok(Change::Deleted));
+        for code in ["", ">f", ">f.st.....", ".L..t......", "<f+++++++++", "*deleted  "] {
+            assert!(code.parse::<Change>().is_err(), "{}", code);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::SyncError;
+#[cfg(unix)]
+use std::io::Write;
+#[cfg(unix)]
+use std::process::{Command, Stdio};
+
+// Secrets live in the platform keyring under this service, each named by
+// the user, e.g. `destination.keyring = backup-url` in a configuration.
+// The keyring is reached through the tool each platform ships for it:
+// `secret-tool` (libsecret) on Linux and the BSDs, `security` on macOS.
+#[cfg(unix)]
+const SERVICE: &str = "rusty_file_sync";
+
+// Runs the keyring tool `command` with `input` on stdin, returning what it
+// printed.
+#[cfg(unix)]
+fn run(mut command: Command, input: &str) -> Result<String, String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("can't run {}: {}", program, e))?;
+    // Dropping stdin closes it, which is where the tools stop reading.
+    let fed = child.stdin.take().unwrap().write_all(input.as_bytes());
+    let output = child.wait_with_output().map_err(|e| format!("{}: {}", program, e))?;
+    if !output.status.success() {
+        let said = String::from_utf8_lossy(&output.stderr);
+        return Err(match said.trim() {
+            "" => format!("{} failed with {}", program, output.status),
+            said => format!("{} failed with {}: {}", program, output.status, said),
+        });
+    }
+    fed.map_err(|e| format!("{}: {}", program, e))?;
+    String::from_utf8(output.stdout).map_err(|_| format!("{} printed something other than UTF-8", program))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn secret_tool(action: &str, name: &str) -> Command {
+    let mut command = Command::new("secret-tool");
+    command.arg(action);
+    if action == "store" {
+        command.arg(format!("--label={}: {}", SERVICE, name));
+    }
+    command.args(["service", SERVICE, "account", name]);
+    command
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn lookup(name: &str) -> Result<String, String> {
+    run(secret_tool("lookup", name), "")
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn store(name: &str, secret: &str) -> Result<(), String> {
+    run(secret_tool("store", name), secret).map(|_| ())
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn clear(name: &str) -> Result<(), String> {
+    run(secret_tool("clear", name), "").map(|_| ())
+}
+
+#[cfg(target_os = "macos")]
+fn security(action: &str, name: &str) -> Command {
+    let mut command = Command::new("security");
+    command.args([action, "-s", SERVICE, "-a", name]);
+    command
+}
+
+#[cfg(target_os = "macos")]
+fn lookup(name: &str) -> Result<String, String> {
+    let mut command = security("find-generic-password", name);
+    command.arg("-w");
+    run(command, "")
+}
+
+// `security -i` reads its commands from stdin, which keeps the secret out
+// of the argument list other users can see.
+#[cfg(target_os = "macos")]
+fn store(name: &str, secret: &str) -> Result<(), String> {
+    let quoted = |value: &str| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""));
+    let line = format!("add-generic-password -U -s {} -a {} -w {}\n", quoted(SERVICE), quoted(name), quoted(secret));
+    let mut command = Command::new("security");
+    command.arg("-i");
+    run(command, &line).map(|_| ())
+}
+
+#[cfg(target_os = "macos")]
+fn clear(name: &str) -> Result<(), String> {
+    run(security("delete-generic-password", name), "").map(|_| ())
+}
+
+// Windows has no command-line tool that reads secrets back out of the
+// Credential Manager.
+#[cfg(not(unix))]
+fn unsupported() -> String {
+    "it is only reachable on Linux, the BSDs and macOS; use `.command` with a secret manager instead".to_string()
+}
+
+#[cfg(not(unix))]
+fn lookup(_name: &str) -> Result<String, String> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn store(_name: &str, _secret: &str) -> Result<(), String> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn clear(_name: &str) -> Result<(), String> {
+    Err(unsupported())
+}
+
+// The secret stored as `name`, up to its first line break.
+pub fn get(name: &str) -> Result<String, SyncError> {
+    let secret = lookup(name).map_err(|e| SyncError::ConfigError(format!("Can't read {} from the keyring: {}", name, e)))?;
+    Ok(secret.lines().next().unwrap_or_default().to_string())
+}
+
+// Stores `secret` as `name`, replacing any secret already stored under it.
+pub fn set(name: &str, secret: &str) -> Result<(), SyncError> {
+    store(name, secret).map_err(|e| SyncError::ConfigError(format!("Can't store {} in the keyring: {}", name, e)))
+}
+
+pub fn remove(name: &str) -> Result<(), SyncError> {
+    clear(name).map_err(|e| SyncError::ConfigError(format!("Can't remove {} from the keyring: {}", name, e)))
+}
@@ -0,0 +1,633 @@
+use log::{info, debug};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+use thiserror::Error;
+use tokio::fs;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+pub mod attrs;
+pub mod backend;
+pub mod battery;
+pub mod bench;
+pub mod chunks;
+pub mod config;
+pub mod conflict;
+pub mod console;
+pub mod control;
+mod copy;
+pub mod doctor;
+pub mod durability;
+pub mod events;
+pub mod faults;
+pub mod fixture;
+pub mod hash;
+pub mod history;
+pub mod itemize;
+pub mod keyring;
+pub mod links;
+mod json;
+pub mod locked;
+pub mod logging;
+pub mod memory;
+mod merge;
+pub mod metered;
+pub mod naming;
+pub mod network;
+pub mod observer;
+pub mod options;
+pub mod paths;
+pub mod plan;
+pub mod progress;
+pub mod quarantine;
+pub mod restore;
+pub mod retention;
+pub mod scheduler;
+pub mod scrub;
+pub mod snapshot;
+pub mod space;
+pub mod special;
+pub mod state;
+pub mod store;
+pub mod template;
+#[cfg(test)]
+mod testing;
+pub mod timestamp;
+pub mod transform;
+pub mod window;
+
+pub const MODES: [&str; 7] = ["one", "bi", "one+no_delete", "bi+no_delete", "store", "snapshot", "metadata"];
+
+// Runs one pass of `mode` from `source` to `destination`, reporting the
+// outcome to the observer in `options`.
+pub async fn sync(source: &str, destination: &str, mode: &str, options: &options::SyncOptions) -> Result<(), SyncError> {
+    let result = match run_pass(source, destination, mode, options).await {
+        // Ownership recorded by --fake-super is saved once the pass is done.
+        Ok(()) if options.fake_super => attrs::save_recorded(Path::new(destination)).await,
+        result => result,
+    };
+    match &result {
+        Ok(()) => options.observer.on_pass_complete(),
+        Err(e) => options.observer.on_error(e),
+    }
+    result
+}
+
+async fn run_pass(source: &str, destination: &str, mode: &str, options: &options::SyncOptions) -> Result<(), SyncError> {
+    // Rewritten names and contents can't be mapped back to their source.
+    let rewrites = !options.transforms.is_empty() || !options.rename.is_empty() || options.name_case != naming::NameCase::Preserve;
+    if rewrites && !matches!(mode, "one" | "one+no_delete") {
+        return Err(SyncError::ConfigError(format!("Transforms and renaming are only supported in one-way mirror modes, not {}", mode)));
+    }
+    if options.read_only_source.is_some() && mode.starts_with("bi") {
+        return Err(SyncError::ConfigError(format!("Mode {} writes to the source, which --source-read-only forbids", mode)));
+    }
+    // Without times there is no telling which side of a pair changed.
+    if options.links == links::LinkPolicy::Recreate && !matches!(mode, "one" | "bi" | "one+no_delete" | "bi+no_delete") {
+        return Err(SyncError::ConfigError(format!("--links recreate is only supported in mirror modes, not {}", mode)));
+    }
+    if options.prune_empty_dirs && !matches!(mode, "one" | "one+no_delete") {
+        return Err(SyncError::ConfigError(format!("--prune-empty-dirs is only supported in one-way mirror modes, not {}", mode)));
+    }
+    if options.chunked.is_some() && mode != "store" {
+        return Err(SyncError::ConfigError(format!("--chunked is only supported in store mode, not {}", mode)));
+    }
+    if options.checksum && !matches!(mode, "one" | "one+no_delete") {
+        return Err(SyncError::ConfigError(format!("--checksum is only supported in one-way mirror modes, not {}", mode)));
+    }
+    let options = &*options.for_destination(Path::new(destination));
+    let (from, to) = (backend::open(source).transpose()?, backend::open(destination).transpose()?);
+    let source_root = from.as_ref().map_or_else(|| Some(PathBuf::from(source)), |from| from.local_path(""));
+    let destination_root = to.as_ref().map_or_else(|| Some(PathBuf::from(destination)), |to| to.local_path(""));
+    if let (Some(source_root), Some(destination_root)) = (source_root, destination_root) {
+        if options.mkpath && !destination_root.exists() && source_root.is_dir() {
+            info!("Creating destination {:?}", destination_root);
+            options.guard_write(&destination_root)?;
+            std::fs::create_dir_all(&destination_root)?;
+        }
+        check_roots(&source_root, &destination_root, mode, options.strict_dest)?;
+    }
+    if from.is_some() || to.is_some() {
+        if !options.transforms.is_empty() {
+            return Err(SyncError::ConfigError("Transforms are not supported for backends".to_string()));
+        }
+        if options.checksum {
+            return Err(SyncError::ConfigError("--checksum is not supported for backends".to_string()));
+        }
+        if options.delete_timing == options::DeleteTiming::During {
+            return Err(SyncError::ConfigError("--delete-during is not supported for backends".to_string()));
+        }
+        if options.delete_grace.is_some() {
+            return Err(SyncError::ConfigError("--delete-after-age is not supported for backends".to_string()));
+        }
+        let from = from.unwrap_or_else(|| Arc::new(backend::LocalBackend::new(source)));
+        let to = to.unwrap_or_else(|| Arc::new(backend::LocalBackend::new(destination)));
+        if let Some(marker) = &options.require_marker {
+            if to.stat(marker)?.is_none() {
+                return Err(missing_marker(marker, destination));
+            }
+        }
+        return match mode {
+            "one" => backend::sync_between(from, to, true, options).await,
+            "one+no_delete" => backend::sync_between(from, to, false, options).await,
+            _ => Err(SyncError::ConfigError(format!("Mode {} is not supported for backends", mode))),
+        };
+    }
+    if let Some(marker) = &options.require_marker {
+        if !Path::new(destination).join(marker).exists() {
+            return Err(missing_marker(marker, destination));
+        }
+    }
+    check_writable(Path::new(destination))?;
+    if options.check_space || options.max_dest_size.is_some() {
+        space::check(source, destination, mode, options).await?;
+    }
+    // Mirror modes hand back what they copied so the manifest can reuse
+    // the hashes computed during the copy.
+    let copied = match mode {
+        "one" => sync_oneway(source, destination, true, options).await?,
+        "bi" => sync_bothways(source, destination, true, options).await?,
+        "one+no_delete" => sync_oneway(source, destination, false, options).await?,
+        "bi+no_delete" => sync_bothways(source, destination, false, options).await?,
+        "store" => return store::sync_store(source, destination, options).await,
+        "snapshot" => return snapshot::sync_snapshot(source, destination, options).await,
+        "metadata" => return attrs::sync_metadata(source, destination, options).await,
+        _ => return Err(SyncError::ConfigError(format!("Invalid mode: {}", mode))),
+    };
+    state::update_manifest(Path::new(destination), &copied, options).await?;
+    if mode.starts_with("bi") && options.conflict == conflict::ConflictPolicy::Merge {
+        conflict::save_bases(Path::new(destination)).await?;
+    }
+    Ok(())
+}
+
+// An unmounted disk leaves behind an empty mountpoint, which a mirror
+// would happily fill up with a new copy of everything.
+pub(crate) fn missing_marker(marker: &str, destination: &str) -> SyncError {
+    SyncError::ConfigError(format!("Marker {} is missing from {}; is the destination mounted?", marker, destination))
+}
+
+// Destinations smaller than this may lose any share of their entries.
+const SHRINK_CHECK_MIN: usize = 10;
+
+// Stops the delete phase when it would remove `deleting` of the destination's
+// `existing` entries after finding nothing at all in the source, or more than
+// `max_delete` percent of them: both look more like a share that failed to
+// mount than like files someone removed.
+pub(crate) fn check_deletions(deleting: usize, existing: usize, source_empty: bool, options: &options::SyncOptions) -> Result<(), SyncError> {
+    if options.force || deleting == 0 {
+        return Ok(());
+    }
+    if source_empty {
+        return Err(SyncError::ConfigError(format!(
+            "The source is empty; refusing to delete {} destination entries (is it mounted? pass --force if this is intended)",
+            deleting
+        )));
+    }
+    let percent = deleting * 100 / existing.max(1);
+    if existing >= SHRINK_CHECK_MIN && percent > options.max_delete as usize {
+        return Err(SyncError::ConfigError(format!(
+            "Refusing to delete {} of {} destination entries ({}%, over --max-delete {}%); pass --force if this is intended",
+            deleting, existing, percent, options.max_delete
+        )));
+    }
+    Ok(())
+}
+
+// Checks both ends of a pass before anything is walked, so a mistyped or
+// unmounted path fails with one error saying what is wrong with it. Store
+// and snapshot modes create their destination; mirrors need it to exist.
+// A strict destination is a mount point, so `store` and `snapshot` modes
+// don't create it either.
+pub(crate) fn check_roots(source: &Path, destination: &Path, mode: &str, strict: bool) -> Result<(), SyncError> {
+    check_root("Source", source, false, "is it mounted?")?;
+    match strict {
+        true => check_root("Destination", destination, false, "is it mounted? (--strict-dest never creates it)")?,
+        false => check_root("Destination", destination, matches!(mode, "store" | "snapshot"), "create it first, or pass --mkpath")?,
+    }
+    check_overlap(&source.canonicalize()?, &options::resolved(destination))
+}
+
+fn check_root(role: &str, path: &Path, may_be_missing: bool, hint: &str) -> Result<(), SyncError> {
+    let canonical = match path.canonicalize() {
+        Ok(canonical) => canonical,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && may_be_missing => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(SyncError::ConfigError(format!("{} {} does not exist; {}", role, path.display(), hint)));
+        }
+        Err(e) => return Err(SyncError::ConfigError(format!("{} {} can't be resolved: {}", role, path.display(), e))),
+    };
+    if !canonical.is_dir() {
+        return Err(SyncError::ConfigError(format!("{} {} is not a directory", role, path.display())));
+    }
+    if let Err(e) = std::fs::read_dir(&canonical) {
+        return Err(SyncError::ConfigError(format!("{} {} can't be listed: {}", role, path.display(), e)));
+    }
+    Ok(())
+}
+
+// A destination inside its source is copied into itself on every pass, and
+// a source inside its destination is deleted by the mirror it feeds.
+fn check_overlap(source: &Path, destination: &Path) -> Result<(), SyncError> {
+    if source == destination {
+        Err(SyncError::ConfigError(format!("Source and destination are the same directory, {}", source.display())))
+    } else if destination.starts_with(source) {
+        Err(SyncError::ConfigError(format!("Destination {} is inside the source {}; it would be copied into itself", destination.display(), source.display())))
+    } else if source.starts_with(destination) {
+        Err(SyncError::ConfigError(format!("Source {} is inside the destination {}; syncing would overwrite or delete it", source.display(), destination.display())))
+    } else {
+        Ok(())
+    }
+}
+
+// A read-only destination would otherwise fail every file it is sent.
+// Destinations not created yet are left to the mode to create.
+#[cfg(unix)]
+fn check_writable(destination: &Path) -> Result<(), SyncError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    if !destination.exists() {
+        return Ok(());
+    }
+    let c_path = std::ffi::CString::new(destination.as_os_str().as_bytes()).map_err(|e| SyncError::ConfigError(e.to_string()))?;
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(SyncError::ConfigError(format!("Destination {} is not writable: {}", destination.display(), e)));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_writable(_destination: &Path) -> Result<(), SyncError> {
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum SyncError {
+    #[error("File system error: {0}")]
+    FileSystemError(#[from] std::io::Error),
+    #[error("Path error: {0}")]
+    PathError(#[from] std::path::StripPrefixError),
+    #[error("WalkDir error: {0}")]
+    WalkDirError(#[from] walkdir::Error),
+    #[error("Store error: {0}")]
+    StoreError(String),
+    #[error("Restore error: {0}")]
+    RestoreError(String),
+    #[error("Scrub error: {0}")]
+    ScrubError(String),
+    #[error("Config error: {0}")]
+    ConfigError(String),
+    #[error("Control error: {0}")]
+    ControlError(String),
+    #[error("Transform error: {0}")]
+    TransformError(String),
+    #[error("Network error: {0}")]
+    NetworkError(String),
+    #[error("Space error: {0}")]
+    SpaceError(String),
+    #[error("Plan error: {0}")]
+    PlanError(String),
+    #[error("Conflict error: {0}")]
+    ConflictError(String),
+}
+
+pub async fn calculate_hash<P: AsRef<Path>>(path: P, buffer_size: usize) -> Result<String, SyncError> {
+    hash::file(path, hash::HashAlgorithm::Sha256, buffer_size).await
+}
+
+// Why the copy at `dest_path` is out of date, or None when it is current.
+// Copies differing in size or older than their source are; with `checksum`
+// set, times are ignored and copies of the same size are hashed instead,
+// reusing the destination's hash from `cached`, its manifest entry, when
+// the file hasn't changed since. Both sides are hashed at once.
+pub(crate) async fn file_change(source_path: &Path, src_metadata: &std::fs::Metadata, dest_path: &Path, cached: Option<&store::TreeEntry>, options: &options::SyncOptions) -> Option<itemize::Change> {
+    let Ok(dest_metadata) = fs::metadata(dest_path).await else {
+        return Some(itemize::Change::Created { dir: false });
+    };
+    let size = dest_metadata.len() != src_metadata.len();
+    if options.checksum {
+        if size {
+            return Some(itemize::Change::Updated { checksum: false, size, time: false });
+        }
+        let (dest_entry, src_hash) = tokio::join!(
+            state::manifest_entry(dest_path, &dest_metadata, cached, options.hash, options.buffer_size),
+            hash::file(source_path, options.hash, options.buffer_size)
+        );
+        let dest_hash = match dest_entry {
+            Ok(store::TreeEntry::File { hash, .. }) => hash,
+            _ => return Some(itemize::Change::Updated { checksum: true, size, time: false }),
+        };
+        let same = src_hash.is_ok_and(|src_hash| src_hash == dest_hash);
+        return (!same).then_some(itemize::Change::Updated { checksum: true, size, time: false });
+    }
+    let time = match (src_metadata.modified(), dest_metadata.modified()) {
+        (Ok(src_modified), Ok(dest_modified)) => options.is_newer(src_modified, dest_modified, !size),
+        _ => true,
+    };
+    (size || time).then_some(itemize::Change::Updated { checksum: false, size, time })
+}
+
+// Returns manifest entries for the files copied into the destination, whose
+// hashes were computed during the copy.
+pub async fn sync_oneway(source: &str, destination: &str, delete: bool, options: &options::SyncOptions) -> Result<store::Tree, SyncError> {
+    let mut copied = store::Tree::new();
+    let mut durability = durability::Durability::new(options.fsync);
+    let mut dest_files = HashSet::new();
+    let transforms_path = state::transforms_path(Path::new(destination));
+    let previous_transforms = state::load_manifest(&transforms_path).await?;
+    let mut transformed = store::Tree::new();
+    let mut mapper = naming::PathMapper::new(options);
+    let cached = match options.checksum {
+        true => state::load_manifest(&state::manifest_path(Path::new(destination))).await?,
+        false => store::Tree::new(),
+    };
+
+    if delete {
+        for entry in WalkDir::new(destination).into_iter().filter_entry(|e| !state::is_state_dir(e)) {
+            let entry = entry?;
+            let path = entry.path().strip_prefix(destination)?.to_path_buf();
+            dest_files.insert(path);
+        }
+    }
+
+    let existing = dest_files.len().saturating_sub(1);
+    // Deleting first, or directory by directory, needs to know up front what
+    // the source no longer has, which takes a walk of its own.
+    let mut extraneous = Vec::new();
+    if delete && options.delete_timing != options::DeleteTiming::After {
+        let mut mapper = naming::PathMapper::new(options);
+        let mut source_empty = true;
+        for entry in links::walk(WalkDir::new(source), options.links) {
+            let entry = entry?;
+            source_empty &= entry.depth() == 0;
+            if let Some(mapped) = mapper.map(entry.path().strip_prefix(source)?, entry.file_type().is_dir()) {
+                for kept in mapped.ancestors() {
+                    dest_files.remove(kept);
+                }
+            }
+        }
+        extraneous = std::mem::take(&mut dest_files).into_iter().filter(|path| !options.is_marker(path)).collect();
+        if let Some(age) = options.delete_grace {
+            extraneous = state::missing_for(Path::new(destination), extraneous, age).await?;
+        }
+        check_deletions(extraneous.len(), existing, source_empty, options)?;
+        if options.delete_timing == options::DeleteTiming::Before {
+            remove_extraneous(std::mem::take(&mut extraneous), destination, options).await?;
+        }
+    }
+
+    let mut source_empty = true;
+    let mut deferred = Vec::new();
+    for entry in links::walk(WalkDir::new(source), options.links) {
+        control::checkpoint(options).await;
+        let entry = entry?;
+        source_empty &= entry.depth() == 0;
+        let source_path = entry.path();
+        let Some(mapped) = mapper.map(source_path.strip_prefix(source)?, entry.file_type().is_dir()) else {
+            continue;
+        };
+        let dest_path = Path::new(destination).join(mapped);
+
+        if delete {
+            // Renamed files can land in directories with no source counterpart.
+            for kept in dest_path.strip_prefix(destination)?.ancestors() {
+                dest_files.remove(kept);
+            }
+        }
+
+        if options.links == links::LinkPolicy::Recreate && entry.depth() > 0 && entry.path_is_symlink() {
+            if links::recreate(source_path, &dest_path, options)? {
+                options.observer.on_change(dest_path.strip_prefix(destination)?, &itemize::Change::Linked);
+            }
+        } else if source_path.is_dir() {
+            if !extraneous.is_empty() {
+                // Whatever this directory no longer holds goes before it is filled.
+                let here = dest_path.strip_prefix(destination)?;
+                let (now, later) = extraneous.into_iter().partition(|path: &PathBuf| path.parent() == Some(here));
+                extraneous = later;
+                remove_extraneous(now, destination, options).await?;
+            }
+            if !dest_path.exists() && options.prune_empty_dirs {
+                // Made on the way if anything is copied into it.
+                deferred.push((source_path.to_path_buf(), dest_path));
+            } else if !dest_path.exists() {
+                info!("Creating directory: {:?}", dest_path);
+                options.observer.on_change(dest_path.strip_prefix(destination)?, &itemize::Change::Created { dir: true });
+                options.guard_write(&dest_path)?;
+                fs::create_dir_all(&dest_path).await?;
+                attrs::settle(&std::fs::metadata(source_path)?, &dest_path, options)?;
+                durability.created(&dest_path);
+            }
+        } else if let Some(kind) = special::kind(&entry.file_type()) {
+            if !special::wanted(kind, options) {
+                special::skip(source_path, kind);
+            } else if special::recreate(&entry.metadata()?, &dest_path, options)? {
+                options.observer.on_change(dest_path.strip_prefix(destination)?, &itemize::Change::Special { device: kind == special::Special::Device });
+            }
+        } else {
+            let key = store::relative_key(dest_path.strip_prefix(destination)?);
+            let src_metadata = std::fs::metadata(source_path)?;
+            let transforms = transform::matching(&options.transforms, source_path);
+            let change = if !dest_path.exists() {
+                Some(itemize::Change::Created { dir: false })
+            } else if transforms.is_empty() {
+                file_change(source_path, &src_metadata, &dest_path, cached.get(&key), options).await
+            } else {
+                match transform::unchanged_record(previous_transforms.get(&key), source_path, &src_metadata, options.buffer_size).await? {
+                    Some(record) => {
+                        transformed.insert(key.clone(), record);
+                        None
+                    }
+                    None => Some(transform::change(previous_transforms.get(&key), &src_metadata)?),
+                }
+            };
+
+            if let Some(change) = change {
+                if metered::defers(source_path, src_metadata.len(), options).await || !locked::readable(source_path, options).await? {
+                    continue;
+                }
+                options.observer.on_change(dest_path.strip_prefix(destination)?, &change);
+                if let Some(parent) = dest_path.parent().filter(|parent| !parent.exists()) {
+                    fs::create_dir_all(parent).await?;
+                    durability.created(parent);
+                }
+                info!("Copying file from {:?} to {:?}", source_path, dest_path);
+                let result = if transforms.is_empty() {
+                    copy::copy_file(source_path, &dest_path, options).await?
+                } else {
+                    let (source_hash, result) = transform::copy_transformed(source_path, &dest_path, &transforms, options).await?;
+                    transformed.insert(
+                        key.clone(),
+                        store::TreeEntry::File {
+                            hash: source_hash,
+                            size: result.source.len(),
+                            modified: timestamp::to_secs(result.source.modified()?),
+                        },
+                    );
+                    result
+                };
+                durability.written(&dest_path)?;
+                let metadata = fs::metadata(&dest_path).await?;
+                copied.insert(
+                    key,
+                    store::TreeEntry::File {
+                        hash: result.hash,
+                        size: metadata.len(),
+                        modified: timestamp::to_secs(metadata.modified()?),
+                    },
+                );
+            } else {
+                debug!("Skipping unchanged file: {:?}", source_path);
+            }
+        }
+    }
+
+    if delete && options.delete_timing == options::DeleteTiming::After {
+        let mut deleting: Vec<PathBuf> = dest_files.into_iter().filter(|path| !options.is_marker(path)).collect();
+        if let Some(age) = options.delete_grace {
+            deleting = state::missing_for(Path::new(destination), deleting, age).await?;
+        }
+        check_deletions(deleting.len(), existing, source_empty, options)?;
+        remove_extraneous(deleting, destination, options).await?;
+    } else {
+        // Left in directories that only exist for renamed files.
+        remove_extraneous(extraneous, destination, options).await?;
+    }
+    if options.prune_empty_dirs {
+        for (source_path, dest_path) in deferred.into_iter().filter(|(_, dest_path)| dest_path.exists()) {
+            options.observer.on_change(dest_path.strip_prefix(destination)?, &itemize::Change::Created { dir: true });
+            attrs::settle(&std::fs::metadata(source_path)?, &dest_path, options)?;
+        }
+        if delete {
+            prune_empty_dirs(destination, options).await?;
+        }
+    }
+
+    if transformed != previous_transforms {
+        store::write_tree_file(&transforms_path, &transformed).await?;
+    }
+    durability.finish()?;
+    Ok(copied)
+}
+
+// Removes `paths`, relative to `destination`, which the source no longer has.
+async fn remove_extraneous(mut paths: Vec<PathBuf>, destination: &str, options: &options::SyncOptions) -> Result<(), SyncError> {
+    // Directories sort before what they hold, which goes with them.
+    paths.sort();
+    for remaining_path in paths {
+        let full_dest_path = Path::new(destination).join(&remaining_path);
+        // Already gone with a directory removed earlier in the loop.
+        if fs::symlink_metadata(&full_dest_path).await.is_err() {
+            continue;
+        }
+        options.guard_write(&full_dest_path)?;
+        options.observer.on_change(&remaining_path, &itemize::Change::Deleted);
+        if full_dest_path.is_dir() {
+            info!("Removing directory: {:?}", full_dest_path);
+            fs::remove_dir_all(&full_dest_path).await?;
+        } else {
+            info!("Removing file: {:?}", full_dest_path);
+            fs::remove_file(&full_dest_path).await?;
+        }
+        options.observer.on_remove(&full_dest_path);
+    }
+    Ok(())
+}
+
+// Removes every directory under `destination` left with nothing in it,
+// innermost first so parents emptied along the way go too.
+async fn prune_empty_dirs(destination: &str, options: &options::SyncOptions) -> Result<(), SyncError> {
+    for entry in WalkDir::new(destination).min_depth(1).contents_first(true).into_iter().filter_entry(|e| !state::is_state_dir(e)) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() || std::fs::read_dir(entry.path())?.next().is_some() {
+            continue;
+        }
+        options.guard_write(entry.path())?;
+        info!("Removing empty directory: {:?}", entry.path());
+        options.observer.on_change(entry.path().strip_prefix(destination)?, &itemize::Change::Deleted);
+        fs::remove_dir(entry.path()).await?;
+        options.observer.on_remove(entry.path());
+    }
+    Ok(())
+}
+
+pub async fn sync_bothways(source: &str, destination: &str, delete: bool, options: &options::SyncOptions) -> Result<store::Tree, SyncError> {
+    conflict::resolve(source, destination, options).await?;
+    let copied = sync_oneway(source, destination, delete, options).await?;
+    sync_oneway(destination, source, delete, options).await?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_to_delete_everything_for_an_empty_source() {
+        let options = options::SyncOptions::default();
+        assert!(check_deletions(0, 100, true, &options).is_ok());
+        assert!(matches!(check_deletions(1, 100, true, &options), Err(SyncError::ConfigError(_))));
+        let forced = options::SyncOptions { force: true, ..options::SyncOptions::default() };
+        assert!(check_deletions(100, 100, true, &forced).is_ok());
+    }
+
+    #[test]
+    fn refuses_to_delete_more_than_max_delete() {
+        let options = options::SyncOptions { max_delete: 50, ..options::SyncOptions::default() };
+        assert!(check_deletions(50, 100, false, &options).is_ok());
+        let error = check_deletions(51, 100, false, &options).unwrap_err().to_string();
+        assert!(error.contains("51 of 100 destination entries (51%, over --max-delete 50%)"), "{}", error);
+        // Small destinations may lose any share of their entries.
+        assert!(check_deletions(9, 9, false, &options).is_ok());
+        assert!(check_deletions(10, 10, false, &options).is_err());
+        let forced = options::SyncOptions { force: true, ..options };
+        assert!(check_deletions(100, 100, false, &forced).is_ok());
+    }
+
+    #[test]
+    fn refuses_overlapping_roots() {
+        let overlaps = |source: &str, destination: &str| check_overlap(Path::new(source), Path::new(destination)).is_err();
+        assert!(overlaps("/data", "/data"));
+        assert!(overlaps("/data", "/data/backup"));
+        assert!(overlaps("/backup/data", "/backup"));
+        // Only whole components nest.
+        assert!(!overlaps("/data", "/data-backup"));
+        assert!(!overlaps("/data/a", "/data/b"));
+    }
+
+    #[test]
+    fn checks_both_roots_before_a_pass() {
+        let dir = testing::scratch("lib-roots");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        let error = |source: &Path, destination: &Path, mode: &str| check_roots(source, destination, mode, false).unwrap_err().to_string();
+        assert!(error(&source, &dest, "one").contains("Source"));
+        std::fs::create_dir(&source).unwrap();
+        assert!(error(&source, &dest, "one").contains("create it first, or pass --mkpath"));
+        for mode in ["store", "snapshot"] {
+            check_roots(&source, &dest, mode, false).unwrap();
+        }
+        testing::write(&dir.join("file"), "");
+        assert!(error(&source, &dir.join("file"), "one").contains("is not a directory"));
+        // Overlaps are judged where the paths lead.
+        assert!(error(&source, &source.join("../source/backup"), "store").contains("inside the source"));
+        std::fs::create_dir(&dest).unwrap();
+        check_roots(&source, &dest, "one", false).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn never_creates_a_strict_destination() {
+        let dir = testing::scratch("lib-strict");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        std::fs::create_dir(&source).unwrap();
+        for mode in ["one", "store", "snapshot"] {
+            let error = check_roots(&source, &dest, mode, true).unwrap_err().to_string();
+            assert!(error.contains("--strict-dest never creates it"), "{}", error);
+        }
+        std::fs::create_dir(&dest).unwrap();
+        check_roots(&source, &dest, "store", true).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
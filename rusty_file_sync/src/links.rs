@@ -0,0 +1,156 @@
+use crate::options::SyncOptions;
+use crate::{state, SyncError};
+use log::{info, warn};
+use std::path::Path;
+use walkdir::{DirEntry, WalkDir};
+
+// What to do with symbolic links in the source, and with junctions, which
+// Windows reports as links too. Other reparse points, such as cloud file
+// placeholders, read like the files they stand for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LinkPolicy {
+    // Copy what links point to, descending into linked directories, but
+    // never into one that contains the link.
+    #[default]
+    Follow,
+    Skip,
+    // Make the same link in the destination; mirror modes only.
+    Recreate,
+}
+
+impl std::str::FromStr for LinkPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "follow" => Ok(LinkPolicy::Follow),
+            "skip" => Ok(LinkPolicy::Skip),
+            "recreate" => Ok(LinkPolicy::Recreate),
+            _ => Err(format!("Invalid link policy: {} (expected follow, skip or recreate)", value)),
+        }
+    }
+}
+
+fn kept(entry: &DirEntry, policy: LinkPolicy) -> bool {
+    if state::is_state_dir(entry) {
+        return false;
+    }
+    if policy == LinkPolicy::Skip && entry.depth() > 0 && entry.path_is_symlink() {
+        info!("Skipping link: {:?}", entry.path());
+        return false;
+    }
+    true
+}
+
+// Walks `walker`'s source as `policy` asks, leaving out the state directory.
+// Followed links that lead back to a directory above them, which would walk
+// forever, and links pointing nowhere are skipped with a warning.
+pub fn walk(walker: WalkDir, policy: LinkPolicy) -> impl Iterator<Item = walkdir::Result<DirEntry>> {
+    walker.follow_links(policy == LinkPolicy::Follow).into_iter().filter_entry(move |entry| kept(entry, policy)).filter(|entry| match entry {
+        Err(e) if e.loop_ancestor().is_some() => {
+            warn!("Skipping {:?}: it links back to {:?}", e.path().unwrap_or(Path::new("")), e.loop_ancestor().unwrap());
+            false
+        }
+        Err(e) if e.io_error().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) && e.path().is_some_and(|path| path.is_symlink()) => {
+            warn!("Skipping {:?}: it links to nothing", e.path().unwrap());
+            false
+        }
+        _ => true,
+    })
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path, _dir: bool) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// Junctions come back as directory links, which need Developer Mode or the
+// privilege to create symbolic links.
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path, dir: bool) -> std::io::Result<()> {
+    match dir {
+        true => std::os::windows::fs::symlink_dir(target, link),
+        false => std::os::windows::fs::symlink_file(target, link),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_target: &Path, _link: &Path, _dir: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "symlinks are not available on this platform"))
+}
+
+// Makes `dest` a link to wherever the link at `source` points, replacing
+// whatever is there unless it already is that link. Returns whether
+// anything changed.
+pub fn recreate(source: &Path, dest: &Path, options: &SyncOptions) -> Result<bool, SyncError> {
+    let target = std::fs::read_link(source)?;
+    if std::fs::read_link(dest).is_ok_and(|current| current == target) {
+        return Ok(false);
+    }
+    options.guard_write(dest)?;
+    match std::fs::symlink_metadata(dest) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(dest)?,
+        Ok(_) => std::fs::remove_file(dest)?,
+        Err(_) => {}
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    info!("Linking {:?} to {:?}", dest, target);
+    symlink(&target, dest, source.is_dir())?;
+    Ok(true)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+    use std::os::unix::fs::symlink;
+
+    fn walked(root: &Path, policy: LinkPolicy) -> Vec<String> {
+        let mut paths: Vec<String> = walk(WalkDir::new(root).min_depth(1), policy)
+            .map(|entry| crate::store::relative_key(entry.unwrap().path().strip_prefix(root).unwrap()))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn source(name: &str) -> std::path::PathBuf {
+        let dir = scratch(name);
+        write(&dir.join("real/file.txt"), "data");
+        write(&dir.join(state::STATE_DIR).join("state"), "");
+        symlink(dir.join("real"), dir.join("linked")).unwrap();
+        symlink(&dir, dir.join("real/up")).unwrap();
+        symlink(dir.join("nowhere"), dir.join("dangling")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn follows_links_but_not_loops() {
+        let dir = source("links-follow");
+        assert_eq!(walked(&dir, LinkPolicy::Follow), ["linked", "linked/file.txt", "real", "real/file.txt"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_or_keeps_links_unfollowed() {
+        let dir = source("links-skip");
+        assert_eq!(walked(&dir, LinkPolicy::Skip), ["real", "real/file.txt"]);
+        assert_eq!(walked(&dir, LinkPolicy::Recreate), ["dangling", "linked", "real", "real/file.txt", "real/up"]);
+        assert!("hardlink".parse::<LinkPolicy>().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recreates_links_once() {
+        let dir = source("links-recreate");
+        let options = SyncOptions::default();
+        write(&dir.join("dest/linked/old.txt"), "replaced");
+        assert!(recreate(&dir.join("linked"), &dir.join("dest/linked"), &options).unwrap());
+        assert_eq!(std::fs::read_link(dir.join("dest/linked")).unwrap(), dir.join("real"));
+        assert!(!recreate(&dir.join("linked"), &dir.join("dest/linked"), &options).unwrap());
+        assert!(recreate(&dir.join("dangling"), &dir.join("dest/new/dangling"), &options).unwrap());
+        assert_eq!(std::fs::read_link(dir.join("dest/new/dangling")).unwrap(), dir.join("nowhere"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
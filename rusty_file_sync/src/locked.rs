@@ -0,0 +1,105 @@
+use crate::options::SyncOptions;
+use crate::SyncError;
+use log::warn;
+use std::path::Path;
+use std::time::Duration;
+
+// What to do with a source file another process holds open exclusively.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LockedPolicy {
+    #[default]
+    Fail,
+    Skip,
+    Retry,
+}
+
+impl std::str::FromStr for LockedPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fail" => Ok(LockedPolicy::Fail),
+            "skip" => Ok(LockedPolicy::Skip),
+            "retry" => Ok(LockedPolicy::Retry),
+            "vss" => Err("Volume Shadow Copy is not supported by this build (use skip or retry)".to_string()),
+            _ => Err(format!("Invalid locked file policy: {} (expected fail, skip or retry)", value)),
+        }
+    }
+}
+
+// Sharing and lock violations on Windows; busy files elsewhere.
+#[cfg(windows)]
+fn is_locked(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(32) | Some(33))
+}
+
+#[cfg(unix)]
+fn is_locked(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EBUSY) | Some(libc::ETXTBSY))
+}
+
+#[cfg(not(any(windows, unix)))]
+fn is_locked(_e: &std::io::Error) -> bool {
+    false
+}
+
+// Checks that `source` can be opened before anything at the destination is
+// touched, so a locked file never truncates its previous copy. Returns false
+// when the file should be skipped for this pass.
+pub async fn readable(source: &Path, options: &SyncOptions) -> Result<bool, SyncError> {
+    let mut attempt = 0;
+    loop {
+        let e = match std::fs::File::open(source) {
+            Ok(_) => return Ok(true),
+            Err(e) if is_locked(&e) => e,
+            Err(e) => return Err(e.into()),
+        };
+        match options.locked {
+            LockedPolicy::Fail => return Err(e.into()),
+            LockedPolicy::Retry if attempt < options.locked_retries => {
+                let delay = Duration::from_secs(1 << attempt.min(6));
+                warn!("{:?} is locked, retrying in {:?}", source, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => {
+                warn!("Skipping locked file {:?}: {}", source, e);
+                return Ok(false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("fail".parse(), Ok(LockedPolicy::Fail));
+        assert_eq!("skip".parse(), Ok(LockedPolicy::Skip));
+        assert_eq!("retry".parse(), Ok(LockedPolicy::Retry));
+        assert!("vss".parse::<LockedPolicy>().unwrap_err().contains("not supported"));
+        assert!("wait".parse::<LockedPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn only_lock_errors_are_left_to_the_policy() {
+        let dir = scratch("locked");
+        write(&dir.join("a.txt"), "alpha");
+        let options = SyncOptions { locked: LockedPolicy::Skip, ..Default::default() };
+        assert!(readable(&dir.join("a.txt"), &options).await.unwrap());
+        // Anything but a lock still fails, even under skip.
+        assert!(readable(&dir.join("missing.txt"), &options).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn busy_files_are_locked() {
+        assert!(is_locked(&std::io::Error::from_raw_os_error(libc::EBUSY)));
+        assert!(is_locked(&std::io::Error::from_raw_os_error(libc::ETXTBSY)));
+        assert!(!is_locked(&std::io::Error::from_raw_os_error(libc::ENOENT)));
+    }
+}
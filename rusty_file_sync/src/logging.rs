@@ -0,0 +1,536 @@
+use crate::itemize::Change;
+use crate::observer::Observer;
+use crate::SyncError;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use env_logger::filter::Filter;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// A log file that is rotated once it reaches `max_size` bytes or has been
+// written to for `every`, keeping `keep` old files as `<path>.1` (newest)
+// to `<path>.<keep>`.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    max_size: Option<u64>,
+    every: Option<Duration>,
+    keep: usize,
+}
+
+fn numbered(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: Option<u64>, every: Option<Duration>, keep: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // Picks up an existing file where it left off, so restarts don't
+        // reset the rotation clock.
+        let opened = metadata.created().unwrap_or_else(|_| SystemTime::now());
+        Ok(RotatingFile { path: path.to_path_buf(), file, size: metadata.len(), opened, max_size, every, keep })
+    }
+
+    fn due(&self, len: usize) -> bool {
+        let full = self.max_size.is_some_and(|max_size| self.size > 0 && self.size + len as u64 > max_size);
+        let old = self.every.is_some_and(|every| self.opened.elapsed().unwrap_or_default() >= every);
+        full || old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(&self.path, self.keep));
+            for index in (1..self.keep).rev() {
+                let from = numbered(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, numbered(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    // Each log record arrives in a single write, so records are never split
+    // across files.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    Syslog,
+    Journald,
+    EventLog,
+}
+
+impl std::str::FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "stderr" => Ok(LogTarget::Stderr),
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            "eventlog" => Ok(LogTarget::EventLog),
+            _ => Err(format!("Invalid log target: {} (expected stderr, syslog, journald or eventlog)", value)),
+        }
+    }
+}
+
+const IDENTIFIER: &str = "rusty_file_sync";
+
+enum Sink {
+    #[cfg(unix)]
+    Socket(std::os::unix::net::UnixDatagram),
+    // An event source handle, kept as an integer so the sink can be shared.
+    #[cfg(windows)]
+    EventLog(isize),
+}
+
+// Sends log records, and the structured entries of `ActionLog`, to the local
+// syslog daemon (`/dev/log`), to journald's native socket or to the Windows
+// Event Log.
+pub struct SystemLog {
+    target: LogTarget,
+    sink: Sink,
+    level: log::LevelFilter,
+}
+
+static SYSTEM_LOG: std::sync::OnceLock<SystemLog> = std::sync::OnceLock::new();
+
+fn system_log() -> Option<&'static SystemLog> {
+    SYSTEM_LOG.get()
+}
+
+// Syslog severities, which journald's PRIORITY shares.
+fn severity(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+// Journald's native protocol: `KEY=value` lines, or for values containing
+// newlines the key, a newline, the little-endian 64-bit length and the value.
+fn journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[cfg(unix)]
+fn open_sink(target: LogTarget) -> io::Result<Sink> {
+    let path = match target {
+        LogTarget::Journald => "/run/systemd/journal/socket",
+        LogTarget::Syslog => "/dev/log",
+        _ => return Err(io::Error::new(io::ErrorKind::Unsupported, "The Windows Event Log is only available on Windows")),
+    };
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.connect(path).map_err(|e| io::Error::new(e.kind(), format!("Cannot connect to {}: {}", path, e)))?;
+    Ok(Sink::Socket(socket))
+}
+
+#[cfg(windows)]
+#[link(name = "advapi32")]
+extern "system" {
+    fn RegisterEventSourceW(server: *const u16, source: *const u16) -> isize;
+    fn ReportEventW(
+        handle: isize,
+        kind: u16,
+        category: u16,
+        event_id: u32,
+        user_sid: *const std::ffi::c_void,
+        string_count: u16,
+        data_size: u32,
+        strings: *const *const u16,
+        data: *const std::ffi::c_void,
+    ) -> i32;
+}
+
+#[cfg(windows)]
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(Some(0)).collect()
+}
+
+// Events go to the Application log under the `rusty_file_sync` source.
+#[cfg(windows)]
+fn open_sink(target: LogTarget) -> io::Result<Sink> {
+    if target != LogTarget::EventLog {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "syslog and journald are only available on Unix"));
+    }
+    let source = wide(IDENTIFIER);
+    let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+    if handle == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Sink::EventLog(handle))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn open_sink(_target: LogTarget) -> io::Result<Sink> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "No system log is available on this platform"))
+}
+
+impl SystemLog {
+    // Opens the system log, for `Logger::install`. The Event Log only gets
+    // warnings and errors unless `event_log_info` is set.
+    pub fn open(target: LogTarget, event_log_info: bool) -> io::Result<&'static SystemLog> {
+        let sink = open_sink(target)?;
+        let level = if target == LogTarget::EventLog && !event_log_info { log::LevelFilter::Warn } else { log::LevelFilter::Trace };
+        Ok(SYSTEM_LOG.get_or_init(|| SystemLog { target, sink, level }))
+    }
+
+    // `fields` use journald's upper-case names; syslog and the Event Log get
+    // them appended to the message as `key="value"`.
+    fn send(&self, severity: u8, module: Option<&str>, message: &str, fields: &[(&str, String)]) {
+        let allowed = self.level.to_level().map_or(0, self::severity);
+        if severity > allowed {
+            return;
+        }
+        let mut line = message.to_string();
+        for (key, value) in fields {
+            line.push_str(&format!(" {}={:?}", key.to_lowercase(), value));
+        }
+        // Nowhere left to report a failure to.
+        match &self.sink {
+            #[cfg(unix)]
+            Sink::Socket(socket) if self.target == LogTarget::Journald => {
+                let mut entry = Vec::new();
+                journal_field(&mut entry, "PRIORITY", &severity.to_string());
+                journal_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+                journal_field(&mut entry, "MESSAGE", message);
+                if let Some(module) = module {
+                    journal_field(&mut entry, "CODE_MODULE", module);
+                }
+                for (key, value) in fields {
+                    journal_field(&mut entry, key, value);
+                }
+                let _ = socket.send(&entry);
+            }
+            #[cfg(unix)]
+            Sink::Socket(socket) => {
+                // Facility daemon (3).
+                let _ = socket.send(format!("<{}>{}[{}]: {}", 3 * 8 + severity, IDENTIFIER, std::process::id(), line).as_bytes());
+            }
+            #[cfg(windows)]
+            Sink::EventLog(handle) => {
+                let _ = module;
+                // EVENTLOG_ERROR_TYPE, EVENTLOG_WARNING_TYPE, EVENTLOG_INFORMATION_TYPE
+                let kind = match severity {
+                    0..=3 => 1,
+                    4 => 2,
+                    _ => 4,
+                };
+                let text = wide(&line);
+                let strings = [text.as_ptr()];
+                unsafe {
+                    ReportEventW(*handle, kind, 0, 0, std::ptr::null(), 1, 0, strings.as_ptr(), std::ptr::null());
+                }
+            }
+        }
+    }
+}
+
+impl log::Log for SystemLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.send(severity(record.level()), Some(record.target()), &record.args().to_string(), &[]);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Records every action of a job in the system log with JOB, ACTION and PATH
+// fields, so log aggregation can filter on them.
+pub struct ActionLog {
+    job: String,
+    inner: Arc<dyn Observer>,
+}
+
+impl ActionLog {
+    // Wraps `inner` when the system log is in use.
+    pub fn wrap(job: &str, inner: Arc<dyn Observer>) -> Arc<dyn Observer> {
+        match system_log() {
+            Some(_) => Arc::new(ActionLog { job: job.to_string(), inner }),
+            None => inner,
+        }
+    }
+
+    fn record(&self, severity: u8, action: &str, path: Option<&Path>, extra: Option<(&str, String)>) {
+        let Some(system_log) = system_log() else {
+            return;
+        };
+        let mut fields = vec![("JOB", self.job.clone()), ("ACTION", action.to_string())];
+        let mut message = format!("{} {}", self.job, action);
+        if let Some(path) = path {
+            let path = path.to_string_lossy().into_owned();
+            message.push_str(&format!(" {}", path));
+            fields.push(("PATH", path));
+        }
+        fields.extend(extra);
+        system_log.send(severity, None, &message, &fields);
+    }
+}
+
+impl Observer for ActionLog {
+    fn on_change(&self, path: &Path, change: &Change) {
+        self.inner.on_change(path, change);
+    }
+
+    fn on_file_start(&self, path: &Path) {
+        self.inner.on_file_start(path);
+    }
+
+    fn on_file_progress(&self, path: &Path, copied: u64, total: u64) {
+        self.inner.on_file_progress(path, copied, total);
+    }
+
+    fn on_file_done(&self, path: &Path, bytes: u64) {
+        self.record(6, "copy", Some(path), Some(("BYTES", bytes.to_string())));
+        self.inner.on_file_done(path, bytes);
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.record(6, "remove", Some(path), None);
+        self.inner.on_remove(path);
+    }
+
+    fn on_error(&self, error: &SyncError) {
+        self.record(3, "error", None, Some(("ERROR", error.to_string())));
+        self.inner.on_error(error);
+    }
+
+    fn on_pass_complete(&self) {
+        self.record(6, "pass_complete", None, None);
+        self.inner.on_pass_complete();
+    }
+}
+
+tokio::task_local! {
+    static JOB: String;
+}
+
+// Filters of the daemon jobs that set their own log level.
+static JOB_FILTERS: Mutex<BTreeMap<String, Arc<Filter>>> = Mutex::new(BTreeMap::new());
+
+// Checks a `--log-level` spec, a comma-separated list of `LEVEL` (the
+// default) and `MODULE=LEVEL` directives, and qualifies module names with
+// the crate name: `copy=debug` covers `rusty_file_sync::copy`, and `sync`
+// stands for the sync engine itself.
+pub fn parse_log_level(spec: &str) -> Result<String, String> {
+    let mut directives = Vec::new();
+    for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+        let invalid = || format!("Invalid log level: {} (expected LEVEL or MODULE=LEVEL)", directive);
+        let (module, level) = match directive.split_once('=') {
+            Some((module, level)) => (Some(module.trim()), level.trim()),
+            None => (None, directive),
+        };
+        level.parse::<log::LevelFilter>().map_err(|_| invalid())?;
+        directives.push(match module {
+            Some("") => return Err(invalid()),
+            Some("sync") => format!("{}={}", IDENTIFIER, level),
+            Some(module) if module == IDENTIFIER || module.contains("::") => format!("{}={}", module, level),
+            Some(module) => format!("{}::{}={}", IDENTIFIER, module, level),
+            None => level.to_string(),
+        });
+    }
+    if directives.is_empty() {
+        return Err("Empty log level".to_string());
+    }
+    Ok(directives.join(","))
+}
+
+fn build_filter(spec: &str) -> Filter {
+    env_logger::filter::Builder::new().parse(spec).build()
+}
+
+// Gives a daemon job its own log level; records logged while one of its
+// passes runs are filtered by `spec` instead of the global one.
+pub fn set_job_level(job: &str, spec: &str) {
+    let filter = build_filter(spec);
+    log::set_max_level(log::max_level().max(filter.filter()));
+    JOB_FILTERS.lock().unwrap().insert(job.to_string(), Arc::new(filter));
+}
+
+// Runs `future` as part of `job`, for `set_job_level`.
+pub async fn with_job<F: std::future::Future>(job: String, future: F) -> F::Output {
+    JOB.scope(job, future).await
+}
+
+// The global logger: filters records by module, and by job for jobs with
+// their own level, before handing them to the chosen target.
+pub struct Logger {
+    target: &'static dyn log::Log,
+    filter: Filter,
+}
+
+impl Logger {
+    // `target` should accept every record; filtering happens here.
+    pub fn install(target: &'static dyn log::Log, spec: &str) -> Result<(), log::SetLoggerError> {
+        let filter = build_filter(spec);
+        let max_level = filter.filter();
+        log::set_boxed_logger(Box::new(Logger { target, filter }))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let job_filter = JOB.try_with(|job| JOB_FILTERS.lock().unwrap().get(job).cloned()).ok().flatten();
+        match job_filter {
+            Some(filter) => filter.enabled(metadata),
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.target.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.target.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    fn lines(path: &Path) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn rotates_full_log_files() {
+        let dir = scratch("logging-rotate");
+        let path = dir.join("logs/sync.log");
+        let mut log = RotatingFile::open(&path, Some(10), None, 2).unwrap();
+        for record in ["one\n", "two\n", "three\n", "four\n", "five-and-more\n"] {
+            log.write_all(record.as_bytes()).unwrap();
+        }
+        // Records are never split, even one larger than the limit.
+        assert_eq!(lines(&path), "five-and-more\n");
+        assert_eq!(lines(&numbered(&path, 1)), "four\n");
+        assert_eq!(lines(&numbered(&path, 2)), "three\n");
+        assert!(!numbered(&path, 3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_no_old_files_when_asked_to() {
+        let dir = scratch("logging-keep-none");
+        let path = dir.join("sync.log");
+        fs::write(&path, "from before\n").unwrap();
+        // A restart picks up the existing file's size.
+        let mut log = RotatingFile::open(&path, Some(16), None, 0).unwrap();
+        log.write_all(b"after restart\n").unwrap();
+        assert_eq!(lines(&path), "after restart\n");
+        assert!(!numbered(&path, 1).exists());
+        let mut log = RotatingFile::open(&path, None, Some(Duration::ZERO), 1).unwrap();
+        log.write_all(b"rotated at once\n").unwrap();
+        assert_eq!(lines(&numbered(&path, 1)), "after restart\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn encodes_journal_fields() {
+        let mut entry = Vec::new();
+        journal_field(&mut entry, "PRIORITY", &severity(log::Level::Warn).to_string());
+        journal_field(&mut entry, "MESSAGE", "two\nlines");
+        assert_eq!(entry, b"PRIORITY=4\nMESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n");
+        assert_eq!(severity(log::Level::Error), 3);
+        assert_eq!(severity(log::Level::Trace), 7);
+        assert_eq!("journald".parse(), Ok(LogTarget::Journald));
+        assert!("file".parse::<LogTarget>().is_err());
+    }
+
+    #[test]
+    fn qualifies_log_level_modules() {
+        assert_eq!(parse_log_level("info").as_deref(), Ok("info"));
+        assert_eq!(parse_log_level("warn, copy=debug").as_deref(), Ok("warn,rusty_file_sync::copy=debug"));
+        assert_eq!(parse_log_level("sync=trace").as_deref(), Ok("rusty_file_sync=trace"));
+        assert_eq!(parse_log_level("hyper::client=off").as_deref(), Ok("hyper::client=off"));
+        for spec in ["", " , ", "loud", "copy=loud", "=debug"] {
+            assert!(parse_log_level(spec).is_err(), "{:?}", spec);
+        }
+    }
+
+    struct Discard;
+
+    impl log::Log for Discard {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, _record: &log::Record) {}
+
+        fn flush(&self) {}
+    }
+
+    fn enabled(logger: &Logger, level: log::Level, module: &str) -> bool {
+        log::Log::enabled(logger, &log::Metadata::builder().level(level).target(module).build())
+    }
+
+    #[tokio::test]
+    async fn filters_by_module_and_by_job() {
+        let logger = Logger { target: &Discard, filter: build_filter(&parse_log_level("warn,copy=debug").unwrap()) };
+        assert!(enabled(&logger, log::Level::Debug, "rusty_file_sync::copy"));
+        assert!(!enabled(&logger, log::Level::Info, "rusty_file_sync::store"));
+        assert!(enabled(&logger, log::Level::Warn, "rusty_file_sync::store"));
+
+        set_job_level("logging-test-job", &parse_log_level("trace").unwrap());
+        let in_job = with_job("logging-test-job".to_string(), async { enabled(&logger, log::Level::Trace, "rusty_file_sync::store") }).await;
+        assert!(in_job);
+        // Jobs without their own level use the global one.
+        let other = with_job("logging-other-job".to_string(), async { enabled(&logger, log::Level::Info, "rusty_file_sync::store") }).await;
+        assert!(!other);
+    }
+}
@@ -1,18 +1,14 @@
-use clap::{Arg, Command};
-use log::{info, error, debug, LevelFilter};
-use sha2::{Sha256, Digest};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use log::{info, error, debug, warn, LevelFilter};
+use rusty_file_sync::{attrs, backend, battery, bench, config, conflict, console, control, doctor, durability, events, faults, fixture, hash, history, itemize, keyring, links, locked, logging, metered, naming, network, options, paths, plan, progress, quarantine, restore, retention, scheduler, scrub, space, template, timestamp, transform, window, SyncError};
 use std::error::Error;
-use std::path::{Path};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use walkdir::WalkDir;
-use thiserror::Error;
-use ctrlc;
-use env_logger;
+use std::time::{Duration, SystemTime};
 use tokio::fs;
-use tokio::io::{self, AsyncBufReadExt, AsyncReadExt};
-use std::collections::HashSet;
+use tokio::io::{self, AsyncBufReadExt};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -20,8 +16,226 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .version("1.0")
         .author("Edward Igarashi <info@igarashi.net>")
         .about("Synchronizes files and directories")
-        .subcommand(Command::new("sync")
-            .about("Synchronizes files between source and destination")
+        .args(log_args())
+        .subcommand(sync_command())
+        .subcommand(Command::new("daemon")
+            .about("Runs every job of a configuration file until interrupted")
+            .arg(Arg::new("config")
+                .help("Configuration file")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("pid-file")
+                .help("Write the process id here so `ctl` can find the daemon")
+                .long("pid-file")
+                .value_name("FILE"))
+            .args(verbosity_args()))
+        .subcommand(Command::new("ctl")
+            .about("Controls a running sync or daemon process")
+            .arg(Arg::new("command")
+                .help("trigger (start a pass now), pause or resume")
+                .required(true)
+                .value_parser(["trigger", "pause", "resume"])
+                .index(1))
+            .arg(Arg::new("pid")
+                .help("Process id of the sync or daemon process")
+                .long("pid")
+                .value_parser(clap::value_parser!(i32))
+                .required_unless_present("pid-file")
+                .conflicts_with("pid-file"))
+            .arg(Arg::new("pid-file")
+                .help("Pid file written by `daemon --pid-file`")
+                .long("pid-file")
+                .value_name("FILE"))
+            .args(verbosity_args()))
+        .subcommand(Command::new("prune")
+            .about("Applies the retention policy to a snapshot or store destination")
+            .arg(Arg::new("destination")
+                .help("Destination directory")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("dry-run")
+                .help("Only report what would be removed")
+                .long("dry-run")
+                .short('n')
+                .action(ArgAction::SetTrue))
+            .args(verbosity_args())
+            .args(retention_args()))
+        .subcommand(Command::new("restore")
+            .about("Restores files from a snapshot or store destination")
+            .arg(Arg::new("destination")
+                .help("Snapshot or store destination to restore from")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("as-of")
+                .help("Restore the newest snapshot/tree taken at or before this UTC time (default: latest)")
+                .long("as-of")
+                .value_name("TIMESTAMP"))
+            .arg(Arg::new("path")
+                .help("Only restore this path, relative to the synced source")
+                .long("path")
+                .value_name("SUBPATH")
+                .default_value(""))
+            .arg(Arg::new("to")
+                .help("Directory to restore into")
+                .long("to")
+                .value_name("DIR")
+                .required(true))
+            .args(verbosity_args()))
+        .subcommand(Command::new("scrub")
+            .about("Re-hashes a destination against its recorded manifest to detect silent corruption")
+            .arg(Arg::new("dir")
+                .help("Destination directory to scrub")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("every")
+                .help("Keep running, scrubbing again after this long (e.g. 1d, 12h)")
+                .long("every")
+                .value_name("DURATION")
+                .value_parser(humantime::parse_duration))
+            .arg(Arg::new("quarantine")
+                .help("Move corrupted files into .rusty_file_sync/quarantine")
+                .long("quarantine")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("source")
+                .help("Source directory to re-fetch quarantined files from")
+                .long("source")
+                .value_name("DIR")
+                .requires("quarantine"))
+            .arg(Arg::new("jobs")
+                .help("Hash up to N files at once [default: one per CPU]")
+                .long("jobs")
+                .short('j')
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize)))
+            .arg(buffer_size_arg())
+            .args(verbosity_args()))
+        .subcommand(Command::new("stats")
+            .about("Shows the recorded passes of a job, with averages and trends")
+            .arg(Arg::new("job")
+                .help("Job name from the daemon configuration, or the destination given to `sync`")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("last")
+                .help("How many recent passes to list and compare with the earlier ones")
+                .long("last")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10"))
+            .arg(history_arg())
+            .args(verbosity_args()))
+        .subcommand(Command::new("plan")
+            .about("Writes the operations of a one-way sync to a JSON plan without performing them")
+            .arg(Arg::new("source")
+                .help("Source directory; ~ and $VARS are expanded")
+                .required(true)
+                .index(1)
+                .value_parser(path_arg))
+            .arg(Arg::new("destination")
+                .help("Destination directory; ~ and $VARS are expanded, and it may contain {hostname}, {user}, {date} and {datetime}")
+                .required(true)
+                .index(2)
+                .value_parser(path_arg))
+            .arg(Arg::new("output")
+                .help("Plan file to write, or - for stdout")
+                .long("output")
+                .short('o')
+                .value_name("FILE")
+                .default_value("-"))
+            .arg(Arg::new("no-delete")
+                .help("Don't plan removing destination files missing from the source")
+                .long("no-delete")
+                .action(ArgAction::SetTrue))
+            .args(time_args())
+            .args(deletion_args())
+            .arg(buffer_size_arg())
+            .args(verbosity_args()))
+        .subcommand(Command::new("apply")
+            .about("Performs the operations of a plan written by `plan`, if the files still match it")
+            .arg(Arg::new("plan")
+                .help("Plan file")
+                .required(true)
+                .index(1))
+            .args(deletion_args())
+            .arg(buffer_size_arg())
+            .args(verbosity_args()))
+        .subcommand(Command::new("conflicts")
+            .about("Lists and settles the conflicts found by bi mode passes")
+            .subcommand_required(true)
+            .subcommand(Command::new("list")
+                .about("Lists the conflicts recorded for a destination")
+                .arg(Arg::new("destination")
+                    .help("Destination directory of the bi mode sync")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::new("all")
+                    .help("Include conflicts already reviewed")
+                    .long("all")
+                    .action(ArgAction::SetTrue)))
+            .subcommand(Command::new("resolve")
+                .about("Marks a conflict as reviewed, optionally settling its conflict copy")
+                .arg(Arg::new("destination")
+                    .help("Destination directory of the bi mode sync")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::new("id")
+                    .help("Conflict id, as shown by `conflicts list`")
+                    .required(true)
+                    .value_parser(clap::value_parser!(u64))
+                    .index(2))
+                .arg(Arg::new("keep")
+                    .help("For a conflict copy: keep the source's version, the destination's, or both")
+                    .long("keep")
+                    .value_name("VERSION")
+                    .value_parser(clap::value_parser!(conflict::Keep)))
+                .arg(Arg::new("source")
+                    .help("Source directory of the sync, needed to keep a single version")
+                    .long("source")
+                    .value_name("DIR")))
+            .args(verbosity_args()))
+        .subcommand(Command::new("credentials")
+            .about("Stores and removes secrets in the platform keyring, for `key.keyring` lines of a daemon configuration")
+            .subcommand_required(true)
+            .subcommand(Command::new("set")
+                .about("Stores a secret read from stdin under NAME, replacing any stored before")
+                .arg(Arg::new("name")
+                    .help("Name the configuration refers to the secret by")
+                    .required(true)
+                    .index(1)))
+            .subcommand(Command::new("remove")
+                .about("Removes the secret stored under NAME")
+                .arg(Arg::new("name")
+                    .help("Name the secret was stored under")
+                    .required(true)
+                    .index(1)))
+            .args(verbosity_args()))
+        .subcommand(Command::new("bench")
+            .about("Times scanning, hashing and copying a generated tree with the given I/O settings")
+            .arg(Arg::new("dir")
+                .help("Directory to generate the tree in, on the disk to measure (default: the temporary directory)")
+                .long("dir")
+                .value_name("DIR"))
+            .arg(Arg::new("files")
+                .help("Number of files to generate")
+                .long("files")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1000"))
+            .arg(Arg::new("size")
+                .help("Size of each generated file")
+                .long("size")
+                .value_name("SIZE")
+                .value_parser(options::parse_size)
+                .default_value("1M"))
+            .arg(Arg::new("keep")
+                .help("Keep the generated tree and its copy afterwards")
+                .long("keep")
+                .action(ArgAction::SetTrue))
+            .arg(buffer_size_arg())
+            .args(io_args())
+            .arg(hash_arg())
+            .args(verbosity_args()))
+        .subcommand(Command::new("doctor")
+            .about("Checks what the source and destination file systems support before syncing")
             .arg(Arg::new("source")
                 .help("Source directory")
                 .required(true)
@@ -30,172 +244,1105 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .help("Destination directory")
                 .required(true)
                 .index(2))
-            .arg(Arg::new("mode")
-                .help("Synchronization mode: one, bi, one+no_delete, bi+no_delete")
+            .args(verbosity_args()))
+        .subcommand(Command::new("fix-perms")
+            .about("Puts back the permissions and ownership of destination entries that have drifted from the source or the rules")
+            .override_usage("rusty_file_sync fix-perms [OPTIONS] [source] <destination>")
+            .arg(Arg::new("source")
+                .help("Source directory holding the expected permissions; may be left out with --chmod or --chown")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("destination")
+                .help("Destination directory to correct")
+                .index(2))
+            .arg(Arg::new("dry-run")
+                .help("Only report the drift")
+                .long("dry-run")
+                .short('n')
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("apply-recorded")
+                .help("Apply the owners and modes recorded by --fake-super instead, which needs root")
+                .long("apply-recorded")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["chmod", "chown", "id-map", "fake-super"]))
+            .args(access_args())
+            .args(verbosity_args()))
+        .subcommand(Command::new("fixture")
+            .about("Creates the tree described by a fixture file, for setting up test scenarios")
+            .arg(Arg::new("fixture")
+                .help("Fixture file")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("destination")
+                .help("Directory or backend URL to create the tree in")
                 .required(true)
-                .index(3))
-            .arg(Arg::new("debug")
-                .help("Enable debug mode")
-                .long("debug")
-                .short('d')))
+                .index(2))
+            .args(verbosity_args()))
         .get_matches();
 
-    let log_level = if matches.contains_id("debug") {
-        LevelFilter::Debug
-    } else {
-        LevelFilter::Info
+    // Interactive and quiet syncs print their actions, or only a summary, to
+    // stdout, leaving only warnings (or errors) to the log unless asked
+    // otherwise.
+    let (console, quiet) = match matches.subcommand() {
+        Some(("sync", m)) => {
+            let stdout_free = m.get_one::<String>("events").is_none_or(|target| target != "-");
+            (stdout_free && (m.get_flag("quiet") || std::io::stdout().is_terminal()), m.get_flag("quiet"))
+        }
+        _ => (false, false),
+    };
+    let log_level = match matches.subcommand() {
+        Some((_, m)) if !m.contains_id("log-level") && !m.get_flag("debug") && quiet => "error".to_string(),
+        Some((_, m)) if !m.contains_id("log-level") && !m.get_flag("debug") && console => "warn".to_string(),
+        // Logging every copied file would skew the timings.
+        Some(("bench", m)) if !m.contains_id("log-level") && !m.get_flag("debug") => "warn".to_string(),
+        Some((_, m)) => log_spec(m),
+        None => "info".to_string(),
     };
 
-    env_logger::builder().filter_level(log_level).init();
-
-    if let Some(matches) = matches.subcommand_matches("sync") {
-        let source = matches.get_one::<String>("source").unwrap();
-        let destination = matches.get_one::<String>("destination").unwrap();
-        let mode = matches.get_one::<String>("mode").unwrap();
-
-        let running = Arc::new(AtomicBool::new(true));
-        let r = running.clone();
-
-        ctrlc::set_handler(move || {
-            r.store(false, Ordering::SeqCst);
-        }).expect("Error setting Ctrl-C handler");
-
-        let source = source.clone();
-        let destination = destination.clone();
-        let mode = mode.clone();
-
-        let sync_thread_running = running.clone();
-        tokio::spawn(async move {
-            while sync_thread_running.load(Ordering::SeqCst) {
-                let result = match mode.as_str() {
-                    "one" => sync_oneway(&source, &destination, true).await,
-                    "bi" => sync_bothways(&source, &destination, true).await,
-                    "one+no_delete" => sync_oneway(&source, &destination, false).await,
-                    "bi+no_delete" => sync_bothways(&source, &destination, false).await,
-                    _ => {
-                        println!("Invalid mode: {}", mode);
-                        return;
-                    }
-                };
+    let target: &'static dyn log::Log = match *matches.get_one::<logging::LogTarget>("log-target").unwrap() {
+        logging::LogTarget::Stderr => {
+            // Filtered by `logging::Logger` instead.
+            let mut logger = env_logger::builder();
+            logger.filter_level(LevelFilter::Trace);
+            if let Some(path) = matches.get_one::<String>("log-file") {
+                let file = logging::RotatingFile::open(
+                    Path::new(path),
+                    matches.get_one::<u64>("log-max-size").copied().filter(|size| *size > 0),
+                    matches.get_one::<Duration>("log-rotate-every").copied(),
+                    *matches.get_one::<usize>("log-keep").unwrap(),
+                )?;
+                logger.target(env_logger::Target::Pipe(Box::new(file)));
+            }
+            Box::leak(Box::new(logger.build()))
+        }
+        target => logging::SystemLog::open(target, matches.get_flag("event-log-info"))?,
+    };
+    logging::Logger::install(target, &log_level)?;
 
-                if let Err(e) = result {
-                    error!("Synchronization failed: {}", e);
-                }
+    match matches.subcommand() {
+        Some(("sync", matches)) => run_sync(matches, console).await,
+        Some(("daemon", matches)) => run_daemon(matches).await?,
+        Some(("ctl", matches)) => run_ctl(matches).await?,
+        Some(("prune", matches)) => run_prune(matches).await?,
+        Some(("restore", matches)) => run_restore(matches).await?,
+        Some(("scrub", matches)) => run_scrub(matches).await?,
+        Some(("stats", matches)) => run_stats(matches).await?,
+        Some(("plan", matches)) => run_plan(matches).await?,
+        Some(("apply", matches)) => run_apply(matches).await?,
+        Some(("conflicts", matches)) => run_conflicts(matches).await?,
+        Some(("credentials", matches)) => run_credentials(matches).await?,
+        Some(("bench", matches)) => run_bench(matches).await?,
+        Some(("doctor", matches)) => run_doctor(matches).await?,
+        Some(("fix-perms", matches)) => run_fix_perms(matches).await?,
+        Some(("fixture", matches)) => run_fixture(matches).await?,
+        _ => {}
+    }
 
-                tokio::time::sleep(Duration::from_secs(10)).await; // Sync interval
-            }
-        });
+    Ok(())
+}
 
-        // Optional: Handle 'q' to quit
-        let stdin = io::BufReader::new(io::stdin());
-        let mut lines = stdin.lines();
-        while running.load(Ordering::SeqCst) {
-            if let Some(line) = lines.next_line().await.unwrap_or(None) {
-                if line == "q" {
-                    running.store(false, Ordering::SeqCst);
-                }
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
+async fn run_sync(matches: &ArgMatches, console: bool) {
+    let running = quit_flag();
+    control::listen();
+    if let Some(faults) = matches.get_one::<faults::Faults>("inject-faults") {
+        faults::inject(faults.clone());
+    }
+    if std::io::stdin().is_terminal() {
+        conflict::accept_answers();
+    }
+    let name = matches.get_one::<String>("destination").unwrap().clone();
+    tokio::spawn(run_job(name, matches.clone(), running.clone(), console));
+    wait_for_quit(&running).await;
+}
+
+async fn run_daemon(matches: &ArgMatches) -> Result<(), SyncError> {
+    let path = matches.get_one::<String>("config").unwrap();
+    let config = config::parse(&fs::read_to_string(path).await?)?;
+    if config.jobs.is_empty() {
+        return Err(SyncError::ConfigError(format!("No jobs configured in {}", path)));
+    }
+
+    let mut jobs = Vec::new();
+    for job in &config.jobs {
+        let matches = sync_command()
+            .try_get_matches_from(&job.args)
+            .map_err(|e| SyncError::ConfigError(format!("Job {}: {}", job.name, e)))?;
+        if matches.contains_id("log-level") || matches.get_flag("debug") {
+            logging::set_job_level(&job.name, &log_spec(&matches));
         }
+        jobs.push((job.name.clone(), matches));
+    }
+
+    scheduler::configure(config.max_concurrent_jobs, config.max_concurrent_transfers);
+    let pid_file = matches.get_one::<String>("pid-file");
+    if let Some(pid_file) = pid_file {
+        fs::write(pid_file, format!("{}\n", std::process::id())).await?;
+    }
+
+    let running = quit_flag();
+    control::listen();
+    for (name, matches) in jobs {
+        info!("Starting job {}", name);
+        tokio::spawn(logging::with_job(name.clone(), run_job(name, matches, running.clone(), false)));
     }
+    wait_for_quit(&running).await;
 
+    if let Some(pid_file) = pid_file {
+        fs::remove_file(pid_file).await?;
+    }
     Ok(())
 }
 
-#[derive(Debug, Error)]
-enum SyncError {
-    #[error("File system error: {0}")]
-    FileSystemError(#[from] std::io::Error),
-    #[error("Path error: {0}")]
-    PathError(#[from] std::path::StripPrefixError),
-    #[error("WalkDir error: {0}")]
-    WalkDirError(#[from] walkdir::Error),
+async fn run_ctl(matches: &ArgMatches) -> Result<(), SyncError> {
+    let command = matches.get_one::<String>("command").unwrap();
+    let pid = match matches.get_one::<i32>("pid") {
+        Some(pid) => *pid,
+        None => {
+            let path = matches.get_one::<String>("pid-file").unwrap();
+            let contents = fs::read_to_string(path).await?;
+            contents.trim().parse().map_err(|_| SyncError::ControlError(format!("Invalid pid file {}", path)))?
+        }
+    };
+    control::send(command, pid)
 }
 
-async fn calculate_hash<P: AsRef<Path>>(path: P) -> Result<String, SyncError> {
-    let mut file = fs::File::open(path).await?;
-    let mut hasher = Sha256::new();
-    let mut buffer = [0; 1024];
-    loop {
-        let n = file.read(&mut buffer).await?;
-        if n == 0 {
-            break;
+fn quit_flag() -> Arc<AtomicBool> {
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    }).expect("Error setting Ctrl-C handler");
+
+    running
+}
+
+async fn run_job(name: String, matches: ArgMatches, running: Arc<AtomicBool>, console: bool) {
+    let source = matches.get_one::<String>("source").unwrap().clone();
+    let template = matches.get_one::<String>("destination").unwrap().clone();
+    let mode = matches.get_one::<String>("mode").unwrap().clone();
+    let mut options = sync_options(&matches);
+    if let Some(path) = matches.get_one::<String>("id-map") {
+        match attrs::load_id_map(Path::new(path), matches.get_flag("numeric-ids")).await {
+            Ok(map) => options.id_map = map,
+            Err(e) => {
+                error!("Cannot load id map {} for {}: {}", path, name, e);
+                return;
+            }
         }
-        hasher.update(&buffer[..n]);
     }
-    let result = hasher.finalize();
-    Ok(format!("{:x}", result))
-}
+    if let Some(target) = matches.get_one::<String>("events") {
+        match events::EventStream::open(target, &name) {
+            Ok(stream) => options.observer = Arc::new(stream),
+            Err(e) => {
+                error!("Cannot open event stream {} for {}: {}", target, name, e);
+                return;
+            }
+        }
+    }
+
+    let progress_interval = matches.get_one::<Duration>("progress").copied();
+    let progress = Arc::new(progress::Progress::new(options.observer.clone()));
+    if progress_interval.is_some() {
+        options.observer = progress.clone();
+    }
+    if console {
+        // NO_COLOR is the common convention for turning colors off everywhere.
+        let color = std::io::stdout().is_terminal() && !matches.get_flag("no-color") && std::env::var_os("NO_COLOR").is_none();
+        // Itemized changes stand in for the console's own lines.
+        let quiet = matches.get_flag("quiet") || matches.get_flag("itemize");
+        options.observer = Arc::new(console::Console::new(color, quiet, options.observer.clone()));
+    }
+    if matches.get_flag("itemize") {
+        options.observer = Arc::new(itemize::Itemizer::new(options.observer.clone()));
+    }
+    options.observer = logging::ActionLog::wrap(&name, options.observer.clone());
+    let history = history_path(&matches);
+    let recorder = Arc::new(history::Recorder::new(options.observer.clone()));
+    options.observer = recorder.clone();
 
-async fn is_file_updated(src_metadata: &std::fs::Metadata, dest_path: &Path) -> bool {
-    if let Ok(dest_metadata) = fs::metadata(dest_path).await {
-        if let Ok(dest_modified) = dest_metadata.modified() {
-            if let Ok(src_modified) = src_metadata.modified() {
-                if src_modified > dest_modified {
-                    return true;
+    let mut backoff = network::Backoff::default();
+    while running.load(Ordering::SeqCst) {
+        let triggers = control::triggers();
+        window::wait_for_window(&options.windows).await;
+        metered::wait_until_unmetered(&options).await;
+        battery::wait_for_power(&options).await;
+        let permit = scheduler::job_permit().await;
+        debug!("Starting pass for {}", name);
+        // Expanded for every pass so `{date}` moves on to a new directory,
+        // which is created as needed, but only inside an existing root when
+        // the destination has to be mounted.
+        let destination = match template::expand(&template) {
+            Ok(destination) => destination,
+            Err(e) => {
+                error!("Invalid destination for {}: {}", name, e);
+                return;
+            }
+        };
+        let start = SystemTime::now();
+        let result = match network::probe(&destination).await {
+            Ok(()) => {
+                let root = template::fixed_root(&template);
+                if destination != template && !Path::new(&destination).exists() && (!options.strict_dest || Path::new(root).is_dir()) {
+                    info!("Creating destination {}", destination);
+                    if let Err(e) = fs::create_dir_all(&destination).await {
+                        error!("Cannot create destination for {}: {}", name, e);
+                    }
+                }
+                let mut ticker = None;
+                if let Some(interval) = progress_interval {
+                    let planned = space::estimate(&source, &destination, &mode, &options).await.map_or(0, |estimate| estimate.transfer);
+                    progress.start(planned);
+                    ticker = Some(tokio::spawn(progress::log_periodically(name.clone(), progress.clone(), interval)));
+                }
+                let result = rusty_file_sync::sync(&source, &destination, &mode, &options).await;
+                if let Some(ticker) = ticker {
+                    ticker.abort();
+                    info!("{}", progress.finished(&name));
+                }
+                result
+            }
+            Err(e) => Err(e),
+        };
+        if let Some(path) = &history {
+            let (files, bytes) = recorder.take();
+            let pass = history::Pass { job: name.clone(), start, end: SystemTime::now(), files, bytes, errors: result.is_err() as u64 };
+            if let Err(e) = history::record(path, &pass).await {
+                warn!("Cannot record pass of {} in {:?}: {}", name, path, e);
+            }
+        }
+
+        match result {
+            // Logged once per outage rather than on every retry.
+            Err(e) if network::is_unreachable(&e) => {
+                if backoff.failed() {
+                    warn!("Destination of {} is unreachable ({}), waiting for network", name, e);
                 } else {
-                    if let (Ok(src_hash), Ok(dest_hash)) = (calculate_hash(dest_path).await, calculate_hash(dest_path).await) {
-                        return src_hash != dest_hash;
+                    debug!("Destination of {} still unreachable: {}", name, e);
+                }
+            }
+            Err(e) => {
+                backoff.succeeded();
+                error!("Synchronization failed for {}: {}", name, e);
+            }
+            Ok(()) => {
+                if backoff.succeeded() {
+                    info!("Destination of {} is reachable again", name);
+                }
+                if !options.retention.is_empty() {
+                    if let Err(e) = retention::apply(&destination, &mode, &options.retention, false).await {
+                        error!("Retention failed for {}: {}", name, e);
                     }
                 }
             }
         }
+        drop(permit);
+
+        control::wait_for_next_pass(battery::interval(&options, backoff.interval(Duration::from_secs(10))), triggers).await; // Sync interval
+    }
+}
+
+async fn wait_for_quit(running: &AtomicBool) {
+    // Optional: Handle 'q' to quit
+    let stdin = io::BufReader::new(io::stdin());
+    let mut lines = stdin.lines();
+    while running.load(Ordering::SeqCst) {
+        if let Some(line) = lines.next_line().await.unwrap_or(None) {
+            if conflict::answer(&line) {
+                continue;
+            }
+            if line == "q" {
+                running.store(false, Ordering::SeqCst);
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn run_prune(matches: &ArgMatches) -> Result<(), SyncError> {
+    let destination = matches.get_one::<String>("destination").unwrap();
+    let policy = retention_policy(matches);
+    if policy.is_empty() {
+        println!("No retention rules given, nothing to prune");
+        return Ok(());
     }
-    true
+
+    let dry_run = matches.get_flag("dry-run");
+    let mode = retention::layout_of(Path::new(destination));
+    let removed = retention::apply(destination, mode, &policy, dry_run).await?;
+    if dry_run {
+        println!("{} {} entries would be removed", removed, mode);
+    } else {
+        println!("Removed {} {} entries", removed, mode);
+    }
+    Ok(())
+}
+
+async fn run_restore(matches: &ArgMatches) -> Result<(), SyncError> {
+    let destination = matches.get_one::<String>("destination").unwrap();
+    let as_of = match matches.get_one::<String>("as-of") {
+        Some(value) => Some(timestamp::parse(value)
+            .ok_or_else(|| SyncError::RestoreError(format!("Invalid timestamp: {}", value)))?),
+        None => None,
+    };
+    let subpath = matches.get_one::<String>("path").unwrap();
+    let target = matches.get_one::<String>("to").unwrap();
+
+    let restored = restore::restore(destination, as_of, subpath, target).await?;
+    println!("Restored {} files to {}", restored, target);
+    Ok(())
 }
 
-async fn sync_oneway(source: &str, destination: &str, delete: bool) -> Result<(), SyncError> {
-    let mut dest_files = HashSet::new();
+async fn run_scrub(matches: &ArgMatches) -> Result<(), SyncError> {
+    let dir = Path::new(matches.get_one::<String>("dir").unwrap());
+    let every = matches.get_one::<Duration>("every");
+    let source = matches.get_one::<String>("source").map(Path::new);
+    let buffer_size = *matches.get_one::<usize>("buffer-size").unwrap();
+    let jobs = matches.get_one::<usize>("jobs").copied().unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
 
-    if delete {
-        for entry in WalkDir::new(destination) {
-            let entry = entry?;
-            let path = entry.path().strip_prefix(destination)?.to_path_buf();
-            dest_files.insert(path);
+    loop {
+        let report = scrub::scrub(dir, jobs, buffer_size).await?;
+        if matches.get_flag("quarantine") {
+            for corruption in &report.corrupted {
+                if let Err(e) = quarantine::quarantine(dir, source, corruption, buffer_size).await {
+                    error!("Failed to quarantine {:?}: {}", corruption.path, e);
+                }
+            }
+        }
+        println!(
+            "Scrubbed {} files: {} corrupted, {} missing",
+            report.checked,
+            report.corrupted.len(),
+            report.missing.len()
+        );
+        match every {
+            Some(interval) => tokio::time::sleep(*interval).await,
+            None if report.is_clean() => return Ok(()),
+            None => return Err(SyncError::ScrubError(format!("{} problems found in {:?}", report.corrupted.len() + report.missing.len(), dir))),
         }
     }
+}
 
-    for entry in WalkDir::new(source) {
-        let entry = entry?;
-        let source_path = entry.path();
-        let dest_path = Path::new(destination).join(source_path.strip_prefix(source)?);
+async fn run_stats(matches: &ArgMatches) -> Result<(), SyncError> {
+    let job = matches.get_one::<String>("job").unwrap();
+    let path = history_path(matches).ok_or_else(|| SyncError::ConfigError("No history file, pass --history".to_string()))?;
+    let passes = history::load(&path, job).await?;
+    println!("{}", history::report(job, &passes, *matches.get_one::<usize>("last").unwrap()));
+    Ok(())
+}
 
-        if delete {
-            dest_files.remove(dest_path.strip_prefix(destination)?);
+async fn run_plan(matches: &ArgMatches) -> Result<(), SyncError> {
+    let source = matches.get_one::<String>("source").unwrap();
+    let destination = template::expand(matches.get_one::<String>("destination").unwrap())?;
+    let options = options::SyncOptions {
+        buffer_size: *matches.get_one::<usize>("buffer-size").unwrap(),
+        modify_window: matches.get_one::<Duration>("modify-window").copied(),
+        dst_shift: *matches.get_one::<timestamp::DstShift>("dst-shift").unwrap(),
+        checksum: matches.get_flag("checksum"),
+        require_marker: matches.get_one::<String>("require-marker").cloned(),
+        max_delete: *matches.get_one::<u8>("max-delete").unwrap(),
+        force: matches.get_flag("force"),
+        ..Default::default()
+    };
+    let plan = plan::make(source, &destination, !matches.get_flag("no-delete"), &options).await?;
+    match matches.get_one::<String>("output").unwrap().as_str() {
+        "-" => print!("{}", plan::to_json(&plan)),
+        output => {
+            fs::write(output, plan::to_json(&plan)).await?;
+            println!("Planned {} operations ({} copies, {} removals) in {}", plan.operations.len(), plan.copies(), plan.removals(), output);
         }
+    }
+    Ok(())
+}
+
+async fn run_apply(matches: &ArgMatches) -> Result<(), SyncError> {
+    let plan = plan::parse(&fs::read_to_string(matches.get_one::<String>("plan").unwrap()).await?)?;
+    let options = options::SyncOptions {
+        buffer_size: *matches.get_one::<usize>("buffer-size").unwrap(),
+        require_marker: matches.get_one::<String>("require-marker").cloned(),
+        max_delete: *matches.get_one::<u8>("max-delete").unwrap(),
+        force: matches.get_flag("force"),
+        ..Default::default()
+    };
+    plan::apply(&plan, &options).await?;
+    println!("Applied {} operations ({} copies, {} removals) planned at {}", plan.operations.len(), plan.copies(), plan.removals(), plan.created);
+    Ok(())
+}
 
-        if source_path.is_dir() {
-            if !dest_path.exists() {
-                info!("Creating directory: {:?}", dest_path);
-                fs::create_dir_all(&dest_path).await?;
+async fn run_credentials(matches: &ArgMatches) -> Result<(), SyncError> {
+    match matches.subcommand() {
+        Some(("set", matches)) => {
+            let name = matches.get_one::<String>("name").unwrap();
+            if std::io::stdin().is_terminal() {
+                eprint!("Secret for {} (shown as typed): ", name);
             }
-        } else {
-            if !dest_path.exists() || is_file_updated(&std::fs::metadata(source_path)?, &dest_path).await {
-                info!("Copying file from {:?} to {:?}", source_path, dest_path);
-                fs::copy(&source_path, &dest_path).await?;
-            } else {
-                debug!("Skipping unchanged file: {:?}", source_path);
+            let mut secret = String::new();
+            io::BufReader::new(io::stdin()).read_line(&mut secret).await?;
+            let secret = secret.trim_end_matches(['\r', '\n']);
+            if secret.is_empty() {
+                return Err(SyncError::ConfigError("No secret given on stdin".to_string()));
             }
+            keyring::set(name, secret)?;
+            println!("Stored {} in the keyring", name);
         }
+        Some(("remove", matches)) => {
+            let name = matches.get_one::<String>("name").unwrap();
+            keyring::remove(name)?;
+            println!("Removed {} from the keyring", name);
+        }
+        _ => {}
     }
+    Ok(())
+}
 
-    if delete {
-        for remaining_path in dest_files {
-            let full_dest_path = Path::new(destination).join(&remaining_path);
-            if full_dest_path.is_dir() {
-                info!("Removing directory: {:?}", full_dest_path);
-                fs::remove_dir_all(full_dest_path).await?;
-            } else {
-                info!("Removing file: {:?}", full_dest_path);
-                fs::remove_file(full_dest_path).await?;
-            }
+async fn run_bench(matches: &ArgMatches) -> Result<(), SyncError> {
+    let parent = matches.get_one::<String>("dir").map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    let dir = parent.join(format!("rusty_file_sync-bench-{}", std::process::id()));
+    let (files, size) = (*matches.get_one::<usize>("files").unwrap(), *matches.get_one::<u64>("size").unwrap());
+    let options = options::SyncOptions {
+        buffer_size: *matches.get_one::<usize>("buffer-size").unwrap(),
+        direct_io: matches.get_one::<u64>("direct-io").copied(),
+        fsync: *matches.get_one::<durability::FsyncPolicy>("fsync").unwrap(),
+        preallocate: matches.get_flag("preallocate"),
+        multi_stream: matches.get_one::<u64>("multi-stream").copied(),
+        streams: *matches.get_one::<usize>("streams").unwrap(),
+        hash: *matches.get_one::<hash::HashAlgorithm>("hash").unwrap(),
+        ..Default::default()
+    };
+    println!("Generating {} files of {} in {}", files, options::format_size(size), dir.display());
+    let result = bench::run(&dir, files, size, &options).await;
+    if !matches.get_flag("keep") {
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+    let report = result?;
+    let megabytes = |elapsed| options::format_size(bench::rate(report.bytes, elapsed));
+    println!("{:<5} {:>9.3}s  {:>9} files/s", "scan", report.scan.as_secs_f64(), bench::rate(report.files as u64, report.scan));
+    println!("{:<5} {:>9.3}s  {:>9}/s  ({:?})", "hash", report.hash.as_secs_f64(), megabytes(report.hash), options.hash);
+    println!("{:<5} {:>9.3}s  {:>9}/s  {:>9} files/s", "copy", report.copy.as_secs_f64(), megabytes(report.copy), bench::rate(report.files as u64, report.copy));
+    Ok(())
+}
+
+async fn run_fix_perms(matches: &ArgMatches) -> Result<(), SyncError> {
+    // A single directory is the destination, checked against the rules alone.
+    let first = Path::new(matches.get_one::<String>("source").unwrap());
+    let (source, destination) = match matches.get_one::<String>("destination") {
+        Some(destination) => (Some(first), Path::new(destination)),
+        None => (None, first),
+    };
+    let mut options = options::SyncOptions {
+        chmod: matches.get_many::<attrs::Chmod>("chmod").into_iter().flatten().cloned().collect(),
+        chown: matches.get_one::<attrs::Chown>("chown").copied().unwrap_or_default(),
+        fake_super: matches.get_flag("fake-super"),
+        ..Default::default()
+    };
+    let dry_run = matches.get_flag("dry-run");
+    if let Some(path) = matches.get_one::<String>("id-map") {
+        options.id_map = attrs::load_id_map(Path::new(path), matches.get_flag("numeric-ids")).await?;
+    }
+    let report = if matches.get_flag("apply-recorded") {
+        attrs::apply_recorded(destination, dry_run).await?
+    } else {
+        if source.is_none() && options.chmod == attrs::Chmod::default() && options.chown == attrs::Chown::default() {
+            return Err(SyncError::ConfigError("fix-perms needs a source directory, --chmod or --chown".to_string()));
+        }
+        attrs::fix_perms(source, destination, dry_run, &options).await?
+    };
+    for (path, change) in &report.drifted {
+        println!("{} {}", change.code(), path.display());
+    }
+    println!(
+        "Checked {} entries: {} {}, {} {}",
+        report.checked,
+        report.drifted.len(),
+        if dry_run { "drifted" } else { "fixed" },
+        report.unmatched,
+        if matches.get_flag("apply-recorded") { "no longer there" } else { "not in the source" }
+    );
+    if report.unowned > 0 {
+        warn!("Couldn't change the owner of {} entries; only root can give files away", report.unowned);
+    }
+    Ok(())
+}
+
+async fn run_doctor(matches: &ArgMatches) -> Result<(), SyncError> {
+    let source = matches.get_one::<String>("source").unwrap();
+    let destination = matches.get_one::<String>("destination").unwrap();
+    let print = |findings: &[doctor::Finding]| {
+        for finding in findings {
+            println!("  {:<4}  {:<8} {}", finding.level.as_str(), finding.check, finding.detail);
         }
+    };
+    let source_probe = doctor::probe(Path::new(source), false).await?;
+    println!("Source {}", source);
+    print(&source_probe.findings);
+    let destination_probe = doctor::probe(Path::new(destination), true).await?;
+    println!("Destination {}", destination);
+    print(&destination_probe.findings);
+    let both = doctor::compare(&source_probe, &destination_probe);
+    if !both.is_empty() {
+        println!("Together");
+        print(&both);
     }
 
+    let findings = || source_probe.findings.iter().chain(&destination_probe.findings).chain(&both);
+    let failed = findings().filter(|finding| finding.level == doctor::Level::Fail).count();
+    let warned = findings().filter(|finding| finding.level == doctor::Level::Warn).count();
+    match failed {
+        0 => {
+            println!("{} warnings", warned);
+            Ok(())
+        }
+        _ => Err(SyncError::ConfigError(format!("{} checks failed, {} warnings", failed, warned))),
+    }
+}
+
+async fn run_fixture(matches: &ArgMatches) -> Result<(), SyncError> {
+    let text = fs::read_to_string(matches.get_one::<String>("fixture").unwrap()).await?;
+    let destination = matches.get_one::<String>("destination").unwrap();
+    let target = match backend::open(destination) {
+        Some(target) => target?,
+        None => {
+            fs::create_dir_all(destination).await?;
+            Arc::new(backend::LocalBackend::new(destination))
+        }
+    };
+    let created = fixture::load(&text, target.as_ref())?;
+    println!("Created {} entries in {}", created, destination);
+    Ok(())
+}
+
+async fn run_conflicts(matches: &ArgMatches) -> Result<(), SyncError> {
+    match matches.subcommand() {
+        Some(("list", matches)) => {
+            let destination = Path::new(matches.get_one::<String>("destination").unwrap());
+            let all = matches.get_flag("all");
+            let conflicts: Vec<_> = conflict::load(destination).await?.into_iter().filter(|c| all || c.status == conflict::Status::Open).collect();
+            if conflicts.is_empty() {
+                println!("No {}conflicts recorded for {}", if all { "" } else { "open " }, destination.display());
+            }
+            for c in conflicts {
+                println!("{:>4}  {}  {:<16}  {:<16}  {}", c.id, c.time, c.resolution.as_str(), c.status.as_str(), c.path);
+                for (side, version) in [("source", &c.source), ("destination", &c.dest)] {
+                    println!("      {:<11} {:.12}  modified {}", side, version.hash, timestamp::format(timestamp::from_secs(version.modified)));
+                }
+            }
+        }
+        Some(("resolve", matches)) => {
+            let destination = Path::new(matches.get_one::<String>("destination").unwrap());
+            let source = matches.get_one::<String>("source").map(Path::new);
+            let keep = matches.get_one::<conflict::Keep>("keep").copied();
+            let reviewed = conflict::review(destination, source, *matches.get_one::<u64>("id").unwrap(), keep).await?;
+            println!("Conflict {} on {} is now {}", reviewed.id, reviewed.path, reviewed.status.as_str());
+        }
+        _ => {}
+    }
     Ok(())
 }
 
-async fn sync_bothways(source: &str, destination: &str, delete: bool) -> Result<(), SyncError> {
-    sync_oneway(source, destination, delete).await?;
-    sync_oneway(destination, source, delete).await
+fn sync_command() -> Command {
+    Command::new("sync")
+        .about("Synchronizes files between source and destination")
+        .arg(Arg::new("source")
+            .help("Source directory; ~ and $VARS are expanded")
+            .required(true)
+            .index(1)
+            .value_parser(path_arg))
+        .arg(Arg::new("destination")
+            .help("Destination directory; ~ and $VARS are expanded, and it may contain {hostname}, {user}, {date} and {datetime}")
+            .required(true)
+            .index(2)
+            .value_parser(path_arg))
+        .arg(Arg::new("mode")
+            .help("Synchronization mode: one, bi, one+no_delete, bi+no_delete, store, snapshot, metadata")
+            .required(true)
+            .value_parser(rusty_file_sync::MODES)
+            .index(3))
+        .arg(buffer_size_arg())
+        .args(io_args())
+        .arg(Arg::new("locked")
+            .help("What to do with files locked by another process: fail, skip or retry")
+            .long("locked")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(locked::LockedPolicy))
+            .default_value("fail"))
+        .arg(Arg::new("locked-retries")
+            .help("Attempts made on a locked file before skipping it under --locked retry")
+            .long("locked-retries")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("3"))
+        .arg(Arg::new("transform")
+            .help("Transform matching files while copying, e.g. crlf-to-lf@txt,md or exec:<command>@jpg (repeatable)")
+            .long("transform")
+            .value_name("RULE")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(transform::TransformRule)))
+        .arg(Arg::new("name-case")
+            .help("Rename files and directories in the destination: preserve, lower or upper")
+            .long("name-case")
+            .value_name("CASE")
+            .value_parser(clap::value_parser!(naming::NameCase))
+            .default_value("preserve"))
+        .arg(Arg::new("rename")
+            .help("Rewrite destination paths: FROM->TO for a leading path, re:PATTERN->REPLACEMENT for a regex (repeatable)")
+            .long("rename")
+            .value_name("RULE")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(naming::RenameRule)))
+        .arg(Arg::new("window")
+            .help("Only start passes during this local time window, e.g. 22:00-06:00 (repeatable)")
+            .long("window")
+            .value_name("HH:MM-HH:MM")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(window::TimeWindow)))
+        .arg(Arg::new("window-policy")
+            .help("What a pass does when its window closes: continue or pause")
+            .long("window-policy")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(window::WindowPolicy))
+            .default_value("continue"))
+        .arg(Arg::new("metered")
+            .help("On metered connections: ignore, defer (whole passes) or defer-large (big files)")
+            .long("metered")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(metered::MeteredPolicy))
+            .default_value("ignore"))
+        .arg(Arg::new("metered-limit")
+            .help("Smallest file deferred by --metered defer-large")
+            .long("metered-limit")
+            .value_name("SIZE")
+            .value_parser(options::parse_size)
+            .default_value("10M"))
+        .arg(Arg::new("check-space")
+            .help("Check the destination has room for the pass before copying anything")
+            .long("check-space")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("space-reserve")
+            .help("Free space --check-space leaves on the destination")
+            .long("space-reserve")
+            .value_name("SIZE")
+            .value_parser(options::parse_size)
+            .default_value("0"))
+        .arg(Arg::new("max-dest-size")
+            .help("Quota for everything kept in the destination, e.g. 500GB")
+            .long("max-dest-size")
+            .value_name("SIZE")
+            .value_parser(options::parse_size))
+        .arg(Arg::new("quota-policy")
+            .help("When a pass would exceed --max-dest-size: fail, or prune the oldest snapshots or trees")
+            .long("quota-policy")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(space::QuotaPolicy))
+            .default_value("fail"))
+        .arg(Arg::new("min-free")
+            .help("Stop copying rather than leave less than this free on the destination volume, e.g. 5% or 20G")
+            .long("min-free")
+            .value_name("SIZE|PERCENT")
+            .value_parser(clap::value_parser!(space::MinFree)))
+        .arg(Arg::new("conflict")
+            .help("Files changed on both sides in bi modes: newer wins, merge text and keep conflict copies, or ask")
+            .long("conflict")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(conflict::ConflictPolicy))
+            .default_value("newer"))
+        .arg(hash_arg())
+        .args(time_args())
+        .args(access_args())
+        .arg(Arg::new("mkpath")
+            .help("Create the destination, and any missing parents, when it doesn't exist yet")
+            .long("mkpath")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("strict-dest")
+            .help("Refuse to run a pass when the destination doesn't exist rather than create it, for destinations that are mounted volumes")
+            .long("strict-dest")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("mkpath"))
+        .args(deletion_args())
+        .arg(Arg::new("source-read-only")
+            .help("Refuse to write anything to the source, rejecting bidirectional modes")
+            .long("source-read-only")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("links")
+            .help("Symbolic links and junctions in the source: follow them, skip them, or recreate them in mirror modes")
+            .long("links")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(links::LinkPolicy))
+            .default_value("follow"))
+        .arg(Arg::new("devices")
+            .help("Recreate block and character devices in mirror modes instead of skipping them; needs root")
+            .long("devices")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("specials")
+            .help("Recreate FIFOs and sockets in mirror modes instead of skipping them")
+            .long("specials")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("delete-before")
+            .help("Delete what the source no longer has before copying, freeing room on a tight destination")
+            .long("delete-before")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["delete-during", "delete-after"]))
+        .arg(Arg::new("delete-during")
+            .help("Delete what each directory no longer has just before copying into it")
+            .long("delete-during")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("delete-after"))
+        .arg(Arg::new("delete-after")
+            .help("Delete what the source no longer has once everything is copied (the default)")
+            .long("delete-after")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("delete-after-age")
+            .help("Only delete destination entries once they have been missing from the source for this long, e.g. 24h")
+            .long("delete-after-age")
+            .value_name("DURATION")
+            .value_parser(humantime::parse_duration))
+        .arg(Arg::new("prune-empty-dirs")
+            .help("Remove destination directories left empty, and don't create empty ones, in one-way mirror modes")
+            .long("prune-empty-dirs")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("chunked")
+            .help("In store mode, split files into content-defined chunks averaging SIZE (default 1M) and store each chunk once")
+            .long("chunked")
+            .value_name("SIZE")
+            .num_args(0..=1)
+            .default_missing_value("1M")
+            .value_parser(options::parse_size))
+        .arg(Arg::new("battery")
+            .help("On battery below --battery-min: ignore, pause (copying) or throttle (start passes every 10 minutes)")
+            .long("battery")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(battery::BatteryPolicy))
+            .default_value("ignore"))
+        .arg(Arg::new("battery-min")
+            .help("Battery charge, in percent, below which --battery applies")
+            .long("battery-min")
+            .value_name("PERCENT")
+            .value_parser(clap::value_parser!(u8).range(0..=100))
+            .default_value("20"))
+        .arg(Arg::new("progress")
+            .help("Log progress, throughput and time left every INTERVAL during a pass (default 10s)")
+            .long("progress")
+            .value_name("INTERVAL")
+            .num_args(0..=1)
+            .default_missing_value("10s")
+            .value_parser(humantime::parse_duration))
+        .arg(Arg::new("file-progress")
+            .help("Report the progress of files of at least SIZE every 10s while they are copied (default 1G)")
+            .long("file-progress")
+            .value_name("SIZE")
+            .num_args(0..=1)
+            .default_missing_value("1G")
+            .value_parser(options::parse_size))
+        .arg(Arg::new("itemize")
+            .help("Print a line for each change with a code telling why it was made, as rsync -i does")
+            .long("itemize")
+            .short('i')
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("quiet")
+            .help("Print only errors and a summary of each pass")
+            .long("quiet")
+            .short('q')
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("no-color")
+            .help("Don't color the actions printed to the terminal")
+            .long("no-color")
+            .action(ArgAction::SetTrue))
+        .arg(history_arg())
+        .arg(Arg::new("events")
+            .help("Write one JSON event per line for every action to FILE (or - for stdout)")
+            .long("events")
+            .value_name("FILE"))
+        .arg(Arg::new("inject-faults")
+            .help("Inject failures for testing, e.g. write-error=3,slow-read=200ms,rename-error=1")
+            .long("inject-faults")
+            .value_name("FAULTS")
+            .value_parser(clap::value_parser!(faults::Faults))
+            .hide(true))
+        .args(verbosity_args())
+        .args(retention_args())
+}
+
+fn log_args() -> Vec<Arg> {
+    vec![
+        Arg::new("log-target")
+            .help("Where logs go: stderr (or --log-file), syslog, journald or eventlog (Windows)")
+            .long("log-target")
+            .value_name("TARGET")
+            .value_parser(clap::value_parser!(logging::LogTarget))
+            .default_value("stderr")
+            .global(true),
+        Arg::new("event-log-info")
+            .help("Also write informational events to the Windows Event Log, not just warnings and errors")
+            .long("event-log-info")
+            .action(ArgAction::SetTrue)
+            .global(true),
+        Arg::new("log-file")
+            .help("Write the log to FILE instead of stderr, rotating it as it grows")
+            .long("log-file")
+            .value_name("FILE")
+            .global(true),
+        Arg::new("log-max-size")
+            .help("Rotate the log file once it reaches SIZE (0 for no limit)")
+            .long("log-max-size")
+            .value_name("SIZE")
+            .value_parser(options::parse_size)
+            .default_value("10M")
+            .global(true),
+        Arg::new("log-rotate-every")
+            .help("Also rotate the log file after this long, e.g. 1d")
+            .long("log-rotate-every")
+            .value_name("DURATION")
+            .value_parser(humantime::parse_duration)
+            .global(true),
+        Arg::new("log-keep")
+            .help("Rotated log files to keep")
+            .long("log-keep")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("5")
+            .global(true),
+    ]
+}
+
+fn history_arg() -> Arg {
+    Arg::new("history")
+        .help("File passes are recorded in (default: rusty_file_sync/history in the user's state directory)")
+        .long("history")
+        .value_name("FILE")
+}
+
+fn history_path(matches: &ArgMatches) -> Option<PathBuf> {
+    matches.get_one::<String>("history").map(PathBuf::from).or_else(history::default_path)
+}
+
+fn buffer_size_arg() -> Arg {
+    Arg::new("buffer-size")
+        .help("I/O buffer size for hashing and copying (e.g. 64K, 4M)")
+        .long("buffer-size")
+        .value_name("SIZE")
+        .value_parser(options::parse_buffer_size)
+        .default_value("1M")
+}
+
+// How copies do their I/O, shared by `sync` and `bench`.
+fn io_args() -> [Arg; 5] {
+    [
+        Arg::new("direct-io")
+            .help("Bypass the page cache (O_DIRECT) when copying files of at least SIZE (default 64M)")
+            .long("direct-io")
+            .value_name("SIZE")
+            .num_args(0..=1)
+            .default_missing_value("64M")
+            .value_parser(options::parse_size),
+        Arg::new("fsync")
+            .help("When to fsync destination files and directories: never, per-file, per-batch, end")
+            .long("fsync")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(durability::FsyncPolicy))
+            .default_value("never"),
+        Arg::new("preallocate")
+            .help("Reserve the full size of each destination file before copying")
+            .long("preallocate")
+            .action(ArgAction::SetTrue),
+        Arg::new("multi-stream")
+            .help("Copy files of at least SIZE (default 1G) with several concurrent readers and writers")
+            .long("multi-stream")
+            .value_name("SIZE")
+            .num_args(0..=1)
+            .default_missing_value("1G")
+            .value_parser(options::parse_size),
+        Arg::new("streams")
+            .help("Readers and writers per file under --multi-stream")
+            .long("streams")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("4"),
+    ]
+}
+
+// Source and destination arguments, expanded and normalized.
+fn path_arg(value: &str) -> Result<String, SyncError> {
+    template::expand_path(value).map(|path| paths::normalize(&path))
+}
+
+fn hash_arg() -> Arg {
+    Arg::new("hash")
+        .help("Digest recorded in manifests: sha256, or sha1 or md5 to match existing listings")
+        .long("hash")
+        .value_name("ALGORITHM")
+        .value_parser(clap::value_parser!(hash::HashAlgorithm))
+        .default_value("sha256")
+}
+
+fn access_args() -> [Arg; 5] {
+    [
+        Arg::new("chmod")
+            .help("Permission rules applied to everything written, in rsync syntax, e.g. D755,F644 or Fgo-w; may be repeated")
+            .long("chmod")
+            .value_name("RULES")
+            .value_parser(clap::value_parser!(attrs::Chmod))
+            .action(ArgAction::Append),
+        Arg::new("chown")
+            .help("Owner and group given to everything written, as USER:GROUP, USER or :GROUP (needs root)")
+            .long("chown")
+            .value_name("USER:GROUP")
+            .value_parser(clap::value_parser!(attrs::Chown)),
+        Arg::new("id-map")
+            .help("File mapping source user and group ids to destination ones where ownership is carried over, e.g. `alice:1000 -> 1500`")
+            .long("id-map")
+            .value_name("FILE"),
+        Arg::new("numeric-ids")
+            .help("Only accept numeric ids in the id map, never looking up names")
+            .long("numeric-ids")
+            .action(ArgAction::SetTrue),
+        Arg::new("fake-super")
+            .help("Record owners that would be given away in the destination's state directory instead, for `fix-perms --apply-recorded` run as root")
+            .long("fake-super")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+fn time_args() -> [Arg; 3] {
+    [
+        Arg::new("checksum")
+            .help("Compare same-size files by content hash, ignoring modification times")
+            .long("checksum")
+            .action(ArgAction::SetTrue),
+        Arg::new("modify-window")
+            .help("Modification times this close count as equal, in ms or with a unit (default 2s on FAT and exFAT, else 0)")
+            .long("modify-window")
+            .value_name("MS")
+            .value_parser(options::parse_modify_window),
+        Arg::new("dst-shift")
+            .help("Same-size files exactly an hour newer, as after a DST change on FAT: copy or ignore")
+            .long("dst-shift")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(timestamp::DstShift))
+            .default_value("copy"),
+    ]
+}
+
+// The checks that keep a pass from emptying a destination whose source or
+// destination isn't mounted.
+fn deletion_args() -> [Arg; 3] {
+    [
+        Arg::new("require-marker")
+            .help("Refuse to run a pass unless FILE exists in the destination, e.g. a marker left on an external disk so its empty mountpoint isn't filled instead")
+            .long("require-marker")
+            .value_name("FILE"),
+        Arg::new("max-delete")
+            .help("Skip deleting when a pass would remove more than PERCENT of the destination's entries")
+            .long("max-delete")
+            .value_name("PERCENT")
+            .value_parser(clap::value_parser!(u8).range(0..=100))
+            .default_value("50"),
+        Arg::new("force")
+            .help("Delete past --max-delete, or everything when the source is empty")
+            .long("force")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+fn verbosity_args() -> [Arg; 2] {
+    [
+        Arg::new("debug")
+            .help("Enable debug mode")
+            .long("debug")
+            .short('d')
+            .action(ArgAction::SetTrue),
+        Arg::new("log-level")
+            .help("Log levels, overall and per module, e.g. warn,copy=debug (overrides --debug)")
+            .long("log-level")
+            .value_name("SPEC")
+            .value_parser(logging::parse_log_level),
+    ]
+}
+
+// The `--log-level` spec, or what `--debug` stands for.
+fn log_spec(matches: &ArgMatches) -> String {
+    match matches.get_one::<String>("log-level") {
+        Some(spec) => spec.clone(),
+        None if matches.get_flag("debug") => "debug".to_string(),
+        None => "info".to_string(),
+    }
+}
+
+fn retention_args() -> [Arg; 4] {
+    [
+        retention_arg("keep-last", "Keep the N most recent snapshots/trees"),
+        retention_arg("keep-daily", "Keep the newest snapshot/tree of each of the last N days"),
+        retention_arg("keep-weekly", "Keep the newest snapshot/tree of each of the last N weeks"),
+        retention_arg("keep-monthly", "Keep the newest snapshot/tree of each of the last N months"),
+    ]
+}
+
+fn retention_arg(name: &'static str, help: &'static str) -> Arg {
+    Arg::new(name)
+        .help(help)
+        .long(name)
+        .value_name("N")
+        .value_parser(clap::value_parser!(usize))
+}
+
+fn sync_options(matches: &ArgMatches) -> options::SyncOptions {
+    options::SyncOptions {
+        retention: retention_policy(matches),
+        buffer_size: *matches.get_one::<usize>("buffer-size").unwrap(),
+        direct_io: matches.get_one::<u64>("direct-io").copied(),
+        fsync: *matches.get_one::<durability::FsyncPolicy>("fsync").unwrap(),
+        preallocate: matches.get_flag("preallocate"),
+        multi_stream: matches.get_one::<u64>("multi-stream").copied(),
+        streams: *matches.get_one::<usize>("streams").unwrap(),
+        locked: *matches.get_one::<locked::LockedPolicy>("locked").unwrap(),
+        locked_retries: *matches.get_one::<u32>("locked-retries").unwrap(),
+        transforms: matches.get_many::<transform::TransformRule>("transform").unwrap_or_default().cloned().collect(),
+        name_case: *matches.get_one::<naming::NameCase>("name-case").unwrap(),
+        rename: matches.get_many::<naming::RenameRule>("rename").unwrap_or_default().cloned().collect(),
+        windows: matches.get_many::<window::TimeWindow>("window").unwrap_or_default().copied().collect(),
+        window_policy: *matches.get_one::<window::WindowPolicy>("window-policy").unwrap(),
+        metered: *matches.get_one::<metered::MeteredPolicy>("metered").unwrap(),
+        metered_limit: *matches.get_one::<u64>("metered-limit").unwrap(),
+        battery: *matches.get_one::<battery::BatteryPolicy>("battery").unwrap(),
+        battery_min: *matches.get_one::<u8>("battery-min").unwrap(),
+        check_space: matches.get_flag("check-space"),
+        space_reserve: *matches.get_one::<u64>("space-reserve").unwrap(),
+        max_dest_size: matches.get_one::<u64>("max-dest-size").copied(),
+        quota_policy: *matches.get_one::<space::QuotaPolicy>("quota-policy").unwrap(),
+        min_free: matches.get_one::<space::MinFree>("min-free").copied(),
+        file_progress: matches.get_one::<u64>("file-progress").copied(),
+        conflict: *matches.get_one::<conflict::ConflictPolicy>("conflict").unwrap(),
+        hash: *matches.get_one::<hash::HashAlgorithm>("hash").unwrap(),
+        modify_window: matches.get_one::<Duration>("modify-window").copied(),
+        dst_shift: *matches.get_one::<timestamp::DstShift>("dst-shift").unwrap(),
+        checksum: matches.get_flag("checksum"),
+        chmod: matches.get_many::<attrs::Chmod>("chmod").into_iter().flatten().cloned().collect(),
+        chown: matches.get_one::<attrs::Chown>("chown").copied().unwrap_or_default(),
+        fake_super: matches.get_flag("fake-super"),
+        read_only_source: matches.get_flag("source-read-only").then(|| PathBuf::from(matches.get_one::<String>("source").unwrap())),
+        require_marker: matches.get_one::<String>("require-marker").cloned(),
+        max_delete: *matches.get_one::<u8>("max-delete").unwrap(),
+        force: matches.get_flag("force"),
+        links: *matches.get_one::<links::LinkPolicy>("links").unwrap(),
+        delete_timing: match (matches.get_flag("delete-before"), matches.get_flag("delete-during")) {
+            (true, _) => options::DeleteTiming::Before,
+            (_, true) => options::DeleteTiming::During,
+            _ => options::DeleteTiming::After,
+        },
+        delete_grace: matches.get_one::<Duration>("delete-after-age").copied(),
+        mkpath: matches.get_flag("mkpath"),
+        strict_dest: matches.get_flag("strict-dest"),
+        prune_empty_dirs: matches.get_flag("prune-empty-dirs"),
+        chunked: matches.get_one::<u64>("chunked").copied(),
+        devices: matches.get_flag("devices"),
+        specials: matches.get_flag("specials"),
+        ..Default::default()
+    }
+}
+
+fn retention_policy(matches: &ArgMatches) -> retention::RetentionPolicy {
+    let count = |name: &str| matches.get_one::<usize>(name).copied().unwrap_or(0);
+    retention::RetentionPolicy {
+        keep_last: count("keep-last"),
+        keep_daily: count("keep-daily"),
+        keep_weekly: count("keep-weekly"),
+        keep_monthly: count("keep-monthly"),
+    }
 }
@@ -0,0 +1,324 @@
+use crate::backend::{FileStat, StorageBackend};
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Writes are stamped by a clock that starts here and ticks a second per
+// write, so the same sequence of operations always gives the same tree.
+const EPOCH_SECS: u64 = 946_684_800; // 2000-01-01T00:00:00
+
+#[derive(Debug, Clone)]
+enum Node {
+    Dir,
+    File(Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+struct Tree {
+    nodes: BTreeMap<String, (Node, SystemTime)>,
+    ticks: u64,
+}
+
+impl Tree {
+    fn tick(&mut self) -> SystemTime {
+        self.ticks += 1;
+        UNIX_EPOCH + Duration::from_secs(EPOCH_SECS + self.ticks)
+    }
+
+    // Creates `path` and any missing parents as directories.
+    fn create_dirs(&mut self, path: &str) -> io::Result<()> {
+        let mut key = String::new();
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            if !key.is_empty() {
+                key.push('/');
+            }
+            key.push_str(part);
+            match self.nodes.get(&key) {
+                Some((Node::Dir, _)) => {}
+                Some((Node::File(_), _)) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is a file", key))),
+                None => {
+                    let now = self.tick();
+                    self.nodes.insert(key.clone(), (Node::Dir, now));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn below<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.nodes.keys().filter(move |key| key.strip_prefix(path).is_some_and(|rest| rest.starts_with('/')))
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.split('/').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("/")
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path))
+}
+
+// An operation made to fail the next `times` it is called.
+#[derive(Debug)]
+struct Fault {
+    times: usize,
+    error: fn() -> io::Error,
+}
+
+// A backend holding everything in memory, for exercising the engine without
+// touching the disk. Instances opened as `memory://<name>` are shared by
+// name for the life of the process.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    tree: Arc<Mutex<Tree>>,
+    faults: Mutex<BTreeMap<String, Fault>>,
+}
+
+static NAMED: Mutex<BTreeMap<String, Arc<MemoryBackend>>> = Mutex::new(BTreeMap::new());
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The instance `memory://<name>` resolves to, created empty on first use.
+    pub fn named(name: &str) -> Arc<MemoryBackend> {
+        NAMED.lock().unwrap().entry(name.to_string()).or_default().clone()
+    }
+
+    pub fn contents(&self, path: &str) -> Option<Vec<u8>> {
+        match self.tree.lock().unwrap().nodes.get(&normalize(path)) {
+            Some((Node::File(data), _)) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    // Writes `data` to `path` directly, creating missing parents.
+    pub fn insert(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let path = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        tree.create_dirs(parent(&path))?;
+        if let Some((Node::Dir, _)) = tree.nodes.get(&path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is a directory", path)));
+        }
+        let now = tree.tick();
+        tree.nodes.insert(path, (Node::File(data.to_vec()), now));
+        Ok(())
+    }
+
+    pub fn set_modified(&self, path: &str, modified: SystemTime) -> io::Result<()> {
+        let path = normalize(path);
+        match self.tree.lock().unwrap().nodes.get_mut(&path) {
+            Some(node) => {
+                node.1 = modified;
+                Ok(())
+            }
+            None => Err(not_found(&path)),
+        }
+    }
+
+    // Every path held, directories included, in sorted order.
+    pub fn paths(&self) -> Vec<String> {
+        self.tree.lock().unwrap().nodes.keys().cloned().collect()
+    }
+
+    // Makes the next `times` calls of the trait method `operation` ("list",
+    // "write", "rename", ...) fail with `error()` before doing anything, to
+    // see how the engine copes with a misbehaving service.
+    pub fn fail(&self, operation: &str, times: usize, error: fn() -> io::Error) {
+        self.faults.lock().unwrap().insert(operation.to_string(), Fault { times, error });
+    }
+
+    fn fault(&self, operation: &str) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        match faults.get_mut(operation) {
+            Some(fault) if fault.times > 0 => {
+                fault.times -= 1;
+                Err((fault.error)())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Buffers a file until it is flushed or dropped, as a real file's contents
+// only become visible once written.
+struct MemoryWriter {
+    tree: Arc<Mutex<Tree>>,
+    path: String,
+    data: Vec<u8>,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+        let now = tree.tick();
+        tree.nodes.insert(self.path.clone(), (Node::File(self.data.clone()), now));
+        Ok(())
+    }
+}
+
+impl Drop for MemoryWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn stat(path: &str, node: &Node, modified: SystemTime) -> FileStat {
+    let (is_dir, size) = match node {
+        Node::Dir => (true, 0),
+        Node::File(data) => (false, data.len() as u64),
+    };
+    FileStat { path: path.to_string(), is_dir, size, modified }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn list(&self, path: &str) -> io::Result<Vec<FileStat>> {
+        self.fault("list")?;
+        let path = normalize(path);
+        let tree = self.tree.lock().unwrap();
+        if !path.is_empty() && !matches!(tree.nodes.get(&path), Some((Node::Dir, _))) {
+            return Err(not_found(&path));
+        }
+        Ok(tree
+            .nodes
+            .iter()
+            .filter(|(key, _)| parent(key) == path)
+            .map(|(key, (node, modified))| stat(key, node, *modified))
+            .collect())
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Option<FileStat>> {
+        self.fault("stat")?;
+        let path = normalize(path);
+        Ok(self.tree.lock().unwrap().nodes.get(&path).map(|(node, modified)| stat(&path, node, *modified)))
+    }
+
+    fn read(&self, path: &str) -> io::Result<Box<dyn Read + Send>> {
+        self.fault("read")?;
+        let path = normalize(path);
+        self.contents(&path).map(|data| Box::new(Cursor::new(data)) as Box<dyn Read + Send>).ok_or_else(|| not_found(&path))
+    }
+
+    fn write(&self, path: &str) -> io::Result<Box<dyn Write + Send>> {
+        self.fault("write")?;
+        let path = normalize(path);
+        self.insert(&path, &[])?;
+        Ok(Box::new(MemoryWriter { tree: self.tree.clone(), path, data: Vec::new() }))
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.fault("create_dir")?;
+        self.tree.lock().unwrap().create_dirs(&normalize(path))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.fault("rename")?;
+        let (from, to) = (normalize(from), normalize(to));
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.nodes.remove(&from).ok_or_else(|| not_found(&from))?;
+        tree.create_dirs(parent(&to))?;
+        let moved: Vec<String> = tree.below(&from).cloned().collect();
+        for key in moved {
+            let child = tree.nodes.remove(&key).unwrap();
+            tree.nodes.insert(format!("{}{}", to, &key[from.len()..]), child);
+        }
+        tree.nodes.insert(to, node);
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        self.fault("delete")?;
+        let path = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        tree.nodes.remove(&path).ok_or_else(|| not_found(&path))?;
+        let children: Vec<String> = tree.below(&path).cloned().collect();
+        for key in children {
+            tree.nodes.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn set_modified(&self, path: &str, modified: SystemTime) -> io::Result<()> {
+        self.fault("set_modified")?;
+        MemoryBackend::set_modified(self, path, modified)
+    }
+
+    // The simulated clock is the point; it isn't compensated for, and
+    // copies keep their source's times.
+    fn remote_clock(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ticks: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(EPOCH_SECS + ticks)
+    }
+
+    #[test]
+    fn stamps_writes_with_a_ticking_clock() {
+        let backend = MemoryBackend::new();
+        backend.insert("dir/a.txt", b"alpha").unwrap();
+        backend.write("b.txt").unwrap().write_all(b"beta").unwrap();
+        assert_eq!(backend.stat("dir").unwrap().unwrap().modified, at(1));
+        assert_eq!(backend.stat("dir/a.txt").unwrap().unwrap().modified, at(2));
+        // Opening for writing creates the file, and dropping the writer
+        // stores what was written.
+        assert_eq!(backend.stat("b.txt").unwrap().unwrap(), FileStat { path: "b.txt".to_string(), is_dir: false, size: 4, modified: at(4) });
+        assert_eq!(backend.paths(), ["b.txt", "dir", "dir/a.txt"]);
+    }
+
+    #[test]
+    fn lists_direct_children() {
+        let backend = MemoryBackend::new();
+        backend.insert("dir/sub/a.txt", b"").unwrap();
+        backend.insert("b.txt", b"").unwrap();
+        let names = |path| backend.list(path).unwrap().into_iter().map(|entry| entry.path).collect::<Vec<_>>();
+        assert_eq!(names(""), ["b.txt", "dir"]);
+        assert_eq!(names("/dir/"), ["dir/sub"]);
+        assert_eq!(backend.list("b.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn renames_and_deletes_directories_with_their_contents() {
+        let backend = MemoryBackend::new();
+        backend.insert("dir/sub/a.txt", b"alpha").unwrap();
+        backend.insert("dirt.txt", b"").unwrap();
+        backend.rename("dir", "moved/here").unwrap();
+        assert_eq!(backend.paths(), ["dirt.txt", "moved", "moved/here", "moved/here/sub", "moved/here/sub/a.txt"]);
+        backend.delete("moved").unwrap();
+        assert_eq!(backend.paths(), ["dirt.txt"]);
+        assert_eq!(backend.delete("moved").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn fails_operations_as_many_times_as_asked() {
+        let backend = MemoryBackend::new();
+        backend.fail("create_dir", 2, || io::Error::from(io::ErrorKind::TimedOut));
+        assert!(backend.create_dir("dir").is_err());
+        assert!(backend.create_dir("dir").is_err());
+        backend.create_dir("dir").unwrap();
+        assert_eq!(backend.paths(), ["dir"]);
+    }
+
+    #[test]
+    fn named_instances_are_shared() {
+        MemoryBackend::named("memory-tests").insert("a.txt", b"alpha").unwrap();
+        assert_eq!(MemoryBackend::named("memory-tests").contents("a.txt").as_deref(), Some(&b"alpha"[..]));
+        assert_eq!(MemoryBackend::named("memory-tests-other").contents("a.txt"), None);
+    }
+}
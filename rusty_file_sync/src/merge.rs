@@ -0,0 +1,197 @@
+// Line-based three-way merge in the manner of diff3: the changes `ours` and
+// `theirs` made to `base` are combined, failing when they touch the same
+// lines differently.
+
+// Largest diff table tried, in cells, once common prefixes and suffixes are
+// set aside; bigger changes are left for the user to merge.
+const MAX_TABLE: usize = 16 << 20;
+
+// For every line of `a`, the line of `b` it is matched with by a longest
+// common subsequence, or None. Matches are increasing in both.
+fn matches(a: &[&str], b: &[&str]) -> Option<Vec<Option<usize>>> {
+    let mut map = vec![None; a.len()];
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+    for (i, m) in map.iter_mut().enumerate().take(prefix) {
+        *m = Some(i);
+    }
+    for i in 0..suffix {
+        map[a.len() - 1 - i] = Some(b.len() - 1 - i);
+    }
+
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let (n, m) = (a_mid.len(), b_mid.len());
+    if n == 0 || m == 0 {
+        return Some(map);
+    }
+    if (n + 1) * (m + 1) > MAX_TABLE {
+        return None;
+    }
+    // lengths[i][j]: longest common subsequence of a_mid[i..] and b_mid[j..].
+    let width = m + 1;
+    let mut lengths = vec![0u32; (n + 1) * width];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[i * width + j] = if a_mid[i] == b_mid[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if a_mid[i] == b_mid[j] {
+            map[prefix + i] = Some(prefix + j);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Some(map)
+}
+
+// The merged text, or None when both sides changed the same region in
+// different ways or the change is too large to diff.
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> Option<String> {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+    let (to_ours, to_theirs) = (matches(&base, &ours)?, matches(&base, &theirs)?);
+
+    let mut merged = String::new();
+    let (mut o, mut a, mut b) = (0, 0, 0);
+    loop {
+        // Lines unchanged on both sides are copied as they are.
+        if o < base.len() && to_ours[o] == Some(a) && to_theirs[o] == Some(b) {
+            merged.push_str(base[o]);
+            o += 1;
+            a += 1;
+            b += 1;
+            continue;
+        }
+        // Otherwise the chunk runs up to the next base line both sides kept.
+        let next = (o..base.len()).find(|&i| to_ours[i].is_some() && to_theirs[i].is_some());
+        let (o_end, a_end, b_end) = match next {
+            Some(i) => (i, to_ours[i].unwrap(), to_theirs[i].unwrap()),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+        let (base_chunk, ours_chunk, theirs_chunk) = (&base[o..o_end], &ours[a..a_end], &theirs[b..b_end]);
+        let chunk = if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            theirs_chunk
+        } else if theirs_chunk == base_chunk {
+            ours_chunk
+        } else {
+            return None;
+        };
+        merged.extend(chunk.iter().copied());
+        if next.is_none() {
+            return Some(merged);
+        }
+        (o, a, b) = (o_end, a_end, b_end);
+    }
+}
+
+// The lines of `ours` and `theirs` that differ, marked `-` and `+`, with
+// up to two unchanged lines around each change. None when the change is
+// too large to diff.
+pub fn diff(ours: &str, theirs: &str) -> Option<String> {
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+    let to_theirs = matches(&ours, &theirs)?;
+
+    // Every line once, in order: (marker, line).
+    let mut lines = Vec::new();
+    let mut b = 0;
+    for (a, matched) in to_theirs.iter().enumerate() {
+        if let Some(j) = *matched {
+            lines.extend(theirs[b..j].iter().map(|line| ('+', *line)));
+            lines.push((' ', ours[a]));
+            b = j + 1;
+        } else {
+            lines.push(('-', ours[a]));
+        }
+    }
+    lines.extend(theirs[b..].iter().map(|line| ('+', *line)));
+
+    let near_change = |i: usize| lines[i.saturating_sub(2)..(i + 3).min(lines.len())].iter().any(|(marker, _)| *marker != ' ');
+    let mut out = String::new();
+    let mut skipped = false;
+    for (i, (marker, line)) in lines.iter().enumerate() {
+        if near_change(i) {
+            if skipped {
+                out.push_str("  ...\n");
+                skipped = false;
+            }
+            out.push_str(&format!("{} {}", marker, line));
+            if !line.ends_with('\n') {
+                out.push('\n');
+            }
+        } else {
+            skipped = true;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "a\nb\nc\nd\ne\n";
+
+    #[test]
+    fn combines_edits_to_different_lines() {
+        assert_eq!(merge3(BASE, "a\nB\nc\nd\ne\n", "a\nb\nc\nD\ne\n").as_deref(), Some("a\nB\nc\nD\ne\n"));
+    }
+
+    #[test]
+    fn takes_the_same_edit_once() {
+        assert_eq!(merge3(BASE, "a\nB\nc\nd\ne\n", "a\nB\nc\nd\ne\n").as_deref(), Some("a\nB\nc\nd\ne\n"));
+        assert_eq!(merge3(BASE, "a\nB\nc\nd\ne\n", BASE).as_deref(), Some("a\nB\nc\nd\ne\n"));
+        assert_eq!(merge3(BASE, BASE, BASE).as_deref(), Some(BASE));
+    }
+
+    #[test]
+    fn refuses_conflicting_edits() {
+        assert_eq!(merge3(BASE, "a\nX\nc\nd\ne\n", "a\nY\nc\nd\ne\n"), None);
+        // Edits to neighbouring lines touch the same region, as in diff3.
+        assert_eq!(merge3(BASE, "a\nB\nc\nd\ne\n", "a\nb\nC\nd\ne\n"), None);
+        // One side edits a line the other deleted.
+        assert_eq!(merge3(BASE, "a\nB\nc\nd\ne\n", "a\nc\nd\ne\n"), None);
+    }
+
+    #[test]
+    fn keeps_inserts_at_both_ends() {
+        assert_eq!(merge3(BASE, "start\na\nb\nc\nd\ne\n", "a\nb\nc\nd\ne\nend\n").as_deref(), Some("start\na\nb\nc\nd\ne\nend\n"));
+        assert_eq!(merge3("", "ours\n", ""), Some("ours\n".to_string()));
+        // Different text inserted at the same place can't be ordered.
+        assert_eq!(merge3(BASE, "a\nb\nc\nd\ne\nours\n", "a\nb\nc\nd\ne\ntheirs\n"), None);
+    }
+
+    #[test]
+    fn applies_deletions_from_both_sides() {
+        assert_eq!(merge3(BASE, "a\nc\nd\ne\n", "a\nb\nc\ne\n").as_deref(), Some("a\nc\ne\n"));
+        assert_eq!(merge3(BASE, "a\nc\nd\ne\n", "a\nc\nd\ne\n").as_deref(), Some("a\nc\nd\ne\n"));
+        assert_eq!(merge3(BASE, "", BASE).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn handles_a_missing_trailing_newline() {
+        assert_eq!(merge3("a\nb\nc", "A\nb\nc", "a\nb\nC").as_deref(), Some("A\nb\nC"));
+        // Adding the newline changes the last line, like any other edit.
+        assert_eq!(merge3("a\nb\nc", "A\nb\nc", "a\nb\nc\n").as_deref(), Some("A\nb\nc\n"));
+        assert_eq!(merge3("a\nb\nc", "a\nb\nc\n", "a\nb\nc!"), None);
+    }
+
+    #[test]
+    fn diffs_with_context() {
+        let ours = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let theirs = "1\n2\n3\n4\n5\n6\n7\neight\n9";
+        assert_eq!(diff(ours, theirs).as_deref(), Some("  ...\n  6\n  7\n- 8\n- 9\n+ eight\n+ 9\n"));
+        assert_eq!(diff("same\n", "same\n").as_deref(), Some(""));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::options::SyncOptions;
+use log::{debug, info};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Asking the OS means running a command, so the answer is reused for a while.
+const CACHE_FOR: Duration = Duration::from_secs(60);
+
+static CACHE: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MeteredPolicy {
+    #[default]
+    Ignore,
+    // No passes while metered.
+    Defer,
+    // Files of at least `--metered-limit` wait for an unmetered network.
+    DeferLarge,
+}
+
+impl std::str::FromStr for MeteredPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ignore" => Ok(MeteredPolicy::Ignore),
+            "defer" => Ok(MeteredPolicy::Defer),
+            "defer-large" => Ok(MeteredPolicy::DeferLarge),
+            _ => Err(format!("Invalid metered policy: {} (expected ignore, defer or defer-large)", value)),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", windows))]
+async fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = tokio::process::Command::new(program).args(args).output().await.ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// NetworkManager reports NM_METERED_YES (1) or NM_METERED_GUESS_YES (3) for
+// the primary connection.
+#[cfg(target_os = "linux")]
+async fn query() -> Option<bool> {
+    let reply = output(
+        "busctl",
+        &["get-property", "org.freedesktop.NetworkManager", "/org/freedesktop/NetworkManager", "org.freedesktop.NetworkManager", "Metered"],
+    )
+    .await?;
+    let value = reply.strip_prefix("u ")?;
+    Some(value == "1" || value == "3")
+}
+
+#[cfg(windows)]
+async fn query() -> Option<bool> {
+    let script = "[Windows.Networking.Connectivity.NetworkInformation,Windows.Networking.Connectivity,ContentType=WindowsRuntime]::GetInternetConnectionProfile().GetConnectionCost().NetworkCostType";
+    let cost = output("powershell", &["-NoProfile", "-Command", script]).await?;
+    Some(cost == "Fixed" || cost == "Variable")
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+async fn query() -> Option<bool> {
+    None
+}
+
+// Unknown counts as unmetered, so machines without NetworkManager sync as
+// before.
+pub async fn is_metered() -> bool {
+    if let Some((checked, metered)) = *CACHE.lock().unwrap() {
+        if checked.elapsed() < CACHE_FOR {
+            return metered;
+        }
+    }
+    let metered = query().await.unwrap_or(false);
+    *CACHE.lock().unwrap() = Some((Instant::now(), metered));
+    metered
+}
+
+pub async fn wait_until_unmetered(options: &SyncOptions) {
+    if options.metered != MeteredPolicy::Defer || !is_metered().await {
+        return;
+    }
+    info!("Network connection is metered, deferring sync");
+    while is_metered().await {
+        tokio::time::sleep(CACHE_FOR).await;
+    }
+    info!("Network connection no longer metered");
+}
+
+// Whether copying `size` bytes from `source` should wait for the next pass.
+pub async fn defers(source: &Path, size: u64, options: &SyncOptions) -> bool {
+    if options.metered != MeteredPolicy::DeferLarge || size < options.metered_limit || !is_metered().await {
+        return false;
+    }
+    debug!("Deferring {:?} ({} bytes) until the connection is unmetered", source, size);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("ignore".parse(), Ok(MeteredPolicy::Ignore));
+        assert_eq!("defer".parse(), Ok(MeteredPolicy::Defer));
+        assert_eq!("defer-large".parse(), Ok(MeteredPolicy::DeferLarge));
+        assert!("defer_large".parse::<MeteredPolicy>().is_err());
+    }
+
+    // Neither case gets as far as asking the OS.
+    #[tokio::test]
+    async fn only_large_files_are_deferred() {
+        let path = Path::new("a.bin");
+        let options = SyncOptions { metered: MeteredPolicy::DeferLarge, metered_limit: 100, ..Default::default() };
+        assert!(!defers(path, 99, &options).await);
+        let options = SyncOptions { metered: MeteredPolicy::Defer, ..options };
+        assert!(!defers(path, 1 << 30, &options).await);
+    }
+}
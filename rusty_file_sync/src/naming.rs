@@ -0,0 +1,207 @@
+use crate::options::SyncOptions;
+use crate::store;
+use log::warn;
+use regex::Regex;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum NameCase {
+    #[default]
+    Preserve,
+    Lower,
+    Upper,
+}
+
+impl std::str::FromStr for NameCase {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "preserve" => Ok(NameCase::Preserve),
+            "lower" => Ok(NameCase::Lower),
+            "upper" => Ok(NameCase::Upper),
+            _ => Err(format!("Invalid name case: {} (expected preserve, lower or upper)", value)),
+        }
+    }
+}
+
+fn convert_case(name: &std::ffi::OsStr, case: NameCase) -> OsString {
+    // Names that aren't valid Unicode are left alone.
+    match (name.to_str(), case) {
+        (Some(name), NameCase::Lower) => name.to_lowercase().into(),
+        (Some(name), NameCase::Upper) => name.to_uppercase().into(),
+        _ => name.to_os_string(),
+    }
+}
+
+// Rewrites `/`-separated relative paths: `FROM->TO` replaces a leading
+// directory (or file) path, `re:PATTERN->REPLACEMENT` substitutes a regex,
+// with `$1` and friends referring to its groups.
+#[derive(Debug, Clone)]
+pub enum RenameRule {
+    Prefix { from: String, to: String },
+    Regex { pattern: Regex, replacement: String },
+}
+
+impl std::str::FromStr for RenameRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((from, to)) = value.split_once("->") else {
+            return Err(format!("Invalid rename rule: {} (expected FROM->TO or re:PATTERN->REPLACEMENT)", value));
+        };
+        match from.strip_prefix("re:") {
+            Some(pattern) => {
+                let pattern = Regex::new(pattern.trim()).map_err(|e| format!("Invalid rename pattern: {}", e))?;
+                Ok(RenameRule::Regex { pattern, replacement: to.trim().to_string() })
+            }
+            None => Ok(RenameRule::Prefix {
+                from: from.trim().trim_matches('/').to_string(),
+                to: to.trim().trim_matches('/').to_string(),
+            }),
+        }
+    }
+}
+
+impl RenameRule {
+    fn apply(&self, key: &str) -> String {
+        match self {
+            RenameRule::Prefix { from, to } => match key.strip_prefix(from.as_str()) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", to, rest),
+                _ => key.to_string(),
+            },
+            RenameRule::Regex { pattern, replacement } => pattern.replace(key, replacement.as_str()).into_owned(),
+        }
+    }
+}
+
+// Applies every rule in order. Empty, `.` and `..` components are dropped
+// so a rule can't point outside the destination.
+fn rename(relative: &Path, rules: &[RenameRule]) -> PathBuf {
+    let key = rules.iter().fold(store::relative_key(relative), |key, rule| rule.apply(&key));
+    key.split('/').filter(|part| !matches!(*part, "" | "." | "..")).collect()
+}
+
+// Maps source-relative paths to where they land in the destination, and
+// notices when two source files would land on the same destination path.
+pub struct PathMapper<'a> {
+    options: &'a SyncOptions,
+    files: HashMap<PathBuf, PathBuf>,
+}
+
+impl<'a> PathMapper<'a> {
+    pub fn new(options: &'a SyncOptions) -> Self {
+        PathMapper { options, files: HashMap::new() }
+    }
+
+    // Returns None, after warning, for a file colliding with one mapped
+    // earlier in the pass; directories may merge. Under rename rules a
+    // directory is not mapped at all: it is created for the files it receives.
+    pub fn map(&mut self, source: &Path, is_dir: bool) -> Option<PathBuf> {
+        if is_dir && !self.options.rename.is_empty() && !source.as_os_str().is_empty() {
+            return None;
+        }
+        let renamed;
+        let relative = if self.options.rename.is_empty() {
+            source
+        } else {
+            renamed = rename(source, &self.options.rename);
+            renamed.as_path()
+        };
+        let mut mapped = PathBuf::new();
+        for component in relative.components() {
+            if let Component::Normal(name) = component {
+                mapped.push(convert_case(name, self.options.name_case));
+            }
+        }
+
+        if !is_dir {
+            if mapped.as_os_str().is_empty() {
+                warn!("Skipping {:?}: its name was renamed away", source);
+                return None;
+            }
+            if let Some(first) = self.files.get(&mapped) {
+                warn!("Skipping {:?}: it maps to {:?}, like {:?}", source, mapped, first);
+                return None;
+            }
+            self.files.insert(mapped.clone(), source.to_path_buf());
+        }
+        Some(mapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(name_case: NameCase) -> SyncOptions {
+        SyncOptions { name_case, ..SyncOptions::default() }
+    }
+
+    #[test]
+    fn converts_the_case_of_every_component() {
+        let (lower, upper, preserve) = (options(NameCase::Lower), options(NameCase::Upper), options(NameCase::Preserve));
+        let mut mapper = PathMapper::new(&lower);
+        assert_eq!(mapper.map(Path::new("Photos/IMG_01.JPG"), false), Some(PathBuf::from("photos/img_01.jpg")));
+        assert_eq!(mapper.map(Path::new("Photos"), true), Some(PathBuf::from("photos")));
+        assert_eq!(PathMapper::new(&upper).map(Path::new("straße.txt"), false), Some(PathBuf::from("STRASSE.TXT")));
+        assert_eq!(PathMapper::new(&preserve).map(Path::new("Mixed/Case"), false), Some(PathBuf::from("Mixed/Case")));
+        assert!("title".parse::<NameCase>().is_err());
+    }
+
+    #[test]
+    fn skips_files_that_collide_once_converted() {
+        let options = options(NameCase::Lower);
+        let mut mapper = PathMapper::new(&options);
+        assert_eq!(mapper.map(Path::new("README"), false), Some(PathBuf::from("readme")));
+        assert_eq!(mapper.map(Path::new("readme"), false), None);
+        // Directories merge instead.
+        assert!(mapper.map(Path::new("Docs"), true).is_some());
+        assert!(mapper.map(Path::new("docs"), true).is_some());
+    }
+
+    fn renamed(rules: &[&str], relative: &str) -> PathBuf {
+        rename(Path::new(relative), &rules.iter().map(|rule| rule.parse().unwrap()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn replaces_leading_components() {
+        assert_eq!(renamed(&["/src/ -> lib"], "src/a/b.rs"), Path::new("lib/a/b.rs"));
+        assert_eq!(renamed(&["src->lib"], "src"), Path::new("lib"));
+        // Only whole components match.
+        assert_eq!(renamed(&["src->lib"], "srcs/a.rs"), Path::new("srcs/a.rs"));
+        assert_eq!(renamed(&["src->lib"], "a/src/b.rs"), Path::new("a/src/b.rs"));
+        // An empty target lifts the contents up a level.
+        assert_eq!(renamed(&["docs->"], "docs/guide.md"), Path::new("guide.md"));
+    }
+
+    #[test]
+    fn substitutes_regexes_in_order() {
+        assert_eq!(renamed(&[r"re:^(\d{4})-(\d\d)-.*\.jpg$ -> $1/$2/photo.jpg"], "2024-03-01.jpg"), Path::new("2024/03/photo.jpg"));
+        assert_eq!(renamed(&["a->b", "b->c"], "a/x"), Path::new("c/x"));
+        assert_eq!(renamed(&["b->c", "a->b"], "a/x"), Path::new("b/x"));
+    }
+
+    #[test]
+    fn keeps_renames_inside_the_destination() {
+        assert_eq!(renamed(&["re:^ -> ../../"], "a/b"), Path::new("a/b"));
+        assert_eq!(renamed(&["re:/ -> //./"], "a/b"), Path::new("a/b"));
+        assert_eq!(renamed(&["re:.* -> "], "a/b"), Path::new(""));
+        for rule in ["src", "re:( -> x"] {
+            assert!(rule.parse::<RenameRule>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn maps_files_to_where_renames_put_them() {
+        let options = SyncOptions { rename: vec!["re:.*\\.tmp$ -> ".parse().unwrap(), "a->b".parse().unwrap()], ..SyncOptions::default() };
+        let mut mapper = PathMapper::new(&options);
+        assert_eq!(mapper.map(Path::new("a/x.txt"), false), Some(PathBuf::from("b/x.txt")));
+        // Directories are left for the files they receive to create.
+        assert_eq!(mapper.map(Path::new("a"), true), None);
+        assert_eq!(mapper.map(Path::new("scratch.tmp"), false), None);
+        assert_eq!(mapper.map(Path::new("b/x.txt"), false), None);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::SyncError;
+use std::path::Path;
+use std::time::Duration;
+
+// How long the destination gets to answer a stat before it counts as
+// unreachable; a hung network mount would otherwise stall the job forever.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+fn is_unreachable_io(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    if matches!(e.kind(), ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected | TimedOut) {
+        return true;
+    }
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        return [libc::ENETDOWN, libc::ENETUNREACH, libc::EHOSTDOWN, libc::EHOSTUNREACH, libc::ESTALE].contains(&code);
+    }
+    // ERROR_BAD_NETPATH, ERROR_NETNAME_DELETED, ERROR_BAD_NET_NAME
+    #[cfg(windows)]
+    if let Some(code) = e.raw_os_error() {
+        return [53, 64, 67].contains(&code);
+    }
+    false
+}
+
+// Errors meaning the destination can't be reached right now, as opposed to
+// something being wrong with the data.
+pub fn is_unreachable(error: &SyncError) -> bool {
+    match error {
+        SyncError::NetworkError(_) => true,
+        SyncError::FileSystemError(e) => is_unreachable_io(e),
+        SyncError::WalkDirError(e) => e.io_error().is_some_and(is_unreachable_io),
+        _ => false,
+    }
+}
+
+// Fails quickly when `destination` doesn't answer.
+pub async fn probe(destination: &str) -> Result<(), SyncError> {
+    let path = Path::new(destination).to_path_buf();
+    match tokio::time::timeout(PROBE_TIMEOUT, tokio::task::spawn_blocking(move || std::fs::metadata(path))).await {
+        Ok(Ok(Err(e))) if is_unreachable_io(&e) => Err(e.into()),
+        Ok(_) => Ok(()),
+        Err(_) => Err(SyncError::NetworkError(format!("{} did not respond within {:?}", destination, PROBE_TIMEOUT))),
+    }
+}
+
+// Doubles the wait between passes while the destination stays unreachable.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    // Returns true for the first failure of a streak.
+    pub fn failed(&mut self) -> bool {
+        self.failures += 1;
+        self.failures == 1
+    }
+
+    // Returns true when this ends a streak of failures.
+    pub fn succeeded(&mut self) -> bool {
+        std::mem::take(&mut self.failures) > 0
+    }
+
+    pub fn interval(&self, base: Duration) -> Duration {
+        if self.failures == 0 {
+            return base;
+        }
+        base.saturating_mul(1 << self.failures.min(16)).min(MAX_BACKOFF.max(base))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn tells_unreachable_destinations_from_bad_data() {
+        assert!(is_unreachable(&SyncError::NetworkError("down".to_string())));
+        assert!(is_unreachable(&Error::from(ErrorKind::TimedOut).into()));
+        assert!(is_unreachable(&Error::from(ErrorKind::ConnectionRefused).into()));
+        assert!(!is_unreachable(&Error::from(ErrorKind::NotFound).into()));
+        assert!(!is_unreachable(&Error::from(ErrorKind::PermissionDenied).into()));
+        assert!(!is_unreachable(&SyncError::StoreError("corrupt".to_string())));
+        #[cfg(unix)]
+        {
+            assert!(is_unreachable(&Error::from_raw_os_error(libc::EHOSTUNREACH).into()));
+            assert!(is_unreachable(&Error::from_raw_os_error(libc::ESTALE).into()));
+            assert!(!is_unreachable(&Error::from_raw_os_error(libc::ENOSPC).into()));
+        }
+    }
+
+    #[test]
+    fn backs_off_while_failures_last() {
+        let (mut backoff, base) = (Backoff::default(), Duration::from_secs(30));
+        assert_eq!(backoff.interval(base), base);
+        assert!(backoff.failed());
+        assert_eq!(backoff.interval(base), Duration::from_secs(60));
+        assert!(!backoff.failed());
+        assert_eq!(backoff.interval(base), Duration::from_secs(120));
+        for _ in 0..40 {
+            backoff.failed();
+        }
+        assert_eq!(backoff.interval(base), MAX_BACKOFF);
+        // Passes already further apart than the cap keep their interval.
+        assert_eq!(backoff.interval(Duration::from_secs(3600)), Duration::from_secs(3600));
+        assert!(backoff.succeeded());
+        assert!(!backoff.succeeded());
+        assert_eq!(backoff.interval(base), base);
+    }
+
+    #[tokio::test]
+    async fn probes_reachable_destinations() {
+        probe(&std::env::temp_dir().to_string_lossy()).await.unwrap();
+        // A missing destination is for the pass to report, not the probe.
+        probe("/no/such/destination").await.unwrap();
+    }
+}
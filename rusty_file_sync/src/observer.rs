@@ -0,0 +1,35 @@
+use crate::itemize::Change;
+use crate::SyncError;
+use std::path::Path;
+
+// Hooks for applications embedding the sync engine, e.g. to render their own
+// progress. Every method defaults to doing nothing.
+pub trait Observer: Send + Sync {
+    // Reported before a path in the destination is written or removed, with
+    // the path relative to the destination.
+    fn on_change(&self, _path: &Path, _change: &Change) {}
+
+    fn on_file_start(&self, _path: &Path) {}
+
+    // Reported periodically while a file above `--file-progress` is copied.
+    fn on_file_progress(&self, _path: &Path, _copied: u64, _total: u64) {}
+
+    fn on_file_done(&self, _path: &Path, _bytes: u64) {}
+
+    fn on_remove(&self, _path: &Path) {}
+
+    fn on_error(&self, _error: &SyncError) {}
+
+    fn on_pass_complete(&self) {}
+}
+
+impl std::fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
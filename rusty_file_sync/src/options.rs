@@ -0,0 +1,351 @@
+use crate::attrs::{Chmod, Chown, IdMap};
+use crate::battery::BatteryPolicy;
+use crate::conflict::ConflictPolicy;
+use crate::durability::FsyncPolicy;
+use crate::hash::HashAlgorithm;
+use crate::locked::LockedPolicy;
+use crate::links::LinkPolicy;
+use crate::metered::MeteredPolicy;
+use crate::naming::{NameCase, RenameRule};
+use crate::observer::{NoopObserver, Observer};
+use crate::transform::TransformRule;
+use crate::window::{TimeWindow, WindowPolicy};
+use crate::timestamp::{self, DstShift};
+use crate::SyncError;
+use log::{debug, warn};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use crate::retention::RetentionPolicy;
+use crate::space::{MinFree, QuotaPolicy};
+
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+// The DST shift hint is given once per run.
+static DST_WARNED: AtomicBool = AtomicBool::new(false);
+
+// When a mirror pass deletes what the source no longer has. Deleting before
+// copying frees room on a destination too small for both versions; during,
+// each directory is cleared just before it is filled; after copying, the
+// default, never leaves a mirror without a file it is about to get back
+// under a new name.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DeleteTiming {
+    Before,
+    During,
+    #[default]
+    After,
+}
+
+// Per-job settings shared by every sync mode, built from the `sync` arguments.
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    pub retention: RetentionPolicy,
+    pub buffer_size: usize,
+    // Files at least this large are copied with O_DIRECT.
+    pub direct_io: Option<u64>,
+    pub fsync: FsyncPolicy,
+    pub preallocate: bool,
+    // Files at least this large are copied by `streams` workers at once.
+    pub multi_stream: Option<u64>,
+    pub streams: usize,
+    // Average size of the chunks `store` mode splits files into.
+    pub chunked: Option<u64>,
+    pub locked: LockedPolicy,
+    // Attempts made under `--locked retry` before a file is skipped.
+    pub locked_retries: u32,
+    pub observer: Arc<dyn Observer>,
+    pub transforms: Vec<TransformRule>,
+    pub name_case: NameCase,
+    pub rename: Vec<RenameRule>,
+    // Local times passes may start in; empty means any time.
+    pub windows: Vec<TimeWindow>,
+    pub window_policy: WindowPolicy,
+    pub metered: MeteredPolicy,
+    // Smallest file deferred under `--metered defer-large`.
+    pub metered_limit: u64,
+    pub battery: BatteryPolicy,
+    // Charge, in percent, below which `battery` kicks in.
+    pub battery_min: u8,
+    // Checks the destination has room for the pass before copying.
+    pub check_space: bool,
+    // Bytes that must stay free after the pass under `check_space`.
+    pub space_reserve: u64,
+    // Most the destination may hold, pass included.
+    pub max_dest_size: Option<u64>,
+    pub quota_policy: QuotaPolicy,
+    // Headroom every write leaves on the destination volume.
+    pub min_free: Option<MinFree>,
+    // Files at least this large report their progress while being copied.
+    pub file_progress: Option<u64>,
+    // Files changed on both sides in bidirectional modes.
+    pub conflict: ConflictPolicy,
+    // Digest recorded in manifests; store objects are always named by SHA-256.
+    pub hash: HashAlgorithm,
+    // Slack allowed when comparing modification times; None picks one from
+    // the destination's file system.
+    pub modify_window: Option<Duration>,
+    // Files whose modification time is an hour off and nothing else.
+    pub dst_shift: DstShift,
+    // Compare mirrored files by content alone, ignoring their times.
+    pub checksum: bool,
+    // Permission rules and ownership applied to everything written.
+    pub chmod: Chmod,
+    pub chown: Chown,
+    // Ownership carried over from the source goes through this first.
+    pub id_map: IdMap,
+    // Record ownership that would be given away instead of changing it.
+    pub fake_super: bool,
+    // A source nothing may be written to, as a last line of defence.
+    pub read_only_source: Option<PathBuf>,
+    // File that must exist in the destination, relative to it, for a pass
+    // to run at all.
+    pub require_marker: Option<String>,
+    // Largest share of the destination, in percent, a pass may delete, and
+    // whether to delete past it (or from an empty source) regardless.
+    pub max_delete: u8,
+    pub force: bool,
+    pub links: LinkPolicy,
+    // When mirror passes delete what the source no longer has.
+    pub delete_timing: DeleteTiming,
+    // How long an entry must have been missing from the source to go.
+    pub delete_grace: Option<Duration>,
+    // Create a missing destination root before the pass.
+    pub mkpath: bool,
+    // Never create the destination root: it is a volume that must be mounted.
+    pub strict_dest: bool,
+    // Leave no empty directories in the destination.
+    pub prune_empty_dirs: bool,
+    // Recreate device nodes, and FIFOs and sockets, in mirror modes.
+    pub devices: bool,
+    pub specials: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            retention: RetentionPolicy::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            direct_io: None,
+            fsync: FsyncPolicy::Never,
+            preallocate: false,
+            multi_stream: None,
+            streams: 4,
+            chunked: None,
+            locked: LockedPolicy::Fail,
+            locked_retries: 3,
+            observer: Arc::new(NoopObserver),
+            transforms: Vec::new(),
+            name_case: NameCase::Preserve,
+            rename: Vec::new(),
+            windows: Vec::new(),
+            window_policy: WindowPolicy::Continue,
+            metered: MeteredPolicy::Ignore,
+            metered_limit: 10 << 20,
+            battery: BatteryPolicy::Ignore,
+            battery_min: 20,
+            check_space: false,
+            space_reserve: 0,
+            max_dest_size: None,
+            quota_policy: QuotaPolicy::Fail,
+            min_free: None,
+            file_progress: None,
+            conflict: ConflictPolicy::Newer,
+            hash: HashAlgorithm::Sha256,
+            modify_window: None,
+            dst_shift: DstShift::Copy,
+            checksum: false,
+            chmod: Chmod::default(),
+            chown: Chown::default(),
+            id_map: IdMap::default(),
+            fake_super: false,
+            read_only_source: None,
+            require_marker: None,
+            max_delete: 50,
+            force: false,
+            links: LinkPolicy::Follow,
+            delete_timing: DeleteTiming::After,
+            delete_grace: None,
+            mkpath: false,
+            strict_dest: false,
+            prune_empty_dirs: false,
+            devices: false,
+            specials: false,
+        }
+    }
+}
+
+impl SyncOptions {
+    // The slack allowed when comparing modification times.
+    pub fn window(&self) -> Duration {
+        self.modify_window.unwrap_or_default()
+    }
+
+    // Whether a source modified at `source` replaces a copy modified at
+    // `dest`. Copies of the same size exactly an hour older are taken for a
+    // daylight saving time shift, copied anyway or skipped per `dst_shift`.
+    pub fn is_newer(&self, source: SystemTime, dest: SystemTime, same_size: bool) -> bool {
+        if !timestamp::newer(source, dest, self.window()) {
+            return false;
+        }
+        if !same_size || !timestamp::dst_shifted(source, dest, self.window()) {
+            return true;
+        }
+        match self.dst_shift {
+            DstShift::Copy => {
+                if !DST_WARNED.swap(true, Ordering::Relaxed) {
+                    warn!("Some files are exactly an hour newer than their copies, as after a daylight saving time change on FAT; --dst-shift ignore skips them");
+                }
+                true
+            }
+            DstShift::Ignore => false,
+        }
+    }
+
+    // Whether `a` and `b` count as the same modification time, as for
+    // copies of the same size.
+    pub fn same_time(&self, a: SystemTime, b: SystemTime) -> bool {
+        !self.is_newer(a, b, true) && !self.is_newer(b, a, true)
+    }
+
+    // Whether `relative`, a path in the destination, is the required marker,
+    // which mirrors keep even though the source doesn't have it.
+    pub fn is_marker(&self, relative: &Path) -> bool {
+        self.require_marker.as_deref().is_some_and(|marker| relative == Path::new(marker))
+    }
+
+    // Fails when `path`, about to be written, created or removed, is inside
+    // the read-only source. Paths are compared as given and resolved, so
+    // neither a relative path nor a symlink slips past.
+    pub fn guard_write(&self, path: &Path) -> Result<(), SyncError> {
+        let Some(source) = &self.read_only_source else {
+            return Ok(());
+        };
+        if path.starts_with(source) || resolved(path).starts_with(resolved(source)) {
+            return Err(SyncError::FileSystemError(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("refusing to write {:?} in the read-only source {:?}", path, source),
+            )));
+        }
+        Ok(())
+    }
+
+    // These options with the modify window settled for `destination`. FAT
+    // destinations round modification times to two seconds, which would
+    // make files look changed on every pass.
+    pub fn for_destination(&self, destination: &Path) -> Cow<'_, SyncOptions> {
+        match self.modify_window.is_none().then(|| timestamp::coarse_granularity(destination)).flatten() {
+            Some(granularity) => {
+                debug!("{} rounds modification times to {:?}, allowing for it", destination.display(), granularity);
+                Cow::Owned(SyncOptions { modify_window: Some(granularity), ..self.clone() })
+            }
+            None => Cow::Borrowed(self),
+        }
+    }
+}
+
+// `path` with its deepest existing ancestor canonicalized, for paths that
+// may not exist yet.
+pub(crate) fn resolved(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(canonical) = ancestor.canonicalize() {
+            return canonical.components().chain(path.strip_prefix(ancestor).unwrap_or(Path::new("")).components()).collect();
+        }
+    }
+    path.to_path_buf()
+}
+
+// Parses byte sizes such as `4096`, `64K`, `1M`, `1.5GB` or `2TiB`; unit
+// prefixes are binary.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: {:?}", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Invalid size unit: {:?}", unit)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+// The reverse of `parse_size`, with one decimal: `1.5G`.
+pub fn format_size(bytes: u64) -> String {
+    let units = [(1u64 << 40, "T"), (1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+    match units.iter().find(|(size, _)| bytes >= *size) {
+        Some((size, unit)) => format!("{:.1}{}", bytes as f64 / *size as f64, unit),
+        None => format!("{}B", bytes),
+    }
+}
+
+pub fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    match parse_size(value)? {
+        0 => Err("Buffer size must be greater than zero".to_string()),
+        size => Ok(size as usize),
+    }
+}
+
+// Milliseconds when plain, e.g. `2000`, or a duration such as `2s`.
+pub fn parse_modify_window(value: &str) -> Result<Duration, String> {
+    match value.parse::<u64>() {
+        Ok(millis) => Ok(Duration::from_millis(millis)),
+        Err(_) => humantime::parse_duration(value).map_err(|e| format!("Invalid modify window {:?}: {}", value, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes_with_binary_units() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64K"), Ok(64 << 10));
+        assert_eq!(parse_size("1m"), Ok(1 << 20));
+        assert_eq!(parse_size(" 1.5GB "), Ok(3 << 29));
+        assert_eq!(parse_size("2TiB"), Ok(2 << 40));
+        assert_eq!(parse_size("8 kb"), Ok(8 << 10));
+        for value in ["", "K", "1.2.3", "10X", "-1", "1KK"] {
+            assert!(parse_size(value).is_err(), "{}", value);
+        }
+        assert_eq!(parse_buffer_size("128K"), Ok(128 << 10));
+        assert!(parse_buffer_size("0").is_err());
+    }
+
+    #[test]
+    fn compares_times_within_the_modify_window() {
+        assert_eq!(parse_modify_window("2000"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_modify_window("1s 500ms"), Ok(Duration::from_millis(1500)));
+        assert!(parse_modify_window("soon").is_err());
+
+        let t = timestamp::from_secs(1_000);
+        let exact = SyncOptions::default();
+        assert!(exact.same_time(t, t));
+        assert!(!exact.same_time(t, t + Duration::from_secs(1)));
+        let fat = SyncOptions { modify_window: Some(Duration::from_secs(2)), ..SyncOptions::default() };
+        assert!(fat.same_time(t, t + Duration::from_secs(2)));
+        assert!(fat.same_time(t + Duration::from_secs(2), t));
+        assert!(!fat.same_time(t, t + Duration::from_secs(3)));
+        assert!(!fat.is_newer(t + Duration::from_secs(2), t, false));
+        assert!(fat.is_newer(t + Duration::from_secs(3), t, false));
+    }
+
+    #[test]
+    fn skips_hour_off_copies_only_when_asked() {
+        let (t, hour) = (timestamp::from_secs(100_000), Duration::from_secs(3600));
+        let copy = SyncOptions::default();
+        assert!(copy.is_newer(t + hour, t, true));
+        let ignore = SyncOptions { dst_shift: DstShift::Ignore, ..SyncOptions::default() };
+        assert!(!ignore.is_newer(t + hour, t, true));
+        assert!(ignore.same_time(t, t + hour));
+        // A different size means it really changed.
+        assert!(ignore.is_newer(t + hour, t, false));
+        assert!(ignore.is_newer(t + hour + Duration::from_secs(1), t, true));
+    }
+}
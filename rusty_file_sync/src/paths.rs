@@ -0,0 +1,116 @@
+use std::path::{Component, Path, PathBuf, Prefix};
+
+// Whether `path` is on a `\server\share`, written plainly or verbatim as
+// `\?\UNC\server\share`. Only Windows parses these prefixes.
+pub fn is_unc(path: &Path) -> bool {
+    match path.components().next() {
+        Some(Component::Prefix(prefix)) => matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..)),
+        _ => false,
+    }
+}
+
+// `\server\share` names a share but not its root directory, which free
+// space and volume queries insist on; this gives it the trailing separator
+// that does and leaves every other path as it is.
+pub fn share_root(path: &Path) -> PathBuf {
+    if !is_unc(path) || path.components().count() > 1 {
+        return path.to_path_buf();
+    }
+    let mut root = path.as_os_str().to_owned();
+    root.push("\\");
+    PathBuf::from(root)
+}
+
+// The path a `file://` URL's location stands for. On Windows,
+// `file:///C:/dir` is `C:/dir` and `file://server/share/dir` is
+// `\server\share\dir`; elsewhere the location is the path.
+pub fn file_url_path(location: &str) -> PathBuf {
+    if !cfg!(windows) {
+        return PathBuf::from(location);
+    }
+    match location.strip_prefix('/') {
+        Some(rest) if rest.as_bytes().get(1) == Some(&b':') && rest.starts_with(|c: char| c.is_ascii_alphabetic()) => PathBuf::from(rest),
+        Some(_) => PathBuf::from(location),
+        None => match location.split_once('/') {
+            Some((host, rest)) if !host.is_empty() && host != "localhost" => PathBuf::from(format!(r"\\{}\{}", host, rest.replace('/', "\\"))),
+            Some(("localhost", rest)) => file_url_path(&format!("/{}", rest)),
+            _ => PathBuf::from(location),
+        },
+    }
+}
+
+// How a path was typed shouldn't change which job it belongs to: trailing
+// separators are dropped and, on Windows, `/` becomes `\` and drive letters
+// are upper-cased, so `c:/Data/` and `C:\Data` are the same destination in
+// comparisons, state and history. Backend URLs are left alone.
+pub fn normalize(path: &str) -> String {
+    if path.contains("://") {
+        return path.to_string();
+    }
+    let mut normalized = if cfg!(windows) { path.replace('/', "\\") } else { path.to_string() };
+    if cfg!(windows) && normalized.as_bytes().get(1) == Some(&b':') && normalized.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        normalized[..1].make_ascii_uppercase();
+    }
+    // Down to a root such as `/` or `C:\`, which the separator is part of.
+    while normalized.len() > 1 && normalized.ends_with(['/', '\\']) && !normalized[..normalized.len() - 1].ends_with(':') {
+        normalized.pop();
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_ordinary_paths_alone() {
+        assert!(!is_unc(Path::new("/mnt/share")));
+        assert_eq!(share_root(Path::new("/mnt/share")), Path::new("/mnt/share"));
+        assert_eq!(share_root(Path::new("relative")), Path::new("relative"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn reads_file_url_locations_as_paths() {
+        assert_eq!(file_url_path("/srv/backup"), Path::new("/srv/backup"));
+        assert_eq!(file_url_path("server/share"), Path::new("server/share"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn finds_share_roots() {
+        assert!(is_unc(Path::new(r"\\server\share")));
+        assert!(is_unc(Path::new(r"\\?\UNC\server\share\dir")));
+        assert!(!is_unc(Path::new(r"C:\dir")));
+        assert_eq!(share_root(Path::new(r"\\server\share")), Path::new(r"\\server\share\"));
+        assert_eq!(share_root(Path::new(r"\\server\share\dir")), Path::new(r"\\server\share\dir"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn reads_file_url_locations_as_windows_paths() {
+        assert_eq!(file_url_path("/C:/dir"), Path::new("C:/dir"));
+        assert_eq!(file_url_path("server/share/dir"), Path::new(r"\\server\share\dir"));
+        assert_eq!(file_url_path("localhost/C:/dir"), Path::new("C:/dir"));
+        assert_eq!(file_url_path("/dir"), Path::new("/dir"));
+    }
+
+    #[test]
+    fn drops_trailing_separators() {
+        assert_eq!(normalize("/data/"), "/data");
+        assert_eq!(normalize("/data///"), "/data");
+        assert_eq!(normalize("data"), "data");
+        // Roots keep theirs.
+        assert_eq!(normalize("/"), "/");
+        assert_eq!(normalize("C:/"), if cfg!(windows) { "C:\\" } else { "C:/" });
+        assert_eq!(normalize("sftp://host/dir/"), "sftp://host/dir/");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn writes_windows_paths_one_way() {
+        assert_eq!(normalize("c:/Data/"), r"C:\Data");
+        assert_eq!(normalize(r"C:\Data"), r"C:\Data");
+        assert_eq!(normalize(r"\\server\share\"), r"\\server\share");
+    }
+}
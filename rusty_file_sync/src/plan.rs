@@ -0,0 +1,457 @@
+use crate::itemize::Change;
+use crate::json::{self, escape, Value};
+use crate::options::SyncOptions;
+use crate::store::{Tree, TreeEntry};
+use crate::{check_deletions, check_roots, copy, durability, links, missing_marker, special, state, store, timestamp, SyncError};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+// Size and modification time (in seconds) of a file when it was planned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stat {
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl Stat {
+    fn of(metadata: &std::fs::Metadata) -> Result<Self, SyncError> {
+        Ok(Stat { size: metadata.len(), modified: timestamp::to_secs(metadata.modified()?) })
+    }
+}
+
+// One step of a one-way mirror pass. Paths are relative keys, the same on
+// both sides.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    Mkdir { path: String },
+    // `dest` is the copy being replaced, if any.
+    Copy { path: String, change: Change, source: Stat, dest: Option<Stat> },
+    Remove { path: String, dir: bool, dest: Stat },
+}
+
+// What a one-way mirror pass from `source` to `destination` would do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub created: String,
+    pub source: String,
+    pub destination: String,
+    pub operations: Vec<Operation>,
+}
+
+impl Plan {
+    pub fn copies(&self) -> usize {
+        self.operations.iter().filter(|op| matches!(op, Operation::Copy { .. })).count()
+    }
+
+    pub fn removals(&self) -> usize {
+        self.operations.iter().filter(|op| matches!(op, Operation::Remove { .. })).count()
+    }
+}
+
+// The checks a `sync` pass makes of both ends before walking them, so a
+// plan is neither made nor applied against an unmounted or mistyped path.
+fn check_ends(source: &str, destination: &str, options: &SyncOptions) -> Result<(), SyncError> {
+    check_roots(Path::new(source), Path::new(destination), "one", options.strict_dest)?;
+    match &options.require_marker {
+        Some(marker) if !Path::new(destination).join(marker).exists() => Err(missing_marker(marker, destination)),
+        _ => Ok(()),
+    }
+}
+
+// Entries below `root`, leaving out its state directory.
+fn count_entries(root: &Path) -> Result<usize, SyncError> {
+    let mut count = 0;
+    for entry in WalkDir::new(root).min_depth(1).into_iter().filter_entry(|e| !state::is_state_dir(e)) {
+        entry?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Works out the operations of a one-way mirror pass without performing any,
+// removing destination entries missing from the source when `delete` is set.
+// Removals are held to `--max-delete` and the empty-source check, as a pass
+// would hold them.
+pub async fn make(source: &str, destination: &str, delete: bool, options: &SyncOptions) -> Result<Plan, SyncError> {
+    let dest_root = Path::new(destination);
+    let options = &*options.for_destination(dest_root);
+    check_ends(source, destination, options)?;
+    let mut operations = Vec::new();
+    let mut planned = HashSet::new();
+    let cached = match options.checksum {
+        true => state::load_manifest(&state::manifest_path(dest_root)).await?,
+        false => store::Tree::new(),
+    };
+
+    for entry in links::walk(WalkDir::new(source).min_depth(1), options.links) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(source)?.to_path_buf();
+        let path = store::relative_key(&relative);
+        let dest_path = dest_root.join(&relative);
+        if entry.path().is_dir() {
+            if !dest_path.exists() {
+                operations.push(Operation::Mkdir { path });
+            }
+        } else if let Some(kind) = special::kind(&entry.file_type()) {
+            special::skip(entry.path(), kind);
+        } else {
+            let metadata = std::fs::metadata(entry.path())?;
+            if let Some(change) = crate::file_change(entry.path(), &metadata, &dest_path, cached.get(&path), options).await {
+                let dest = match std::fs::metadata(&dest_path) {
+                    Ok(dest) => Some(Stat::of(&dest)?),
+                    Err(_) => None,
+                };
+                operations.push(Operation::Copy { path, change, source: Stat::of(&metadata)?, dest });
+            }
+        }
+        planned.insert(relative);
+    }
+
+    if delete && dest_root.exists() {
+        let mut removed: Vec<PathBuf> = Vec::new();
+        let (mut existing, mut deleting) = (0, 0);
+        for entry in WalkDir::new(destination).min_depth(1).into_iter().filter_entry(|e| !state::is_state_dir(e)) {
+            let entry = entry?;
+            existing += 1;
+            let relative = entry.path().strip_prefix(destination)?.to_path_buf();
+            if planned.contains(&relative) || options.is_marker(&relative) {
+                continue;
+            }
+            deleting += 1;
+            if removed.iter().any(|dir| relative.starts_with(dir)) {
+                continue;
+            }
+            let dir = entry.file_type().is_dir();
+            let dest = Stat::of(&entry.metadata()?)?;
+            operations.push(Operation::Remove { path: store::relative_key(&relative), dir, dest });
+            if dir {
+                removed.push(relative);
+            }
+        }
+        check_deletions(deleting, existing, planned.is_empty(), options)?;
+    }
+
+    Ok(Plan { created: timestamp::now(), source: source.to_string(), destination: destination.to_string(), operations })
+}
+
+fn stat_fields(prefix: &str, stat: Option<&Stat>) -> String {
+    match stat {
+        Some(stat) => format!("\"{}size\":{},\"{}modified\":{}", prefix, stat.size, prefix, stat.modified),
+        None => format!("\"{}size\":null,\"{}modified\":null", prefix, prefix),
+    }
+}
+
+fn operation_json(operation: &Operation) -> String {
+    match operation {
+        Operation::Mkdir { path } => format!("{{\"op\":\"mkdir\",\"path\":{}}}", escape(path)),
+        Operation::Copy { path, change, source, dest } => format!(
+            "{{\"op\":\"copy\",\"path\":{},\"change\":{},{},{}}}",
+            escape(path),
+            escape(&change.code()),
+            stat_fields("", Some(source)),
+            stat_fields("dest_", dest.as_ref())
+        ),
+        Operation::Remove { path, dir, dest } => format!(
+            "{{\"op\":\"remove\",\"path\":{},\"dir\":{},{}}}",
+            escape(path),
+            dir,
+            stat_fields("dest_", Some(dest))
+        ),
+    }
+}
+
+// The plan as a JSON document, with one operation per line so plans can be
+// reviewed and diffed as text:
+//
+//     {
+//       "version": 1,
+//       "created": "2026-10-14T10:00:00",
+//       "source": "/home/me/Documents",
+//       "destination": "/mnt/backup/documents",
+//       "operations": [
+//         {"op":"copy","path":"report.odt","change":">f.st......","size":48213,"modified":1791972000,"dest_size":47102,"dest_modified":1791800000}
+//       ]
+//     }
+pub fn to_json(plan: &Plan) -> String {
+    let operations: Vec<String> = plan.operations.iter().map(|op| format!("    {}", operation_json(op))).collect();
+    format!(
+        "{{\n  \"version\": 1,\n  \"created\": {},\n  \"source\": {},\n  \"destination\": {},\n  \"operations\": [\n{}{}  ]\n}}\n",
+        escape(&plan.created),
+        escape(&plan.source),
+        escape(&plan.destination),
+        operations.join(",\n"),
+        if operations.is_empty() { "" } else { "\n" }
+    )
+}
+
+fn plan_error(what: &str) -> SyncError {
+    SyncError::PlanError(format!("Malformed plan: {}", what))
+}
+
+fn read_stat(value: &Value, prefix: &str) -> Result<Option<Stat>, SyncError> {
+    let size = value.get(&format!("{}size", prefix)).ok_or_else(|| plan_error("missing size"))?;
+    let modified = value.get(&format!("{}modified", prefix)).ok_or_else(|| plan_error("missing modification time"))?;
+    match (size, modified) {
+        (Value::Null, Value::Null) => Ok(None),
+        _ => Ok(Some(Stat {
+            size: size.as_u64().ok_or_else(|| plan_error("invalid size"))?,
+            modified: modified.as_u64().ok_or_else(|| plan_error("invalid modification time"))?,
+        })),
+    }
+}
+
+fn read_operation(value: &Value) -> Result<Operation, SyncError> {
+    let path = value.get("path").and_then(Value::as_str).ok_or_else(|| plan_error("operation without a path"))?.to_string();
+    // Keys are relative, so a tampered plan can't reach outside the destination.
+    if Path::new(&path).components().any(|c| !matches!(c, std::path::Component::Normal(_))) {
+        return Err(SyncError::PlanError(format!("Path outside the destination: {}", path)));
+    }
+    match value.get("op").and_then(Value::as_str) {
+        Some("mkdir") => Ok(Operation::Mkdir { path }),
+        Some("copy") => Ok(Operation::Copy {
+            change: value.get("change").and_then(Value::as_str).ok_or_else(|| plan_error("copy without a change"))?.parse().map_err(|e: String| plan_error(&e))?,
+            source: read_stat(value, "")?.ok_or_else(|| plan_error("copy without a source"))?,
+            dest: read_stat(value, "dest_")?,
+            path,
+        }),
+        Some("remove") => Ok(Operation::Remove {
+            dir: value.get("dir").and_then(Value::as_bool).ok_or_else(|| plan_error("remove without dir"))?,
+            dest: read_stat(value, "dest_")?.ok_or_else(|| plan_error("remove without a destination"))?,
+            path,
+        }),
+        _ => Err(plan_error("unknown operation")),
+    }
+}
+
+// Reads back a plan written by `to_json`.
+pub fn parse(text: &str) -> Result<Plan, SyncError> {
+    let document = json::parse(text).map_err(|e| plan_error(&e))?;
+    if document.get("version").and_then(Value::as_u64) != Some(1) {
+        return Err(SyncError::PlanError("Unsupported plan version".to_string()));
+    }
+    let field = |name: &str| document.get(name).and_then(Value::as_str).map(str::to_string).ok_or_else(|| plan_error(&format!("missing {}", name)));
+    Ok(Plan {
+        created: field("created")?,
+        source: field("source")?,
+        destination: field("destination")?,
+        operations: document
+            .get("operations")
+            .and_then(Value::as_array)
+            .ok_or_else(|| plan_error("missing operations"))?
+            .iter()
+            .map(read_operation)
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn current(path: &Path) -> Result<Option<Stat>, SyncError> {
+    match std::fs::symlink_metadata(path) {
+        Ok(_) => Ok(Some(Stat::of(&std::fs::metadata(path)?)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Why `operation` can no longer be applied as planned, if it can't.
+fn stale(plan: &Plan, operation: &Operation) -> Result<Option<String>, SyncError> {
+    let dest_root = Path::new(&plan.destination);
+    Ok(match operation {
+        Operation::Mkdir { path } => match std::fs::metadata(store::key_path(dest_root, path)) {
+            Ok(metadata) if !metadata.is_dir() => Some(format!("{}: a file is in the way", path)),
+            _ => None,
+        },
+        Operation::Copy { path, source, dest, .. } => {
+            if current(&store::key_path(Path::new(&plan.source), path))? != Some(*source) {
+                Some(format!("{}: the source changed", path))
+            } else if current(&store::key_path(dest_root, path))? != *dest {
+                Some(format!("{}: the destination changed", path))
+            } else {
+                None
+            }
+        }
+        Operation::Remove { path, dir, dest } => match current(&store::key_path(dest_root, path))? {
+            None => Some(format!("{}: already gone", path)),
+            // Directory sizes say nothing about their contents.
+            Some(stat) if stat.modified != dest.modified || (!dir && stat.size != dest.size) => Some(format!("{}: the destination changed", path)),
+            Some(_) => None,
+        },
+    })
+}
+
+// Performs the operations of `plan`, and nothing else. Every operation is
+// checked first against the files it was planned from, and nothing is done
+// at all when any of them changed since. The checks of `make` are made
+// again, since the disks may have changed in between.
+pub async fn apply(plan: &Plan, options: &SyncOptions) -> Result<(), SyncError> {
+    check_ends(&plan.source, &plan.destination, options)?;
+    let dest_root = Path::new(&plan.destination);
+    let mut deleting = 0;
+    for operation in &plan.operations {
+        if let Operation::Remove { path, .. } = operation {
+            let target = store::key_path(dest_root, path);
+            deleting += 1 + if target.is_dir() { count_entries(&target)? } else { 0 };
+        }
+    }
+    if deleting > 0 {
+        check_deletions(deleting, count_entries(dest_root)?, count_entries(Path::new(&plan.source))? == 0, options)?;
+    }
+
+    let mut problems = 0;
+    for operation in &plan.operations {
+        if let Some(problem) = stale(plan, operation)? {
+            warn!("Stale plan operation, {}", problem);
+            problems += 1;
+        }
+    }
+    if problems > 0 {
+        return Err(SyncError::PlanError(format!("{} operations no longer match the files, plan again", problems)));
+    }
+
+    let source_root = Path::new(&plan.source);
+    let mut durability = durability::Durability::new(options.fsync);
+    let mut copied = Tree::new();
+    for operation in &plan.operations {
+        match operation {
+            Operation::Mkdir { path } => {
+                let dir = store::key_path(dest_root, path);
+                if !dir.exists() {
+                    info!("Creating directory: {:?}", dir);
+                    options.guard_write(&dir)?;
+                    options.observer.on_change(Path::new(path), &Change::Created { dir: true });
+                    tokio::fs::create_dir_all(&dir).await?;
+                    durability.created(&dir);
+                }
+            }
+            Operation::Copy { path, change, .. } => {
+                let (source, dest) = (store::key_path(source_root, path), store::key_path(dest_root, path));
+                if let Some(parent) = dest.parent().filter(|parent| !parent.exists()) {
+                    tokio::fs::create_dir_all(parent).await?;
+                    durability.created(parent);
+                }
+                info!("Copying file from {:?} to {:?}", source, dest);
+                options.observer.on_change(Path::new(path), change);
+                let result = copy::copy_file(&source, &dest, options).await?;
+                durability.written(&dest)?;
+                let metadata = tokio::fs::metadata(&dest).await?;
+                copied.insert(
+                    path.clone(),
+                    TreeEntry::File { hash: result.hash, size: metadata.len(), modified: timestamp::to_secs(metadata.modified()?) },
+                );
+            }
+            Operation::Remove { path, dir, .. } => {
+                let target = store::key_path(dest_root, path);
+                options.guard_write(&target)?;
+                info!("Removing {:?}", target);
+                options.observer.on_change(Path::new(path), &Change::Deleted);
+                if *dir {
+                    tokio::fs::remove_dir_all(&target).await?;
+                } else {
+                    tokio::fs::remove_file(&target).await?;
+                }
+                options.observer.on_remove(&target);
+            }
+        }
+    }
+    durability.finish()?;
+    state::update_manifest(dest_root, &copied, options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+
+    fn ends(name: &str) -> (PathBuf, String, String) {
+        let root = scratch(name);
+        std::fs::create_dir_all(root.join("source")).unwrap();
+        std::fs::create_dir_all(root.join("dest")).unwrap();
+        let (source, dest) = (root.join("source").to_str().unwrap().to_string(), root.join("dest").to_str().unwrap().to_string());
+        (root, source, dest)
+    }
+
+    fn refusal(result: Result<impl std::fmt::Debug, SyncError>) -> String {
+        match result {
+            Err(SyncError::ConfigError(message)) => message,
+            other => panic!("not refused: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn plans_and_applies_a_mirror() {
+        let (root, source, dest) = ends("plan-mirror");
+        write(&Path::new(&source).join("dir/a.txt"), "alpha");
+        write(&Path::new(&dest).join("old.txt"), "gone");
+        let plan = make(&source, &dest, true, &SyncOptions::default()).await.unwrap();
+        assert_eq!((plan.copies(), plan.removals()), (1, 1));
+        assert_eq!(parse(&to_json(&plan)).unwrap(), plan);
+
+        apply(&plan, &SyncOptions::default()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(Path::new(&dest).join("dir/a.txt")).unwrap(), "alpha");
+        assert!(!Path::new(&dest).join("old.txt").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn reads_sizes_and_times_exactly() {
+        let huge = Stat { size: (1 << 53) + 1, modified: u64::MAX };
+        let plan = Plan {
+            created: "2026-10-14T10:00:00".to_string(),
+            source: "/source".to_string(),
+            destination: "/dest".to_string(),
+            operations: vec![Operation::Copy { path: "big".to_string(), change: Change::Created { dir: false }, source: huge, dest: None }],
+        };
+        assert_eq!(parse(&to_json(&plan)).unwrap(), plan);
+        let tampered = to_json(&plan).replace("\"op\":\"copy\",\"path\":\"big\"", "\"op\":\"copy\",\"path\":\"../big\"");
+        assert!(matches!(parse(&tampered), Err(SyncError::PlanError(message)) if message == "Path outside the destination: ../big"));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_plan_emptying_the_destination() {
+        let (root, source, dest) = ends("plan-empty");
+        write(&Path::new(&dest).join("a.txt"), "alpha");
+        let message = refusal(make(&source, &dest, true, &SyncOptions::default()).await);
+        assert!(message.starts_with("The source is empty; refusing to delete 1 destination entries"), "{}", message);
+
+        // A plan forced through is still refused when applied without --force.
+        let force = SyncOptions { force: true, ..SyncOptions::default() };
+        let plan = make(&source, &dest, true, &force).await.unwrap();
+        assert!(refusal(apply(&plan, &SyncOptions::default()).await).starts_with("The source is empty"));
+        assert!(Path::new(&dest).join("a.txt").exists());
+        apply(&plan, &force).await.unwrap();
+        assert!(!Path::new(&dest).join("a.txt").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn holds_removals_to_max_delete() {
+        let (root, source, dest) = ends("plan-max-delete");
+        write(&Path::new(&source).join("kept.txt"), "kept");
+        write(&Path::new(&dest).join("kept.txt"), "kept");
+        for i in 0..10 {
+            write(&Path::new(&dest).join(format!("old/{}.txt", i)), "gone");
+        }
+        let message = refusal(make(&source, &dest, true, &SyncOptions::default()).await);
+        assert!(message.starts_with("Refusing to delete 11 of 12 destination entries"), "{}", message);
+        assert!(make(&source, &dest, true, &SyncOptions { max_delete: 100, ..SyncOptions::default() }).await.is_ok());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn checks_both_ends() {
+        let (root, source, dest) = ends("plan-ends");
+        let missing = root.join("missing").to_str().unwrap().to_string();
+        assert!(refusal(make(&missing, &dest, true, &SyncOptions::default()).await).contains("does not exist"));
+
+        let marked = SyncOptions { require_marker: Some(".mounted".to_string()), ..SyncOptions::default() };
+        assert!(refusal(make(&source, &dest, true, &marked).await).starts_with("Marker .mounted is missing"));
+        write(&Path::new(&dest).join(".mounted"), "");
+        let plan = make(&source, &dest, true, &marked).await.unwrap();
+        assert_eq!(plan.removals(), 0);
+
+        std::fs::remove_file(Path::new(&dest).join(".mounted")).unwrap();
+        assert!(refusal(apply(&plan, &marked).await).starts_with("Marker .mounted is missing"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use crate::itemize::Change;
+use crate::observer::Observer;
+use crate::options::format_size;
+use crate::SyncError;
+use log::info;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Tracks the bytes copied by a pass against the total planned by the scan,
+// on their way to the observer it wraps.
+pub struct Progress {
+    inner: Arc<dyn Observer>,
+    planned: AtomicU64,
+    done: AtomicU64,
+    started: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new(inner: Arc<dyn Observer>) -> Self {
+        Progress { inner, planned: AtomicU64::new(0), done: AtomicU64::new(0), started: Mutex::new(Instant::now()) }
+    }
+
+    // Resets the counters for a pass expected to copy `planned` bytes.
+    pub fn start(&self, planned: u64) {
+        self.planned.store(planned, Ordering::SeqCst);
+        self.done.store(0, Ordering::SeqCst);
+        *self.started.lock().unwrap() = Instant::now();
+    }
+
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.lock().unwrap().elapsed()
+    }
+
+    // One line on where the pass stands; `current` is the rate since the
+    // previous line.
+    pub fn summary(&self, job: &str, current: f64) -> String {
+        let done = self.done();
+        // The scan can only guess, so never report more done than planned.
+        let planned = self.planned.load(Ordering::SeqCst).max(done);
+        let average = rate(done, self.elapsed());
+        let percent = if planned == 0 { 100.0 } else { done as f64 / planned as f64 * 100.0 };
+        let remaining = if average > 0.0 {
+            let secs = ((planned - done) as f64 / average).round() as u64;
+            format!("about {} left", humantime::format_duration(Duration::from_secs(secs)))
+        } else {
+            "time left unknown".to_string()
+        };
+        format!(
+            "{}: {} of {} ({:.0}%), {}/s now, {}/s average, {}",
+            job,
+            format_size(done),
+            format_size(planned),
+            percent,
+            format_size(current as u64),
+            format_size(average as u64),
+            remaining
+        )
+    }
+
+    pub fn finished(&self, job: &str) -> String {
+        let elapsed = self.elapsed();
+        format!(
+            "{}: copied {} in {}, {}/s average",
+            job,
+            format_size(self.done()),
+            humantime::format_duration(Duration::from_secs(elapsed.as_secs())),
+            format_size(rate(self.done(), elapsed) as u64)
+        )
+    }
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+// Logs the progress of the running pass every `interval` until aborted.
+pub async fn log_periodically(job: String, progress: Arc<Progress>, interval: Duration) {
+    let mut last = (Instant::now(), progress.done());
+    loop {
+        tokio::time::sleep(interval).await;
+        let done = progress.done();
+        let current = rate(done.saturating_sub(last.1), last.0.elapsed());
+        last = (Instant::now(), done);
+        info!("{}", progress.summary(&job, current));
+    }
+}
+
+impl Observer for Progress {
+    fn on_change(&self, path: &Path, change: &Change) {
+        self.inner.on_change(path, change);
+    }
+
+    fn on_file_start(&self, path: &Path) {
+        self.inner.on_file_start(path);
+    }
+
+    fn on_file_progress(&self, path: &Path, copied: u64, total: u64) {
+        self.inner.on_file_progress(path, copied, total);
+    }
+
+    fn on_file_done(&self, path: &Path, bytes: u64) {
+        self.done.fetch_add(bytes, Ordering::SeqCst);
+        self.inner.on_file_done(path, bytes);
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.inner.on_remove(path);
+    }
+
+    fn on_error(&self, error: &SyncError) {
+        self.inner.on_error(error);
+    }
+
+    fn on_pass_complete(&self) {
+        self.inner.on_pass_complete();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::NoopObserver;
+
+    #[test]
+    fn summarizes_where_a_pass_stands() {
+        let progress = Progress::new(Arc::new(NoopObserver));
+        progress.start(4096);
+        assert_eq!(progress.summary("docs", 0.0), "docs: 0B of 4.0K (0%), 0B/s now, 0B/s average, time left unknown");
+        progress.on_file_done(Path::new("a"), 1024);
+        let summary = progress.summary("docs", 2048.0);
+        assert!(summary.starts_with("docs: 1.0K of 4.0K (25%), 2.0K/s now, "), "{}", summary);
+        assert!(summary.contains(" left"), "{}", summary);
+
+        // The scan's guess may fall short.
+        progress.on_file_done(Path::new("b"), 8192);
+        assert!(progress.summary("docs", 0.0).starts_with("docs: 9.0K of 9.0K (100%)"));
+        assert!(progress.finished("docs").starts_with("docs: copied 9.0K in "));
+        progress.start(0);
+        assert_eq!(progress.done(), 0);
+        assert!(progress.summary("docs", 0.0).starts_with("docs: 0B of 0B (100%)"));
+    }
+
+    #[test]
+    fn rates_bytes_over_time() {
+        assert_eq!(rate(1000, Duration::from_secs(4)), 250.0);
+        assert_eq!(rate(1000, Duration::ZERO), 0.0);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::scrub::Corruption;
+use crate::{hash, retention, state, store, timestamp, SyncError};
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+pub fn quarantine_dir(destination: &Path) -> PathBuf {
+    state::state_dir(destination).join("quarantine")
+}
+
+async fn log_incident(destination: &Path, line: &str) -> Result<(), SyncError> {
+    let dir = quarantine_dir(destination);
+    fs::create_dir_all(&dir).await?;
+    let mut log = fs::OpenOptions::new().create(true).append(true).open(dir.join("incidents")).await?;
+    log.write_all(format!("{}\t{}\n", timestamp::now(), line).as_bytes()).await?;
+    log.flush().await?;
+    Ok(())
+}
+
+// Store objects don't know which source path they came from; any path in
+// the latest tree carrying the same hash will do.
+async fn source_key(destination: &Path, corruption: &Corruption) -> Result<Option<String>, SyncError> {
+    if corruption.key.is_some() || retention::layout_of(destination) != "store" {
+        return Ok(corruption.key.clone());
+    }
+    Ok(store::latest_tree(destination).await?.and_then(|(_, tree)| {
+        tree.into_iter()
+            .find(|(_, entry)| entry.hash() == Some(corruption.expected.as_str()))
+            .map(|(key, _)| key)
+    }))
+}
+
+// Moves a corrupted destination copy aside and, when the source still holds
+// the expected content, copies it back in place.
+pub async fn quarantine(destination: &Path, source: Option<&Path>, corruption: &Corruption, buffer_size: usize) -> Result<(), SyncError> {
+    let relative = corruption.path.strip_prefix(destination)?;
+    let quarantined = quarantine_dir(destination).join(timestamp::now()).join(relative);
+    fs::create_dir_all(quarantined.parent().unwrap()).await?;
+    warn!("Quarantining {:?} to {:?}", corruption.path, quarantined);
+    fs::rename(&corruption.path, &quarantined).await?;
+    log_incident(destination, &format!("quarantined\t{}\t{:?}", corruption.expected, relative)).await?;
+
+    let (Some(source), Some(key)) = (source, source_key(destination, corruption).await?) else {
+        return Ok(());
+    };
+    let source_path = store::key_path(source, &key);
+    if !source_path.is_file() || !hash::matches(&source_path, &corruption.expected, buffer_size).await? {
+        warn!("Source copy {:?} no longer matches, leaving it to the next sync", source_path);
+        return Ok(());
+    }
+
+    info!("Re-fetching {:?} from {:?}", corruption.path, source_path);
+    fs::copy(&source_path, &corruption.path).await?;
+    let modified = std::fs::metadata(&source_path)?.modified()?;
+    std::fs::File::options().write(true).open(&corruption.path)?.set_modified(modified)?;
+    log_incident(destination, &format!("refetched\t{}\t{:?}", corruption.expected, relative)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::HashAlgorithm;
+    use crate::testing::{scratch, write};
+
+    fn quarantined(destination: &Path) -> Vec<PathBuf> {
+        walkdir::WalkDir::new(quarantine_dir(destination))
+            .into_iter()
+            .map(|e| e.unwrap())
+            .filter(|e| e.file_type().is_file() && e.file_name() != "incidents")
+            .map(|e| e.into_path())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn moves_the_copy_aside_and_refetches_it() {
+        let dir = scratch("quarantine-refetch");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        write(&source.join("docs/a.txt"), "alpha");
+        write(&dest.join("docs/a.txt"), "ALPHA");
+        let expected = hash::file(source.join("docs/a.txt"), HashAlgorithm::Sha256, 4096).await.unwrap();
+        let corruption = Corruption { path: dest.join("docs/a.txt"), key: Some("docs/a.txt".to_string()), expected };
+
+        quarantine(&dest, Some(&source), &corruption, 4096).await.unwrap();
+        assert_eq!(std::fs::read_to_string(dest.join("docs/a.txt")).unwrap(), "alpha");
+        let moved = quarantined(&dest);
+        assert_eq!(moved.len(), 1);
+        assert!(moved[0].ends_with("docs/a.txt"));
+        assert_eq!(std::fs::read_to_string(&moved[0]).unwrap(), "ALPHA");
+        let incidents = std::fs::read_to_string(quarantine_dir(&dest).join("incidents")).unwrap();
+        let kinds: Vec<_> = incidents.lines().map(|line| line.split('\t').nth(1).unwrap()).collect();
+        assert_eq!(kinds, ["quarantined", "refetched"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_a_changed_source_to_the_next_sync() {
+        let dir = scratch("quarantine-changed");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        write(&source.join("a.txt"), "edited");
+        write(&dest.join("a.txt"), "ALPHA");
+        let expected = hash::file(dest.join("a.txt"), HashAlgorithm::Sha256, 4096).await.unwrap();
+        let corruption = Corruption { path: dest.join("a.txt"), key: Some("a.txt".to_string()), expected };
+
+        quarantine(&dest, Some(&source), &corruption, 4096).await.unwrap();
+        assert!(!dest.join("a.txt").exists());
+        assert_eq!(quarantined(&dest).len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
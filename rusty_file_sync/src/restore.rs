@@ -0,0 +1,164 @@
+use crate::store::{self, TreeEntry};
+use crate::{retention, snapshot, timestamp, SyncError};
+use log::info;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use walkdir::WalkDir;
+
+// Picks the newest snapshot or tree taken at or before `as_of`.
+fn select(names: Vec<String>, as_of: Option<SystemTime>) -> Option<String> {
+    names.into_iter().rfind(|name| match (as_of, timestamp::parse(name)) {
+        (Some(as_of), Some(taken)) => taken <= as_of,
+        _ => true,
+    })
+}
+
+async fn restore_file(from: &Path, to: &Path, modified: SystemTime) -> Result<(), SyncError> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    info!("Restoring {:?} to {:?}", from, to);
+    fs::copy(from, to).await?;
+    std::fs::File::options().write(true).open(to)?.set_modified(modified)?;
+    Ok(())
+}
+
+// Puts a file stored with `--chunked` back together from its recipe.
+async fn restore_chunks(destination: &Path, hash: &str, to: &Path, modified: SystemTime) -> Result<(), SyncError> {
+    let recipe = store::recipe_path(destination, hash);
+    if !recipe.exists() {
+        return Err(SyncError::RestoreError(format!("No object or recipe stored for {}", hash)));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    info!("Restoring {:?} from chunks", to);
+    let mut file = fs::File::create(to).await?;
+    for (chunk, _) in store::read_recipe(&recipe).await? {
+        file.write_all(&fs::read(store::object_path(destination, &chunk)).await?).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    std::fs::File::options().write(true).open(to)?.set_modified(modified)?;
+    Ok(())
+}
+
+async fn restore_snapshot(snapshot: &Path, subpath: &str, target: &Path) -> Result<usize, SyncError> {
+    let root = snapshot.join(subpath);
+    if !root.exists() {
+        return Err(SyncError::RestoreError(format!("{:?} not found in snapshot {:?}", subpath, snapshot)));
+    }
+    let mut restored = 0;
+    for entry in WalkDir::new(&root) {
+        let entry = entry?;
+        let dest_path = target.join(entry.path().strip_prefix(snapshot)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest_path).await?;
+        } else {
+            restore_file(entry.path(), &dest_path, entry.metadata()?.modified()?).await?;
+            restored += 1;
+        }
+    }
+    Ok(restored)
+}
+
+async fn restore_tree(destination: &Path, name: &str, subpath: &str, target: &Path) -> Result<usize, SyncError> {
+    let tree = store::read_tree(&destination.join(store::TREES_DIR).join(name)).await?;
+    let prefix = format!("{}/", subpath);
+    let mut restored = 0;
+    for (key, entry) in tree.iter().filter(|(key, _)| subpath.is_empty() || *key == subpath || key.starts_with(&prefix)) {
+        let dest_path = store::key_path(target, key);
+        match entry {
+            TreeEntry::Dir => fs::create_dir_all(&dest_path).await?,
+            TreeEntry::File { hash, modified, .. } => {
+                let object = store::object_path(destination, hash);
+                match object.exists() {
+                    true => restore_file(&object, &dest_path, timestamp::from_secs(*modified)).await?,
+                    false => restore_chunks(destination, hash, &dest_path, timestamp::from_secs(*modified)).await?,
+                }
+                restored += 1;
+            }
+        }
+    }
+    if restored == 0 && !tree.contains_key(subpath) && !subpath.is_empty() {
+        return Err(SyncError::RestoreError(format!("{:?} not found in tree {}", subpath, name)));
+    }
+    Ok(restored)
+}
+
+pub async fn restore(destination: &str, as_of: Option<SystemTime>, subpath: &str, target: &str) -> Result<usize, SyncError> {
+    let destination = Path::new(destination);
+    let subpath = store::relative_key(Path::new(subpath));
+    let target = PathBuf::from(target);
+
+    match retention::layout_of(destination) {
+        "store" => {
+            let name = select(store::list_trees(destination).await?, as_of)
+                .ok_or_else(|| SyncError::RestoreError("No tree old enough to restore from".to_string()))?;
+            info!("Restoring from tree {}", name);
+            restore_tree(destination, &name, &subpath, &target).await
+        }
+        _ => {
+            let name = select(snapshot::list_snapshots(destination).await?, as_of)
+                .ok_or_else(|| SyncError::RestoreError("No snapshot old enough to restore from".to_string()))?;
+            info!("Restoring from snapshot {}", name);
+            restore_snapshot(&destination.join(name), &subpath, &target).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::SyncOptions;
+    use crate::testing::{scratch, write};
+
+    #[test]
+    fn picks_the_newest_version_old_enough() {
+        let names = || vec!["2024-01-01T00:00:00".to_string(), "2024-02-01T00:00:00".to_string(), "2024-03-01T00:00:00".to_string()];
+        assert_eq!(select(names(), None).as_deref(), Some("2024-03-01T00:00:00"));
+        assert_eq!(select(names(), timestamp::parse("2024-02-15")).as_deref(), Some("2024-02-01T00:00:00"));
+        assert_eq!(select(names(), timestamp::parse("2024-02-01")).as_deref(), Some("2024-02-01T00:00:00"));
+        assert_eq!(select(names(), timestamp::parse("2023-12-31")), None);
+        assert_eq!(select(Vec::new(), None), None);
+    }
+
+    #[tokio::test]
+    async fn restores_paths_from_a_store() {
+        let dir = scratch("restore-store");
+        let (source, store, target) = (dir.join("source"), dir.join("store"), dir.join("target"));
+        write(&source.join("docs/a.txt"), "alpha");
+        write(&source.join("docs/b.txt"), "beta");
+        write(&source.join("docs-old/c.txt"), "gamma");
+        crate::store::sync_store(source.to_str().unwrap(), store.to_str().unwrap(), &SyncOptions::default()).await.unwrap();
+
+        let (store, target_str) = (store.to_str().unwrap(), target.to_str().unwrap());
+        // `docs` doesn't take in its sibling `docs-old`.
+        assert_eq!(restore(store, None, "docs", target_str).await.unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(target.join("docs/a.txt")).unwrap(), "alpha");
+        assert!(!target.join("docs-old").exists());
+        // Trees keep modification times to the second.
+        let secs = |path: &Path| std::fs::metadata(path).unwrap().modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(secs(&target.join("docs/b.txt")), secs(&source.join("docs/b.txt")));
+        assert!(matches!(restore(store, None, "nothing", target_str).await, Err(SyncError::RestoreError(_))));
+        assert!(matches!(restore(store, timestamp::parse("2000-01-01"), "", target_str).await, Err(SyncError::RestoreError(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restores_paths_from_snapshots() {
+        let dir = scratch("restore-snapshot");
+        let (source, dest, target) = (dir.join("source"), dir.join("dest"), dir.join("target"));
+        write(&source.join("docs/a.txt"), "alpha");
+        write(&source.join("other.txt"), "other");
+        crate::snapshot::sync_snapshot(source.to_str().unwrap(), dest.to_str().unwrap(), &SyncOptions::default()).await.unwrap();
+
+        let (dest, target_str) = (dest.to_str().unwrap(), target.to_str().unwrap());
+        assert_eq!(restore(dest, None, "", target_str).await.unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(target.join("docs/a.txt")).unwrap(), "alpha");
+        assert!(matches!(restore(dest, None, "missing", target_str).await, Err(SyncError::RestoreError(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use crate::{snapshot, state, store, timestamp, SyncError};
+use log::{debug, info};
+use std::collections::HashSet;
+use std::path::Path;
+use tokio::fs;
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0 && self.keep_daily == 0 && self.keep_weekly == 0 && self.keep_monthly == 0
+    }
+
+    // Takes timestamp names in ascending order and returns the ones the
+    // policy does not keep. Each bucketed rule keeps the newest entry of its
+    // N most recent days, weeks or months.
+    pub fn expired(&self, names: &[String]) -> Vec<String> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+
+        let mut keep = HashSet::new();
+        let mut daily = HashSet::new();
+        let mut weekly = HashSet::new();
+        let mut monthly = HashSet::new();
+
+        for (index, name) in names.iter().rev().enumerate() {
+            if index < self.keep_last {
+                keep.insert(name);
+            }
+            if bucket(&mut daily, self.keep_daily, name.get(..10).unwrap_or(name).to_string()) {
+                keep.insert(name);
+            }
+            if bucket(&mut weekly, self.keep_weekly, week_of(name)) {
+                keep.insert(name);
+            }
+            if bucket(&mut monthly, self.keep_monthly, name.get(..7).unwrap_or(name).to_string()) {
+                keep.insert(name);
+            }
+        }
+
+        names.iter().filter(|name| !keep.contains(name)).cloned().collect()
+    }
+}
+
+fn bucket(seen: &mut HashSet<String>, limit: usize, key: String) -> bool {
+    if seen.len() >= limit || seen.contains(&key) {
+        return false;
+    }
+    seen.insert(key);
+    true
+}
+
+// Weeks start on Monday; the Unix epoch was a Thursday.
+fn week_of(name: &str) -> String {
+    let days = timestamp::parse(name).map(|time| timestamp::to_secs(time) / 86_400).unwrap_or(0);
+    ((days + 3) / 7).to_string()
+}
+
+pub async fn prune_snapshots(destination: &Path, policy: &RetentionPolicy, dry_run: bool) -> Result<usize, SyncError> {
+    let expired = policy.expired(&snapshot::list_snapshots(destination).await?);
+    for name in &expired {
+        if dry_run {
+            info!("Would remove expired snapshot {:?}", destination.join(name));
+        } else {
+            info!("Removing expired snapshot {:?}", destination.join(name));
+            fs::remove_dir_all(destination.join(name)).await?;
+            let manifest = state::snapshot_manifest_path(destination, name);
+            if manifest.exists() {
+                fs::remove_file(manifest).await?;
+            }
+        }
+    }
+    Ok(expired.len())
+}
+
+pub async fn prune_store(destination: &Path, policy: &RetentionPolicy, dry_run: bool) -> Result<usize, SyncError> {
+    // A dry run writes nothing, not even the lock, and deletes nothing a
+    // concurrent pass could be reusing.
+    let _lock = match dry_run {
+        true => None,
+        false => Some(store::lock(destination).await?),
+    };
+    let trees = store::list_trees(destination).await?;
+    let expired = policy.expired(&trees);
+    if expired.is_empty() {
+        return Ok(0);
+    }
+
+    for name in &expired {
+        if dry_run {
+            info!("Would remove expired tree {}", name);
+        } else {
+            info!("Removing expired tree {}", name);
+            fs::remove_file(destination.join(store::TREES_DIR).join(name)).await?;
+        }
+    }
+
+    let mut referenced = HashSet::new();
+    for name in trees.iter().filter(|name| !expired.contains(name)) {
+        for entry in store::read_tree(&destination.join(store::TREES_DIR).join(name)).await?.into_values() {
+            if let Some(hash) = entry.hash() {
+                referenced.insert(hash.to_string());
+            }
+        }
+    }
+
+    // Chunks stay for as long as a recipe still in use lists them.
+    let mut recipes = HashSet::new();
+    for hash in &referenced {
+        let recipe = store::recipe_path(destination, hash);
+        if recipe.exists() {
+            recipes.insert(hash.clone());
+        }
+    }
+    for hash in &recipes {
+        for (chunk, _) in store::read_recipe(&store::recipe_path(destination, hash)).await? {
+            referenced.insert(chunk);
+        }
+    }
+
+    for (dir, kind, kept) in [(store::OBJECTS_DIR, "object", &referenced), (store::RECIPES_DIR, "recipe", &recipes)] {
+        if !destination.join(dir).is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(destination.join(dir)).min_depth(2) {
+            let entry = entry?;
+            let hash = format!(
+                "{}{}",
+                entry.path().parent().and_then(|p| p.file_name()).unwrap_or_default().to_string_lossy(),
+                entry.file_name().to_string_lossy()
+            );
+            if kept.contains(&hash) {
+                continue;
+            }
+            if dry_run {
+                debug!("Would remove unreferenced {} {}", kind, hash);
+            } else {
+                debug!("Removing unreferenced {} {}", kind, hash);
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+    }
+
+    Ok(expired.len())
+}
+
+// A destination holding a trees directory was written by the store mode,
+// anything else is treated as a snapshot destination.
+pub fn layout_of(destination: &Path) -> &'static str {
+    if destination.join(store::TREES_DIR).is_dir() {
+        "store"
+    } else {
+        "snapshot"
+    }
+}
+
+pub async fn apply(destination: &str, mode: &str, policy: &RetentionPolicy, dry_run: bool) -> Result<usize, SyncError> {
+    let destination = Path::new(destination);
+    match mode {
+        "snapshot" => prune_snapshots(destination, policy, dry_run).await,
+        "store" => prune_store(destination, policy, dry_run).await,
+        _ => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn keeps_everything_without_rules() {
+        let all = names(&["2024-06-01T12:00:00", "2024-06-02T12:00:00"]);
+        assert!(RetentionPolicy::default().expired(&all).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_last_n() {
+        let all = names(&["2024-06-01T12:00:00", "2024-06-02T12:00:00", "2024-06-03T12:00:00", "2024-06-04T12:00:00"]);
+        let policy = RetentionPolicy { keep_last: 2, ..RetentionPolicy::default() };
+        assert_eq!(policy.expired(&all), names(&["2024-06-01T12:00:00", "2024-06-02T12:00:00"]));
+    }
+
+    #[test]
+    fn keeps_the_newest_of_each_recent_day() {
+        let all = names(&["2024-06-01T08:00:00", "2024-06-01T20:00:00", "2024-06-03T08:00:00", "2024-06-03T09:00:00", "2024-06-04T08:00:00"]);
+        let policy = RetentionPolicy { keep_daily: 2, ..RetentionPolicy::default() };
+        // Days without snapshots don't count towards the limit.
+        assert_eq!(policy.expired(&all), names(&["2024-06-01T08:00:00", "2024-06-01T20:00:00", "2024-06-03T08:00:00"]));
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        // 2024-06-03 was a Monday.
+        assert_eq!(week_of("2024-06-03T00:00:00"), week_of("2024-06-09T23:59:59"));
+        assert_ne!(week_of("2024-06-02T23:59:59"), week_of("2024-06-03T00:00:00"));
+        assert_eq!(week_of("1970-01-01T00:00:00"), "0");
+        assert_eq!(week_of("1970-01-05T00:00:00"), "1");
+
+        let all = names(&["2024-06-02T12:00:00", "2024-06-03T12:00:00", "2024-06-09T12:00:00", "2024-06-10T12:00:00"]);
+        let policy = RetentionPolicy { keep_weekly: 2, ..RetentionPolicy::default() };
+        assert_eq!(policy.expired(&all), names(&["2024-06-02T12:00:00", "2024-06-03T12:00:00"]));
+    }
+
+    #[test]
+    fn keeps_the_newest_of_each_recent_month() {
+        let all = names(&["2024-04-30T12:00:00", "2024-05-01T12:00:00", "2024-05-31T12:00:00", "2024-06-01T12:00:00"]);
+        let policy = RetentionPolicy { keep_monthly: 2, ..RetentionPolicy::default() };
+        assert_eq!(policy.expired(&all), names(&["2024-04-30T12:00:00", "2024-05-01T12:00:00"]));
+    }
+
+    #[test]
+    fn keeps_what_any_rule_keeps() {
+        let all = names(&["2024-04-30T12:00:00", "2024-05-01T12:00:00", "2024-06-01T08:00:00", "2024-06-01T12:00:00"]);
+        let policy = RetentionPolicy { keep_last: 1, keep_daily: 1, keep_monthly: 3, ..RetentionPolicy::default() };
+        assert_eq!(policy.expired(&all), names(&["2024-06-01T08:00:00"]));
+    }
+
+    #[tokio::test]
+    async fn dry_runs_leave_the_store_untouched() {
+        let store = scratch("retention-dry-run");
+        for name in ["2024-06-01T12:00:00", "2024-06-02T12:00:00"] {
+            std::fs::create_dir_all(store.join(store::TREES_DIR)).unwrap();
+            store::write_tree_file(&store.join(store::TREES_DIR).join(name), &store::Tree::new()).await.unwrap();
+        }
+        let policy = RetentionPolicy { keep_last: 1, ..RetentionPolicy::default() };
+        assert_eq!(prune_store(&store, &policy, true).await.unwrap(), 1);
+        assert_eq!(store::list_trees(&store).await.unwrap().len(), 2);
+        assert!(!state::state_dir(&store).exists());
+
+        assert_eq!(prune_store(&store, &policy, false).await.unwrap(), 1);
+        assert_eq!(store::list_trees(&store).await.unwrap(), ["2024-06-02T12:00:00"]);
+        std::fs::remove_dir_all(store).unwrap();
+    }
+}
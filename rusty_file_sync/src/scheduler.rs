@@ -0,0 +1,31 @@
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Process-wide limits shared by every job. Unset limits hand out no permit
+// and never block.
+static JOBS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+static TRANSFERS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+pub fn configure(max_concurrent_jobs: usize, max_concurrent_transfers: usize) {
+    if max_concurrent_jobs > 0 {
+        let _ = JOBS.set(Arc::new(Semaphore::new(max_concurrent_jobs)));
+    }
+    if max_concurrent_transfers > 0 {
+        let _ = TRANSFERS.set(Arc::new(Semaphore::new(max_concurrent_transfers)));
+    }
+}
+
+async fn acquire(limit: &OnceLock<Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match limit.get() {
+        Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+        None => None,
+    }
+}
+
+pub async fn job_permit() -> Option<OwnedSemaphorePermit> {
+    acquire(&JOBS).await
+}
+
+pub async fn transfer_permit() -> Option<OwnedSemaphorePermit> {
+    acquire(&TRANSFERS).await
+}
@@ -0,0 +1,240 @@
+use crate::store::{self, Tree, TreeEntry};
+use crate::{hash, retention, snapshot, state, timestamp, SyncError};
+use log::{debug, error, info};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+#[derive(Debug)]
+pub struct Corruption {
+    pub path: PathBuf,
+    // Path relative to the synced source, when known.
+    pub key: Option<String>,
+    pub expected: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub checked: usize,
+    pub corrupted: Vec<Corruption>,
+    pub missing: Vec<PathBuf>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupted.is_empty() && self.missing.is_empty()
+    }
+}
+
+// A file to re-hash and the digest it should still have.
+struct Check {
+    path: PathBuf,
+    key: Option<String>,
+    expected: String,
+}
+
+// Objects are named after their hash, so they verify themselves.
+fn object_checks(destination: &Path, checks: &mut Vec<Check>) -> Result<(), SyncError> {
+    for entry in WalkDir::new(destination.join(store::OBJECTS_DIR)).min_depth(2) {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy();
+        if name.starts_with('.') {
+            continue;
+        }
+        let expected = format!(
+            "{}{}",
+            entry.path().parent().and_then(|p| p.file_name()).unwrap_or_default().to_string_lossy(),
+            name
+        );
+        checks.push(Check { path: entry.path().to_path_buf(), key: None, expected });
+    }
+    Ok(())
+}
+
+// Chunks are checked as objects; their recipes only need them all there.
+async fn recipe_checks(destination: &Path, report: &mut ScrubReport) -> Result<(), SyncError> {
+    let recipes = destination.join(store::RECIPES_DIR);
+    if !recipes.is_dir() {
+        return Ok(());
+    }
+    for entry in WalkDir::new(recipes).min_depth(2) {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        for (chunk, _) in store::read_recipe(entry.path()).await? {
+            let object = store::object_path(destination, &chunk);
+            if !object.exists() {
+                error!("Missing chunk {:?} of {:?}", object, entry.path());
+                report.missing.push(object);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Files whose size or mtime moved on since the manifest was written were
+// legitimately changed and are not counted as corruption.
+fn manifest_checks(root: &Path, manifest: &Tree, checks: &mut Vec<Check>, report: &mut ScrubReport) -> Result<(), SyncError> {
+    for (key, entry) in manifest {
+        let TreeEntry::File { hash, size, modified } = entry else {
+            continue;
+        };
+        let path = store::key_path(root, key);
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                error!("Missing file {:?}", path);
+                report.missing.push(path);
+                continue;
+            }
+        };
+        if metadata.len() != *size || timestamp::to_secs(metadata.modified()?) != *modified {
+            debug!("Skipping {:?}, changed since the manifest was written", path);
+            continue;
+        }
+        checks.push(Check { path, key: Some(key.clone()), expected: hash.clone() });
+    }
+    Ok(())
+}
+
+// Re-hashes `checks` with up to `jobs` files in flight, since one file at a
+// time leaves most of a disk or array, and every core but one, idle.
+async fn run_checks(checks: Vec<Check>, jobs: usize, buffer_size: usize, report: &mut ScrubReport) -> Result<(), SyncError> {
+    let mut pending = checks.into_iter();
+    let mut running = tokio::task::JoinSet::new();
+    loop {
+        while running.len() < jobs.max(1) {
+            let Some(check) = pending.next() else {
+                break;
+            };
+            running.spawn(async move {
+                let matches = hash::matches(&check.path, &check.expected, buffer_size).await;
+                (check, matches)
+            });
+        }
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let (check, matches) = joined.map_err(|e| SyncError::FileSystemError(std::io::Error::other(e)))?;
+        report.checked += 1;
+        if !matches? {
+            error!("Corrupted {} {:?}", if check.key.is_some() { "file" } else { "object" }, check.path);
+            report.corrupted.push(Corruption { path: check.path, key: check.key, expected: check.expected });
+        }
+    }
+    // Finished in whatever order the hashing did.
+    report.corrupted.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(())
+}
+
+// Scrubs `dir`, hashing up to `jobs` files at once.
+pub async fn scrub(dir: &Path, jobs: usize, buffer_size: usize) -> Result<ScrubReport, SyncError> {
+    let mut report = ScrubReport::default();
+    let mut checks = Vec::new();
+
+    if retention::layout_of(dir) == "store" {
+        info!("Scrubbing store objects in {:?}", dir);
+        object_checks(dir, &mut checks)?;
+        recipe_checks(dir, &mut report).await?;
+        run_checks(checks, jobs, buffer_size, &mut report).await?;
+        return Ok(report);
+    }
+
+    let mut found = false;
+    let manifest = state::manifest_path(dir);
+    if manifest.exists() {
+        info!("Scrubbing {:?} against its manifest", dir);
+        manifest_checks(dir, &state::load_manifest(&manifest).await?, &mut checks, &mut report)?;
+        found = true;
+    }
+    for name in snapshot::list_snapshots(dir).await? {
+        let manifest = state::snapshot_manifest_path(dir, &name);
+        if manifest.exists() {
+            info!("Scrubbing snapshot {}", name);
+            manifest_checks(&dir.join(&name), &state::load_manifest(&manifest).await?, &mut checks, &mut report)?;
+            found = true;
+        }
+    }
+
+    if !found {
+        return Err(SyncError::ScrubError(format!("No manifest found in {:?}", dir)));
+    }
+    run_checks(checks, jobs, buffer_size, &mut report).await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::SyncOptions;
+    use crate::testing::{scratch, touch, write};
+
+    // Overwrites `path` with `contents` of the same length, keeping its
+    // modification time so the change looks like rot rather than an edit.
+    fn rot(path: &Path, contents: &str) {
+        let modified = std::fs::metadata(path).unwrap().modified().unwrap();
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finds_rot_and_missing_files_against_the_manifest() {
+        let dir = scratch("scrub-manifest");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        write(&source.join("a.txt"), "alpha");
+        write(&source.join("b.txt"), "beta");
+        write(&source.join("c.txt"), "gamma");
+        std::fs::create_dir(&dest).unwrap();
+        crate::sync(source.to_str().unwrap(), dest.to_str().unwrap(), "one", &SyncOptions::default()).await.unwrap();
+
+        let report = scrub(&dest, 2, 4096).await.unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.checked, 3);
+
+        rot(&dest.join("a.txt"), "ALPHA");
+        std::fs::remove_file(dest.join("b.txt")).unwrap();
+        // A legitimate edit changes the mtime and isn't rot.
+        std::fs::write(dest.join("c.txt"), "GAMMA").unwrap();
+        touch(&dest.join("c.txt"), 60);
+        let report = scrub(&dest, 2, 4096).await.unwrap();
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.corrupted.len(), 1);
+        assert_eq!(report.corrupted[0].key.as_deref(), Some("a.txt"));
+        assert_eq!(report.missing, vec![dest.join("b.txt")]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn store_objects_verify_themselves() {
+        let dir = scratch("scrub-store");
+        let (source, store) = (dir.join("source"), dir.join("store"));
+        write(&source.join("a.txt"), "alpha");
+        write(&source.join("b.txt"), "beta");
+        crate::sync(source.to_str().unwrap(), store.to_str().unwrap(), "store", &SyncOptions::default()).await.unwrap();
+
+        let report = scrub(&store, 1, 4096).await.unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.checked, 2);
+
+        let object = WalkDir::new(store.join(store::OBJECTS_DIR))
+            .min_depth(2)
+            .into_iter()
+            .map(|e| e.unwrap().into_path())
+            .find(|p| std::fs::read_to_string(p).unwrap() == "alpha")
+            .unwrap();
+        std::fs::write(&object, "ALPHA").unwrap();
+        let report = scrub(&store, 1, 4096).await.unwrap();
+        assert_eq!(report.corrupted.len(), 1);
+        assert_eq!(report.corrupted[0].path, object);
+        assert_eq!(report.corrupted[0].key, None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn needs_a_manifest() {
+        let dir = scratch("scrub-none");
+        write(&dir.join("a.txt"), "alpha");
+        assert!(matches!(scrub(&dir, 1, 4096).await, Err(SyncError::ScrubError(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use crate::durability::Durability;
+use crate::options::SyncOptions;
+use crate::store::{self, Tree, TreeEntry};
+use crate::{attrs, control, copy, faults, links, locked, metered, special, state, timestamp, SyncError};
+use log::{debug, info, warn};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use walkdir::WalkDir;
+
+const PARTIAL_SUFFIX: &str = ".partial";
+
+pub async fn list_snapshots(destination: &Path) -> Result<Vec<String>, SyncError> {
+    let mut names = Vec::new();
+    if !destination.exists() {
+        return Ok(names);
+    }
+    let mut entries = fs::read_dir(destination).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type().await?.is_dir() && timestamp::parse(&name).is_some() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+// Leftovers from an interrupted run are never valid snapshots.
+async fn remove_partials(destination: &Path) -> Result<(), SyncError> {
+    let mut entries = fs::read_dir(destination).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && name.ends_with(PARTIAL_SUFFIX) {
+            warn!("Removing incomplete snapshot {:?}", entry.path());
+            fs::remove_dir_all(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+// Snapshot copies keep their source's modification time, give or take the
+// destination's rounding.
+fn is_unchanged(src_metadata: &std::fs::Metadata, previous: &Path, options: &SyncOptions) -> bool {
+    match std::fs::metadata(previous) {
+        Ok(prev_metadata) => {
+            prev_metadata.is_file()
+                && prev_metadata.len() == src_metadata.len()
+                && matches!((prev_metadata.modified(), src_metadata.modified()), (Ok(prev), Ok(src)) if options.same_time(src, prev))
+        }
+        Err(_) => false,
+    }
+}
+
+async fn copy_preserving_mtime(source: &Path, dest: &Path, options: &SyncOptions) -> Result<copy::Copied, SyncError> {
+    let copied = copy::copy_file(source, dest, options).await?;
+    attrs::set_modified(dest, copied.source.modified()?)?;
+    Ok(copied)
+}
+
+pub async fn sync_snapshot(source: &str, destination: &str, options: &SyncOptions) -> Result<(), SyncError> {
+    let destination = Path::new(destination);
+    fs::create_dir_all(destination).await?;
+    remove_partials(destination).await?;
+
+    let previous_name = list_snapshots(destination).await?.pop();
+    let previous: Option<PathBuf> = previous_name.as_ref().map(|name| destination.join(name));
+    let previous_manifest = match &previous_name {
+        Some(name) => state::load_manifest(&state::snapshot_manifest_path(destination, name)).await?,
+        None => Tree::new(),
+    };
+    let mut manifest = Tree::new();
+    let name = timestamp::now();
+    let partial = destination.join(format!(".{}{}", name, PARTIAL_SUFFIX));
+    fs::create_dir_all(&partial).await?;
+
+    let mut durability = Durability::new(options.fsync);
+    for entry in links::walk(WalkDir::new(source).min_depth(1), options.links) {
+        control::checkpoint(options).await;
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(source)?;
+        let dest_path = partial.join(relative);
+        let key = store::relative_key(relative);
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest_path).await?;
+            durability.created(&dest_path);
+            manifest.insert(key, TreeEntry::Dir);
+            continue;
+        }
+        if let Some(kind) = special::kind(&entry.file_type()) {
+            special::skip(entry.path(), kind);
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let linked = match &previous {
+            Some(prev) if is_unchanged(&metadata, &prev.join(relative), options) => {
+                fs::hard_link(prev.join(relative), &dest_path).await.is_ok()
+            }
+            _ => false,
+        };
+
+        let file = if linked {
+            debug!("Linking unchanged file: {:?}", entry.path());
+            state::manifest_entry(&dest_path, &metadata, previous_manifest.get(&key), options.hash, options.buffer_size).await?
+        } else if metered::defers(entry.path(), metadata.len(), options).await || !locked::readable(entry.path(), options).await? {
+            // Keep the last copy of a locked or deferred file rather than
+            // dropping it from the snapshot.
+            match (&previous, previous_manifest.get(&key)) {
+                (Some(prev), Some(recorded)) if fs::hard_link(prev.join(relative), &dest_path).await.is_ok() => recorded.clone(),
+                _ => continue,
+            }
+        } else {
+            info!("Copying file from {:?} to {:?}", entry.path(), dest_path);
+            let result = copy_preserving_mtime(entry.path(), &dest_path, options).await?;
+            durability.written(&dest_path)?;
+            TreeEntry::File {
+                hash: result.hash,
+                size: result.source.len(),
+                modified: timestamp::to_secs(result.source.modified()?),
+            }
+        };
+        manifest.insert(key, file);
+    }
+
+    // The manifest lists everything the snapshot holds, so an unchanged one
+    // means the same tree with the same contents and times.
+    if previous.is_some() && manifest == previous_manifest {
+        debug!("Source unchanged since last snapshot, discarding {:?}", partial);
+        fs::remove_dir_all(&partial).await?;
+    } else {
+        info!("Recording snapshot {:?}", destination.join(&name));
+        store::write_tree_file(&state::snapshot_manifest_path(destination, &name), &manifest).await?;
+        // Everything is flushed under the partial name before the rename
+        // makes the snapshot visible.
+        durability.flush()?;
+        faults::before_rename()?;
+        fs::rename(&partial, destination.join(&name)).await?;
+        durability.created(&destination.join(&name));
+    }
+    durability.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+
+    #[tokio::test]
+    async fn links_unchanged_files_into_new_snapshots() {
+        let dir = scratch("snapshot-sync");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        write(&source.join("same.txt"), "unchanged");
+        write(&source.join("sub/edited.txt"), "first");
+        std::fs::create_dir_all(dest.join(".2024-01-01T00:00:00.partial/left")).unwrap();
+        let (source_str, dest_str) = (source.to_str().unwrap(), dest.to_str().unwrap());
+        let options = SyncOptions::default();
+
+        sync_snapshot(source_str, dest_str, &options).await.unwrap();
+        assert!(!dest.join(".2024-01-01T00:00:00.partial").exists());
+        // An unchanged source makes no new snapshot.
+        sync_snapshot(source_str, dest_str, &options).await.unwrap();
+        let first = list_snapshots(&dest).await.unwrap();
+        assert_eq!(first.len(), 1);
+
+        // Snapshots are named to the second.
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        write(&source.join("sub/edited.txt"), "second");
+        sync_snapshot(source_str, dest_str, &options).await.unwrap();
+        let snapshots = list_snapshots(&dest).await.unwrap();
+        assert_eq!(snapshots.len(), 2);
+        let (old, new) = (dest.join(&snapshots[0]), dest.join(&snapshots[1]));
+        assert_eq!(std::fs::read_to_string(old.join("sub/edited.txt")).unwrap(), "first");
+        assert_eq!(std::fs::read_to_string(new.join("sub/edited.txt")).unwrap(), "second");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |path: &Path| std::fs::metadata(path).unwrap().ino();
+            assert_eq!(inode(&old.join("same.txt")), inode(&new.join("same.txt")));
+            assert_ne!(inode(&old.join("sub/edited.txt")), inode(&new.join("sub/edited.txt")));
+        }
+        let manifest = state::load_manifest(&state::snapshot_manifest_path(&dest, &snapshots[1])).await.unwrap();
+        assert_eq!(manifest.keys().collect::<Vec<_>>(), ["same.txt", "sub", "sub/edited.txt"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+use crate::options::{format_size, SyncOptions};
+use crate::retention::{self, RetentionPolicy};
+use crate::{naming, snapshot, state, store, timestamp, SyncError};
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::path::Path;
+use walkdir::WalkDir;
+
+// Headroom left on the destination volume, as bytes or as a share of it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MinFree {
+    Bytes(u64),
+    Percent(f64),
+}
+
+// `5%`, or a size such as `20G`.
+impl std::str::FromStr for MinFree {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(percent) if (0.0..100.0).contains(&percent) => Ok(MinFree::Percent(percent)),
+                _ => Err(format!("Invalid free space: {} (expected a percentage below 100 or a size)", value)),
+            },
+            None => crate::options::parse_size(value).map(MinFree::Bytes),
+        }
+    }
+}
+
+impl MinFree {
+    fn bytes(&self, space: &DiskSpace) -> u64 {
+        match self {
+            MinFree::Bytes(bytes) => *bytes,
+            MinFree::Percent(percent) => (space.total as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+// Called before each file is written: fails the pass rather than let `size`
+// more bytes eat into the `--min-free` headroom of the volume holding `dest`.
+pub fn ensure_headroom(dest: &Path, size: u64, options: &SyncOptions) -> Result<(), SyncError> {
+    let Some(min_free) = options.min_free else {
+        return Ok(());
+    };
+    let space = disk_space(dest)?;
+    let headroom = min_free.bytes(&space);
+    if space.available.saturating_sub(size) < headroom {
+        return Err(SyncError::SpaceError(format!(
+            "Stopping before {:?}: only {} free on its volume, {} must stay free",
+            dest,
+            format_size(space.available),
+            format_size(headroom)
+        )));
+    }
+    Ok(())
+}
+
+// What happens when a pass would take the destination over its quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum QuotaPolicy {
+    #[default]
+    Fail,
+    // Remove the oldest snapshots or trees until the pass fits.
+    Prune,
+}
+
+impl std::str::FromStr for QuotaPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fail" => Ok(QuotaPolicy::Fail),
+            "prune" => Ok(QuotaPolicy::Prune),
+            _ => Err(format!("Invalid quota policy: {} (expected fail or prune)", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiskSpace {
+    // Bytes this user may still write.
+    pub available: u64,
+    pub total: u64,
+}
+
+// The file system holding `path`, or that of its nearest existing ancestor
+// when it hasn't been created yet.
+#[cfg(unix)]
+pub fn disk_space(path: &Path) -> std::io::Result<DiskSpace> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(Path::new("."));
+    let existing = if existing.as_os_str().is_empty() { Path::new(".") } else { existing };
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(DiskSpace {
+        available: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total: stat.f_blocks as u64 * stat.f_frsize as u64,
+    })
+}
+
+#[cfg(windows)]
+pub fn disk_space(path: &Path) -> std::io::Result<DiskSpace> {
+    use std::os::windows::ffi::OsStrExt;
+
+    extern "system" {
+        fn GetDiskFreeSpaceExW(directory: *const u16, available: *mut u64, total: *mut u64, free: *mut u64) -> i32;
+    }
+
+    let existing = crate::paths::share_root(path.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(Path::new(".")));
+    let wide: Vec<u16> = existing.as_os_str().encode_wide().chain(Some(0)).collect();
+    let (mut available, mut total) = (0u64, 0u64);
+    if unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, &mut total, std::ptr::null_mut()) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(DiskSpace { available, total })
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn disk_space(_path: &Path) -> std::io::Result<DiskSpace> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "free space is not available on this platform"))
+}
+
+// What a pass is expected to write.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Estimate {
+    // Bytes copied.
+    pub transfer: u64,
+    // Bytes the destination grows by. Mirrors overwrite files in place, so
+    // a changed file only needs room for whatever it grew by.
+    pub growth: u64,
+}
+
+impl Estimate {
+    fn add(&mut self, transfer: u64, growth: u64) {
+        self.transfer += transfer;
+        self.growth += growth;
+    }
+}
+
+// The size of the existing copy when `existing` is missing or looks older
+// or different, None when it looks current.
+fn replaced_size(src_metadata: &std::fs::Metadata, existing: &Path, options: &SyncOptions) -> Option<u64> {
+    match std::fs::metadata(existing) {
+        Ok(dest_metadata) if dest_metadata.is_file() => {
+            let newer = match (src_metadata.modified(), dest_metadata.modified()) {
+                (Ok(src), Ok(dest)) => options.is_newer(src, dest, src_metadata.len() == dest_metadata.len()),
+                _ => false,
+            };
+            (newer || dest_metadata.len() != src_metadata.len()).then_some(dest_metadata.len())
+        }
+        _ => Some(0),
+    }
+}
+
+// An upper bound on what a pass of `mode` will write to `destination`,
+// from sizes and mtimes alone. Contents aren't compared, so some of the
+// files counted may turn out to be unchanged.
+pub async fn estimate(source: &str, destination: &str, mode: &str, options: &SyncOptions) -> Result<Estimate, SyncError> {
+    let destination = Path::new(destination);
+    let mut bytes = Estimate::default();
+    match mode {
+        // Only attributes are written.
+        "metadata" => {}
+        "snapshot" => {
+            let previous = snapshot::list_snapshots(destination).await?.pop().map(|name| destination.join(name));
+            for entry in WalkDir::new(source).min_depth(1) {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    let metadata = entry.metadata()?;
+                    let relative = entry.path().strip_prefix(source)?;
+                    // Unchanged files are hardlinked, everything else is copied whole.
+                    let unchanged = previous.as_ref().is_some_and(|prev| {
+                        std::fs::metadata(prev.join(relative))
+                            .is_ok_and(|prev| prev.len() == metadata.len() && prev.modified().ok() == metadata.modified().ok())
+                    });
+                    if !unchanged {
+                        bytes.add(metadata.len(), metadata.len());
+                    }
+                }
+            }
+        }
+        "store" => {
+            let previous = match store::list_trees(destination).await?.pop() {
+                Some(name) => store::read_tree(&destination.join(store::TREES_DIR).join(name)).await?,
+                None => store::Tree::new(),
+            };
+            for entry in WalkDir::new(source).min_depth(1) {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    let metadata = entry.metadata()?;
+                    let key = store::relative_key(entry.path().strip_prefix(source)?);
+                    let modified = timestamp::to_secs(metadata.modified()?);
+                    let unchanged = matches!(previous.get(&key), Some(store::TreeEntry::File { size, modified: m, .. }) if *size == metadata.len() && *m == modified);
+                    if !unchanged {
+                        bytes.add(metadata.len(), metadata.len());
+                    }
+                }
+            }
+        }
+        _ => {
+            let mut mapper = naming::PathMapper::new(options);
+            for entry in WalkDir::new(source).into_iter().filter_entry(|e| !state::is_state_dir(e)) {
+                let entry = entry?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                if let Some(mapped) = mapper.map(entry.path().strip_prefix(source)?, false) {
+                    let metadata = entry.metadata()?;
+                    if let Some(replaced) = replaced_size(&metadata, &destination.join(mapped), options) {
+                        bytes.add(metadata.len(), metadata.len().saturating_sub(replaced));
+                    }
+                }
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(unix)]
+fn first_link(metadata: &std::fs::Metadata, seen: &mut HashSet<(u64, u64)>) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() < 2 || seen.insert((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn first_link(_metadata: &std::fs::Metadata, _seen: &mut HashSet<(u64, u64)>) -> bool {
+    true
+}
+
+// Bytes taken by everything under `destination`. Hardlinked snapshot files
+// are only counted once.
+pub fn used(destination: &Path) -> Result<u64, SyncError> {
+    let mut seen = HashSet::new();
+    let mut bytes = 0u64;
+    if !destination.exists() {
+        return Ok(0);
+    }
+    for entry in WalkDir::new(destination).min_depth(1) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if first_link(&metadata, &mut seen) {
+            bytes += metadata.len();
+        }
+    }
+    Ok(bytes)
+}
+
+async fn versions(destination: &Path, mode: &str) -> Result<usize, SyncError> {
+    Ok(match mode {
+        "snapshot" => snapshot::list_snapshots(destination).await?.len(),
+        "store" => store::list_trees(destination).await?.len(),
+        _ => 0,
+    })
+}
+
+// Keeps `destination` under `quota` once `needed` more bytes are written,
+// pruning the oldest versions under `QuotaPolicy::Prune`. The newest
+// version is never pruned.
+async fn enforce_quota(destination: &Path, mode: &str, needed: u64, quota: u64, policy: QuotaPolicy) -> Result<(), SyncError> {
+    loop {
+        let used = used(destination)?;
+        if used.saturating_add(needed) <= quota {
+            return Ok(());
+        }
+        let versions = versions(destination, mode).await?;
+        if policy != QuotaPolicy::Prune || versions < 2 {
+            return Err(SyncError::SpaceError(format!(
+                "{} would grow to {} with this pass, over its quota of {}",
+                destination.display(),
+                format_size(used.saturating_add(needed)),
+                format_size(quota)
+            )));
+        }
+        warn!("{:?} is over its quota of {}, pruning its oldest version", destination, format_size(quota));
+        let policy = RetentionPolicy { keep_last: versions - 1, ..Default::default() };
+        retention::apply(&destination.to_string_lossy(), mode, &policy, false).await?;
+    }
+}
+
+// Fails before anything is copied when the pass is not expected to fit on
+// the destination with `options.space_reserve` bytes to spare, or within
+// `options.max_dest_size`.
+pub async fn check(source: &str, destination: &str, mode: &str, options: &SyncOptions) -> Result<(), SyncError> {
+    let needed = estimate(source, destination, mode, options).await?.growth;
+    if let Some(quota) = options.max_dest_size {
+        enforce_quota(Path::new(destination), mode, needed, quota, options.quota_policy).await?;
+    }
+    if !options.check_space {
+        return Ok(());
+    }
+    let available = disk_space(Path::new(destination))?.available;
+    debug!("Pass needs up to {} on {}, {} available", format_size(needed), destination, format_size(available));
+    if needed.saturating_add(options.space_reserve) > available {
+        return Err(SyncError::SpaceError(format!(
+            "{} needs up to {} (plus a reserve of {}) but only {} is free",
+            destination,
+            format_size(needed),
+            format_size(options.space_reserve),
+            format_size(available)
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, touch, write};
+
+    fn same_time(from: &Path, to: &Path) {
+        let modified = std::fs::metadata(from).unwrap().modified().unwrap();
+        std::fs::File::options().write(true).open(to).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn formats_sizes_with_one_decimal() {
+        assert_eq!(format_size(0), "0B");
+        assert_eq!(format_size(1023), "1023B");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(3 << 29), "1.5G");
+        assert_eq!(format_size(5 << 40), "5.0T");
+    }
+
+    #[tokio::test]
+    async fn estimates_what_a_mirror_writes() {
+        let dir = scratch("space-estimate");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        write(&source.join("new.txt"), "0123456789");
+        write(&source.join("grown.txt"), "12345");
+        write(&source.join("same.txt"), "abc");
+        write(&dest.join("grown.txt"), "123");
+        touch(&dest.join("grown.txt"), 3600);
+        write(&dest.join("same.txt"), "abc");
+        same_time(&source.join("same.txt"), &dest.join("same.txt"));
+
+        let (source, dest) = (source.to_str().unwrap(), dest.to_str().unwrap());
+        let options = SyncOptions::default();
+        // The grown file only needs room for what it grew by.
+        let mirror = estimate(source, dest, "mirror", &options).await.unwrap();
+        assert_eq!((mirror.transfer, mirror.growth), (15, 12));
+        let metadata = estimate(source, dest, "metadata", &options).await.unwrap();
+        assert_eq!((metadata.transfer, metadata.growth), (0, 0));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_passes_that_need_more_than_is_free() {
+        let dir = scratch("space-check");
+        write(&dir.join("source/a.txt"), "data");
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        let (source, dest) = (source.to_str().unwrap(), dest.to_str().unwrap());
+        let options = SyncOptions { check_space: true, ..SyncOptions::default() };
+        check(source, dest, "mirror", &options).await.unwrap();
+        let options = SyncOptions { space_reserve: u64::MAX, ..options };
+        assert!(matches!(check(source, dest, "mirror", &options).await, Err(SyncError::SpaceError(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn snapshots(dest: &Path) {
+        for name in ["2024-01-01T00:00:00", "2024-01-02T00:00:00", "2024-01-03T00:00:00"] {
+            write(&dest.join(name).join("file"), &"x".repeat(100));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn counts_hardlinked_files_once() {
+        let dir = scratch("space-used");
+        write(&dir.join("a/file"), &"x".repeat(100));
+        std::fs::create_dir(dir.join("b")).unwrap();
+        std::fs::hard_link(dir.join("a/file"), dir.join("b/file")).unwrap();
+        assert_eq!(used(&dir).unwrap(), 100);
+        assert_eq!(used(&dir.join("missing")).unwrap(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_passes_over_the_quota() {
+        let dir = scratch("space-quota-fail");
+        snapshots(&dir);
+        enforce_quota(&dir, "snapshot", 0, 300, QuotaPolicy::Fail).await.unwrap();
+        assert!(matches!(enforce_quota(&dir, "snapshot", 1, 300, QuotaPolicy::Fail).await, Err(SyncError::SpaceError(_))));
+        assert_eq!(snapshot::list_snapshots(&dir).await.unwrap().len(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prunes_the_oldest_versions_to_fit() {
+        let dir = scratch("space-quota-prune");
+        snapshots(&dir);
+        enforce_quota(&dir, "snapshot", 50, 250, QuotaPolicy::Prune).await.unwrap();
+        assert_eq!(snapshot::list_snapshots(&dir).await.unwrap(), ["2024-01-02T00:00:00", "2024-01-03T00:00:00"]);
+        // The newest version stays even when it alone is over the quota.
+        assert!(matches!(enforce_quota(&dir, "snapshot", 0, 50, QuotaPolicy::Prune).await, Err(SyncError::SpaceError(_))));
+        assert_eq!(snapshot::list_snapshots(&dir).await.unwrap().len(), 1);
+        assert_eq!("prune".parse(), Ok(QuotaPolicy::Prune));
+        assert!("delete".parse::<QuotaPolicy>().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_headroom_as_a_size_or_a_share() {
+        assert_eq!("20G".parse(), Ok(MinFree::Bytes(20 << 30)));
+        assert_eq!(" 5 %".parse(), Ok(MinFree::Percent(5.0)));
+        for value in ["100%", "-1%", "many%", "lots"] {
+            assert!(value.parse::<MinFree>().is_err(), "{}", value);
+        }
+        let space = DiskSpace { available: 0, total: 1000 };
+        assert_eq!(MinFree::Percent(2.5).bytes(&space), 25);
+        assert_eq!(MinFree::Bytes(7).bytes(&space), 7);
+    }
+
+    #[test]
+    fn stops_writes_that_eat_into_the_headroom() {
+        let dir = scratch("space-headroom");
+        let available = disk_space(&dir).unwrap().available;
+        ensure_headroom(&dir.join("file"), u64::MAX, &SyncOptions::default()).unwrap();
+        let options = SyncOptions { min_free: Some(MinFree::Bytes(available / 2)), ..SyncOptions::default() };
+        ensure_headroom(&dir.join("file"), 0, &options).unwrap();
+        assert!(matches!(ensure_headroom(&dir.join("file"), available, &options), Err(SyncError::SpaceError(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use crate::options::SyncOptions;
+use crate::SyncError;
+use log::{info, warn};
+use std::fs::{FileType, Metadata};
+use std::path::Path;
+
+// What a file that holds no data is, going by rsync's names: block and
+// character devices are devices, FIFOs and sockets are specials.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Special {
+    Device,
+    Other,
+}
+
+#[cfg(unix)]
+pub fn kind(file_type: &FileType) -> Option<Special> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_block_device() || file_type.is_char_device() {
+        Some(Special::Device)
+    } else if file_type.is_fifo() || file_type.is_socket() {
+        Some(Special::Other)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kind(_file_type: &FileType) -> Option<Special> {
+    None
+}
+
+// Whether `special` is recreated rather than skipped in mirror modes.
+pub fn wanted(special: Special, options: &SyncOptions) -> bool {
+    match special {
+        Special::Device => options.devices,
+        Special::Other => options.specials,
+    }
+}
+
+// Reading a FIFO blocks until something writes to it and a device can go
+// on forever, so files that aren't regular are never copied. Logs `path`
+// as skipped.
+pub fn skip(path: &Path, special: Special) {
+    match special {
+        Special::Device => warn!("Skipping device {:?} (pass --devices to recreate it in mirror modes)", path),
+        Special::Other => warn!("Skipping special file {:?} (pass --specials to recreate it in mirror modes)", path),
+    }
+}
+
+#[cfg(unix)]
+fn same_node(source: &Metadata, dest: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::symlink_metadata(dest).is_ok_and(|dest| dest.mode() & libc::S_IFMT == source.mode() & libc::S_IFMT && dest.rdev() == source.rdev())
+}
+
+#[cfg(unix)]
+fn mknod(source: &Metadata, dest: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let c_path = std::ffi::CString::new(dest.as_os_str().as_bytes()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mode = source.mode() as libc::mode_t;
+    // Not every system lets mknod make FIFOs.
+    let made = match mode & libc::S_IFMT == libc::S_IFIFO {
+        true => unsafe { libc::mkfifo(c_path.as_ptr(), mode & 0o7777) },
+        false => unsafe { libc::mknod(c_path.as_ptr(), mode, source.rdev() as libc::dev_t) },
+    };
+    if made != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn same_node(_source: &Metadata, _dest: &Path) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn mknod(_source: &Metadata, _dest: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "special files are not available on this platform"))
+}
+
+// Makes `dest` the same kind of node as the one `source` describes,
+// replacing whatever is there unless it already is one. Creating devices
+// takes root; when it is refused the node is skipped with a warning rather
+// than failing the pass. Returns whether anything changed.
+pub fn recreate(source: &Metadata, dest: &Path, options: &SyncOptions) -> Result<bool, SyncError> {
+    if same_node(source, dest) {
+        return Ok(false);
+    }
+    options.guard_write(dest)?;
+    match std::fs::symlink_metadata(dest) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(dest)?,
+        Ok(_) => std::fs::remove_file(dest)?,
+        Err(_) => {}
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match mknod(source, dest) {
+        Ok(()) => {
+            info!("Created node {:?}", dest);
+            Ok(true)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            warn!("Can't create {:?}: {}; devices need root", dest, e);
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_and_specials_are_wanted_separately() {
+        let options = SyncOptions { devices: true, ..Default::default() };
+        assert!(wanted(Special::Device, &options));
+        assert!(!wanted(Special::Other, &options));
+        let options = SyncOptions { specials: true, ..Default::default() };
+        assert!(!wanted(Special::Device, &options));
+        assert!(wanted(Special::Other, &options));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recreates_fifos() {
+        use crate::testing::{scratch, write};
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = scratch("special-fifo");
+        let fifo = dir.join("fifo");
+        let c_path = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+        let source = std::fs::symlink_metadata(&fifo).unwrap();
+        assert_eq!(kind(&source.file_type()), Some(Special::Other));
+        assert_eq!(kind(&std::fs::metadata(&dir).unwrap().file_type()), None);
+
+        // Whatever is in the way is replaced, and a matching node is left be.
+        let dest = dir.join("dest/fifo");
+        write(&dest, "not a fifo");
+        let options = SyncOptions::default();
+        assert!(recreate(&source, &dest, &options).unwrap());
+        assert_eq!(kind(&std::fs::symlink_metadata(&dest).unwrap().file_type()), Some(Special::Other));
+        assert!(!recreate(&source, &dest, &options).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use crate::options::SyncOptions;
+use crate::store::{self, Tree, TreeEntry};
+use crate::hash::{self, HashAlgorithm};
+use crate::{control, special, timestamp, SyncError};
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::{DirEntry, WalkDir};
+
+// Bookkeeping lives in a hidden directory at the root of the destination,
+// which every mirror walk skips so it is never copied back or deleted.
+pub const STATE_DIR: &str = ".rusty_file_sync";
+
+pub fn state_dir(root: &Path) -> PathBuf {
+    root.join(STATE_DIR)
+}
+
+pub fn is_state_dir(entry: &DirEntry) -> bool {
+    entry.depth() == 1 && entry.file_name() == STATE_DIR
+}
+
+pub fn manifest_path(root: &Path) -> PathBuf {
+    state_dir(root).join("manifest")
+}
+
+// Source versions behind transformed destination files.
+pub fn transforms_path(root: &Path) -> PathBuf {
+    state_dir(root).join("transforms")
+}
+
+// When entries were first found missing from the source, for
+// `--delete-after-age`.
+pub fn missing_path(root: &Path) -> PathBuf {
+    state_dir(root).join("missing")
+}
+
+pub fn snapshot_manifest_path(destination: &Path, name: &str) -> PathBuf {
+    state_dir(destination).join("snapshots").join(name)
+}
+
+pub async fn load_manifest(path: &Path) -> Result<Tree, SyncError> {
+    if !path.exists() {
+        return Ok(Tree::new());
+    }
+    store::read_tree(path).await
+}
+
+// Hashes `path` unless `previous` already holds an entry with the same size
+// and mtime made with `algorithm`, in which case the recorded hash is reused.
+pub async fn manifest_entry(path: &Path, metadata: &std::fs::Metadata, previous: Option<&TreeEntry>, algorithm: HashAlgorithm, buffer_size: usize) -> Result<TreeEntry, SyncError> {
+    let size = metadata.len();
+    let modified = timestamp::to_secs(metadata.modified()?);
+    let hash = match previous {
+        Some(TreeEntry::File { hash, size: s, modified: m }) if *s == size && *m == modified && HashAlgorithm::of(hash) == algorithm => hash.clone(),
+        _ => hash::file(path, algorithm, buffer_size).await?,
+    };
+    Ok(TreeEntry::File { hash, size, modified })
+}
+
+// Records the hash of every file currently under `root` for later scrubbing.
+// Entries in `fresh` were just written and take precedence over the manifest.
+pub async fn update_manifest(root: &Path, fresh: &Tree, options: &SyncOptions) -> Result<(), SyncError> {
+    let path = manifest_path(root);
+    let previous = load_manifest(&path).await?;
+    let mut manifest = Tree::new();
+
+    for entry in WalkDir::new(root).min_depth(1).into_iter().filter_entry(|e| !is_state_dir(e)) {
+        control::checkpoint(options).await;
+        let entry = entry?;
+        let key = store::relative_key(entry.path().strip_prefix(root)?);
+        if entry.file_type().is_dir() {
+            manifest.insert(key, TreeEntry::Dir);
+        } else if entry.path_is_symlink() || special::kind(&entry.file_type()).is_some() {
+            // Recreated links and nodes hold no data to scrub.
+            continue;
+        } else {
+            let recorded = fresh.get(&key).or(previous.get(&key));
+            let file = manifest_entry(entry.path(), &entry.metadata()?, recorded, options.hash, options.buffer_size).await?;
+            manifest.insert(key, file);
+        }
+    }
+
+    if manifest != previous {
+        debug!("Updating manifest {:?}", path);
+        store::write_tree_file(&path, &manifest).await?;
+    }
+    Ok(())
+}
+
+// Of the entries under `root` that the source no longer has, those missing
+// for at least `age`. The others are kept on record as missing since the
+// first pass that found them so, and taken off it once deleted or back in
+// the source.
+pub async fn missing_for(root: &Path, missing: Vec<PathBuf>, age: Duration) -> Result<Vec<PathBuf>, SyncError> {
+    let path = missing_path(root);
+    let recorded: BTreeMap<String, u64> = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents
+            .lines()
+            .filter_map(|line| {
+                let (since, key) = line.split_once('\t')?;
+                Some((key.to_string(), since.parse().ok()?))
+            })
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let now = timestamp::to_secs(SystemTime::now());
+    let (mut pending, mut due) = (BTreeMap::new(), Vec::new());
+    for relative in missing {
+        let key = store::relative_key(&relative);
+        let since = recorded.get(&key).copied().unwrap_or(now);
+        if now.saturating_sub(since) >= age.as_secs() {
+            due.push(relative);
+        } else {
+            if since == now {
+                info!("Keeping {:?} for {} before deleting it", relative, humantime::format_duration(age));
+            }
+            pending.insert(key, since);
+        }
+    }
+    if pending != recorded {
+        let contents: String = pending.iter().map(|(key, since)| format!("{}\t{}\n", since, key)).collect();
+        tokio::fs::create_dir_all(state_dir(root)).await?;
+        tokio::fs::write(&path, contents).await?;
+    }
+    Ok(due)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    #[tokio::test]
+    async fn holds_deletions_until_missing_long_enough() {
+        let root = scratch("state-missing");
+        let hour = Duration::from_secs(3600);
+        let (a, b) = (PathBuf::from("a.txt"), PathBuf::from("docs/b.txt"));
+        assert_eq!(missing_for(&root, vec![a.clone()], Duration::ZERO).await.unwrap(), vec![a.clone()]);
+        assert!(!missing_path(&root).exists());
+
+        assert!(missing_for(&root, vec![a.clone(), b.clone()], hour).await.unwrap().is_empty());
+        let recorded = std::fs::read_to_string(missing_path(&root)).unwrap();
+        assert_eq!(recorded.lines().count(), 2);
+
+        // Backdate `a`; `b` is back in the source and comes off the record.
+        let long_ago = timestamp::to_secs(SystemTime::now()) - 7200;
+        std::fs::write(missing_path(&root), format!("{}\ta.txt\n{}", long_ago, recorded.lines().nth(1).unwrap())).unwrap();
+        assert_eq!(missing_for(&root, vec![a.clone()], hour).await.unwrap(), vec![a]);
+        assert_eq!(std::fs::read_to_string(missing_path(&root)).unwrap(), "");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,506 @@
+use crate::durability::Durability;
+use crate::hash::{HashAlgorithm, Hasher};
+use crate::options::SyncOptions;
+use crate::{chunks, control, copy, faults, links, locked, metered, scheduler, space, special, state, timestamp, SyncError};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::fs;
+use walkdir::WalkDir;
+
+pub const OBJECTS_DIR: &str = "objects";
+pub const TREES_DIR: &str = "trees";
+pub const RECIPES_DIR: &str = "recipes";
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TreeEntry {
+    Dir,
+    File { hash: String, size: u64, modified: u64 },
+}
+
+impl TreeEntry {
+    pub fn hash(&self) -> Option<&str> {
+        match self {
+            TreeEntry::File { hash, .. } => Some(hash),
+            TreeEntry::Dir => None,
+        }
+    }
+}
+
+// A tree maps `/`-separated paths relative to the source root to entries.
+pub type Tree = BTreeMap<String, TreeEntry>;
+
+pub fn relative_key(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// The path `key` names under `root`, joined one component at a time: a
+// `/` inside a verbatim Windows path such as `\\?\UNC\server\share`
+// would be taken as part of a file name.
+pub fn key_path(root: &Path, key: &str) -> PathBuf {
+    key.split('/').filter(|part| !part.is_empty()).fold(root.to_path_buf(), |full, part| full.join(part))
+}
+
+pub fn object_path(store: &Path, hash: &str) -> PathBuf {
+    let (prefix, rest) = hash.split_at(2.min(hash.len()));
+    store.join(OBJECTS_DIR).join(prefix).join(rest)
+}
+
+// Chunk hashes and sizes, in file order.
+pub type Recipe = Vec<(String, u64)>;
+
+// The list of chunks a file split by `--chunked` is put back together from,
+// named after the hash of the whole file like the object it stands in for.
+pub fn recipe_path(store: &Path, hash: &str) -> PathBuf {
+    let (prefix, rest) = hash.split_at(2.min(hash.len()));
+    store.join(RECIPES_DIR).join(prefix).join(rest)
+}
+
+pub async fn read_recipe(path: &Path) -> Result<Recipe, SyncError> {
+    let contents = fs::read_to_string(path).await?;
+    let mut chunks = Vec::new();
+    for line in contents.lines() {
+        let (hash, size) = line.split_once('\t').ok_or_else(|| SyncError::StoreError(format!("Malformed recipe line: {:?}", line)))?;
+        let size = size.parse().map_err(|_| SyncError::StoreError(format!("Bad size in {:?}", line)))?;
+        chunks.push((hash.to_string(), size));
+    }
+    Ok(chunks)
+}
+
+// Held by store passes and by pruning while they work on a store, so pruning
+// never removes objects a pass has written but not yet recorded in a tree,
+// or has found stored already and is about to reuse. The system releases it
+// with the file, even when the process dies.
+pub struct StoreLock {
+    _file: std::fs::File,
+}
+
+#[cfg(unix)]
+fn try_lock(path: &Path) -> std::io::Result<Option<std::fs::File>> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+    }
+    match std::io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(None),
+        e => Err(e),
+    }
+}
+
+// Opened without sharing, the file itself is the lock.
+#[cfg(windows)]
+fn try_lock(path: &Path) -> std::io::Result<Option<std::fs::File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    match std::fs::OpenOptions::new().create(true).truncate(false).write(true).share_mode(0).open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(32) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn try_lock(path: &Path) -> std::io::Result<Option<std::fs::File>> {
+    std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path).map(Some)
+}
+
+// Locks `store`, waiting for whichever pass or prune holds it to finish.
+pub async fn lock(store: &Path) -> Result<StoreLock, SyncError> {
+    let dir = state::state_dir(store);
+    fs::create_dir_all(&dir).await?;
+    let path = dir.join("store.lock");
+    let mut waiting = false;
+    loop {
+        if let Some(file) = try_lock(&path)? {
+            return Ok(StoreLock { _file: file });
+        }
+        if !waiting {
+            info!("Waiting for another pass or prune of {:?} to finish", store);
+            waiting = true;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+pub async fn list_trees(store: &Path) -> Result<Vec<String>, SyncError> {
+    let mut names = Vec::new();
+    let dir = store.join(TREES_DIR);
+    if !dir.exists() {
+        return Ok(names);
+    }
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if timestamp::parse(&name).is_some() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub async fn read_tree(path: &Path) -> Result<Tree, SyncError> {
+    let contents = fs::read_to_string(path).await?;
+    let mut tree = Tree::new();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        match fields.as_slice() {
+            ["D", path] => {
+                tree.insert(path.to_string(), TreeEntry::Dir);
+            }
+            ["F", hash, size, modified, path] => {
+                let size = size.parse().map_err(|_| SyncError::StoreError(format!("Bad size in {:?}", line)))?;
+                let modified = modified.parse().map_err(|_| SyncError::StoreError(format!("Bad mtime in {:?}", line)))?;
+                tree.insert(path.to_string(), TreeEntry::File { hash: hash.to_string(), size, modified });
+            }
+            _ => return Err(SyncError::StoreError(format!("Malformed tree line: {:?}", line))),
+        }
+    }
+    Ok(tree)
+}
+
+pub async fn write_tree_file(path: &Path, tree: &Tree) -> Result<(), SyncError> {
+    let mut contents = String::new();
+    for (path, entry) in tree {
+        match entry {
+            TreeEntry::Dir => contents.push_str(&format!("D\t{}\n", path)),
+            TreeEntry::File { hash, size, modified } => {
+                contents.push_str(&format!("F\t{}\t{}\t{}\t{}\n", hash, size, modified, path))
+            }
+        }
+    }
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).await?;
+    let tmp = dir.join(format!(".{}.tmp", path.file_name().unwrap().to_string_lossy()));
+    faults::before_write()?;
+    fs::write(&tmp, contents).await?;
+    faults::before_rename()?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+pub async fn latest_tree(store: &Path) -> Result<Option<(String, Tree)>, SyncError> {
+    match list_trees(store).await?.pop() {
+        Some(name) => {
+            let tree = read_tree(&store.join(TREES_DIR).join(&name)).await?;
+            Ok(Some((name, tree)))
+        }
+        None => Ok(None),
+    }
+}
+
+// Changed files are streamed into a temporary object while being hashed and
+// only kept if no object with that hash exists yet.
+async fn store_object(store: &Path, source_path: &Path, options: &SyncOptions, durability: &mut Durability) -> Result<TreeEntry, SyncError> {
+    let objects = store.join(OBJECTS_DIR);
+    fs::create_dir_all(&objects).await?;
+    let tmp = tmp_path(&objects);
+    // Objects are found by name, which a weaker digest could make collide.
+    let options = &SyncOptions { hash: HashAlgorithm::Sha256, ..options.clone() };
+    let copied = copy::copy_file(source_path, &tmp, options).await?;
+    let hash = copied.hash;
+
+    let object = object_path(store, &hash);
+    if object.exists() {
+        debug!("Object already stored for {:?}", source_path);
+        fs::remove_file(&tmp).await?;
+    } else {
+        info!("Storing object {} for {:?}", hash, source_path);
+        fs::create_dir_all(object.parent().unwrap()).await?;
+        faults::before_rename()?;
+        fs::rename(&tmp, &object).await?;
+        durability.written(&object)?;
+    }
+    Ok(TreeEntry::File {
+        hash,
+        size: copied.source.len(),
+        modified: timestamp::to_secs(copied.source.modified()?),
+    })
+}
+
+fn tmp_path(dir: &Path) -> PathBuf {
+    dir.join(format!(".{}-{}.tmp", std::process::id(), TMP_COUNTER.fetch_add(1, Ordering::Relaxed)))
+}
+
+// Stores `chunk` as an object unless one with its hash exists already.
+// Returns the hash, and the object when it was written.
+fn store_chunk(store: &Path, chunk: &[u8]) -> std::io::Result<(String, Option<PathBuf>)> {
+    let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+    hasher.update(chunk);
+    let hash = hasher.finish();
+    let object = object_path(store, &hash);
+    if object.exists() {
+        return Ok((hash, None));
+    }
+    std::fs::create_dir_all(object.parent().unwrap())?;
+    let tmp = tmp_path(&store.join(OBJECTS_DIR));
+    faults::before_write()?;
+    std::fs::write(&tmp, chunk)?;
+    faults::before_rename()?;
+    std::fs::rename(&tmp, &object)?;
+    Ok((hash, Some(object)))
+}
+
+// Splits `source_path` into chunks, storing each new one. Returns the hash
+// of the whole file, its chunks and the objects written.
+fn write_chunks(store: &Path, source_path: &Path, average: usize) -> std::io::Result<(String, Recipe, Vec<PathBuf>)> {
+    let mut file = std::fs::File::open(source_path)?;
+    let mut whole = Hasher::new(HashAlgorithm::Sha256);
+    let (mut list, mut written) = (Vec::new(), Vec::new());
+    let mut keep = |chunk: &[u8]| {
+        whole.update(chunk);
+        let (hash, object) = store_chunk(store, chunk)?;
+        list.push((hash, chunk.len() as u64));
+        written.extend(object);
+        Ok(())
+    };
+    // An empty file is one empty chunk.
+    match file.metadata()?.len() {
+        0 => keep(&[])?,
+        _ => chunks::split(&mut file, &chunks::Chunker::new(average), &mut keep)?,
+    }
+    Ok((whole.finish(), list, written))
+}
+
+// With `--chunked`, files are split into content-defined chunks stored as
+// objects of their own, so content shared between files, or left in place
+// by an edit, is stored once. A file of one chunk is that chunk's object;
+// longer ones get a recipe listing their chunks.
+async fn store_chunked(store: &Path, source_path: &Path, average: u64, options: &SyncOptions, durability: &mut Durability) -> Result<TreeEntry, SyncError> {
+    options.guard_write(store)?;
+    let _permit = scheduler::transfer_permit().await;
+    let metadata = std::fs::metadata(source_path)?;
+    space::ensure_headroom(store, metadata.len(), options)?;
+    let (root, path) = (store.to_path_buf(), source_path.to_path_buf());
+    let (hash, chunks, written) = tokio::task::spawn_blocking(move || write_chunks(&root, &path, average as usize))
+        .await
+        .map_err(|e| SyncError::FileSystemError(std::io::Error::other(e)))??;
+    for object in &written {
+        durability.written(object)?;
+    }
+    let after = std::fs::metadata(source_path)?;
+    if after.len() != metadata.len() || after.modified().ok() != metadata.modified().ok() {
+        warn!("{:?} changed while being stored, the stored version may be inconsistent", source_path);
+    }
+
+    let recipe = recipe_path(store, &hash);
+    if chunks.len() > 1 && !recipe.exists() && !object_path(store, &hash).exists() {
+        info!("Storing {:?} as {} chunks, {} of them new", source_path, chunks.len(), written.len());
+        fs::create_dir_all(recipe.parent().unwrap()).await?;
+        let contents: String = chunks.iter().map(|(hash, size)| format!("{}\t{}\n", hash, size)).collect();
+        let tmp = tmp_path(recipe.parent().unwrap());
+        faults::before_write()?;
+        fs::write(&tmp, contents).await?;
+        faults::before_rename()?;
+        fs::rename(&tmp, &recipe).await?;
+        durability.written(&recipe)?;
+    } else {
+        debug!("Object already stored for {:?}", source_path);
+    }
+    Ok(TreeEntry::File { hash, size: metadata.len(), modified: timestamp::to_secs(metadata.modified()?) })
+}
+
+pub async fn sync_store(source: &str, destination: &str, options: &SyncOptions) -> Result<(), SyncError> {
+    let store = Path::new(destination);
+    let _lock = lock(store).await?;
+    let previous = latest_tree(store).await?.map(|(_, tree)| tree).unwrap_or_default();
+    let mut tree = Tree::new();
+    let mut durability = Durability::new(options.fsync);
+
+    for entry in links::walk(WalkDir::new(source).min_depth(1), options.links) {
+        control::checkpoint(options).await;
+        let entry = entry?;
+        let source_path = entry.path();
+        let key = relative_key(source_path.strip_prefix(source)?);
+
+        if entry.file_type().is_dir() {
+            tree.insert(key, TreeEntry::Dir);
+            continue;
+        }
+        if let Some(kind) = special::kind(&entry.file_type()) {
+            special::skip(source_path, kind);
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let size = metadata.len();
+        let modified = timestamp::to_secs(metadata.modified()?);
+        let file = match previous.get(&key) {
+            Some(TreeEntry::File { hash, size: s, modified: m }) if *s == size && *m == modified => {
+                debug!("Object already stored for {:?}", source_path);
+                TreeEntry::File { hash: hash.clone(), size, modified }
+            }
+            recorded => {
+                if metered::defers(source_path, size, options).await || !locked::readable(source_path, options).await? {
+                    // Keep the previously stored version of a locked or deferred file.
+                    if let Some(recorded) = recorded {
+                        tree.insert(key, recorded.clone());
+                    }
+                    continue;
+                }
+                match options.chunked {
+                    Some(average) => store_chunked(store, source_path, average, options, &mut durability).await?,
+                    None => store_object(store, source_path, options, &mut durability).await?,
+                }
+            }
+        };
+
+        tree.insert(key, file);
+    }
+
+    if tree == previous {
+        debug!("Source unchanged since last tree, not recording a new one");
+    } else {
+        let name = timestamp::now();
+        info!("Recording tree {} with {} entries", name, tree.len());
+        let path = store.join(TREES_DIR).join(&name);
+        write_tree_file(&path, &tree).await?;
+        durability.written(&path)?;
+    }
+    durability.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+
+    fn objects(store: &Path) -> usize {
+        WalkDir::new(store.join(OBJECTS_DIR)).into_iter().filter(|entry| entry.as_ref().unwrap().file_type().is_file()).count()
+    }
+
+    #[test]
+    fn keys_paths_with_forward_slashes() {
+        assert_eq!(relative_key(Path::new("a/b/c.txt")), "a/b/c.txt");
+        assert_eq!(relative_key(Path::new("./a//b/")), "a/b");
+        assert_eq!(relative_key(Path::new("")), "");
+        assert_eq!(key_path(Path::new("/store"), "a/b/c.txt"), Path::new("/store").join("a").join("b").join("c.txt"));
+        assert_eq!(key_path(Path::new("/store"), ""), Path::new("/store"));
+        assert_eq!(object_path(Path::new("/s"), "abcdef"), Path::new("/s/objects/ab/cdef"));
+        assert_eq!(recipe_path(Path::new("/s"), "abcdef"), Path::new("/s/recipes/ab/cdef"));
+    }
+
+    #[tokio::test]
+    async fn reads_back_written_trees() {
+        let dir = scratch("store-tree");
+        let mut tree = Tree::new();
+        tree.insert("docs".to_string(), TreeEntry::Dir);
+        tree.insert("docs/a\tb.txt".to_string(), TreeEntry::File { hash: "ab12".to_string(), size: u64::MAX, modified: 1_700_000_000 });
+        let path = dir.join(TREES_DIR).join("2024-01-01T00:00:00");
+        write_tree_file(&path, &tree).await.unwrap();
+        assert_eq!(read_tree(&path).await.unwrap(), tree);
+        assert_eq!(tree["docs/a\tb.txt"].hash(), Some("ab12"));
+        assert_eq!(tree["docs"].hash(), None);
+
+        for line in ["X\tdocs", "F\tab12\t1\tdocs", "F\tab12\tbig\t1\tdocs", "F\tab12\t1\tlate\tdocs"] {
+            std::fs::write(&path, line).unwrap();
+            assert!(matches!(read_tree(&path).await, Err(SyncError::StoreError(_))), "{}", line);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_trees_oldest_first() {
+        let dir = scratch("store-list");
+        assert!(list_trees(&dir).await.unwrap().is_empty());
+        assert!(latest_tree(&dir).await.unwrap().is_none());
+        for name in ["2024-02-01T00:00:00", "2024-01-01T00:00:00", ".2024-03-01T00:00:00.tmp", "notes"] {
+            write_tree_file(&dir.join(TREES_DIR).join(name), &Tree::new()).await.unwrap();
+        }
+        assert_eq!(list_trees(&dir).await.unwrap(), ["2024-01-01T00:00:00", "2024-02-01T00:00:00"]);
+        assert_eq!(latest_tree(&dir).await.unwrap().unwrap().0, "2024-02-01T00:00:00");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_each_content_once() {
+        let dir = scratch("store-sync");
+        let (source, store) = (dir.join("source"), dir.join("store"));
+        write(&source.join("a.txt"), "same");
+        write(&source.join("sub/b.txt"), "same");
+        write(&source.join("c.txt"), "different");
+        let (source_str, store_str) = (source.to_str().unwrap(), store.to_str().unwrap());
+        sync_store(source_str, store_str, &SyncOptions::default()).await.unwrap();
+        assert_eq!(objects(&store), 2);
+        let (_, tree) = latest_tree(&store).await.unwrap().unwrap();
+        assert_eq!(tree.keys().collect::<Vec<_>>(), ["a.txt", "c.txt", "sub", "sub/b.txt"]);
+        let hash = tree["a.txt"].hash().unwrap();
+        assert_eq!(tree["sub/b.txt"].hash(), Some(hash));
+        assert_eq!(std::fs::read_to_string(object_path(&store, hash)).unwrap(), "same");
+
+        // An unchanged source records no new tree.
+        sync_store(source_str, store_str, &SyncOptions::default()).await.unwrap();
+        assert_eq!(list_trees(&store).await.unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lets_one_holder_lock_a_store_at_a_time() {
+        let dir = scratch("store-lock");
+        let held = lock(&dir).await.unwrap();
+        let path = state::state_dir(&dir).join("store.lock");
+        assert!(try_lock(&path).unwrap().is_none());
+        let waiting = tokio::spawn({
+            let dir = dir.clone();
+            async move { lock(&dir).await.map(|_| ()) }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        drop(held);
+        waiting.await.unwrap().unwrap();
+        assert!(try_lock(&path).unwrap().is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_shared_chunks_once() {
+        let dir = scratch("store-chunked");
+        let (source, store) = (dir.join("source"), dir.join("store"));
+        let mut state = 7u64;
+        let data: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        let mut edited = data.clone();
+        edited.splice(60_000..60_000, *b"an edit");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("a.bin"), &data).unwrap();
+        std::fs::write(source.join("b.bin"), &edited).unwrap();
+        write(&source.join("small.txt"), "one chunk");
+        write(&source.join("empty"), "");
+        let options = SyncOptions { chunked: Some(1024), ..SyncOptions::default() };
+        sync_store(source.to_str().unwrap(), store.to_str().unwrap(), &options).await.unwrap();
+
+        let (_, tree) = latest_tree(&store).await.unwrap().unwrap();
+        let recipe = read_recipe(&recipe_path(&store, tree["a.bin"].hash().unwrap())).await.unwrap();
+        let stored: Vec<u8> = recipe.iter().flat_map(|(hash, _)| std::fs::read(object_path(&store, hash)).unwrap()).collect();
+        assert_eq!(stored, data);
+        assert_eq!(recipe.iter().map(|(_, size)| size).sum::<u64>(), data.len() as u64);
+        // The edit only adds the chunks around it.
+        let edited_chunks = read_recipe(&recipe_path(&store, tree["b.bin"].hash().unwrap())).await.unwrap();
+        let new = edited_chunks.iter().filter(|chunk| !recipe.contains(chunk)).count();
+        assert!(new <= 3, "{} new chunks", new);
+        assert_eq!(objects(&store), recipe.len() + new + 2);
+        // Files of one chunk are that chunk's object.
+        for small in ["small.txt", "empty"] {
+            let hash = tree[small].hash().unwrap();
+            assert!(object_path(&store, hash).exists() && !recipe_path(&store, hash).exists());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
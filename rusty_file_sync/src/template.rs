@@ -0,0 +1,220 @@
+use crate::{timestamp, SyncError};
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    let len = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+}
+
+#[cfg(unix)]
+fn user() -> String {
+    if let Ok(user) = std::env::var("USER") {
+        return user;
+    }
+    // Services often run without USER set.
+    let passwd = unsafe { libc::getpwuid(libc::getuid()) };
+    if passwd.is_null() {
+        return String::new();
+    }
+    unsafe { std::ffi::CStr::from_ptr((*passwd).pw_name) }.to_string_lossy().into_owned()
+}
+
+#[cfg(not(unix))]
+fn user() -> String {
+    std::env::var("USERNAME").unwrap_or_default()
+}
+
+// Expands `{hostname}`, `{user}`, `{date}` (UTC `YYYY-MM-DD`) and
+// `{datetime}` (UTC `YYYY-MM-DDTHH:MM:SS`) in `template`; `{{` and `}}`
+// stand for literal braces.
+pub fn expand(template: &str) -> Result<String, SyncError> {
+    let now = timestamp::now();
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            expanded.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let end = match (tail.starts_with('{'), tail.find('}')) {
+            (true, Some(end)) => end,
+            _ => return Err(SyncError::ConfigError(format!("Unbalanced brace in {:?}", template))),
+        };
+        match &tail[1..end] {
+            "hostname" => expanded.push_str(&hostname()),
+            "user" => expanded.push_str(&user()),
+            "date" => expanded.push_str(&now[..10]),
+            "datetime" => expanded.push_str(&now),
+            name => return Err(SyncError::ConfigError(format!("Unknown variable {{{}}} in {:?}", name, template))),
+        }
+        rest = &tail[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// The directory `template` lives in before any variable is expanded, such
+// as `/mnt/backup` for `/mnt/backup/{hostname}/{date}`: the part that has to
+// exist already rather than be created for a new date.
+pub fn fixed_root(template: &str) -> &str {
+    let fixed = &template[..template.find('{').unwrap_or(template.len())];
+    match fixed.rfind(['/', '\\']) {
+        Some(0) => &fixed[..1],
+        Some(end) => &fixed[..end],
+        None => "",
+    }
+}
+
+// Home directory of `name`, or of whoever runs us when it is empty.
+#[cfg(unix)]
+fn home(name: &str) -> Option<String> {
+    if name.is_empty() {
+        if let Ok(home) = std::env::var("HOME") {
+            return Some(home);
+        }
+    }
+    let passwd = match name.is_empty() {
+        true => unsafe { libc::getpwuid(libc::getuid()) },
+        false => {
+            let c_name = std::ffi::CString::new(name).ok()?;
+            unsafe { libc::getpwnam(c_name.as_ptr()) }
+        }
+    };
+    if passwd.is_null() {
+        return None;
+    }
+    Some(unsafe { std::ffi::CStr::from_ptr((*passwd).pw_dir) }.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn home(name: &str) -> Option<String> {
+    match name.is_empty() {
+        true => std::env::var("USERPROFILE").ok(),
+        false => None,
+    }
+}
+
+// Expands a leading `~` or `~user`, and `$VAR` or `${VAR}` anywhere, the way
+// a shell would, so paths in configs shared between users needn't be
+// absolute. A `$` followed by anything else, as in `\\server\c$`, is kept.
+pub fn expand_path(path: &str) -> Result<String, SyncError> {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if let Some(tilde) = path.strip_prefix('~') {
+        let end = tilde.find(['/', '\\']).unwrap_or(tilde.len());
+        let name = &tilde[..end];
+        let dir = home(name).ok_or_else(|| match name {
+            "" => SyncError::ConfigError(format!("No home directory to expand ~ to in {:?}", path)),
+            name => SyncError::ConfigError(format!("Unknown user ~{} in {:?}", name, path)),
+        })?;
+        expanded.push_str(&dir);
+        rest = &tilde[end..];
+    }
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let tail = &rest[start + 1..];
+        let (name, after) = match tail.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err(SyncError::ConfigError(format!("Unbalanced brace in {:?}", path))),
+            },
+            None => {
+                let end = tail.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(tail.len());
+                (&tail[..end], &tail[end..])
+            }
+        };
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            expanded.push('$');
+            rest = tail;
+            continue;
+        }
+        let value = std::env::var(name).map_err(|_| SyncError::ConfigError(format!("Environment variable ${} in {:?} is not set", name, path)))?;
+        expanded.push_str(&value);
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_variables() {
+        assert_eq!(expand("/backup/{hostname}").unwrap(), format!("/backup/{}", hostname()));
+        assert_eq!(expand("{user}@{hostname}").unwrap(), format!("{}@{}", user(), hostname()));
+        assert!(!hostname().is_empty());
+        let date = expand("{date}").unwrap();
+        assert_eq!(date.len(), 10);
+        assert!(timestamp::parse(&date).is_some());
+        let datetime = expand("{datetime}").unwrap();
+        assert!(datetime.starts_with(&date[..8]) && datetime.len() == 19);
+        assert!(timestamp::parse(&datetime).is_some());
+    }
+
+    #[test]
+    fn keeps_doubled_braces_literal() {
+        assert_eq!(expand("a{{b}}c").unwrap(), "a{b}c");
+        assert_eq!(expand("{{date}}").unwrap(), "{date}");
+        assert_eq!(expand("no variables").unwrap(), "no variables");
+    }
+
+    #[test]
+    fn rejects_unknown_variables_and_stray_braces() {
+        for template in ["{host}", "{}", "a{date", "a}b", "{date}}"] {
+            assert!(matches!(expand(template), Err(SyncError::ConfigError(_))), "{}", template);
+        }
+    }
+
+    #[test]
+    fn expands_home_directories() {
+        let home = home("").unwrap();
+        assert_eq!(expand_path("~").unwrap(), home);
+        assert_eq!(expand_path("~/backup").unwrap(), format!("{}/backup", home));
+        // Only a leading tilde is special.
+        assert_eq!(expand_path("a/~/b").unwrap(), "a/~/b");
+        assert!(expand_path("~no-such-user-here/x").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn expands_other_users_homes() {
+        assert_eq!(expand_path("~root/x").unwrap(), format!("{}/x", home("root").unwrap()));
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        std::env::set_var("RUSTY_FILE_SYNC_TEST_DIR", "/data");
+        assert_eq!(expand_path("$RUSTY_FILE_SYNC_TEST_DIR/a").unwrap(), "/data/a");
+        assert_eq!(expand_path("${RUSTY_FILE_SYNC_TEST_DIR}x").unwrap(), "/datax");
+        // A `$` that starts no name is kept.
+        assert_eq!(expand_path(r"\\server\c$").unwrap(), r"\\server\c$");
+        assert_eq!(expand_path("cost$5/$").unwrap(), "cost$5/$");
+        assert!(expand_path("$RUSTY_FILE_SYNC_TEST_UNSET").is_err());
+        assert!(expand_path("${RUSTY_FILE_SYNC_TEST_DIR").is_err());
+    }
+
+    #[test]
+    fn finds_the_directory_before_any_variable() {
+        assert_eq!(fixed_root("/mnt/backup/{hostname}/{date}"), "/mnt/backup");
+        assert_eq!(fixed_root("/mnt/backup/host-{hostname}"), "/mnt/backup");
+        assert_eq!(fixed_root("/{user}"), "/");
+        assert_eq!(fixed_root(r"D:\backup\{date}"), r"D:\backup");
+        assert_eq!(fixed_root("{hostname}/x"), "");
+        assert_eq!(fixed_root("relative"), "");
+        assert_eq!(fixed_root("/mnt/backup"), "/mnt");
+    }
+}
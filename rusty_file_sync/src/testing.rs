@@ -0,0 +1,23 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// A fresh empty directory under the system's temporary one, named after the
+// test so parallel tests don't share it.
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rusty_file_sync-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Writes `contents` to `path`, creating its parents.
+pub fn write(path: &Path, contents: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+// Sets the modification time of `path` to `ago` seconds before now.
+pub fn touch(path: &Path, ago: u64) {
+    let file = std::fs::File::options().write(true).open(path).unwrap();
+    file.set_modified(SystemTime::now() - Duration::from_secs(ago)).unwrap();
+}
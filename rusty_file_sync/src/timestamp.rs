@@ -0,0 +1,205 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Timestamps are rendered in UTC as `YYYY-MM-DDTHH:MM:SS`, which sorts
+// lexically in chronological order and is used for snapshot and tree names.
+pub fn format(time: SystemTime) -> String {
+    let formatted = humantime::format_rfc3339_seconds(time).to_string();
+    formatted.trim_end_matches('Z').to_string()
+}
+
+pub fn now() -> String {
+    format(SystemTime::now())
+}
+
+// Accepts `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM` and `YYYY-MM-DDTHH:MM:SS`, with
+// either `T` or a space between date and time and an optional trailing `Z`.
+pub fn parse(value: &str) -> Option<SystemTime> {
+    let value = value.trim().trim_end_matches('Z');
+    let full = match value.len() {
+        10 => format!("{}T00:00:00", value),
+        16 => format!("{}:00", value),
+        _ => value.to_string(),
+    };
+    humantime::parse_rfc3339_weak(&full).ok()
+}
+
+pub fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+pub fn from_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+// Whether `a` is newer than `b` by more than `window`, the slack allowed
+// for file systems that round modification times.
+pub fn newer(a: SystemTime, b: SystemTime, window: Duration) -> bool {
+    a.duration_since(b).is_ok_and(|ahead| ahead > window)
+}
+
+// FAT stores local time rather than UTC, so Windows shows every file on it an
+// hour off once daylight saving time starts or ends.
+const DST_SHIFT: Duration = Duration::from_secs(3600);
+
+// Whether `a` and `b` are an hour apart, give or take `window`.
+pub fn dst_shifted(a: SystemTime, b: SystemTime, window: Duration) -> bool {
+    let apart = a.duration_since(b).unwrap_or_else(|e| e.duration());
+    apart.abs_diff(DST_SHIFT) <= window
+}
+
+// What to do with a file whose only difference is a modification time
+// exactly an hour off.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DstShift {
+    #[default]
+    Copy,
+    Ignore,
+}
+
+impl std::str::FromStr for DstShift {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "copy" => Ok(DstShift::Copy),
+            "ignore" => Ok(DstShift::Ignore),
+            _ => Err(format!("Invalid DST shift policy: {} (expected copy or ignore)", value)),
+        }
+    }
+}
+
+// FAT and exFAT keep modification times to two seconds.
+const FAT_GRANULARITY: Duration = Duration::from_secs(2);
+
+// How coarsely the file system holding `path` keeps modification times,
+// when it is one known for rounding them.
+#[cfg(target_os = "linux")]
+pub fn coarse_granularity(path: &Path) -> Option<Duration> {
+    use std::os::unix::ffi::OsStrExt;
+
+    const MSDOS_SUPER_MAGIC: i64 = 0x4d44;
+    const EXFAT_SUPER_MAGIC: i64 = 0x2011_bab0;
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    [MSDOS_SUPER_MAGIC, EXFAT_SUPER_MAGIC].contains(&(stat.f_type as i64)).then_some(FAT_GRANULARITY)
+}
+
+#[cfg(target_os = "macos")]
+pub fn coarse_granularity(path: &Path) -> Option<Duration> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    matches!(name.to_bytes(), b"msdos" | b"exfat").then_some(FAT_GRANULARITY)
+}
+
+#[cfg(windows)]
+pub fn coarse_granularity(path: &Path) -> Option<Duration> {
+    use std::os::windows::ffi::OsStrExt;
+
+    extern "system" {
+        fn GetVolumePathNameW(file: *const u16, volume: *mut u16, length: u32) -> i32;
+        fn GetVolumeInformationW(
+            root: *const u16,
+            name: *mut u16,
+            name_length: u32,
+            serial: *mut u32,
+            component_length: *mut u32,
+            flags: *mut u32,
+            file_system: *mut u16,
+            file_system_length: u32,
+        ) -> i32;
+    }
+
+    let existing = crate::paths::share_root(path.ancestors().find(|ancestor| ancestor.exists())?);
+    let wide: Vec<u16> = existing.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut volume = [0u16; 261];
+    let mut file_system = [0u16; 32];
+    let found = unsafe {
+        GetVolumePathNameW(wide.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) != 0
+            && GetVolumeInformationW(
+                volume.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                file_system.as_mut_ptr(),
+                file_system.len() as u32,
+            ) != 0
+    };
+    let end = file_system.iter().position(|c| *c == 0).unwrap_or(file_system.len());
+    let name = String::from_utf16_lossy(&file_system[..end]);
+    (found && matches!(name.as_str(), "FAT" | "FAT32" | "exFAT")).then_some(FAT_GRANULARITY)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn coarse_granularity(_path: &Path) -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_parses_utc_times() {
+        let time = from_secs(1_700_000_000);
+        assert_eq!(format(time), "2023-11-14T22:13:20");
+        assert_eq!(parse(&format(time)), Some(time));
+        assert_eq!(to_secs(time), 1_700_000_000);
+        // Sub-second parts are dropped.
+        assert_eq!(format(time + Duration::from_millis(999)), "2023-11-14T22:13:20");
+        assert_eq!(to_secs(UNIX_EPOCH - Duration::from_secs(1)), 0);
+    }
+
+    #[test]
+    fn parses_shorter_and_spaced_forms() {
+        assert_eq!(parse("2023-11-14"), Some(from_secs(1_699_920_000)));
+        assert_eq!(parse("2023-11-14T22:13"), Some(from_secs(1_700_000_000 - 20)));
+        assert_eq!(parse(" 2023-11-14 22:13:20Z "), Some(from_secs(1_700_000_000)));
+        for value in ["", "2023", "2023-11", "2023-13-01", "14/11/2023", "2023-11-14T25:00"] {
+            assert_eq!(parse(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn is_newer_only_beyond_the_window() {
+        let (t, window) = (from_secs(1_000), Duration::from_secs(2));
+        assert!(newer(t + Duration::from_secs(3), t, window));
+        assert!(!newer(t + Duration::from_secs(2), t, window));
+        assert!(!newer(t, t, window));
+        assert!(!newer(t, t + Duration::from_secs(60), window));
+        assert!(newer(t + Duration::from_nanos(1), t, Duration::ZERO));
+    }
+
+    #[test]
+    fn judges_missing_paths_by_their_nearest_ancestor() {
+        let dir = crate::testing::scratch("timestamp-granularity");
+        assert_eq!(coarse_granularity(&dir.join("not/yet")), coarse_granularity(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn spots_times_an_hour_apart() {
+        let (t, window) = (from_secs(10_000), Duration::from_secs(2));
+        assert!(dst_shifted(t + DST_SHIFT, t, window));
+        assert!(dst_shifted(t, t + DST_SHIFT, window));
+        assert!(dst_shifted(t + DST_SHIFT + window, t, window));
+        assert!(dst_shifted(t + DST_SHIFT - window, t, window));
+        assert!(!dst_shifted(t + DST_SHIFT + Duration::from_secs(3), t, window));
+        assert!(!dst_shifted(t, t, window));
+        assert_eq!("ignore".parse(), Ok(DstShift::Ignore));
+        assert!("skip".parse::<DstShift>().is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::copy::Copied;
+use crate::itemize::Change;
+use crate::options::SyncOptions;
+use crate::store::TreeEntry;
+use crate::{attrs, calculate_hash, scheduler, space, timestamp, SyncError};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    CrlfToLf,
+    LfToCrlf,
+    StripBom,
+    // Shell command reading the file on stdin and writing the result to stdout.
+    Exec(String),
+}
+
+// A transform, optionally limited to files with some extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformRule {
+    pub transform: Transform,
+    pub extensions: Vec<String>,
+}
+
+// Rules look like `crlf-to-lf`, `strip-bom@txt,csv` or
+// `exec:jpegoptim --stdout -@jpg,jpeg`.
+impl std::str::FromStr for TransformRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, extensions) = match value.rsplit_once('@') {
+            Some((name, extensions)) => (name, extensions.split(',').map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase()).collect()),
+            None => (value, Vec::new()),
+        };
+        let transform = match name {
+            "crlf-to-lf" => Transform::CrlfToLf,
+            "lf-to-crlf" => Transform::LfToCrlf,
+            "strip-bom" => Transform::StripBom,
+            _ => match name.strip_prefix("exec:") {
+                Some(command) if !command.trim().is_empty() => Transform::Exec(command.to_string()),
+                _ => return Err(format!("Invalid transform: {} (expected crlf-to-lf, lf-to-crlf, strip-bom or exec:<command>)", name)),
+            },
+        };
+        Ok(TransformRule { transform, extensions })
+    }
+}
+
+pub fn matching<'a>(rules: &'a [TransformRule], path: &Path) -> Vec<&'a Transform> {
+    let extension = path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    rules
+        .iter()
+        .filter(|rule| rule.extensions.is_empty() || extension.as_ref().is_some_and(|ext| rule.extensions.contains(ext)))
+        .map(|rule| &rule.transform)
+        .collect()
+}
+
+// Transformed copies never match their source, so they are checked against
+// the source version recorded when they were written: same size and mtime,
+// or failing that the same content. Returns the record for the current
+// source version when the copy is still up to date.
+pub async fn unchanged_record(record: Option<&TreeEntry>, source: &Path, metadata: &std::fs::Metadata, buffer_size: usize) -> Result<Option<TreeEntry>, SyncError> {
+    let Some(TreeEntry::File { hash, size, modified }) = record else {
+        return Ok(None);
+    };
+    let current_modified = timestamp::to_secs(metadata.modified()?);
+    if *size == metadata.len() && *modified == current_modified {
+        return Ok(record.cloned());
+    }
+    if *size == metadata.len() && calculate_hash(source, buffer_size).await? == *hash {
+        return Ok(Some(TreeEntry::File { hash: hash.clone(), size: *size, modified: current_modified }));
+    }
+    Ok(None)
+}
+
+// Why a transformed file with no unchanged record is copied again, judged
+// against the source it was last transformed from.
+pub fn change(record: Option<&TreeEntry>, metadata: &std::fs::Metadata) -> Result<Change, SyncError> {
+    let Some(TreeEntry::File { size, modified, .. }) = record else {
+        return Ok(Change::Updated { checksum: false, size: false, time: false });
+    };
+    let size = *size != metadata.len();
+    // Only files of the same size have their contents compared.
+    Ok(Change::Updated { checksum: !size, size, time: *modified != timestamp::to_secs(metadata.modified()?) })
+}
+
+fn crlf_to_lf(data: Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, byte) in data.iter().enumerate() {
+        if *byte != b'\r' || data.get(i + 1) != Some(&b'\n') {
+            out.push(*byte);
+        }
+    }
+    out
+}
+
+fn lf_to_crlf(data: Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 32);
+    for (i, byte) in data.iter().enumerate() {
+        if *byte == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(*byte);
+    }
+    out
+}
+
+async fn exec(command: &str, input: Vec<u8>) -> Result<Vec<u8>, SyncError> {
+    let mut child = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(command).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?
+    } else {
+        Command::new("sh").arg("-c").arg(command).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?
+    };
+    // Fed from a separate task so a command producing output before it has
+    // read all of its input can't deadlock.
+    let mut stdin = child.stdin.take().unwrap();
+    let feeder = tokio::spawn(async move { stdin.write_all(&input).await });
+    let output = child.wait_with_output().await?;
+    if let Ok(Err(e)) = feeder.await {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(e.into());
+        }
+    }
+    if !output.status.success() {
+        return Err(SyncError::TransformError(format!("{:?} exited with {}", command, output.status)));
+    }
+    Ok(output.stdout)
+}
+
+// Copies `source` to `dest` through `transforms`, returning the hash of the
+// source content alongside the copy, whose hash is that of what was written.
+// Files are transformed in memory.
+pub async fn copy_transformed(source: &Path, dest: &Path, transforms: &[&Transform], options: &SyncOptions) -> Result<(String, Copied), SyncError> {
+    let _permit = scheduler::transfer_permit().await;
+    options.observer.on_file_start(source);
+    let metadata = std::fs::metadata(source)?;
+    let mut data = tokio::fs::read(source).await?;
+    let source_hash = format!("{:x}", Sha256::digest(&data));
+
+    for transform in transforms {
+        data = match transform {
+            Transform::CrlfToLf => crlf_to_lf(data),
+            Transform::LfToCrlf => lf_to_crlf(data),
+            Transform::StripBom => match data.strip_prefix(BOM) {
+                Some(stripped) => stripped.to_vec(),
+                None => data,
+            },
+            Transform::Exec(command) => exec(command, data).await?,
+        };
+    }
+
+    space::ensure_headroom(dest, data.len() as u64, options)?;
+    options.guard_write(dest)?;
+    tokio::fs::write(dest, &data).await?;
+    attrs::settle(&metadata, dest, options)?;
+    options.observer.on_file_done(source, metadata.len());
+    let hash = format!("{:x}", Sha256::digest(&data));
+    Ok((source_hash, Copied { hash, source: metadata }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    fn rule(value: &str) -> TransformRule {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(rule("crlf-to-lf"), TransformRule { transform: Transform::CrlfToLf, extensions: vec![] });
+        assert_eq!(rule("strip-bom@txt, .CSV").extensions, ["txt", "csv"]);
+        // Only the last `@` starts the extensions, leaving the command whole.
+        assert_eq!(
+            rule("exec:jpegoptim --stdout -@jpg,jpeg"),
+            TransformRule { transform: Transform::Exec("jpegoptim --stdout -".to_string()), extensions: vec!["jpg".to_string(), "jpeg".to_string()] }
+        );
+        for value in ["", "crlf", "exec:", "exec:  @txt", "lf-to-CRLF"] {
+            assert!(value.parse::<TransformRule>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn matches_rules_by_extension() {
+        let rules = [rule("strip-bom"), rule("crlf-to-lf@txt"), rule("lf-to-crlf@bat")];
+        assert_eq!(matching(&rules, Path::new("a/notes.TXT")), [&Transform::StripBom, &Transform::CrlfToLf]);
+        assert_eq!(matching(&rules, Path::new("run.bat")), [&Transform::StripBom, &Transform::LfToCrlf]);
+        assert_eq!(matching(&rules, Path::new("Makefile")), [&Transform::StripBom]);
+    }
+
+    #[test]
+    fn converts_line_endings() {
+        assert_eq!(crlf_to_lf(b"a\r\nb\rc\r\n\r\n".to_vec()), b"a\nb\rc\n\n");
+        assert_eq!(lf_to_crlf(b"\na\nb\r\nc".to_vec()), b"\r\na\r\nb\r\nc");
+        // Converting back and forth is lossless for consistent endings.
+        assert_eq!(crlf_to_lf(lf_to_crlf(b"x\ny\n".to_vec())), b"x\ny\n");
+    }
+
+    #[tokio::test]
+    async fn copies_through_each_transform_in_turn() {
+        let dir = scratch("transform-copy");
+        let (source, dest) = (dir.join("in.txt"), dir.join("out.txt"));
+        std::fs::write(&source, b"\xEF\xBB\xBFone\r\ntwo\r\n").unwrap();
+        let transforms = [Transform::StripBom, Transform::CrlfToLf];
+        let (source_hash, copied) = copy_transformed(&source, &dest, &transforms.iter().collect::<Vec<_>>(), &SyncOptions::default()).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"one\ntwo\n");
+        assert_eq!(source_hash, calculate_hash(&source, 4096).await.unwrap());
+        assert_eq!(copied.hash, calculate_hash(&dest, 4096).await.unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn runs_commands_over_the_content() {
+        assert_eq!(exec("tr a-z A-Z", b"shout".to_vec()).await.unwrap(), b"SHOUT");
+        // Commands that ignore their input still succeed.
+        assert_eq!(exec("echo hi", vec![b'x'; 1 << 20]).await.unwrap(), b"hi\n");
+        assert!(matches!(exec("exit 3", Vec::new()).await, Err(SyncError::TransformError(_))));
+    }
+}
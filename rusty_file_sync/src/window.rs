@@ -0,0 +1,142 @@
+use log::info;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How often a job waiting for its window checks the clock again.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// A daily range of local time, in minutes since midnight; `end` before
+// `start` wraps past midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    start: u32,
+    end: u32,
+}
+
+fn parse_time(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours <= 24 && minutes < 60 && hours * 60 + minutes <= 24 * 60).then_some(hours * 60 + minutes)
+}
+
+// Windows look like `22:00-06:00`.
+impl std::str::FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid time window: {} (expected HH:MM-HH:MM)", value);
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+        match (parse_time(start), parse_time(end)) {
+            (Some(start), Some(end)) if start != end => Ok(TimeWindow { start, end }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TimeWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+#[cfg(unix)]
+fn local_minute() -> u32 {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return utc_minute();
+    }
+    tm.tm_hour as u32 * 60 + tm.tm_min as u32
+}
+
+// Without a portable way to get the local offset, other platforms use UTC.
+#[cfg(not(unix))]
+fn local_minute() -> u32 {
+    utc_minute()
+}
+
+fn utc_minute() -> u32 {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    ((secs % 86400) / 60) as u32
+}
+
+// No windows means any time is fine.
+pub fn is_open(windows: &[TimeWindow]) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(local_minute()))
+}
+
+pub async fn wait_for_window(windows: &[TimeWindow]) {
+    if is_open(windows) {
+        return;
+    }
+    info!("Outside the allowed time windows, waiting");
+    while !is_open(windows) {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    info!("Time window open");
+}
+
+// What happens to a pass still running when its window closes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WindowPolicy {
+    #[default]
+    Continue,
+    Pause,
+}
+
+impl std::str::FromStr for WindowPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "continue" => Ok(WindowPolicy::Continue),
+            "pause" => Ok(WindowPolicy::Pause),
+            _ => Err(format!("Invalid window policy: {} (expected continue or pause)", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(value: &str) -> TimeWindow {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(window("09:30-17:00"), TimeWindow { start: 570, end: 1020 });
+        assert_eq!(window(" 22:00 - 6:00 "), TimeWindow { start: 1320, end: 360 });
+        assert_eq!(window("00:00-24:00"), TimeWindow { start: 0, end: 1440 });
+        for value in ["", "22:00", "22-06", "22:00-22:00", "25:00-01:00", "24:01-01:00", "10:60-11:00", "-1:00-02:00"] {
+            assert!(value.parse::<TimeWindow>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn contains_its_start_but_not_its_end() {
+        let day = window("09:00-17:00");
+        assert!(day.contains(540) && day.contains(1019));
+        assert!(!day.contains(539) && !day.contains(1020));
+        assert!(window("00:00-24:00").contains(1439));
+    }
+
+    #[test]
+    fn wraps_past_midnight() {
+        let night = window("22:00-06:00");
+        assert!(night.contains(1320) && night.contains(1439) && night.contains(0) && night.contains(359));
+        assert!(!night.contains(360) && !night.contains(720) && !night.contains(1319));
+    }
+
+    #[test]
+    fn is_open_without_windows() {
+        assert!(is_open(&[]));
+        assert!(is_open(&[window("00:00-24:00")]));
+        assert_eq!("pause".parse(), Ok(WindowPolicy::Pause));
+        assert!("stop".parse::<WindowPolicy>().is_err());
+    }
+}